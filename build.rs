@@ -48,12 +48,17 @@ fn main() {
     std::fs::create_dir_all(&template_output_dir).unwrap();
     for t in templates_dir.read_dir().unwrap() {
         let template_path = t.unwrap().path();
-        if !matches!(
-            template_path.extension().map(|s| s.to_str().unwrap()),
-            Some("html") | Some("css")
-        ) {
-            continue;
+        match template_path.extension().map(|s| s.to_str().unwrap()) {
+            Some("html") | Some("css") => minify_template(&template_path, &template_output_dir),
+            // Not HTML, so it's copied as it is
+            Some("xml") => {
+                fs::copy(
+                    &template_path,
+                    template_output_dir.join(template_path.file_name().unwrap()),
+                )
+                .unwrap();
+            }
+            _ => {}
         }
-        minify_template(&template_path, &template_output_dir);
     }
 }
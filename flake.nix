@@ -28,8 +28,9 @@
         };
 
         craneLib = (crane.mkLib pkgs).overrideToolchain rustToolchain;
-        templateFilter = path: _type: builtins.match ".*templates/.*html$" path != null;
-        templateOrCargo = path: type: (templateFilter path type) || (craneLib.filterCargoSources path type);
+        templateFilter = path: _type: builtins.match ".*templates/.*" path != null;
+        staticFilter = path: _type: builtins.match ".*static/.*" path != null;
+        templateOrCargo = path: type: (templateFilter path type) || (staticFilter path type) || (craneLib.filterCargoSources path type);
         src = pkgs.lib.cleanSourceWith {
           src = ./.;
           filter = templateOrCargo;
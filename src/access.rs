@@ -0,0 +1,204 @@
+use axum::{async_trait, extract::FromRequestParts, http::request::Parts};
+use camino::{Utf8Path, Utf8PathBuf};
+use std::{convert::Infallible, str::FromStr};
+
+use crate::{
+    archive::ArchiveEntry,
+    auth::ApiToken,
+    dir_cache::{CacheEntry, DirContents},
+    dir_view::normalise_path,
+    AppState,
+};
+
+/// Who can reach what's under a path
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Access {
+    /// Anyone, with or without a token
+    Public,
+    /// Only these tokens, or any of them if one is `*`
+    Tokens(Vec<String>),
+}
+
+/// Who can reach a directory and everything inside it, parsed from `<path> => public` or
+/// `<path> => <token>, <token>`, with `unlisted` before who can reach it to hide it too
+///
+/// The rule with the longest path wins, paths without one need a token if there are any
+#[derive(Debug, Clone)]
+pub struct AccessRule {
+    pub path: Utf8PathBuf,
+    pub access: Access,
+    /// Left out of the listings, archives and searches of the directories it's in, so it can
+    /// only be reached by knowing where it is
+    pub unlisted: bool,
+}
+
+impl FromStr for AccessRule {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let Some((path, access)) = s.split_once("=>") else {
+            return Err(format!(
+                "Access rule should be `<path> => public` or `<path> => <token>, <token>`, was {s}"
+            ));
+        };
+        let path = normalise_path(Utf8Path::new(path.trim().trim_start_matches('/')))
+            .map_err(|e| format!("Invalid path for access rule: {e}"))?;
+        let access = access.trim();
+        let (unlisted, access) = match access.strip_prefix("unlisted ") {
+            Some(access) => (true, access.trim()),
+            None => (false, access),
+        };
+        let access = match access {
+            "public" => Access::Public,
+            tokens => {
+                let tokens: Vec<_> = tokens
+                    .split(',')
+                    .map(str::trim)
+                    .filter(|t| !t.is_empty())
+                    .map(str::to_owned)
+                    .collect();
+                if tokens.is_empty() {
+                    return Err(format!("Access rule for {path:?} allows no one"));
+                }
+                Access::Tokens(tokens)
+            }
+        };
+        Ok(Self {
+            path,
+            access,
+            unlisted,
+        })
+    }
+}
+
+/// Whoever made the request, with the token they sent if it was valid
+#[derive(Debug, Clone, Default)]
+pub struct Visitor {
+    pub token: Option<ApiToken>,
+}
+
+/// Put in the request by the middleware checking tokens, nobody if it didn't
+#[async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for Visitor {
+    type Rejection = Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        Ok(parts.extensions.get::<Self>().cloned().unwrap_or_default())
+    }
+}
+
+impl Visitor {
+    /// Whether the file or directory at `path` can be seen and downloaded
+    pub fn can_reach(&self, state: &AppState, path: &Utf8Path) -> bool {
+        let rule = state
+            .access_rules
+            .iter()
+            .filter(|r| path.starts_with(&r.path))
+            .max_by_key(|r| r.path.components().count());
+        if rule.is_some_and(|r| r.access == Access::Public) {
+            return true;
+        }
+        let Some(token) = &self.token else {
+            return rule.is_none() && state.api_tokens.is_empty();
+        };
+        if !token.allows(Some(path)) {
+            return false;
+        }
+        match rule.map(|r| &r.access) {
+            Some(Access::Tokens(tokens)) => tokens.iter().any(|t| t == "*" || token.matches(t)),
+            _ => true,
+        }
+    }
+
+    /// Whether `path` is left out of the entries of the directories it's in, even if it can be
+    /// reached, because it's unlisted or a hidden mount
+    fn is_hidden(&self, state: &AppState, path: &Utf8Path) -> bool {
+        !self.can_reach(state, path)
+            || state
+                .access_rules
+                .iter()
+                .any(|r| r.unlisted && r.path == path)
+            || state.roots.hidden_mounts().any(|m| m == path)
+    }
+
+    /// Whether nothing can't be reached, so routes that aren't of a single path, like the
+    /// download stats, can show anything
+    pub fn reaches_everything(&self, state: &AppState) -> bool {
+        self.can_reach(state, Utf8Path::new(""))
+            && state
+                .access_rules
+                .iter()
+                .all(|r| self.can_reach(state, &r.path))
+    }
+
+    /// Whether something under `dir` could be reached differently than `dir` itself, because a
+    /// rule, the token or a hidden mount starts below it
+    fn changes_under(&self, state: &AppState, dir: &Utf8Path) -> bool {
+        let token_path = self.token.as_ref().and_then(|t| t.prefix.as_deref());
+        state
+            .access_rules
+            .iter()
+            .map(|r| r.path.as_path())
+            .chain(token_path)
+            .chain(state.roots.hidden_mounts())
+            .any(|path| path != dir && path.starts_with(dir))
+    }
+
+    /// `entries` of `dir` without the ones that can't be reached or are unlisted, at any depth, or
+    /// `None` if none of them are
+    pub fn prune(
+        &self,
+        state: &AppState,
+        dir: &Utf8Path,
+        entries: &DirContents,
+    ) -> Option<DirContents> {
+        if !self.changes_under(state, dir) {
+            return None;
+        }
+        let mut changed = false;
+        let mut kept = Vec::with_capacity(entries.len());
+        for entry in entries.iter() {
+            let path = dir.join(entry.name());
+            if self.is_hidden(state, &path) {
+                changed = true;
+                continue;
+            }
+            match entry {
+                CacheEntry::Dir(d) => match self.prune(state, &path, &d.children) {
+                    Some(children) => {
+                        changed = true;
+                        let mut d = d.clone();
+                        d.children = children;
+                        d.update_totals();
+                        kept.push(CacheEntry::Dir(d));
+                    }
+                    None => kept.push(entry.clone()),
+                },
+                CacheEntry::File(_) => kept.push(entry.clone()),
+            }
+        }
+        changed.then(|| kept.into())
+    }
+
+    /// Removes the `entries` collected from `dir` that can't be reached or are unlisted, whose
+    /// names start with `prefix`, returning whether there were any
+    pub fn retain_reachable(
+        &self,
+        state: &AppState,
+        dir: &Utf8Path,
+        prefix: &str,
+        entries: &mut Vec<ArchiveEntry>,
+    ) -> bool {
+        if !self.changes_under(state, dir) {
+            return false;
+        }
+        let len = entries.len();
+        entries.retain(|e| {
+            let Some(name) = e.name.strip_prefix(prefix) else {
+                return true;
+            };
+            !self.is_hidden(state, &dir.join(name.trim_end_matches('/')))
+        });
+        entries.len() != len
+    }
+}
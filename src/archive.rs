@@ -0,0 +1,1247 @@
+use axum::body::Body;
+use bytes::Bytes;
+use camino::{Utf8Path, Utf8PathBuf};
+use chrono::{DateTime, Datelike as _, Timelike as _, Utc};
+use flate2::{
+    write::{DeflateEncoder, GzEncoder},
+    Compression,
+};
+use serde::Deserialize;
+use sha2::{Digest as _, Sha256};
+use std::{
+    io::{self, Write as _},
+    ops::Range,
+    sync::Arc,
+};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt as _},
+    sync::mpsc,
+};
+use tracing::{debug, error, warn};
+
+use crate::archive_cache::SpoolFile;
+use crate::checksums::Checksums;
+use crate::dir_cache::CacheEntry;
+use crate::roots::DataRoots;
+
+/// Size of the chunks sent to the client, and read from files
+const CHUNK_SIZE: usize = 64 * 1024;
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum ArchiveFormat {
+    #[default]
+    #[serde(rename = "zip")]
+    Zip,
+    #[serde(rename = "tar.gz")]
+    TarGz,
+    #[serde(rename = "tar.zst")]
+    TarZst,
+}
+
+impl ArchiveFormat {
+    pub const fn extension(self) -> &'static str {
+        match self {
+            Self::Zip => "zip",
+            Self::TarGz => "tar.gz",
+            Self::TarZst => "tar.zst",
+        }
+    }
+
+    pub const fn content_type(self) -> &'static str {
+        match self {
+            Self::Zip => "application/zip",
+            Self::TarGz => "application/gzip",
+            Self::TarZst => "application/zstd",
+        }
+    }
+}
+
+/// Where the contents of an entry come from
+#[derive(Debug, Clone)]
+pub enum Source {
+    Disk(Utf8PathBuf),
+    /// Files made up for the archive, which don't exist in the data dir
+    Memory(Bytes),
+}
+
+impl std::fmt::Display for Source {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Disk(path) => write!(f, "{path}"),
+            Self::Memory(_) => write!(f, "generated file"),
+        }
+    }
+}
+
+/// File or directory that goes into an archive
+#[derive(Debug, Clone)]
+pub struct ArchiveEntry {
+    /// Path inside the archive, `/` separated, with directories ending in `/`
+    pub name: String,
+    pub source: Source,
+    /// Size of files when the cache was built, `None` for directories
+    pub size: Option<u64>,
+    /// Modification time when the cache was built, in seconds since the unix epoch
+    pub modified: i64,
+    /// Permission bits, `None` if they aren't known
+    pub mode: Option<u32>,
+}
+
+impl ArchiveEntry {
+    /// File with `contents` that only exists in the archive
+    pub fn generated(name: String, contents: Bytes, modified: i64) -> Self {
+        Self {
+            name,
+            size: Some(contents.len() as u64),
+            source: Source::Memory(contents),
+            modified,
+            mode: None,
+        }
+    }
+
+    const fn is_dir(&self) -> bool {
+        self.size.is_none()
+    }
+
+    /// Permission bits, or the usual ones for its type if they aren't known
+    fn mode(&self) -> u32 {
+        self.mode
+            .unwrap_or(if self.is_dir() { 0o755 } else { 0o644 })
+    }
+}
+
+/// Adds every entry under `entries`, which are at `dir` in the cache, to `out`, with names
+/// starting with `prefix`
+///
+/// Entries are sorted by name, so the same tree always produces the same archive
+pub fn collect_entries(
+    prefix: &str,
+    roots: &DataRoots,
+    dir: &Utf8Path,
+    entries: &[CacheEntry],
+    out: &mut Vec<ArchiveEntry>,
+) {
+    let mut entries: Vec<&CacheEntry> = entries.iter().collect();
+    entries.sort_by(|e1, e2| e1.name().cmp(e2.name()));
+
+    for entry in entries {
+        collect_entry(prefix, roots, dir, entry, out);
+    }
+}
+
+/// Adds `entry` to `out`, along with everything inside it if it's a directory
+pub fn collect_entry(
+    prefix: &str,
+    roots: &DataRoots,
+    dir: &Utf8Path,
+    entry: &CacheEntry,
+    out: &mut Vec<ArchiveEntry>,
+) {
+    let name = format!("{prefix}{}", entry.name());
+    let path = dir.join(entry.name());
+    let Some(source) = roots.fs_path(&path) else {
+        return;
+    };
+    match entry {
+        // Symlinks that aren't followed can't be downloaded either
+        CacheEntry::File(f) if f.link => {}
+        CacheEntry::File(f) => out.push(ArchiveEntry {
+            name,
+            source: Source::Disk(source),
+            size: Some(f.size),
+            modified: f.modified,
+            mode: f.mode,
+        }),
+        CacheEntry::Dir(d) => {
+            let name = format!("{name}/");
+            out.push(ArchiveEntry {
+                name: name.clone(),
+                source: Source::Disk(source),
+                size: None,
+                modified: d.modified,
+                mode: d.mode,
+            });
+            collect_entries(&name, roots, &path, &d.children, out);
+        }
+    }
+}
+
+/// Where archives get written to, sends everything to the response body in chunks
+struct Output {
+    tx: mpsc::Sender<io::Result<Bytes>>,
+    buf: Vec<u8>,
+    /// Bytes written so far, the offset of the next write, counting the ones that weren't sent
+    written: u64,
+    /// Only this part of the archive is sent
+    range: Range<u64>,
+    /// Also gets everything that's sent, so the archive can be served again
+    spool: Option<SpoolFile>,
+}
+
+impl Output {
+    fn new(
+        tx: mpsc::Sender<io::Result<Bytes>>,
+        range: Range<u64>,
+        spool: Option<SpoolFile>,
+    ) -> Self {
+        Self {
+            tx,
+            buf: Vec::with_capacity(CHUNK_SIZE),
+            written: 0,
+            range,
+            spool,
+        }
+    }
+
+    async fn write_all(&mut self, data: &[u8]) -> io::Result<()> {
+        let start = self.written;
+        let len = data.len() as u64;
+        self.written += len;
+
+        let from = self.range.start.saturating_sub(start).min(len);
+        let to = self.range.end.saturating_sub(start).min(len);
+        if from < to {
+            self.buf
+                .extend_from_slice(&data[from as usize..to as usize]);
+        }
+        if self.buf.len() >= CHUNK_SIZE {
+            self.flush().await?;
+        }
+        Ok(())
+    }
+
+    /// Counts `len` bytes as written without having them, which is only right if they're all
+    /// before the range
+    fn skip(&mut self, len: u64) {
+        debug_assert!(self.written + len <= self.range.start);
+        self.written += len;
+    }
+
+    /// Whether everything in the range was already written
+    const fn is_full(&self) -> bool {
+        self.written >= self.range.end
+    }
+
+    async fn flush(&mut self) -> io::Result<()> {
+        if self.buf.is_empty() {
+            return Ok(());
+        }
+        let chunk = Bytes::from(std::mem::replace(
+            &mut self.buf,
+            Vec::with_capacity(CHUNK_SIZE),
+        ));
+        if let Some(spool) = &mut self.spool {
+            if let Err(e) = spool.write_all(&chunk).await {
+                // Not being able to cache it shouldn't stop the download
+                warn!("Failed writing archive to the cache: {e}");
+                self.spool = None;
+            }
+        }
+        self.tx.send(Ok(chunk)).await.map_err(|_| {
+            io::Error::new(
+                io::ErrorKind::BrokenPipe,
+                "Client stopped receiving the archive",
+            )
+        })
+    }
+
+    /// Makes the response body fail with `e`, so the client doesn't think it got the whole archive
+    async fn fail(self, e: io::Error) {
+        // If the client is gone there's nobody to tell
+        _ = self.tx.send(Err(e)).await;
+    }
+}
+
+/// Builds the archive in the background while `range` of it is being sent, and written to
+/// `spool` if there is one
+fn stream_archive<F, Fut>(range: Range<u64>, spool: Option<SpoolFile>, write: F) -> Body
+where
+    F: FnOnce(Output) -> Fut,
+    Fut: std::future::Future<Output = (Output, io::Result<()>)> + Send + 'static,
+{
+    let (tx, rx) = mpsc::channel(4);
+    let task = write(Output::new(tx, range, spool));
+    tokio::spawn(async move {
+        let (mut out, res) = task.await;
+        match res {
+            Ok(()) => {
+                debug!("Finished sending archive");
+                if let Some(spool) = out.spool.take() {
+                    if let Err(e) = spool.finish().await {
+                        warn!("Failed adding archive to the cache: {e}");
+                    }
+                }
+            }
+            Err(e) if e.kind() == io::ErrorKind::BrokenPipe => {
+                debug!("Client stopped downloading the archive");
+            }
+            Err(e) => {
+                error!("Failed writing archive: {e}");
+                out.fail(e).await;
+            }
+        }
+    });
+
+    let stream = futures_util::stream::unfold(rx, |mut rx| async move {
+        rx.recv().await.map(|chunk| (chunk, rx))
+    });
+    Body::from_stream(stream)
+}
+
+/// Streams an archive with `entries` to the returned body, and to `spool` to be cached
+pub fn stream(
+    format: ArchiveFormat,
+    entries: Vec<ArchiveEntry>,
+    zstd_level: i32,
+    spool: Option<SpoolFile>,
+) -> Body {
+    stream_archive(0..u64::MAX, spool, move |mut out| async move {
+        let res = match format {
+            ArchiveFormat::Zip => write_zip(&mut out, entries, false, None).await,
+            ArchiveFormat::TarGz => {
+                let encoder = Encoder::Gzip(GzEncoder::new(Vec::new(), Compression::default()));
+                write_tar(&mut out, entries, encoder).await
+            }
+            ArchiveFormat::TarZst => {
+                match zstd::stream::write::Encoder::new(Vec::new(), zstd_level) {
+                    Ok(encoder) => write_tar(&mut out, entries, Encoder::Zstd(encoder)).await,
+                    Err(e) => Err(e),
+                }
+            }
+        };
+        (out, res)
+    })
+}
+
+/// Streams `range` of an uncompressed ZIP with `entries` to the returned body
+///
+/// Files before the range that are in `checksums` aren't read, since their CRC is cached
+pub fn stream_stored_zip(
+    entries: Vec<ArchiveEntry>,
+    range: Range<u64>,
+    checksums: Option<Arc<Checksums>>,
+) -> Body {
+    stream_archive(range, None, move |mut out| async move {
+        let res = write_zip(&mut out, entries, true, checksums.as_deref()).await;
+        (out, res)
+    })
+}
+
+type Reader = Box<dyn AsyncRead + Unpin + Send>;
+
+/// Opens `source`, returning it along with its current size
+async fn open_source(source: &Source) -> io::Result<(Reader, u64)> {
+    match source {
+        Source::Disk(path) => {
+            let file = tokio::fs::File::open(path)
+                .await
+                .map_err(|e| io::Error::new(e.kind(), format!("Failed to open {path}: {e}")))?;
+            let len = file.metadata().await?.len();
+            Ok((Box::new(file), len))
+        }
+        Source::Memory(contents) => Ok((
+            Box::new(io::Cursor::new(contents.clone())),
+            contents.len() as u64,
+        )),
+    }
+}
+
+// https://pkware.cachefly.net/webdocs/casestudies/APPNOTE.TXT
+const LOCAL_FILE_HEADER_SIGNATURE: u32 = 0x0403_4b50;
+const DATA_DESCRIPTOR_SIGNATURE: u32 = 0x0807_4b50;
+const CENTRAL_DIRECTORY_HEADER_SIGNATURE: u32 = 0x0201_4b50;
+const END_OF_CENTRAL_DIRECTORY_SIGNATURE: u32 = 0x0605_4b50;
+const ZIP64_END_OF_CENTRAL_DIRECTORY_SIGNATURE: u32 = 0x0606_4b50;
+const ZIP64_END_OF_CENTRAL_DIRECTORY_LOCATOR_SIGNATURE: u32 = 0x0706_4b50;
+const ZIP64_EXTRA_FIELD_ID: u16 = 0x0001;
+
+/// 2.0, needed for deflate and directories
+const VERSION_NEEDED: u16 = 20;
+/// 4.5, needed for ZIP64
+const VERSION_NEEDED_ZIP64: u16 = 45;
+/// Unix, so the external attributes are read as a mode
+const VERSION_MADE_BY: u16 = (3 << 8) | VERSION_NEEDED_ZIP64;
+
+/// Files at least this big get ZIP64 sizes, since they aren't known until the file is compressed.
+/// Deflate makes incompressible data slightly bigger, so this leaves some room below 4 GiB
+const ZIP64_FILE_THRESHOLD: u64 = 0xF000_0000;
+
+/// The CRC and sizes are in a data descriptor after the data, since they aren't known up front
+const FLAG_DATA_DESCRIPTOR: u16 = 1 << 3;
+/// Names are UTF-8
+const FLAG_UTF8: u16 = 1 << 11;
+
+const METHOD_STORE: u16 = 0;
+const METHOD_DEFLATE: u16 = 8;
+
+/// File type bits of a unix mode
+const S_IFDIR: u32 = 0o040_000;
+const S_IFREG: u32 = 0o100_000;
+/// MS-DOS directory bit, in the low bytes of the external attributes
+const DOS_DIRECTORY: u32 = 0x10;
+
+/// Everything about an entry that goes in its headers
+struct ZipRecord {
+    name: String,
+    /// Whether the local header and data descriptor have ZIP64 sizes
+    zip64: bool,
+    flags: u16,
+    method: u16,
+    time: u16,
+    date: u16,
+    crc: u32,
+    compressed_size: u64,
+    uncompressed_size: u64,
+    offset: u64,
+    external_attributes: u32,
+}
+
+fn put_u16(buf: &mut Vec<u8>, v: u16) {
+    buf.extend_from_slice(&v.to_le_bytes());
+}
+
+fn put_u32(buf: &mut Vec<u8>, v: u32) {
+    buf.extend_from_slice(&v.to_le_bytes());
+}
+
+fn put_u64(buf: &mut Vec<u8>, v: u64) {
+    buf.extend_from_slice(&v.to_le_bytes());
+}
+
+fn zip_u16(v: usize, what: &str) -> io::Result<u16> {
+    u16::try_from(v).map_err(|_| io::Error::other(format!("Too many {what} for a ZIP: {v}")))
+}
+
+fn zip_u32(v: u64, what: &str) -> io::Result<u32> {
+    u32::try_from(v).map_err(|_| io::Error::other(format!("{what} too big for a ZIP: {v}")))
+}
+
+/// `v` if it fits in a regular field, otherwise adds it to the ZIP64 `extra` field and returns
+/// the value saying it's there instead
+fn u32_or_zip64(v: u64, extra: &mut Vec<u8>) -> u32 {
+    match u32::try_from(v) {
+        Ok(v) if v != u32::MAX => v,
+        _ => {
+            put_u64(extra, v);
+            u32::MAX
+        }
+    }
+}
+
+/// MS-DOS time and date, as used by ZIP
+fn dos_datetime(time: DateTime<Utc>) -> (u16, u16) {
+    // Can only store years from 1980 to 2107
+    let year = (time.year() - 1980).clamp(0, 127) as u16;
+    let date = (year << 9) | ((time.month() as u16) << 5) | time.day() as u16;
+    let time =
+        ((time.hour() as u16) << 11) | ((time.minute() as u16) << 5) | (time.second() as u16 / 2);
+    (time, date)
+}
+
+/// Stored files need ZIP64 sizes when their size doesn't fit in the regular fields
+fn stored_zip64(size: u64) -> bool {
+    size >= u64::from(u32::MAX)
+}
+
+impl ZipRecord {
+    /// Record for `entry` before its contents are written. The sizes of stored entries are
+    /// already known, while the sizes of deflated ones and the CRC are set after writing them
+    fn new(
+        entry: &ArchiveEntry,
+        offset: u64,
+        method: u16,
+        zip64: bool,
+        (time, date): (u16, u16),
+    ) -> Self {
+        let (flags, external_attributes) = if entry.is_dir() {
+            (FLAG_UTF8, ((S_IFDIR | entry.mode()) << 16) | DOS_DIRECTORY)
+        } else {
+            (
+                FLAG_UTF8 | FLAG_DATA_DESCRIPTOR,
+                (S_IFREG | entry.mode()) << 16,
+            )
+        };
+        let size = if method == METHOD_STORE {
+            entry.size.unwrap_or(0)
+        } else {
+            0
+        };
+        Self {
+            name: entry.name.clone(),
+            zip64,
+            flags,
+            method,
+            time,
+            date,
+            crc: 0,
+            compressed_size: size,
+            uncompressed_size: size,
+            offset,
+            external_attributes,
+        }
+    }
+
+    const fn version_needed(&self) -> u16 {
+        if self.zip64 {
+            VERSION_NEEDED_ZIP64
+        } else {
+            VERSION_NEEDED
+        }
+    }
+
+    fn local_header(&self) -> io::Result<Vec<u8>> {
+        // Sizes of deflated files are only in the data descriptor
+        let (compressed_size, uncompressed_size) = if self.method == METHOD_STORE {
+            (self.compressed_size, self.uncompressed_size)
+        } else {
+            (0, 0)
+        };
+
+        let mut header = vec![];
+        put_u32(&mut header, LOCAL_FILE_HEADER_SIGNATURE);
+        put_u16(&mut header, self.version_needed());
+        put_u16(&mut header, self.flags);
+        put_u16(&mut header, self.method);
+        put_u16(&mut header, self.time);
+        put_u16(&mut header, self.date);
+        // The CRC is either 0 for directories or in the descriptor
+        put_u32(&mut header, 0);
+        if self.zip64 {
+            put_u32(&mut header, u32::MAX);
+            put_u32(&mut header, u32::MAX);
+        } else {
+            put_u32(&mut header, zip_u32(compressed_size, "File")?);
+            put_u32(&mut header, zip_u32(uncompressed_size, "File")?);
+        }
+        put_u16(&mut header, zip_u16(self.name.len(), "bytes in name")?);
+        put_u16(&mut header, if self.zip64 { 20 } else { 0 });
+        header.extend_from_slice(self.name.as_bytes());
+        if self.zip64 {
+            // Also makes the data descriptor use 8 byte sizes
+            put_u16(&mut header, ZIP64_EXTRA_FIELD_ID);
+            put_u16(&mut header, 16);
+            put_u64(&mut header, uncompressed_size);
+            put_u64(&mut header, compressed_size);
+        }
+        Ok(header)
+    }
+
+    fn data_descriptor(&self) -> io::Result<Vec<u8>> {
+        let mut descriptor = vec![];
+        put_u32(&mut descriptor, DATA_DESCRIPTOR_SIGNATURE);
+        put_u32(&mut descriptor, self.crc);
+        if self.zip64 {
+            put_u64(&mut descriptor, self.compressed_size);
+            put_u64(&mut descriptor, self.uncompressed_size);
+        } else {
+            put_u32(&mut descriptor, zip_u32(self.compressed_size, "File")?);
+            put_u32(&mut descriptor, zip_u32(self.uncompressed_size, "File")?);
+        }
+        Ok(descriptor)
+    }
+
+    fn central_directory_header(&self) -> io::Result<Vec<u8>> {
+        // Only has the values that don't fit in their field, in this order
+        let mut zip64_values = vec![];
+        let uncompressed_size = u32_or_zip64(self.uncompressed_size, &mut zip64_values);
+        let compressed_size = u32_or_zip64(self.compressed_size, &mut zip64_values);
+        let offset = u32_or_zip64(self.offset, &mut zip64_values);
+        let mut extra = vec![];
+        if !zip64_values.is_empty() {
+            put_u16(&mut extra, ZIP64_EXTRA_FIELD_ID);
+            put_u16(&mut extra, zip_u16(zip64_values.len(), "ZIP64 values")?);
+            extra.extend_from_slice(&zip64_values);
+        }
+        let version_needed = if extra.is_empty() {
+            self.version_needed()
+        } else {
+            VERSION_NEEDED_ZIP64
+        };
+
+        let mut header = vec![];
+        put_u32(&mut header, CENTRAL_DIRECTORY_HEADER_SIGNATURE);
+        put_u16(&mut header, VERSION_MADE_BY);
+        put_u16(&mut header, version_needed);
+        put_u16(&mut header, self.flags);
+        put_u16(&mut header, self.method);
+        put_u16(&mut header, self.time);
+        put_u16(&mut header, self.date);
+        put_u32(&mut header, self.crc);
+        put_u32(&mut header, compressed_size);
+        put_u32(&mut header, uncompressed_size);
+        put_u16(&mut header, zip_u16(self.name.len(), "bytes in name")?);
+        put_u16(&mut header, zip_u16(extra.len(), "bytes in extra field")?);
+        // Comment length, disk number, internal attributes
+        put_u16(&mut header, 0);
+        put_u16(&mut header, 0);
+        put_u16(&mut header, 0);
+        put_u32(&mut header, self.external_attributes);
+        put_u32(&mut header, offset);
+        header.extend_from_slice(self.name.as_bytes());
+        header.extend_from_slice(&extra);
+        Ok(header)
+    }
+}
+
+fn end_of_central_directory(
+    entry_count: usize,
+    central_directory_offset: u64,
+    central_directory_size: u64,
+) -> Vec<u8> {
+    let entry_count = entry_count as u64;
+    let mut end = vec![];
+    if entry_count >= u64::from(u16::MAX)
+        || central_directory_size >= u64::from(u32::MAX)
+        || central_directory_offset >= u64::from(u32::MAX)
+    {
+        let zip64_end_offset = central_directory_offset + central_directory_size;
+        put_u32(&mut end, ZIP64_END_OF_CENTRAL_DIRECTORY_SIGNATURE);
+        // Size of the rest of the record
+        put_u64(&mut end, 44);
+        put_u16(&mut end, VERSION_MADE_BY);
+        put_u16(&mut end, VERSION_NEEDED_ZIP64);
+        // Disk numbers
+        put_u32(&mut end, 0);
+        put_u32(&mut end, 0);
+        put_u64(&mut end, entry_count);
+        put_u64(&mut end, entry_count);
+        put_u64(&mut end, central_directory_size);
+        put_u64(&mut end, central_directory_offset);
+
+        put_u32(&mut end, ZIP64_END_OF_CENTRAL_DIRECTORY_LOCATOR_SIGNATURE);
+        // Disk with the ZIP64 end of central directory, and total number of disks
+        put_u32(&mut end, 0);
+        put_u64(&mut end, zip64_end_offset);
+        put_u32(&mut end, 1);
+    }
+
+    // Values that don't fit are only in the ZIP64 record
+    let entry_count = u16::try_from(entry_count).unwrap_or(u16::MAX);
+    put_u32(&mut end, END_OF_CENTRAL_DIRECTORY_SIGNATURE);
+    // Disk numbers
+    put_u16(&mut end, 0);
+    put_u16(&mut end, 0);
+    put_u16(&mut end, entry_count);
+    put_u16(&mut end, entry_count);
+    put_u32(
+        &mut end,
+        u32::try_from(central_directory_size).unwrap_or(u32::MAX),
+    );
+    put_u32(
+        &mut end,
+        u32::try_from(central_directory_offset).unwrap_or(u32::MAX),
+    );
+    // Comment length
+    put_u16(&mut end, 0);
+    end
+}
+
+/// Size of an uncompressed ZIP with `entries`, which only depends on the sizes in the cache
+pub fn stored_zip_len(entries: &[ArchiveEntry]) -> io::Result<u64> {
+    let mut len = 0;
+    let mut records = Vec::with_capacity(entries.len());
+    for entry in entries {
+        let zip64 = entry.size.is_some_and(stored_zip64);
+        let record = ZipRecord::new(entry, len, METHOD_STORE, zip64, (0, 0));
+        len += record.local_header()?.len() as u64 + record.compressed_size;
+        if !entry.is_dir() {
+            len += record.data_descriptor()?.len() as u64;
+        }
+        records.push(record);
+    }
+
+    let central_directory_offset = len;
+    for record in &records {
+        len += record.central_directory_header()?.len() as u64;
+    }
+    let end = end_of_central_directory(
+        records.len(),
+        central_directory_offset,
+        len - central_directory_offset,
+    );
+    Ok(len + end.len() as u64)
+}
+
+/// Strong ETag of an uncompressed ZIP with `entries`, which has the same bytes as long as the
+/// entries have the same names, sizes, times and permissions
+pub fn stored_zip_etag(entries: &[ArchiveEntry]) -> String {
+    let mut hasher = Sha256::new();
+    for entry in entries {
+        hasher.update(entry.name.as_bytes());
+        // Names can't have NULs, so entries can't run into each other
+        hasher.update([0]);
+        hasher.update(entry.size.map_or(u64::MAX, |s| s).to_le_bytes());
+        hasher.update(entry.modified.to_le_bytes());
+        hasher.update(entry.mode().to_le_bytes());
+        if let Source::Memory(contents) = &entry.source {
+            hasher.update(contents);
+        }
+    }
+    format!("\"{:x}\"", hasher.finalize())
+}
+
+/// Writes a ZIP with `entries`, deflating files unless `store` is set
+///
+/// Stored files are the size they had in the cache, so the archive is exactly
+/// [`stored_zip_len`] bytes long. Their CRCs come first, so files before the range have to be
+/// read for them, unless they're in `checksums` already
+async fn write_zip(
+    out: &mut Output,
+    entries: Vec<ArchiveEntry>,
+    store: bool,
+    checksums: Option<&Checksums>,
+) -> io::Result<()> {
+    let mut records = Vec::with_capacity(entries.len());
+    for entry in &entries {
+        if out.is_full() {
+            // Nothing else would be sent
+            return out.flush().await;
+        }
+
+        let file = match entry.size {
+            // Stored files are only opened once it's known whether they have to be read
+            Some(_) if !store => Some(open_source(&entry.source).await?),
+            _ => None,
+        };
+        let (method, zip64) = match (entry.size, &file) {
+            (Some(size), _) if store => (METHOD_STORE, stored_zip64(size)),
+            (_, Some((_, len))) => (METHOD_DEFLATE, *len >= ZIP64_FILE_THRESHOLD),
+            _ => (METHOD_STORE, false),
+        };
+        // Comes from the cache, so it's the same every time and downloads can be resumed
+        let modified = DateTime::from_timestamp(entry.modified, 0).unwrap_or_default();
+
+        let mut record = ZipRecord::new(entry, out.written, method, zip64, dos_datetime(modified));
+        out.write_all(&record.local_header()?).await?;
+        match (entry.size, file) {
+            (Some(size), _) if store => {
+                let cached_crc = match (&entry.source, checksums) {
+                    (Source::Disk(path), Some(checksums))
+                        if out.written + size <= out.range.start =>
+                    {
+                        checksums.crc32(path, size, entry.modified)
+                    }
+                    _ => None,
+                };
+                record.crc = match cached_crc {
+                    Some(crc) => {
+                        out.skip(size);
+                        crc
+                    }
+                    None => {
+                        let (file, _) = open_source(&entry.source).await?;
+                        write_stored(out, file, &entry.source, size).await?
+                    }
+                };
+                out.write_all(&record.data_descriptor()?).await?;
+            }
+            (_, Some((file, _))) => {
+                (record.crc, record.compressed_size, record.uncompressed_size) =
+                    write_deflated(out, file).await?;
+                out.write_all(&record.data_descriptor()?).await?;
+            }
+            _ => {}
+        }
+        records.push(record);
+    }
+
+    let central_directory_offset = out.written;
+    for record in &records {
+        out.write_all(&record.central_directory_header()?).await?;
+    }
+    let central_directory_size = out.written - central_directory_offset;
+    out.write_all(&end_of_central_directory(
+        records.len(),
+        central_directory_offset,
+        central_directory_size,
+    ))
+    .await?;
+
+    out.flush().await
+}
+
+/// Writes exactly `size` bytes of `file`, returning their CRC
+///
+/// Files that are skipped because of a range still have to be read for the CRC, unless it's
+/// cached
+async fn write_stored(
+    out: &mut Output,
+    mut file: Reader,
+    source: &Source,
+    size: u64,
+) -> io::Result<u32> {
+    let mut hasher = crc32fast::Hasher::new();
+    let mut buf = vec![0; CHUNK_SIZE];
+    let mut remaining = size;
+    while remaining > 0 {
+        let to_read = usize::try_from(remaining).map_or(CHUNK_SIZE, |r| r.min(CHUNK_SIZE));
+        let n = file.read(&mut buf[..to_read]).await?;
+        if n == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                format!("{source} is smaller than when it was indexed"),
+            ));
+        }
+        hasher.update(&buf[..n]);
+        out.write_all(&buf[..n]).await?;
+        remaining -= n as u64;
+    }
+    Ok(hasher.finalize())
+}
+
+/// Writes the deflated contents of `file`, returning its CRC and compressed and uncompressed
+/// sizes
+async fn write_deflated(out: &mut Output, mut file: Reader) -> io::Result<(u32, u64, u64)> {
+    let mut hasher = crc32fast::Hasher::new();
+    // FIXME: Compressing blocks the runtime, maybe use spawn_blocking
+    let mut encoder = DeflateEncoder::new(Vec::new(), Compression::default());
+    let mut buf = vec![0; CHUNK_SIZE];
+    let mut compressed_size = 0;
+    let mut uncompressed_size = 0;
+
+    loop {
+        let n = file.read(&mut buf).await?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buf[..n]);
+        encoder.write_all(&buf[..n])?;
+        uncompressed_size += n as u64;
+
+        let compressed = std::mem::take(encoder.get_mut());
+        compressed_size += compressed.len() as u64;
+        out.write_all(&compressed).await?;
+    }
+    let compressed = encoder.finish()?;
+    compressed_size += compressed.len() as u64;
+    out.write_all(&compressed).await?;
+
+    Ok((hasher.finalize(), compressed_size, uncompressed_size))
+}
+
+/// Compresses everything written through it before it gets to the client
+enum Encoder {
+    Gzip(GzEncoder<Vec<u8>>),
+    Zstd(zstd::stream::write::Encoder<'static, Vec<u8>>),
+}
+
+impl Encoder {
+    /// Compresses `data`, returning whatever compressed output is ready
+    fn compress(&mut self, data: &[u8]) -> io::Result<Vec<u8>> {
+        match self {
+            Self::Gzip(e) => {
+                e.write_all(data)?;
+                Ok(std::mem::take(e.get_mut()))
+            }
+            Self::Zstd(e) => {
+                e.write_all(data)?;
+                Ok(std::mem::take(e.get_mut()))
+            }
+        }
+    }
+
+    fn finish(self) -> io::Result<Vec<u8>> {
+        match self {
+            Self::Gzip(e) => e.finish(),
+            Self::Zstd(e) => e.finish(),
+        }
+    }
+}
+
+struct CompressedOutput<'a> {
+    out: &'a mut Output,
+    encoder: Encoder,
+}
+
+impl CompressedOutput<'_> {
+    async fn write_all(&mut self, data: &[u8]) -> io::Result<()> {
+        let compressed = self.encoder.compress(data)?;
+        self.out.write_all(&compressed).await
+    }
+
+    async fn finish(self) -> io::Result<()> {
+        let compressed = self.encoder.finish()?;
+        self.out.write_all(&compressed).await?;
+        self.out.flush().await
+    }
+}
+
+// https://www.gnu.org/software/tar/manual/html_node/Standard.html
+const TAR_BLOCK_SIZE: usize = 512;
+/// Biggest size that fits in the 11 octal digits of the header, anything bigger needs PAX
+const TAR_MAX_SIZE: u64 = 0o777_7777_7777;
+
+const TAR_TYPE_FILE: u8 = b'0';
+const TAR_TYPE_DIR: u8 = b'5';
+const TAR_TYPE_PAX: u8 = b'x';
+
+/// Writes `v` as a zero padded octal number, followed by a NUL
+fn put_octal(field: &mut [u8], v: u64) {
+    let width = field.len() - 1;
+    let digits = format!("{v:0width$o}");
+    field[..width].copy_from_slice(&digits.as_bytes()[digits.len() - width..]);
+    field[width] = 0;
+}
+
+fn ustar_header(
+    name: &str,
+    prefix: &str,
+    size: u64,
+    mode: u32,
+    mtime: u64,
+    kind: u8,
+) -> [u8; TAR_BLOCK_SIZE] {
+    let mut header = [0; TAR_BLOCK_SIZE];
+    header[..name.len()].copy_from_slice(name.as_bytes());
+    put_octal(&mut header[100..108], mode.into());
+    // uid and gid
+    put_octal(&mut header[108..116], 0);
+    put_octal(&mut header[116..124], 0);
+    put_octal(&mut header[124..136], size);
+    put_octal(&mut header[136..148], mtime);
+    header[156] = kind;
+    header[257..263].copy_from_slice(b"ustar\0");
+    header[263..265].copy_from_slice(b"00");
+    header[345..345 + prefix.len()].copy_from_slice(prefix.as_bytes());
+
+    // The checksum is calculated as if its own field was all spaces, and is 6 digits, a NUL and a
+    // space
+    header[148..156].fill(b' ');
+    let checksum: u64 = header.iter().map(|&b| u64::from(b)).sum();
+    put_octal(&mut header[148..155], checksum);
+    header
+}
+
+/// Splits `name` into the name and prefix fields of a ustar header, if it fits in them
+fn split_ustar_name(name: &str) -> Option<(&str, &str)> {
+    if name.len() <= 100 {
+        return Some((name, ""));
+    }
+    name.match_indices('/')
+        .map(|(i, _)| (&name[i + 1..], &name[..i]))
+        .find(|(name, prefix)| !name.is_empty() && name.len() <= 100 && prefix.len() <= 155)
+}
+
+/// Adds a `<len> <key>=<value>\n` record, where the length counts itself
+fn pax_record(records: &mut String, key: &str, value: &str) {
+    let rest = format!(" {key}={value}\n");
+    let mut len = rest.len();
+    loop {
+        let total = len.to_string().len() + rest.len();
+        if total == len {
+            break;
+        }
+        len = total;
+    }
+    records.push_str(&len.to_string());
+    records.push_str(&rest);
+}
+
+const fn tar_padding(size: u64) -> usize {
+    (TAR_BLOCK_SIZE - (size % TAR_BLOCK_SIZE as u64) as usize) % TAR_BLOCK_SIZE
+}
+
+/// Headers for an entry, with a PAX extended header in front if the name or the size don't fit in
+/// a ustar one
+fn tar_headers(name: &str, size: u64, mode: u32, mtime: u64, kind: u8) -> Vec<u8> {
+    let mut headers = vec![];
+    let mut pax = String::new();
+
+    let (ustar_name, prefix) = split_ustar_name(name).unwrap_or_else(|| {
+        pax_record(&mut pax, "path", name);
+        let mut end = 100;
+        while !name.is_char_boundary(end) {
+            end -= 1;
+        }
+        (&name[..end], "")
+    });
+    if size > TAR_MAX_SIZE {
+        pax_record(&mut pax, "size", &size.to_string());
+    }
+
+    if !pax.is_empty() {
+        let pax_len = pax.len() as u64;
+        headers.extend_from_slice(&ustar_header(
+            "././@PaxHeader",
+            "",
+            pax_len,
+            0o644,
+            mtime,
+            TAR_TYPE_PAX,
+        ));
+        headers.extend_from_slice(pax.as_bytes());
+        headers.resize(headers.len() + tar_padding(pax_len), 0);
+    }
+    headers.extend_from_slice(&ustar_header(
+        ustar_name,
+        prefix,
+        size.min(TAR_MAX_SIZE),
+        mode,
+        mtime,
+        kind,
+    ));
+    headers
+}
+
+async fn write_tar(
+    out: &mut Output,
+    entries: Vec<ArchiveEntry>,
+    encoder: Encoder,
+) -> io::Result<()> {
+    let mut out = CompressedOutput { out, encoder };
+    let mut buf = vec![0; CHUNK_SIZE];
+
+    for entry in entries {
+        let source = &entry.source;
+        // ustar can't have times before the epoch
+        let mtime = u64::try_from(entry.modified).unwrap_or(0);
+        if entry.is_dir() {
+            out.write_all(&tar_headers(
+                &entry.name,
+                0,
+                entry.mode(),
+                mtime,
+                TAR_TYPE_DIR,
+            ))
+            .await?;
+            continue;
+        }
+
+        // The size has to be known before the data, so it comes from the file itself rather than
+        // the cache, which might be out of date
+        let (mut file, size) = open_source(source).await?;
+        out.write_all(&tar_headers(
+            &entry.name,
+            size,
+            entry.mode(),
+            mtime,
+            TAR_TYPE_FILE,
+        ))
+        .await?;
+
+        let mut remaining = size;
+        while remaining > 0 {
+            let to_read = usize::try_from(remaining).map_or(CHUNK_SIZE, |r| r.min(CHUNK_SIZE));
+            let n = file.read(&mut buf[..to_read]).await?;
+            if n == 0 {
+                return Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    format!("{source} got smaller while it was being archived"),
+                ));
+            }
+            out.write_all(&buf[..n]).await?;
+            remaining -= n as u64;
+        }
+        out.write_all(&[0; TAR_BLOCK_SIZE][..tar_padding(size)])
+            .await?;
+    }
+
+    // The archive ends with two empty blocks
+    out.write_all(&[0; 2 * TAR_BLOCK_SIZE]).await?;
+    out.finish().await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn u16_at(buf: &[u8], at: usize) -> u16 {
+        u16::from_le_bytes(
+            buf[at..at + 2]
+                .try_into()
+                .expect("slice has the length of the value"),
+        )
+    }
+
+    fn u32_at(buf: &[u8], at: usize) -> u32 {
+        u32::from_le_bytes(
+            buf[at..at + 4]
+                .try_into()
+                .expect("slice has the length of the value"),
+        )
+    }
+
+    fn u64_at(buf: &[u8], at: usize) -> u64 {
+        u64::from_le_bytes(
+            buf[at..at + 8]
+                .try_into()
+                .expect("slice has the length of the value"),
+        )
+    }
+
+    fn record(size: u64, offset: u64) -> ZipRecord {
+        ZipRecord {
+            name: "a.bin".to_owned(),
+            zip64: stored_zip64(size),
+            flags: FLAG_UTF8 | FLAG_DATA_DESCRIPTOR,
+            method: METHOD_STORE,
+            time: 0,
+            date: 0,
+            crc: 0x1234_5678,
+            compressed_size: size,
+            uncompressed_size: size,
+            offset,
+            external_attributes: (S_IFREG | 0o644) << 16,
+        }
+    }
+
+    #[test]
+    fn end_of_central_directory_without_zip64() {
+        let end = end_of_central_directory(0xFFFE, 0xFFFF_FFFE, 0xFFFF_FFFE);
+        assert_eq!(end.len(), 22);
+        assert_eq!(u32_at(&end, 0), END_OF_CENTRAL_DIRECTORY_SIGNATURE);
+        assert_eq!(u16_at(&end, 8), 0xFFFE);
+        assert_eq!(u16_at(&end, 10), 0xFFFE);
+        assert_eq!(u32_at(&end, 12), 0xFFFF_FFFE);
+        assert_eq!(u32_at(&end, 16), 0xFFFF_FFFE);
+        assert_eq!(u16_at(&end, 20), 0);
+    }
+
+    #[test]
+    fn too_many_entries_need_zip64() {
+        let end = end_of_central_directory(0xFFFF, 1000, 500);
+        // ZIP64 end of central directory, its locator and the regular one
+        assert_eq!(end.len(), 56 + 20 + 22);
+
+        assert_eq!(u32_at(&end, 0), ZIP64_END_OF_CENTRAL_DIRECTORY_SIGNATURE);
+        assert_eq!(u64_at(&end, 4), 44);
+        assert_eq!(u16_at(&end, 12), VERSION_MADE_BY);
+        assert_eq!(u16_at(&end, 14), VERSION_NEEDED_ZIP64);
+        assert_eq!(u32_at(&end, 16), 0);
+        assert_eq!(u32_at(&end, 20), 0);
+        assert_eq!(u64_at(&end, 24), 0xFFFF);
+        assert_eq!(u64_at(&end, 32), 0xFFFF);
+        assert_eq!(u64_at(&end, 40), 500);
+        assert_eq!(u64_at(&end, 48), 1000);
+
+        assert_eq!(
+            u32_at(&end, 56),
+            ZIP64_END_OF_CENTRAL_DIRECTORY_LOCATOR_SIGNATURE
+        );
+        assert_eq!(u32_at(&end, 60), 0);
+        // Right after the central directory
+        assert_eq!(u64_at(&end, 64), 1500);
+        assert_eq!(u32_at(&end, 72), 1);
+
+        let regular = &end[76..];
+        assert_eq!(u32_at(regular, 0), END_OF_CENTRAL_DIRECTORY_SIGNATURE);
+        assert_eq!(u16_at(regular, 8), 0xFFFF);
+        assert_eq!(u16_at(regular, 10), 0xFFFF);
+        assert_eq!(u32_at(regular, 12), 500);
+        assert_eq!(u32_at(regular, 16), 1000);
+    }
+
+    #[test]
+    fn far_central_directory_needs_zip64() {
+        let offset = 5 * 1024 * 1024 * 1024;
+        let end = end_of_central_directory(3, offset, 200);
+        assert_eq!(end.len(), 98);
+        assert_eq!(u64_at(&end, 24), 3);
+        assert_eq!(u64_at(&end, 40), 200);
+        assert_eq!(u64_at(&end, 48), offset);
+        assert_eq!(u64_at(&end, 64), offset + 200);
+
+        let regular = &end[76..];
+        assert_eq!(u16_at(regular, 8), 3);
+        assert_eq!(u32_at(regular, 12), 200);
+        assert_eq!(u32_at(regular, 16), 0xFFFF_FFFF);
+
+        // u32::MAX itself means the value is in the ZIP64 record
+        let end = end_of_central_directory(3, 0xFFFF_FFFF, 200);
+        assert_eq!(end.len(), 98);
+        assert_eq!(u64_at(&end, 48), 0xFFFF_FFFF);
+    }
+
+    #[test]
+    fn central_directory_header_without_zip64() {
+        let header = record(0xFFFF_FFFE, 0xFFFF_FFFE)
+            .central_directory_header()
+            .expect("record fits in a ZIP");
+        assert_eq!(header.len(), 46 + "a.bin".len());
+        assert_eq!(u32_at(&header, 0), CENTRAL_DIRECTORY_HEADER_SIGNATURE);
+        assert_eq!(u16_at(&header, 6), VERSION_NEEDED);
+        assert_eq!(u32_at(&header, 16), 0x1234_5678);
+        assert_eq!(u32_at(&header, 20), 0xFFFF_FFFE);
+        assert_eq!(u32_at(&header, 24), 0xFFFF_FFFE);
+        assert_eq!(u16_at(&header, 28), 5);
+        assert_eq!(u16_at(&header, 30), 0);
+        assert_eq!(u32_at(&header, 42), 0xFFFF_FFFE);
+        assert_eq!(&header[46..], b"a.bin");
+    }
+
+    #[test]
+    fn central_directory_header_with_far_offset() {
+        let offset = 0xFFFF_FFFF;
+        let header = record(10, offset)
+            .central_directory_header()
+            .expect("record fits in a ZIP");
+        assert_eq!(u16_at(&header, 6), VERSION_NEEDED_ZIP64);
+        // Sizes fit, so only the offset is in the extra field
+        assert_eq!(u32_at(&header, 20), 10);
+        assert_eq!(u32_at(&header, 24), 10);
+        assert_eq!(u16_at(&header, 30), 4 + 8);
+        assert_eq!(u32_at(&header, 42), 0xFFFF_FFFF);
+        let extra = &header[46 + 5..];
+        assert_eq!(u16_at(extra, 0), ZIP64_EXTRA_FIELD_ID);
+        assert_eq!(u16_at(extra, 2), 8);
+        assert_eq!(u64_at(extra, 4), offset);
+    }
+
+    #[test]
+    fn central_directory_header_with_big_file() {
+        let size = 6 * 1024 * 1024 * 1024;
+        let offset = 8 * 1024 * 1024 * 1024;
+        let header = record(size, offset)
+            .central_directory_header()
+            .expect("record fits in a ZIP");
+        assert_eq!(u16_at(&header, 6), VERSION_NEEDED_ZIP64);
+        assert_eq!(u32_at(&header, 20), 0xFFFF_FFFF);
+        assert_eq!(u32_at(&header, 24), 0xFFFF_FFFF);
+        assert_eq!(u16_at(&header, 30), 4 + 3 * 8);
+        assert_eq!(u32_at(&header, 42), 0xFFFF_FFFF);
+        // Uncompressed size, compressed size and offset, in that order
+        let extra = &header[46 + 5..];
+        assert_eq!(u16_at(extra, 0), ZIP64_EXTRA_FIELD_ID);
+        assert_eq!(u16_at(extra, 2), 24);
+        assert_eq!(u64_at(extra, 4), size);
+        assert_eq!(u64_at(extra, 12), size);
+        assert_eq!(u64_at(extra, 20), offset);
+    }
+
+    #[test]
+    fn big_file_has_zip64_local_header_and_descriptor() {
+        let size = 6 * 1024 * 1024 * 1024;
+        let record = record(size, 0);
+        assert!(record.zip64);
+
+        let header = record.local_header().expect("record fits in a ZIP");
+        assert_eq!(header.len(), 30 + 5 + 20);
+        assert_eq!(u16_at(&header, 4), VERSION_NEEDED_ZIP64);
+        assert_eq!(u32_at(&header, 18), 0xFFFF_FFFF);
+        assert_eq!(u32_at(&header, 22), 0xFFFF_FFFF);
+        assert_eq!(u16_at(&header, 28), 20);
+        let extra = &header[30 + 5..];
+        assert_eq!(u16_at(extra, 0), ZIP64_EXTRA_FIELD_ID);
+        assert_eq!(u16_at(extra, 2), 16);
+        assert_eq!(u64_at(extra, 4), size);
+        assert_eq!(u64_at(extra, 12), size);
+
+        let descriptor = record.data_descriptor().expect("record fits in a ZIP");
+        assert_eq!(descriptor.len(), 4 + 4 + 2 * 8);
+        assert_eq!(u32_at(&descriptor, 0), DATA_DESCRIPTOR_SIGNATURE);
+        assert_eq!(u32_at(&descriptor, 4), 0x1234_5678);
+        assert_eq!(u64_at(&descriptor, 8), size);
+        assert_eq!(u64_at(&descriptor, 16), size);
+    }
+
+    #[test]
+    fn stored_zip64_threshold() {
+        assert!(!stored_zip64(0xFFFF_FFFE));
+        assert!(stored_zip64(0xFFFF_FFFF));
+        let header = record(0xFFFF_FFFE, 0)
+            .local_header()
+            .expect("record fits in a ZIP");
+        assert_eq!(header.len(), 30 + 5);
+        assert_eq!(u32_at(&header, 18), 0xFFFF_FFFE);
+    }
+}
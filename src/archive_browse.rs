@@ -0,0 +1,372 @@
+use askama::{filters::urlencode, Template};
+use axum::{
+    body::Body,
+    extract::{self, Query, State},
+    http::{header, StatusCode},
+    response::Response,
+};
+use bytes::Bytes;
+use camino::{Utf8Path, Utf8PathBuf};
+use chrono::{DateTime, NaiveDate, Utc};
+use serde::{Deserialize, Serialize};
+use std::{
+    fs::File,
+    io::{self, Read},
+    path::PathBuf,
+};
+use tokio::sync::{mpsc, oneshot};
+use tracing::{debug, info, warn};
+use zip::{result::ZipError, ZipArchive};
+
+use crate::{
+    color_scheme::ColorScheme,
+    dir_view::{entry_from_cache, normalise_path},
+    download::Disposition,
+    i18n::{Locale, Messages},
+    mime, AppState,
+};
+
+/// Most members listed, so an archive of a whole tree doesn't render a page too big to show
+const MAX_MEMBERS: usize = 10_000;
+/// Size of the chunks members are sent in
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// Archives that can be browsed, going by the extension of their name
+#[derive(Debug, Clone, Copy)]
+enum ArchiveKind {
+    Zip,
+    Tar,
+}
+
+impl ArchiveKind {
+    fn for_name(name: &str) -> Option<Self> {
+        let ext = Utf8Path::new(name).extension()?;
+        if ext.eq_ignore_ascii_case("zip") {
+            Some(Self::Zip)
+        } else if ext.eq_ignore_ascii_case("tar") {
+            Some(Self::Tar)
+        } else {
+            None
+        }
+    }
+}
+
+/// Whether the file called `name` can be browsed in `/browse-archive`
+pub fn can_browse(name: &str) -> bool {
+    ArchiveKind::for_name(name).is_some()
+}
+
+#[derive(Deserialize, Debug)]
+pub struct ArchiveQuery {
+    /// Path of a file in the archive to download, instead of listing them
+    member: Option<String>,
+}
+
+/// File or directory in an archive, as its headers say
+struct RawMember {
+    path: String,
+    size: u64,
+    modified: Option<DateTime<Utc>>,
+    is_dir: bool,
+}
+
+/// File or directory in the list of the contents of an archive
+#[derive(Serialize)]
+pub struct Member {
+    /// Path inside the archive
+    path: String,
+    /// Path urlencoded, to download it
+    encoded_path: String,
+    size: String,
+    /// Already formatted, since archives don't always have it
+    modified: Option<String>,
+    is_dir: bool,
+}
+
+#[derive(Template, Serialize)]
+#[template(path = "archive.html")]
+pub struct ArchiveTemplate {
+    /// Name of the archive
+    name: String,
+    /// Path of the archive urlencoded
+    encoded_path: String,
+    /// Directory the archive is in urlencoded, ending in `/` unless it's the root
+    encoded_dirname: String,
+    members: Vec<Member>,
+    /// Whether there were more members than the ones listed
+    truncated: bool,
+    color_scheme: ColorScheme,
+    locale: Locale,
+    /// Every string of the page, in the language of the locale
+    t: &'static Messages,
+}
+
+fn zip_error(e: ZipError) -> io::Error {
+    match e {
+        ZipError::Io(e) => e,
+        ZipError::FileNotFound => io::ErrorKind::NotFound.into(),
+        e => io::Error::other(e),
+    }
+}
+
+/// Date and time of the DOS format zip uses, which has no timezone, so it's taken as UTC
+fn zip_datetime(time: zip::DateTime) -> Option<DateTime<Utc>> {
+    NaiveDate::from_ymd_opt(time.year().into(), time.month().into(), time.day().into())?
+        .and_hms_opt(
+            time.hour().into(),
+            time.minute().into(),
+            time.second().into(),
+        )
+        .map(|time| time.and_utc())
+}
+
+/// Every file and directory in the archive, only reading its headers
+fn read_members(kind: ArchiveKind, path: &Utf8Path) -> io::Result<Vec<RawMember>> {
+    let file = File::open(path)?;
+    match kind {
+        ArchiveKind::Zip => {
+            let mut archive = ZipArchive::new(file).map_err(zip_error)?;
+            (0..archive.len())
+                .map(|i| {
+                    let member = archive.by_index_raw(i).map_err(zip_error)?;
+                    Ok(RawMember {
+                        path: member.name().to_owned(),
+                        size: member.size(),
+                        modified: member.last_modified().and_then(zip_datetime),
+                        is_dir: member.is_dir(),
+                    })
+                })
+                .collect()
+        }
+        ArchiveKind::Tar => {
+            let mut archive = tar::Archive::new(file);
+            let mut members = vec![];
+            // Seeking skips over the contents instead of reading them
+            for member in archive.entries_with_seek()? {
+                let member = member?;
+                let header = member.header();
+                let entry_type = header.entry_type();
+                if !(entry_type.is_file() || entry_type.is_dir()) {
+                    continue;
+                }
+                members.push(RawMember {
+                    path: member.path()?.to_string_lossy().into_owned(),
+                    size: header.size()?,
+                    modified: header
+                        .mtime()
+                        .ok()
+                        .and_then(|mtime| DateTime::from_timestamp(mtime.try_into().ok()?, 0)),
+                    is_dir: entry_type.is_dir(),
+                });
+            }
+            Ok(members)
+        }
+    }
+}
+
+/// Calls `send` with the size and contents of the file called `member` in the archive, failing
+/// with `NotFound` if there's none
+fn read_member(
+    kind: ArchiveKind,
+    path: &Utf8Path,
+    member: &str,
+    send: impl FnOnce(u64, &mut dyn Read) -> io::Result<()>,
+) -> io::Result<()> {
+    let file = File::open(path)?;
+    match kind {
+        ArchiveKind::Zip => {
+            let mut archive = ZipArchive::new(file).map_err(zip_error)?;
+            let mut member = archive.by_name(member).map_err(zip_error)?;
+            if member.is_dir() {
+                return Err(io::ErrorKind::NotFound.into());
+            }
+            send(member.size(), &mut member)
+        }
+        ArchiveKind::Tar => {
+            let mut archive = tar::Archive::new(file);
+            for entry in archive.entries_with_seek()? {
+                let mut entry = entry?;
+                if entry.header().entry_type().is_file() && entry.path()?.as_os_str() == member {
+                    return send(entry.header().size()?, &mut entry);
+                }
+            }
+            Err(io::ErrorKind::NotFound.into())
+        }
+    }
+}
+
+/// Sends everything in `reader` to `tx`, stopping early if the client stopped downloading
+fn send_chunks(reader: &mut dyn Read, tx: &mpsc::Sender<io::Result<Bytes>>) -> io::Result<()> {
+    let mut buf = vec![0; CHUNK_SIZE];
+    loop {
+        let read = match reader.read(&mut buf) {
+            Ok(0) => return Ok(()),
+            Ok(read) => read,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+        if tx
+            .blocking_send(Ok(Bytes::copy_from_slice(&buf[..read])))
+            .is_err()
+        {
+            debug!("Client stopped downloading the archive member");
+            return Ok(());
+        }
+    }
+}
+
+/// Streams the file called `member` out of the archive, without extracting anything else
+async fn download_member(
+    kind: ArchiveKind,
+    fs_path: Utf8PathBuf,
+    member: String,
+) -> Result<Response, (StatusCode, String)> {
+    info!(?fs_path, member, "Downloading archive member");
+    let file_name = Utf8Path::new(&member)
+        .file_name()
+        .unwrap_or(&member)
+        .to_owned();
+    let content_type = Utf8Path::new(&file_name)
+        .extension()
+        .and_then(|ext| mime::from_extension(&ext.to_ascii_lowercase()))
+        .unwrap_or("application/octet-stream");
+
+    let (found_tx, found_rx) = oneshot::channel();
+    let (tx, rx) = mpsc::channel(4);
+    let not_found = format!("Archive has no file {member:?}");
+    tokio::task::spawn_blocking(move || {
+        let mut found_tx = Some(found_tx);
+        let res = read_member(kind, &fs_path, &member, |size, reader| {
+            if let Some(found_tx) = found_tx.take() {
+                _ = found_tx.send(Ok(size));
+            }
+            send_chunks(reader, &tx)
+        });
+        if let Err(e) = res {
+            match found_tx.take() {
+                Some(found_tx) => {
+                    _ = found_tx.send(Err(e));
+                }
+                None => {
+                    warn!(?fs_path, member, "Failed reading archive member: {e}");
+                    _ = tx.blocking_send(Err(e));
+                }
+            }
+        }
+    });
+
+    let size = found_rx
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?
+        .map_err(|e| match e.kind() {
+            io::ErrorKind::NotFound => (StatusCode::NOT_FOUND, not_found),
+            _ => (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()),
+        })?;
+
+    let stream = futures_util::stream::unfold(rx, |mut rx| async move {
+        rx.recv().await.map(|chunk| (chunk, rx))
+    });
+    Response::builder()
+        .header(header::CONTENT_TYPE, content_type)
+        .header(header::CONTENT_LENGTH, size)
+        .header(
+            header::CONTENT_DISPOSITION,
+            Disposition::Attachment.header_value(&file_name),
+        )
+        .body(Body::from_stream(stream))
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))
+}
+
+/// Lists the contents of a zip or tar archive, and downloads single files out of it with
+/// `?member=`, so the whole archive doesn't have to be downloaded to get one file
+pub async fn browse_archive(
+    extract::Path(path): extract::Path<PathBuf>,
+    State(state): State<AppState>,
+    Query(query): Query<ArchiveQuery>,
+    color_scheme: ColorScheme,
+    locale: Locale,
+) -> Result<Response, (StatusCode, String)> {
+    let path = Utf8PathBuf::from_path_buf(path)
+        .map_err(|p| (StatusCode::BAD_REQUEST, format!("Path {p:?} was not UTF-8")))?;
+    let path = normalise_path(&path).map_err(|e| (StatusCode::BAD_REQUEST, e.to_string()))?;
+
+    let name = path.file_name().unwrap_or_default().to_owned();
+    let Some(kind) = ArchiveKind::for_name(&name) else {
+        return Err((
+            StatusCode::NOT_FOUND,
+            format!("File {path:?} is not a zip or tar archive"),
+        ));
+    };
+    let not_found = || {
+        (
+            StatusCode::NOT_FOUND,
+            format!("File {path:?} does not exist"),
+        )
+    };
+    state.load_path(&path, false).await;
+    {
+        let lock = state.cache.read();
+        let entry = entry_from_cache(&path, &lock).ok_or_else(not_found)?;
+        if !entry.is_file() || entry.as_file().link {
+            return Err(not_found());
+        }
+    }
+    state
+        .scan_options
+        .check_download(&state.roots, &path)
+        .map_err(|e| (StatusCode::FORBIDDEN, format!("{e:#}")))?;
+    let fs_path = state.roots.fs_path(&path).ok_or_else(not_found)?;
+
+    if let Some(member) = query.member {
+        if state.roots.is_preview_only(&path) {
+            return Err((
+                StatusCode::FORBIDDEN,
+                format!("Archive {path:?} can only be listed, not extracted"),
+            ));
+        }
+        return download_member(kind, fs_path, member).await;
+    }
+
+    info!(?path, "Listing archive");
+    let mut members = tokio::task::spawn_blocking(move || read_members(kind, &fs_path))
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?
+        .map_err(|e| {
+            (
+                StatusCode::UNPROCESSABLE_ENTITY,
+                format!("Failed reading archive {path:?}: {e}"),
+            )
+        })?;
+    let truncated = members.len() > MAX_MEMBERS;
+    members.truncate(MAX_MEMBERS);
+    let members = members
+        .into_iter()
+        .map(|member| Member {
+            encoded_path: urlencode(&member.path).expect("TODO: Handle invalid chars in name"),
+            path: member.path,
+            size: state.size_units.format(member.size),
+            modified: member.modified.map(|time| state.dates.format(time)),
+            is_dir: member.is_dir,
+        })
+        .collect();
+
+    let mut dirname = path.parent().map(Utf8Path::to_string).unwrap_or_default();
+    if !dirname.is_empty() {
+        dirname.push('/');
+    }
+    let encode =
+        |s: &str| urlencode(s).map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()));
+    Ok(state.theme.page(
+        "archive.html",
+        ArchiveTemplate {
+            name,
+            encoded_path: encode(path.as_str())?,
+            encoded_dirname: encode(&dirname)?,
+            members,
+            truncated,
+            color_scheme,
+            locale,
+            t: locale.messages(),
+        },
+    ))
+}
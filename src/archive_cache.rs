@@ -0,0 +1,164 @@
+use camino::{Utf8Path, Utf8PathBuf};
+use color_eyre::{eyre::WrapErr, Result};
+use parking_lot::Mutex;
+use std::{
+    collections::HashMap,
+    io,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+};
+use tokio::io::AsyncWriteExt as _;
+use tracing::{debug, warn};
+
+use crate::archive::ArchiveFormat;
+
+/// Every file written to the spool dir starts with this, so leftovers from previous runs can be
+/// told apart from anything else in it
+const SPOOL_FILE_PREFIX: &str = "sfsb-archive-";
+
+/// Which archive was asked for
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct ArchiveKey {
+    pub path: Utf8PathBuf,
+    pub format: ArchiveFormat,
+    /// Whether it has a `SHA256SUMS` file
+    pub checksums: bool,
+}
+
+#[derive(Debug, Clone)]
+pub struct CachedArchive {
+    pub path: Utf8PathBuf,
+    pub len: u64,
+}
+
+/// Archives that were already generated, kept in a spool dir until the directory cache changes
+#[derive(Debug)]
+pub struct ArchiveCache {
+    dir: Utf8PathBuf,
+    /// Bumped every time the directory cache is refreshed, archives started before that are
+    /// out of date
+    generation: AtomicU64,
+    next_id: AtomicU64,
+    archives: Mutex<HashMap<ArchiveKey, CachedArchive>>,
+}
+
+impl ArchiveCache {
+    pub fn new(dir: &Utf8Path) -> Result<Self> {
+        std::fs::create_dir_all(dir)
+            .wrap_err_with(|| format!("Failed to create archive cache dir {dir}"))?;
+        for entry in dir
+            .read_dir_utf8()
+            .wrap_err_with(|| format!("Failed to read archive cache dir {dir}"))?
+        {
+            let entry = entry.wrap_err("Failed to read entry of archive cache dir")?;
+            if entry.file_name().starts_with(SPOOL_FILE_PREFIX) {
+                std::fs::remove_file(entry.path())
+                    .wrap_err_with(|| format!("Failed to remove old archive {}", entry.path()))?;
+            }
+        }
+
+        Ok(Self {
+            dir: dir.to_owned(),
+            generation: AtomicU64::new(0),
+            next_id: AtomicU64::new(0),
+            archives: Mutex::default(),
+        })
+    }
+
+    pub fn get(&self, key: &ArchiveKey) -> Option<CachedArchive> {
+        self.archives.lock().get(key).cloned()
+    }
+
+    /// Starts writing a new archive for `key`, which is only added to the cache once it's
+    /// finished, and only if the directory cache didn't change in the meantime
+    pub async fn spool(self: &Arc<Self>, key: ArchiveKey) -> io::Result<SpoolFile> {
+        let generation = self.generation.load(Ordering::Acquire);
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let path = self.dir.join(format!("{SPOOL_FILE_PREFIX}{id}"));
+        let file = tokio::fs::File::create(&path).await?;
+
+        Ok(SpoolFile {
+            cache: Arc::clone(self),
+            key,
+            generation,
+            file: Some(file),
+            path,
+            len: 0,
+        })
+    }
+
+    /// Throws away every archive, since the files in them might have changed
+    pub fn invalidate(&self) {
+        self.generation.fetch_add(1, Ordering::AcqRel);
+        let archives = std::mem::take(&mut *self.archives.lock());
+        if !archives.is_empty() {
+            debug!("Removing {} cached archives", archives.len());
+        }
+        for archive in archives.into_values() {
+            remove_spool_file(&archive.path);
+        }
+    }
+}
+
+fn remove_spool_file(path: &Utf8Path) {
+    if let Err(e) = std::fs::remove_file(path) {
+        warn!("Failed to remove cached archive {path}: {e}");
+    }
+}
+
+/// Archive being written to the spool dir, removed if it's dropped before it's finished
+#[derive(Debug)]
+pub struct SpoolFile {
+    cache: Arc<ArchiveCache>,
+    key: ArchiveKey,
+    generation: u64,
+    /// `None` once it's finished
+    file: Option<tokio::fs::File>,
+    path: Utf8PathBuf,
+    len: u64,
+}
+
+impl SpoolFile {
+    pub async fn write_all(&mut self, data: &[u8]) -> io::Result<()> {
+        if let Some(file) = &mut self.file {
+            file.write_all(data).await?;
+            self.len += data.len() as u64;
+        }
+        Ok(())
+    }
+
+    pub async fn finish(mut self) -> io::Result<()> {
+        let Some(mut file) = self.file.take() else {
+            return Ok(());
+        };
+        if let Err(e) = file.sync_all().await {
+            remove_spool_file(&self.path);
+            return Err(e);
+        }
+
+        let mut archives = self.cache.archives.lock();
+        if self.cache.generation.load(Ordering::Acquire) != self.generation {
+            debug!(key = ?self.key, "Not caching archive, the directory cache changed");
+            remove_spool_file(&self.path);
+            return Ok(());
+        }
+        let archive = CachedArchive {
+            path: self.path.clone(),
+            len: self.len,
+        };
+        if let Some(old) = archives.insert(self.key.clone(), archive) {
+            remove_spool_file(&old.path);
+        }
+        Ok(())
+    }
+}
+
+impl Drop for SpoolFile {
+    fn drop(&mut self) {
+        if self.file.is_some() {
+            remove_spool_file(&self.path);
+        }
+    }
+}
@@ -0,0 +1,242 @@
+use axum::{
+    extract::{self, State},
+    http::{header, StatusCode},
+    response::IntoResponse,
+};
+use camino::Utf8Path;
+use color_eyre::{
+    eyre::{eyre, WrapErr},
+    Result,
+};
+use serde_json::json;
+use std::{collections::HashMap, sync::OnceLock};
+
+use crate::{mime, AppState};
+
+const FAVICON_ICO: &[u8] = include_bytes!("../static/favicon.ico");
+const FAVICON_SVG: &[u8] = include_bytes!("../static/favicon.svg");
+const ICON_192: &[u8] = include_bytes!("../static/icon-192.png");
+const ICON_512: &[u8] = include_bytes!("../static/icon-512.png");
+
+/// None of the embedded assets change without a new build
+const CACHE_CONTROL: &str = "public, max-age=86400";
+/// Files served from `/static/` are linked with a hash of their contents, so they can be kept
+/// until they change
+const STATIC_CACHE_CONTROL: &str = "public, max-age=31536000, immutable";
+
+/// Stylesheets and scripts the pages link to, with their content type
+const STATIC_FILES: [(&str, &[u8], &str); 11] = [
+    (
+        "archive.css",
+        include_bytes!("../static/archive.css"),
+        "text/css",
+    ),
+    (
+        "colors.css",
+        include_bytes!("../static/colors.css"),
+        "text/css",
+    ),
+    (
+        "copy.js",
+        include_bytes!("../static/copy.js"),
+        "text/javascript",
+    ),
+    (
+        "dir_view.css",
+        include_bytes!("../static/dir_view.css"),
+        "text/css",
+    ),
+    (
+        "error.css",
+        include_bytes!("../static/error.css"),
+        "text/css",
+    ),
+    ("info.css", include_bytes!("../static/info.css"), "text/css"),
+    (
+        "recent.css",
+        include_bytes!("../static/recent.css"),
+        "text/css",
+    ),
+    (
+        "search.css",
+        include_bytes!("../static/search.css"),
+        "text/css",
+    ),
+    ("tree.css", include_bytes!("../static/tree.css"), "text/css"),
+    ("view.css", include_bytes!("../static/view.css"), "text/css"),
+    (
+        "listing.js",
+        include_bytes!("../static/listing.js"),
+        "text/javascript",
+    ),
+];
+
+/// Everything the web app manifest and icon routes serve
+pub struct WebApp {
+    pub name: String,
+    manifest: String,
+    /// Custom PNG icon replacing the embedded ones
+    icon: Option<Vec<u8>>,
+}
+
+impl WebApp {
+    pub fn new(name: &str, theme_color: &str, icon_path: Option<&Utf8Path>) -> Result<Self> {
+        let icon = icon_path
+            .map(|p| std::fs::read(p).wrap_err_with(|| format!("Failed to read app icon {p}")))
+            .transpose()?;
+
+        let icons = if icon.is_some() {
+            json!([{ "src": "/icon.png", "type": "image/png", "sizes": "any" }])
+        } else {
+            json!([
+                { "src": "/icon-192.png", "type": "image/png", "sizes": "192x192" },
+                { "src": "/icon-512.png", "type": "image/png", "sizes": "512x512" }
+            ])
+        };
+        let manifest = json!({
+            "name": name,
+            "short_name": name,
+            "start_url": "/browse/",
+            "display": "standalone",
+            "theme_color": theme_color,
+            "background_color": theme_color,
+            "icons": icons
+        })
+        .to_string();
+
+        Ok(Self {
+            name: name.to_owned(),
+            manifest,
+            icon,
+        })
+    }
+}
+
+/// How the instance calls itself in its pages
+#[derive(Debug)]
+pub struct Branding {
+    pub title: String,
+    /// Shown under the title in the header
+    pub tagline: Option<String>,
+    /// Picture shown next to the title, and its content type
+    logo: Option<(Vec<u8>, &'static str)>,
+}
+
+impl Branding {
+    pub fn new(title: &str, tagline: Option<&str>, logo_path: Option<&Utf8Path>) -> Result<Self> {
+        let logo = logo_path
+            .map(|p| {
+                let content_type = p
+                    .extension()
+                    .and_then(|ext| mime::from_extension(&ext.to_ascii_lowercase()))
+                    .filter(|content_type| content_type.starts_with("image/"))
+                    .ok_or_else(|| eyre!("Logo {p} is not a picture"))?;
+                let logo = std::fs::read(p).wrap_err_with(|| format!("Failed to read logo {p}"))?;
+                Ok::<_, color_eyre::Report>((logo, content_type))
+            })
+            .transpose()?;
+
+        Ok(Self {
+            title: title.to_owned(),
+            tagline: tagline.map(str::to_owned),
+            logo,
+        })
+    }
+
+    pub const fn has_logo(&self) -> bool {
+        self.logo.is_some()
+    }
+}
+
+pub async fn favicon_ico() -> impl IntoResponse {
+    (
+        [
+            (header::CONTENT_TYPE, "image/x-icon"),
+            (header::CACHE_CONTROL, CACHE_CONTROL),
+        ],
+        FAVICON_ICO,
+    )
+}
+
+pub async fn favicon_svg() -> impl IntoResponse {
+    (
+        [
+            (header::CONTENT_TYPE, "image/svg+xml"),
+            (header::CACHE_CONTROL, CACHE_CONTROL),
+        ],
+        FAVICON_SVG,
+    )
+}
+
+/// Url the pages link to the static file called `name` with, which changes whenever the file
+/// does
+pub fn static_url(name: &str) -> &'static str {
+    static URLS: OnceLock<HashMap<&str, String>> = OnceLock::new();
+    URLS.get_or_init(|| {
+        STATIC_FILES
+            .iter()
+            .map(|(name, contents, _)| {
+                let hash = crc32fast::hash(contents);
+                (*name, format!("/static/{name}?v={hash:08x}"))
+            })
+            .collect()
+    })
+    .get(name)
+    .map(String::as_str)
+    .unwrap_or_else(|| panic!("Static file {name} is not embedded"))
+}
+
+/// Stylesheets and scripts of the pages, like the colors of the light and dark schemes
+pub async fn serve_static(extract::Path(name): extract::Path<String>) -> impl IntoResponse {
+    match STATIC_FILES.iter().find(|(n, ..)| *n == name) {
+        Some((_, contents, content_type)) => Ok((
+            [
+                (header::CONTENT_TYPE, *content_type),
+                (header::CACHE_CONTROL, STATIC_CACHE_CONTROL),
+            ],
+            *contents,
+        )),
+        None => Err(StatusCode::NOT_FOUND),
+    }
+}
+
+pub async fn icon_192() -> impl IntoResponse {
+    (
+        [
+            (header::CONTENT_TYPE, "image/png"),
+            (header::CACHE_CONTROL, CACHE_CONTROL),
+        ],
+        ICON_192,
+    )
+}
+
+pub async fn icon_512() -> impl IntoResponse {
+    (
+        [
+            (header::CONTENT_TYPE, "image/png"),
+            (header::CACHE_CONTROL, CACHE_CONTROL),
+        ],
+        ICON_512,
+    )
+}
+
+pub async fn custom_icon(State(state): State<AppState>) -> impl IntoResponse {
+    match &state.web_app.icon {
+        Some(icon) => Ok(([(header::CONTENT_TYPE, "image/png")], icon.clone())),
+        None => Err(StatusCode::NOT_FOUND),
+    }
+}
+
+pub async fn logo(State(state): State<AppState>) -> impl IntoResponse {
+    match &state.branding.logo {
+        Some((logo, content_type)) => Ok(([(header::CONTENT_TYPE, *content_type)], logo.clone())),
+        None => Err(StatusCode::NOT_FOUND),
+    }
+}
+
+pub async fn manifest(State(state): State<AppState>) -> impl IntoResponse {
+    (
+        [(header::CONTENT_TYPE, "application/manifest+json")],
+        state.web_app.manifest.clone(),
+    )
+}
@@ -0,0 +1,175 @@
+use axum::{
+    body::Body,
+    extract::{Request, State},
+    http::header,
+    middleware::Next,
+    response::Response,
+};
+use camino::{Utf8Path, Utf8PathBuf};
+use chrono::{DateTime, Utc};
+use color_eyre::{eyre::WrapErr, Result};
+use futures_util::StreamExt as _;
+use serde::Serialize;
+use std::{
+    fs::{File, OpenOptions},
+    io::Write as _,
+    net::IpAddr,
+    sync::mpsc::{self, Sender},
+};
+use tracing::{debug, error};
+
+use crate::{access::Visitor, auth::ApiToken, proxy, AppState};
+
+/// What was sent for a `/dl` or `/arc` request, written as a line of JSON
+#[derive(Serialize, Debug)]
+struct AuditRecord {
+    /// When the response started
+    timestamp: DateTime<Utc>,
+    /// Address of the client, or the one a trusted proxy forwarded it for
+    client: Option<IpAddr>,
+    /// Fingerprint of the token the request was made with, if it had a valid one
+    token: Option<String>,
+    /// Path of the url, still urlencoded
+    path: String,
+    status: u16,
+    /// `Range` header of the request, if it asked for part of the file
+    range: Option<String>,
+    /// Bytes of the body that were sent before it ended or the client went away
+    bytes: u64,
+    /// `Content-Length` of the response, if it was known beforehand
+    length: Option<u64>,
+}
+
+/// Appends a record of every download to a file, from its own thread so requests never wait for
+/// the disk
+#[derive(Debug)]
+pub struct AuditLog {
+    tx: Sender<AuditRecord>,
+}
+
+fn open(path: &Utf8Path) -> Result<File> {
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .wrap_err_with(|| format!("Failed opening audit log {path}"))
+}
+
+impl AuditLog {
+    /// Starts the thread writing to the file at `path`, which stops once this is dropped
+    ///
+    /// The file is moved to the same name with `.1` after it once it would get bigger than
+    /// `max_bytes`, replacing the one that was there
+    pub fn start(path: &Utf8Path, max_bytes: u64) -> Result<Self> {
+        let mut file = open(path)?;
+        let path = path.to_owned();
+        let (tx, rx) = mpsc::channel::<AuditRecord>();
+        std::thread::spawn(move || {
+            for record in rx {
+                let mut line = match serde_json::to_vec(&record) {
+                    Ok(line) => line,
+                    Err(e) => {
+                        error!("Failed serializing audit record {record:?}: {e}");
+                        continue;
+                    }
+                };
+                line.push(b'\n');
+                let len = file.metadata().map_or(0, |m| m.len());
+                // A record bigger than the limit still goes in a file of its own
+                if len > 0 && len + line.len() as u64 > max_bytes {
+                    if let Err(e) = rotate(&path).map(|f| file = f) {
+                        error!("Failed rotating audit log: {e:#}");
+                    }
+                }
+                if let Err(e) = file.write_all(&line) {
+                    error!("Failed writing to audit log {path}: {e}");
+                }
+            }
+            debug!("Stopping audit log thread");
+        });
+        Ok(Self { tx })
+    }
+}
+
+fn rotate(path: &Utf8Path) -> Result<File> {
+    let old = Utf8PathBuf::from(format!("{path}.1"));
+    std::fs::rename(path, &old).wrap_err_with(|| format!("Failed moving {path} to {old}"))?;
+    open(path)
+}
+
+/// Counts what's sent of a body, and sends the record once it's done with
+struct Audit {
+    tx: Sender<AuditRecord>,
+    record: Option<AuditRecord>,
+}
+
+impl Drop for Audit {
+    fn drop(&mut self) {
+        if let Some(record) = self.record.take() {
+            _ = self.tx.send(record);
+        }
+    }
+}
+
+/// Whether `path` is a download, through `/dl` or `/arc`
+pub fn is_download(path: &str) -> bool {
+    path.starts_with("/dl/") || path == "/arc" || path.starts_with("/arc/")
+}
+
+/// Middleware that adds a record to the audit log for every download, once its body was sent or
+/// the client went away
+///
+/// It goes outside of the access checks, so downloads that were refused are recorded too
+pub async fn audit_downloads(
+    State(state): State<AppState>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let Some(audit_log) = &state.audit_log else {
+        return next.run(request).await;
+    };
+    if !is_download(request.uri().path()) {
+        return next.run(request).await;
+    }
+
+    let timestamp = Utc::now();
+    let client = proxy::client_addr(&state, &request);
+    let path = request.uri().path().to_owned();
+    let range = request
+        .headers()
+        .get(header::RANGE)
+        .and_then(|r| r.to_str().ok())
+        .map(str::to_owned);
+
+    let (parts, body) = next.run(request).await.into_parts();
+    let token = parts
+        .extensions
+        .get::<Visitor>()
+        .and_then(|v| v.token.as_ref())
+        .map(ApiToken::fingerprint);
+    let length = parts
+        .headers
+        .get(header::CONTENT_LENGTH)
+        .and_then(|l| l.to_str().ok())
+        .and_then(|l| l.parse().ok());
+    let mut audit = Audit {
+        tx: audit_log.tx.clone(),
+        record: Some(AuditRecord {
+            timestamp,
+            client,
+            token,
+            path,
+            status: parts.status.as_u16(),
+            range,
+            bytes: 0,
+            length,
+        }),
+    };
+    let body = body.into_data_stream().map(move |chunk| {
+        if let (Ok(chunk), Some(record)) = (&chunk, &mut audit.record) {
+            record.bytes += chunk.len() as u64;
+        }
+        chunk
+    });
+    Response::from_parts(parts, Body::from_stream(body))
+}
@@ -0,0 +1,260 @@
+use axum::{
+    extract::{Query, Request, State},
+    http::{header, HeaderMap, HeaderValue, StatusCode, Uri},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use camino::{Utf8Path, Utf8PathBuf};
+use percent_encoding::{percent_decode_str, utf8_percent_encode, NON_ALPHANUMERIC};
+use serde::Deserialize;
+use sha2::{Digest as _, Sha256};
+use std::{str::FromStr, sync::Arc};
+use tracing::warn;
+
+use crate::{
+    access::Visitor, audit, dir_view::normalise_path, error_page::PATH_ROUTES, proxy, quota,
+    AppState,
+};
+
+/// Target of the log lines of invalid tokens, which are always a single line like
+/// `Failed authentication from 192.0.2.1 for "/dl/file": invalid token` so fail2ban can match
+/// them with `failregex = Failed authentication from <HOST> for`. Behind a trusted proxy, the
+/// address is the client the proxy forwarded the request for, so the proxy itself isn't banned
+const AUTH_FAILURE_TARGET: &str = "sfsb::auth_failures";
+/// Routes that show entries from anywhere in the data dir, but leave out the ones that can't be
+/// reached, so they only need the root to be reachable
+const PRUNED_ROUTES: [&str; 2] = ["recent", "search"];
+/// Name of the cookie a token from the query is kept in, so browsers can keep browsing with it
+const COOKIE_NAME: &str = "token";
+/// Routes anyone can get, since there's nothing from the data dir in them
+const PUBLIC_ROUTES: [&str; 9] = [
+    "favicon.ico",
+    "favicon.svg",
+    "icon-192.png",
+    "icon-512.png",
+    "icon.png",
+    "logo",
+    "manifest.json",
+    "static",
+    "theme",
+];
+
+/// Static token scripts authenticate with, parsed from `<token>` or `<token>:<path>`
+#[derive(Debug, Clone)]
+pub struct ApiToken {
+    pub token: String,
+    /// Only what's under this path can be reached with the token, everything if not set
+    pub prefix: Option<Utf8PathBuf>,
+}
+
+impl FromStr for ApiToken {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (token, prefix) = match s.split_once(':') {
+            Some((token, prefix)) => {
+                let prefix = normalise_path(Utf8Path::new(prefix.trim()))
+                    .map_err(|e| format!("Invalid path for token: {e}"))?;
+                (token, (!prefix.as_str().is_empty()).then_some(prefix))
+            }
+            None => (s, None),
+        };
+        let token = token.trim();
+        if token.is_empty() {
+            return Err(format!("API token should be `<token>[:<path>]`, was {s}"));
+        }
+        Ok(Self {
+            token: token.to_owned(),
+            prefix,
+        })
+    }
+}
+
+impl ApiToken {
+    /// Compares every byte, so how long it takes doesn't tell how much of the token was right
+    pub fn matches(&self, token: &str) -> bool {
+        self.token.len() == token.len()
+            && self
+                .token
+                .bytes()
+                .zip(token.bytes())
+                .fold(0, |diff, (a, b)| diff | (a ^ b))
+                == 0
+    }
+
+    /// Start of the SHA-256 of the token, which tells tokens apart in logs without giving them away
+    pub fn fingerprint(&self) -> String {
+        let digest = format!("{:x}", Sha256::digest(self.token.as_bytes()));
+        digest[..12].to_owned()
+    }
+
+    pub fn allows(&self, path: Option<&Utf8Path>) -> bool {
+        match (&self.prefix, path) {
+            (None, _) => true,
+            (Some(prefix), Some(path)) => path.starts_with(prefix),
+            (Some(_), None) => false,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct TokenQuery {
+    token: Option<String>,
+}
+
+fn from_query(uri: &Uri) -> Option<String> {
+    Query::<TokenQuery>::try_from_uri(uri).ok()?.0.token
+}
+
+fn from_headers(headers: &HeaderMap) -> Option<String> {
+    let bearer = headers
+        .get(header::AUTHORIZATION)
+        .and_then(|h| h.to_str().ok())
+        .and_then(|h| h.strip_prefix("Bearer "));
+    if let Some(token) = bearer {
+        return Some(token.trim().to_owned());
+    }
+    headers
+        .get_all(header::COOKIE)
+        .iter()
+        .filter_map(|h| h.to_str().ok())
+        .flat_map(|h| h.split(';'))
+        .filter_map(|cookie| cookie.trim().split_once('='))
+        .find(|(name, _)| *name == COOKIE_NAME)
+        .map(|(_, value)| percent_decode_str(value).decode_utf8_lossy().into_owned())
+}
+
+/// First segment of the url path, and the path in the data dir after it for routes of files and
+/// directories
+fn route_and_path(path: &str) -> (String, Option<Utf8PathBuf>) {
+    let path = percent_decode_str(path).decode_utf8_lossy();
+    let path = path.trim_start_matches('/');
+    let (route, rest) = path.split_once('/').unwrap_or((path, ""));
+    let data_path = if PRUNED_ROUTES.contains(&route) {
+        Some(Utf8PathBuf::new())
+    } else {
+        (PATH_ROUTES.contains(&route) || route == "browse-archive")
+            .then(|| normalise_path(Utf8Path::new(rest)).ok())
+            .flatten()
+    };
+    (route.to_owned(), data_path)
+}
+
+#[derive(Deserialize)]
+struct LinkQuery {
+    /// Of `/qr`
+    target: Option<String>,
+    /// Of `/oembed`
+    url: Option<String>,
+}
+
+/// Path in the data dir of the `/browse`, `/dl` or `/view` url a `/qr` or `/oembed` request is
+/// for, so they can be reached by whoever can reach that path
+fn linked_path(state: &AppState, uri: &Uri) -> Option<Utf8PathBuf> {
+    let query = Query::<LinkQuery>::try_from_uri(uri).ok()?.0;
+    let link = query.target.or(query.url)?;
+    let url = state.base_url.join(link.trim_start_matches('/')).ok()?;
+    let (route, path) = route_and_path(url.path());
+    matches!(route.as_str(), "browse" | "dl" | "view")
+        .then_some(path)
+        .flatten()
+}
+
+fn unauthorized() -> Response {
+    (
+        StatusCode::UNAUTHORIZED,
+        [(header::WWW_AUTHENTICATE, "Bearer")],
+        "A valid token is needed, in an `Authorization: Bearer` header or a `?token=` query",
+    )
+        .into_response()
+}
+
+/// Middleware that only lets requests reach the paths the access rules allow, with one of the
+/// configured tokens in an `Authorization: Bearer` header, a `?token=` query or the cookie a query
+/// sets if they need one, and puts who made them in the request as a [`Visitor`]
+///
+/// The visitor is put in the response too, for the audit log outside of this. Downloads with a
+/// token are refused once it's over its quota, and counted against it otherwise
+///
+/// Does nothing if there are no tokens or rules
+pub async fn check_access(
+    State(state): State<AppState>,
+    mut request: Request,
+    next: Next,
+) -> Response {
+    if state.api_tokens.is_empty() && state.access_rules.is_empty() {
+        return next.run(request).await;
+    }
+    let (route, path) = route_and_path(request.uri().path());
+    if PUBLIC_ROUTES.contains(&route.as_str()) {
+        return next.run(request).await;
+    }
+    let path = match route.as_str() {
+        "qr" | "oembed" => linked_path(&state, request.uri()),
+        _ => path,
+    };
+
+    let from_query = from_query(request.uri());
+    let token = match from_query
+        .clone()
+        .or_else(|| from_headers(request.headers()))
+    {
+        Some(given) => match state.api_tokens.iter().find(|t| t.matches(&given)) {
+            Some(token) => Some(token.clone()),
+            None => {
+                let client = proxy::client_addr(&state, &request)
+                    .map_or_else(|| "unknown".to_owned(), |addr| addr.to_string());
+                warn!(
+                    target: AUTH_FAILURE_TARGET,
+                    "Failed authentication from {client} for {:?}: invalid token",
+                    request.uri().path()
+                );
+                return unauthorized();
+            }
+        },
+        None => None,
+    };
+    let visitor = Visitor { token };
+    let reachable = match &path {
+        Some(path) => visitor.can_reach(&state, path),
+        None => visitor.reaches_everything(&state),
+    };
+    if !reachable {
+        if visitor.token.is_none() {
+            return unauthorized();
+        }
+        let mut response = (
+            StatusCode::FORBIDDEN,
+            format!("The token can't reach {:?}", request.uri().path()),
+        )
+            .into_response();
+        response.extensions_mut().insert(visitor);
+        return response;
+    }
+    let counted = match &visitor.token {
+        Some(token) if state.quota.is_set() && audit::is_download(request.uri().path()) => {
+            let fingerprint = token.fingerprint();
+            if let Some(mut refusal) = state.quota.refusal(&state.token_usage.get(&fingerprint)) {
+                refusal.extensions_mut().insert(visitor);
+                return refusal;
+            }
+            Some(fingerprint)
+        }
+        _ => None,
+    };
+    request.extensions_mut().insert(visitor.clone());
+
+    let mut response = next.run(request).await;
+    if let Some(fingerprint) = counted.filter(|_| response.status().is_success()) {
+        response = quota::count_body(Arc::clone(&state.token_usage), fingerprint, response);
+    }
+    response.extensions_mut().insert(visitor);
+    if let Some(token) = from_query {
+        let encoded = utf8_percent_encode(&token, NON_ALPHANUMERIC);
+        let cookie = format!("{COOKIE_NAME}={encoded}; Path=/; HttpOnly; SameSite=Lax");
+        if let Ok(cookie) = HeaderValue::try_from(cookie) {
+            response.headers_mut().append(header::SET_COOKIE, cookie);
+        }
+    }
+    response
+}
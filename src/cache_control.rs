@@ -0,0 +1,75 @@
+use axum::{
+    extract::{Request, State},
+    http::HeaderValue,
+    middleware::Next,
+    response::Response,
+};
+use percent_encoding::percent_decode_str;
+use std::str::FromStr;
+
+use crate::{utils::glob_match, AppState};
+
+/// `Cache-Control` value for responses whose path matches a pattern
+///
+/// Parsed from `<pattern>=><value>`. Patterns starting with `/` are matched against the whole
+/// path of the request, like `/browse/*`, and the rest only against its last segment, like `*.iso`
+#[derive(Debug, Clone)]
+pub struct CacheControlRule {
+    pub pattern: String,
+    pub value: HeaderValue,
+}
+
+impl CacheControlRule {
+    fn matches(&self, path: &str) -> bool {
+        if self.pattern.starts_with('/') {
+            glob_match(&self.pattern, path)
+        } else {
+            let name = path.rsplit('/').next().unwrap_or(path);
+            glob_match(&self.pattern, name)
+        }
+    }
+}
+
+impl FromStr for CacheControlRule {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let Some((pattern, value)) = s.split_once("=>") else {
+            return Err(format!(
+                "Cache-Control rule should be `<pattern> => <value>`, was {s}"
+            ));
+        };
+        let value = HeaderValue::from_str(value.trim())
+            .map_err(|e| format!("Invalid Cache-Control value {value}: {e}"))?;
+        Ok(Self {
+            pattern: pattern.trim().to_owned(),
+            value,
+        })
+    }
+}
+
+/// Middleware that adds the `Cache-Control` of the first rule matching the path to successful
+/// responses that don't have one yet
+pub async fn add_cache_control(
+    State(state): State<AppState>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let path = percent_decode_str(request.uri().path())
+        .decode_utf8_lossy()
+        .into_owned();
+    let mut response = next.run(request).await;
+
+    let status = response.status();
+    if !(status.is_success() || status.is_redirection())
+        || response.headers().contains_key("Cache-Control")
+    {
+        return response;
+    }
+    if let Some(rule) = state.cache_control.iter().find(|r| r.matches(&path)) {
+        response
+            .headers_mut()
+            .insert("Cache-Control", rule.value.clone());
+    }
+    response
+}
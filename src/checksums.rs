@@ -0,0 +1,262 @@
+use camino::{Utf8Path, Utf8PathBuf};
+use color_eyre::{eyre::WrapErr, Result};
+use md5::Md5;
+use parking_lot::RwLock;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest as _, Sha256};
+use std::{
+    collections::{HashMap, HashSet},
+    fmt::Write as _,
+    io::{self, Read as _},
+    sync::{
+        mpsc::{self, SyncSender},
+        Arc, Weak,
+    },
+};
+use tracing::{debug, error, info, warn};
+
+use crate::archive::{ArchiveEntry, Source};
+use crate::dir_cache::{CacheEntry, DirContents};
+use crate::roots::DataRoots;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct FileDigest {
+    /// Size and modification time of the file when it was hashed, if they changed the digest is
+    /// out of date
+    size: u64,
+    modified: i64,
+    sha256: String,
+    md5: String,
+    /// Digests saved before CRCs were kept don't have one, so those files are hashed again
+    #[serde(default)]
+    crc32: Option<u32>,
+}
+
+/// Algorithms files are hashed with
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Algorithm {
+    Sha256,
+    Md5,
+}
+
+impl Algorithm {
+    /// Name of the manifest file the usual tool for it makes
+    pub const fn manifest_name(self) -> &'static str {
+        match self {
+            Self::Sha256 => "SHA256SUMS",
+            Self::Md5 => "MD5SUMS",
+        }
+    }
+}
+
+/// SHA-256 digests of every file in the directory cache, computed in the background after every
+/// refresh
+#[derive(Debug)]
+pub struct Checksums {
+    /// Indexed by the path of the file, including the data dir
+    digests: RwLock<HashMap<Utf8PathBuf, FileDigest>>,
+    update_tx: SyncSender<()>,
+    /// Where the digests are saved after every pass, so they're kept across restarts
+    file: Option<Utf8PathBuf>,
+}
+
+/// Digests saved to `path`, or none if it doesn't exist yet
+fn load(path: &Utf8Path) -> Result<HashMap<Utf8PathBuf, FileDigest>> {
+    match std::fs::read(path) {
+        Ok(contents) => serde_json::from_slice(&contents)
+            .wrap_err_with(|| format!("Failed parsing checksums from {path}")),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(HashMap::new()),
+        Err(e) => Err(e).wrap_err_with(|| format!("Failed reading checksums from {path}")),
+    }
+}
+
+impl Checksums {
+    /// Starts the thread that hashes the files in `cache`, which stops once this is dropped,
+    /// starting from the digests saved to `file` if there is one
+    pub fn start(
+        cache: Arc<RwLock<DirContents>>,
+        roots: Arc<DataRoots>,
+        file: Option<Utf8PathBuf>,
+    ) -> Result<Arc<Self>> {
+        let digests = file.as_deref().map(load).transpose()?.unwrap_or_default();
+        // Updates asked for while hashing only need to cause a single new pass
+        let (update_tx, update_rx) = mpsc::sync_channel(1);
+        let checksums = Arc::new(Self {
+            digests: RwLock::new(digests),
+            update_tx,
+            file,
+        });
+
+        let weak = Arc::downgrade(&checksums);
+        std::thread::spawn(move || {
+            while update_rx.recv().is_ok() {
+                let Some(checksums) = Weak::upgrade(&weak) else {
+                    break;
+                };
+                checksums.hash_files(&cache, &roots);
+            }
+            debug!("Stopping checksum thread");
+        });
+
+        Ok(checksums)
+    }
+
+    /// Hashes any file that changed since it was last hashed
+    pub fn update(&self) {
+        _ = self.update_tx.try_send(());
+    }
+
+    /// Digest of the file at `path` on disk, if it was already hashed with this size and
+    /// modification time
+    pub fn get(
+        &self,
+        algorithm: Algorithm,
+        path: &Utf8Path,
+        size: u64,
+        modified: i64,
+    ) -> Option<String> {
+        self.digests
+            .read()
+            .get(path)
+            .filter(|d| d.size == size && d.modified == modified)
+            .map(|d| match algorithm {
+                Algorithm::Sha256 => d.sha256.clone(),
+                Algorithm::Md5 => d.md5.clone(),
+            })
+    }
+
+    /// SHA-256 of the file at `path` on disk, if it was already hashed with this size and
+    /// modification time
+    pub fn sha256(&self, path: &Utf8Path, size: u64, modified: i64) -> Option<String> {
+        self.get(Algorithm::Sha256, path, size, modified)
+    }
+
+    /// CRC-32 of the file at `path` on disk, if it was already hashed with this size and
+    /// modification time, so resumed ZIPs don't have to read it again
+    pub fn crc32(&self, path: &Utf8Path, size: u64, modified: i64) -> Option<u32> {
+        self.digests
+            .read()
+            .get(path)
+            .filter(|d| d.size == size && d.modified == modified)
+            .and_then(|d| d.crc32)
+    }
+
+    fn hash_files(&self, cache: &RwLock<DirContents>, roots: &DataRoots) {
+        let mut files = vec![];
+        collect_files(roots, Utf8Path::new(""), &cache.read(), &mut files);
+
+        let mut hashed = 0;
+        for (path, size, modified) in &files {
+            if self.crc32(path, *size, *modified).is_some() {
+                continue;
+            }
+            match hash_file(path) {
+                Ok((sha256, md5, crc32)) => {
+                    let digest = FileDigest {
+                        size: *size,
+                        modified: *modified,
+                        sha256,
+                        md5,
+                        crc32: Some(crc32),
+                    };
+                    self.digests.write().insert(path.clone(), digest);
+                    hashed += 1;
+                }
+                Err(e) => warn!("Failed to hash {path}: {e}"),
+            }
+        }
+
+        let present: HashSet<_> = files.into_iter().map(|(path, _, _)| path).collect();
+        let removed = {
+            let mut digests = self.digests.write();
+            let len = digests.len();
+            digests.retain(|path, _| present.contains(path));
+            len - digests.len()
+        };
+        info!("Hashed {hashed} new or changed files");
+
+        if let Some(file) = &self.file {
+            if hashed > 0 || removed > 0 {
+                if let Err(e) = self.save(file) {
+                    error!("Failed saving checksums: {e:#}");
+                }
+            }
+        }
+    }
+
+    fn save(&self, path: &Utf8Path) -> Result<()> {
+        let contents = serde_json::to_vec(&*self.digests.read())?;
+        // Written next to it and renamed, so a crash can't leave half of a file behind
+        let tmp_path = path.with_extension("tmp");
+        std::fs::write(&tmp_path, contents)
+            .wrap_err_with(|| format!("Failed writing checksums to {tmp_path}"))?;
+        std::fs::rename(&tmp_path, path)
+            .wrap_err_with(|| format!("Failed moving checksums to {path}"))?;
+        Ok(())
+    }
+
+    /// Contents of a `SHA256SUMS` or `MD5SUMS` file for every file in `entries`, with paths
+    /// relative to `prefix`, or `None` if some of them weren't hashed yet
+    pub fn manifest(
+        &self,
+        algorithm: Algorithm,
+        prefix: &str,
+        entries: &[ArchiveEntry],
+    ) -> Option<String> {
+        let mut manifest = String::new();
+        for entry in entries {
+            let (Some(size), Source::Disk(path)) = (entry.size, &entry.source) else {
+                continue;
+            };
+            let digest = self.get(algorithm, path, size, entry.modified)?;
+            let name = entry.name.strip_prefix(prefix).unwrap_or(&entry.name);
+            _ = writeln!(manifest, "{digest}  {name}");
+        }
+        Some(manifest)
+    }
+}
+
+/// Path on disk, size and modification time of every file in `entries`, which are at `dir` in
+/// the cache
+fn collect_files(
+    roots: &DataRoots,
+    dir: &Utf8Path,
+    entries: &[CacheEntry],
+    out: &mut Vec<(Utf8PathBuf, u64, i64)>,
+) {
+    for entry in entries {
+        let path = dir.join(entry.name());
+        match entry {
+            CacheEntry::File(f) if f.link => {}
+            CacheEntry::File(f) => {
+                if let Some(fs_path) = roots.fs_path(&path) {
+                    out.push((fs_path, f.size, f.modified));
+                }
+            }
+            CacheEntry::Dir(d) => collect_files(roots, &path, &d.children, out),
+        }
+    }
+}
+
+/// SHA-256, MD5 and CRC-32 of the file, reading it once for all of them
+fn hash_file(path: &Utf8Path) -> io::Result<(String, String, u32)> {
+    let mut file = std::fs::File::open(path)?;
+    let mut sha256 = Sha256::new();
+    let mut md5 = Md5::new();
+    let mut crc32 = crc32fast::Hasher::new();
+    let mut buf = vec![0; 64 * 1024];
+    loop {
+        let n = file.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        sha256.update(&buf[..n]);
+        md5.update(&buf[..n]);
+        crc32.update(&buf[..n]);
+    }
+    Ok((
+        format!("{:x}", sha256.finalize()),
+        format!("{:x}", md5.finalize()),
+        crc32.finalize(),
+    ))
+}
@@ -0,0 +1,96 @@
+use axum::{
+    async_trait,
+    extract::{FromRequestParts, Query, Request},
+    http::{header, request::Parts, HeaderMap, HeaderValue, Uri},
+    middleware::Next,
+    response::Response,
+};
+use serde::{Deserialize, Serialize};
+use std::convert::Infallible;
+
+/// Name of the cookie the picked scheme is kept in
+const COOKIE_NAME: &str = "theme";
+/// The scheme is kept for a year
+const COOKIE_MAX_AGE: u64 = 365 * 24 * 60 * 60;
+
+/// Colors the pages are shown in, picked with `?theme=` and then kept in a cookie
+#[derive(Deserialize, Serialize, Debug, Default, Clone, Copy, PartialEq, Eq, Hash)]
+#[serde(rename_all = "lowercase")]
+pub enum ColorScheme {
+    /// Whatever the browser prefers
+    #[default]
+    Auto,
+    Light,
+    Dark,
+}
+
+#[derive(Deserialize)]
+struct ColorSchemeQuery {
+    theme: Option<ColorScheme>,
+}
+
+impl ColorScheme {
+    /// Value of the `data-theme` attribute of the page, none when following the browser
+    pub const fn attribute(self) -> Option<&'static str> {
+        match self {
+            Self::Auto => None,
+            Self::Light => Some("light"),
+            Self::Dark => Some("dark"),
+        }
+    }
+
+    fn from_query(uri: &Uri) -> Option<Self> {
+        Query::<ColorSchemeQuery>::try_from_uri(uri).ok()?.0.theme
+    }
+
+    fn from_cookie(headers: &HeaderMap) -> Option<Self> {
+        headers
+            .get_all(header::COOKIE)
+            .iter()
+            .filter_map(|h| h.to_str().ok())
+            .flat_map(|h| h.split(';'))
+            .filter_map(|cookie| cookie.trim().split_once('='))
+            .find(|(name, _)| *name == COOKIE_NAME)
+            .and_then(|(_, value)| match value {
+                "light" => Some(Self::Light),
+                "dark" => Some(Self::Dark),
+                _ => None,
+            })
+    }
+
+    /// `Set-Cookie` that keeps this scheme, or forgets the one that was kept for `Auto`
+    fn cookie(self) -> HeaderValue {
+        let cookie = match self.attribute() {
+            Some(value) => {
+                format!("{COOKIE_NAME}={value}; Path=/; Max-Age={COOKIE_MAX_AGE}; SameSite=Lax")
+            }
+            None => format!("{COOKIE_NAME}=; Path=/; Max-Age=0; SameSite=Lax"),
+        };
+        HeaderValue::try_from(cookie).expect("Cookie is a valid header value")
+    }
+}
+
+/// The scheme from the query if there's one, or the one kept in the cookie
+#[async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for ColorScheme {
+    type Rejection = Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        Ok(Self::from_query(&parts.uri)
+            .or_else(|| Self::from_cookie(&parts.headers))
+            .unwrap_or_default())
+    }
+}
+
+/// Middleware that keeps the scheme picked with `?theme=` in a cookie, so every page after it is
+/// shown in it too
+pub async fn remember_color_scheme(request: Request, next: Next) -> Response {
+    let picked = ColorScheme::from_query(request.uri());
+    let mut response = next.run(request).await;
+    if let Some(scheme) = picked {
+        response
+            .headers_mut()
+            .append(header::SET_COOKIE, scheme.cookie());
+    }
+    response
+}
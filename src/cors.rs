@@ -0,0 +1,78 @@
+use axum::http::{header, HeaderName, HeaderValue, Method};
+use color_eyre::{eyre::WrapErr, Result};
+use std::time::Duration;
+use tower_http::cors::{AllowOrigin, CorsLayer};
+
+/// How long browsers can keep the answer to a preflight request
+const PREFLIGHT_MAX_AGE: Duration = Duration::from_secs(60 * 60);
+
+/// Which other sites can call the listings and downloads from the browser, none if there are no
+/// origins
+#[derive(Debug, Clone)]
+pub struct Cors {
+    /// Like `https://example.com`, or `*` for any
+    pub origins: Vec<String>,
+    pub methods: Vec<String>,
+    /// Request headers they can send, on top of the ones that are always allowed
+    pub headers: Vec<String>,
+}
+
+impl Default for Cors {
+    fn default() -> Self {
+        Self {
+            origins: vec![],
+            methods: vec!["GET".to_owned(), "HEAD".to_owned()],
+            headers: vec!["Authorization".to_owned(), "Range".to_owned()],
+        }
+    }
+}
+
+impl Cors {
+    /// Layer answering preflight requests and adding the CORS headers to responses, `None` if no
+    /// origin is allowed
+    pub fn layer(&self) -> Result<Option<CorsLayer>> {
+        if self.origins.is_empty() {
+            return Ok(None);
+        }
+        let origins = if self.origins.iter().any(|o| o == "*") {
+            AllowOrigin::any()
+        } else {
+            let origins = self
+                .origins
+                .iter()
+                .map(|o| HeaderValue::from_str(o).wrap_err_with(|| format!("Invalid origin {o}")))
+                .collect::<Result<Vec<_>>>()?;
+            AllowOrigin::list(origins)
+        };
+        let methods = self
+            .methods
+            .iter()
+            .map(|m| {
+                Method::from_bytes(m.as_bytes()).wrap_err_with(|| format!("Invalid method {m}"))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let headers = self
+            .headers
+            .iter()
+            .map(|h| {
+                HeaderName::from_bytes(h.as_bytes()).wrap_err_with(|| format!("Invalid header {h}"))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        Ok(Some(
+            CorsLayer::new()
+                .allow_origin(origins)
+                .allow_methods(methods)
+                .allow_headers(headers)
+                // So downloads can be resumed and checked from scripts on the other site
+                .expose_headers([
+                    header::ACCEPT_RANGES,
+                    header::CONTENT_DISPOSITION,
+                    header::CONTENT_LENGTH,
+                    header::CONTENT_RANGE,
+                    header::ETAG,
+                    header::LAST_MODIFIED,
+                ])
+                .max_age(PREFLIGHT_MAX_AGE),
+        ))
+    }
+}
@@ -0,0 +1,146 @@
+use axum::http::HeaderMap;
+use base64::{engine::general_purpose::STANDARD, Engine as _};
+use camino::Utf8Path;
+use std::cmp::Reverse;
+
+use crate::checksums::{Algorithm, Checksums};
+
+/// Digests of a file taken from the checksum cache, for the `Repr-Digest` and `Content-Digest`
+/// headers of RFC 9530, and the older `Digest` of RFC 3230
+#[derive(Debug)]
+pub struct Digests {
+    sha256: Vec<u8>,
+    md5: Vec<u8>,
+}
+
+/// Bytes of a lowercase hex string, like the digests in the checksum cache
+fn from_hex(hex: &str) -> Option<Vec<u8>> {
+    if hex.len() % 2 != 0 {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+/// Algorithms asked for in a `Want-Repr-Digest` or `Want-Content-Digest`, like
+/// `sha-256=10, md5=1`, most preferred first. Ones with a preference of 0 aren't wanted
+fn wanted(want: &str) -> Vec<Algorithm> {
+    let mut wanted: Vec<_> = want
+        .split(',')
+        .filter_map(|item| {
+            let (name, preference) = item.split_once('=').unwrap_or((item, "1"));
+            let algorithm = match name.trim() {
+                "sha-256" => Algorithm::Sha256,
+                "md5" => Algorithm::Md5,
+                _ => return None,
+            };
+            let preference: u8 = preference.trim().parse().ok()?;
+            (preference > 0).then_some((algorithm, preference))
+        })
+        .collect();
+    wanted.sort_by_key(|&(_, preference)| Reverse(preference));
+    wanted.into_iter().map(|(algorithm, _)| algorithm).collect()
+}
+
+/// Algorithms asked for in a `Want-Digest`, like `SHA-256;q=0.5, MD5`, most preferred first
+fn wanted_legacy(want: &str) -> Vec<Algorithm> {
+    let mut wanted: Vec<_> = want
+        .split(',')
+        .filter_map(|item| {
+            let mut params = item.split(';').map(str::trim);
+            let name = params.next()?;
+            let algorithm = if name.eq_ignore_ascii_case("sha-256") {
+                Algorithm::Sha256
+            } else if name.eq_ignore_ascii_case("md5") {
+                Algorithm::Md5
+            } else {
+                return None;
+            };
+            let q = params
+                .find_map(|p| p.strip_prefix("q="))
+                .map_or(Some(1.0), |q| q.parse::<f32>().ok())?;
+            // Only to sort them, the precision of the q-value is 3 decimals anyway
+            let q = (q * 1000.0) as u16;
+            (q > 0).then_some((algorithm, q))
+        })
+        .collect();
+    wanted.sort_by_key(|&(_, q)| Reverse(q));
+    wanted.into_iter().map(|(algorithm, _)| algorithm).collect()
+}
+
+impl Digests {
+    /// Digests of the file at `path` on disk, if it was already hashed with this size and
+    /// modification time
+    pub fn get(checksums: &Checksums, path: &Utf8Path, size: u64, modified: i64) -> Option<Self> {
+        Some(Self {
+            sha256: from_hex(&checksums.get(Algorithm::Sha256, path, size, modified)?)?,
+            md5: from_hex(&checksums.get(Algorithm::Md5, path, size, modified)?)?,
+        })
+    }
+
+    fn encoded(&self, algorithm: Algorithm) -> String {
+        STANDARD.encode(match algorithm {
+            Algorithm::Sha256 => &self.sha256,
+            Algorithm::Md5 => &self.md5,
+        })
+    }
+
+    /// Dictionary of `algorithms` and their digests, like `sha-256=:<base64>:`
+    fn field(&self, algorithms: &[Algorithm]) -> Option<String> {
+        let field = algorithms
+            .iter()
+            .map(|&algorithm| {
+                let name = match algorithm {
+                    Algorithm::Sha256 => "sha-256",
+                    Algorithm::Md5 => "md5",
+                };
+                format!("{name}=:{}:", self.encoded(algorithm))
+            })
+            .collect::<Vec<_>>()
+            .join(", ");
+        (!field.is_empty()).then_some(field)
+    }
+
+    fn legacy_field(&self, algorithms: &[Algorithm]) -> Option<String> {
+        let field = algorithms
+            .iter()
+            .map(|&algorithm| {
+                let name = match algorithm {
+                    Algorithm::Sha256 => "SHA-256",
+                    Algorithm::Md5 => "MD5",
+                };
+                format!("{name}={}", self.encoded(algorithm))
+            })
+            .collect::<Vec<_>>()
+            .join(",");
+        (!field.is_empty()).then_some(field)
+    }
+
+    /// Headers for a response to a request with `headers`, `whole` if its body is the whole file
+    ///
+    /// `Repr-Digest` is always sent, with SHA-256 unless others were asked for.
+    /// `Content-Digest` and `Digest` are only sent when asked for, and only when they'd be the
+    /// same as the digest of the file, since ranges aren't hashed
+    pub fn headers(&self, headers: &HeaderMap, whole: bool) -> Vec<(&'static str, String)> {
+        let want = |name| headers.get(name).and_then(|v| v.to_str().ok());
+        let repr = want("Want-Repr-Digest").map_or_else(|| vec![Algorithm::Sha256], wanted);
+
+        let mut fields = vec![];
+        if let Some(field) = self.field(&repr) {
+            fields.push(("Repr-Digest", field));
+        }
+        if whole {
+            let content = want("Want-Content-Digest").map(wanted);
+            if let Some(field) = content.and_then(|c| self.field(&c)) {
+                fields.push(("Content-Digest", field));
+            }
+            let legacy = want("Want-Digest").map(wanted_legacy);
+            if let Some(field) = legacy.and_then(|l| self.legacy_field(&l)) {
+                fields.push(("Digest", field));
+            }
+        }
+        fields
+    }
+}
@@ -1,49 +1,84 @@
 use askama::filters::urlencode;
+use camino::{Utf8Component, Utf8Path, Utf8PathBuf};
 use chrono::{DateTime, Utc};
 use color_eyre::{
-    eyre::{ContextCompat, WrapErr},
-    Report, Result,
+    eyre::{bail, ensure, ContextCompat, WrapErr},
+    Result,
+};
+use ignore::gitignore::Gitignore;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::hash_map::RandomState,
+    fs::{File, Metadata},
+    hash::BuildHasher as _,
+    io,
+    ops::Deref,
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::Arc,
 };
 
-#[derive(Debug, Clone)]
+use crate::exclude::{Excludes, IGNORE_FILE};
+use crate::roots::{DataRoots, Mount};
+
+/// A file or directory inside the data dir, as kept in the directory cache
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
 pub enum CacheEntry {
     File(FileEntry),
     Dir(DirEntry),
 }
 
 impl CacheEntry {
-    pub fn size(&self) -> u64 {
+    pub const fn size(&self) -> u64 {
         match self {
             Self::File(f) => f.size,
-            Self::Dir(d) => d.children.iter().map(Self::size).sum(),
-        }
-    }
-
-    #[allow(clippy::cast_precision_loss)]
-    pub fn size_str(&self) -> String {
-        const BYTE_SIZE: u64 = 1024;
-
-        let size = self.size();
-        if size < BYTE_SIZE {
-            format!("{size} B")
-        } else if size < BYTE_SIZE.pow(2) {
-            let size = (size as f64) / (BYTE_SIZE as f64).powi(1);
-            format!("{size:.1} KiB")
-        } else if size < BYTE_SIZE.pow(3) {
-            let size = (size as f64) / (BYTE_SIZE as f64).powi(2);
-            format!("{size:.1} MiB")
-        } else if size < BYTE_SIZE.pow(4) {
-            let size = (size as f64) / (BYTE_SIZE as f64).powi(3);
-            format!("{size:.1} GiB")
-        } else {
-            "You really shouldn't be serving files that big with this tool...".to_owned()
+            Self::Dir(d) => d.size,
+        }
+    }
+
+    /// Files inside it, counting the ones in every directory, or 1 for a file
+    pub const fn file_count(&self) -> u64 {
+        match self {
+            Self::File(_) => 1,
+            Self::Dir(d) => d.file_count,
+        }
+    }
+
+    pub const fn created(&self) -> DateTime<Utc> {
+        match self {
+            Self::File(f) => f.created,
+            Self::Dir(d) => d.created,
+        }
+    }
+
+    /// Last modification time, in seconds since the unix epoch
+    pub const fn modified(&self) -> i64 {
+        match self {
+            Self::File(f) => f.modified,
+            Self::Dir(d) => d.modified,
+        }
+    }
+
+    /// Permission bits, only known on unix
+    pub const fn mode(&self) -> Option<u32> {
+        match self {
+            Self::File(f) => f.mode,
+            Self::Dir(d) => d.mode,
         }
     }
 
-    pub fn created(&self) -> &str {
+    pub const fn uid(&self) -> Option<u32> {
         match self {
-            Self::File(f) => &f.created,
-            Self::Dir(d) => &d.created,
+            Self::File(f) => f.uid,
+            Self::Dir(d) => d.uid,
+        }
+    }
+
+    pub const fn gid(&self) -> Option<u32> {
+        match self {
+            Self::File(f) => f.gid,
+            Self::Dir(d) => d.gid,
         }
     }
 
@@ -87,16 +122,210 @@ impl CacheEntry {
     }
 }
 
+/// Slot of a [`NameIndex`] that has no entry in it
+const EMPTY_SLOT: u32 = u32::MAX;
+
+/// Positions of the entries of a directory in an open addressed table hashed by their names
+///
+/// Slots only have the `u32` position of an entry, so names are only kept in the entries
+/// themselves, and the index takes 4 to 8 bytes per entry whatever their names are like
+#[derive(Debug, Clone, Default)]
+struct NameIndex {
+    /// Its length is a power of two, or 0 before anything is added, and less than 3/4 of it is
+    /// used, so looking for a name always ends at an empty slot
+    slots: Box<[u32]>,
+    hasher: RandomState,
+}
+
+impl NameIndex {
+    fn new(entries: &[CacheEntry]) -> Self {
+        let len = (entries.len() * 4 / 3 + 1).next_power_of_two();
+        let mut index = Self {
+            slots: vec![EMPTY_SLOT; len].into(),
+            hasher: RandomState::new(),
+        };
+        for i in 0..entries.len() {
+            index.place(entries, i);
+        }
+        index
+    }
+
+    /// Slot that `name` goes in when nothing else is there
+    fn home(&self, name: &str) -> usize {
+        self.hasher.hash_one(name) as usize & (self.slots.len() - 1)
+    }
+
+    /// Slot of the entry named `name`, out of `entries`
+    fn find(&self, entries: &[CacheEntry], name: &str) -> Option<usize> {
+        if self.slots.is_empty() {
+            return None;
+        }
+        let mask = self.slots.len() - 1;
+        let mut slot = self.home(name);
+        loop {
+            match self.slots[slot] {
+                EMPTY_SLOT => return None,
+                i if entries[i as usize].name() == name => return Some(slot),
+                _ => slot = (slot + 1) & mask,
+            }
+        }
+    }
+
+    /// Position in `entries` of the one named `name`
+    fn get(&self, entries: &[CacheEntry], name: &str) -> Option<usize> {
+        self.find(entries, name)
+            .map(|slot| self.slots[slot] as usize)
+    }
+
+    /// Puts the position `i` in the first empty slot from the one of its name
+    fn place(&mut self, entries: &[CacheEntry], i: usize) {
+        let mask = self.slots.len() - 1;
+        let mut slot = self.home(entries[i].name());
+        while self.slots[slot] != EMPTY_SLOT {
+            slot = (slot + 1) & mask;
+        }
+        self.slots[slot] = u32::try_from(i).expect("Directory has fewer than 2^32 - 1 entries");
+    }
+
+    /// Adds the last of `entries`, which was just pushed to them
+    fn push(&mut self, entries: &[CacheEntry]) {
+        if entries.len() * 4 >= self.slots.len() * 3 {
+            *self = Self::new(entries);
+        } else {
+            self.place(entries, entries.len() - 1);
+        }
+    }
+
+    /// Empties `slot`, moving the entries after it that were pushed past it back, since
+    /// looking for them would stop at the empty slot otherwise
+    fn clear(&mut self, entries: &[CacheEntry], mut slot: usize) {
+        let mask = self.slots.len() - 1;
+        self.slots[slot] = EMPTY_SLOT;
+        let mut next = slot;
+        loop {
+            next = (next + 1) & mask;
+            let i = self.slots[next];
+            if i == EMPTY_SLOT {
+                break;
+            }
+            // It can go in the empty slot if that's between its home and where it is now
+            let home = self.home(entries[i as usize].name());
+            if next.wrapping_sub(home) & mask >= next.wrapping_sub(slot) & mask {
+                self.slots[slot] = i;
+                self.slots[next] = EMPTY_SLOT;
+                slot = next;
+            }
+        }
+    }
+}
+
+/// Entries inside a directory, which can be looked up by name in constant time
+///
+/// Cloning it is cheap, since the entries are shared until one of the clones is changed
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(from = "Vec<CacheEntry>", into = "Vec<CacheEntry>")]
+pub struct DirContents {
+    entries: Arc<Vec<CacheEntry>>,
+    by_name: Arc<NameIndex>,
+}
+
+impl DirContents {
+    pub fn get(&self, name: &str) -> Option<&CacheEntry> {
+        self.by_name
+            .get(&self.entries, name)
+            .map(|i| &self.entries[i])
+    }
+
+    /// Only the entries of this directory are copied if they're shared, not their children
+    fn get_mut(&mut self, name: &str) -> Option<&mut CacheEntry> {
+        let i = self.by_name.get(&self.entries, name)?;
+        Some(&mut Arc::make_mut(&mut self.entries)[i])
+    }
+
+    /// Adds `entry`, replacing the one with the same name if there was one
+    pub fn insert(&mut self, entry: CacheEntry) {
+        let entries = Arc::make_mut(&mut self.entries);
+        if let Some(i) = self.by_name.get(entries, entry.name()) {
+            entries[i] = entry;
+        } else {
+            entries.push(entry);
+            Arc::make_mut(&mut self.by_name).push(entries);
+        }
+    }
+
+    pub fn remove(&mut self, name: &str) -> Option<CacheEntry> {
+        let by_name = Arc::make_mut(&mut self.by_name);
+        let slot = by_name.find(&self.entries, name)?;
+        let index = by_name.slots[slot];
+        by_name.clear(&self.entries, slot);
+        let i = index as usize;
+        let entries = Arc::make_mut(&mut self.entries);
+        let last = entries.len() - 1;
+        if i != last {
+            // The last entry takes its place
+            let moved = by_name
+                .find(entries, entries[last].name())
+                .expect("Every entry is in the index");
+            by_name.slots[moved] = index;
+        }
+        Some(entries.swap_remove(i))
+    }
+}
+
+impl From<Vec<CacheEntry>> for DirContents {
+    fn from(mut entries: Vec<CacheEntry>) -> Self {
+        // Directories are read by pushing to it, which leaves up to half of it unused
+        entries.shrink_to_fit();
+        let by_name = NameIndex::new(&entries);
+        Self {
+            entries: Arc::new(entries),
+            by_name: Arc::new(by_name),
+        }
+    }
+}
+
+impl From<DirContents> for Vec<CacheEntry> {
+    fn from(contents: DirContents) -> Self {
+        Arc::unwrap_or_clone(contents.entries)
+    }
+}
+
+impl Deref for DirContents {
+    type Target = [CacheEntry];
+
+    fn deref(&self) -> &Self::Target {
+        &self.entries
+    }
+}
+
 /// Struct that represents a file/directory inside a directory, that can
 /// access all its fields without erroring, because it errors upon construction
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct DirEntry {
     /// Name of the file
     pub name: String,
-    /// UTC time this file was modified, in format `%Y-%m-%d [%H:%M:%S]`
-    pub created: String,
+    /// Time this directory was created, or modified if that isn't known
+    pub created: DateTime<Utc>,
+    /// Last modification time, in seconds since the unix epoch
+    pub modified: i64,
+    /// Permission bits, only known on unix
+    pub mode: Option<u32>,
+    /// Owner and group ids, only known on unix
+    #[serde(default)]
+    pub uid: Option<u32>,
+    #[serde(default)]
+    pub gid: Option<u32>,
     /// Children
-    pub children: Vec<CacheEntry>,
+    pub children: DirContents,
+    /// Size of every file inside it, counting the ones in every directory
+    #[serde(default)]
+    pub size: u64,
+    /// Files inside it, counting the ones in every directory
+    #[serde(default)]
+    pub file_count: u64,
+    /// Whether the children weren't read yet, because the directory is deeper than the cache depth
+    #[serde(default)]
+    pub unread: bool,
 }
 
 impl DirEntry {
@@ -104,6 +333,12 @@ impl DirEntry {
         self.children.len()
     }
 
+    /// Computes the size and file count again from the ones of its children
+    pub fn update_totals(&mut self) {
+        self.size = self.children.iter().map(CacheEntry::size).sum();
+        self.file_count = self.children.iter().map(CacheEntry::file_count).sum();
+    }
+
     pub fn max_depth(&self) -> usize {
         self.children
             .iter()
@@ -122,70 +357,811 @@ impl DirEntry {
 
 /// Struct that represents a file/directory inside a directory, that can
 /// access all its fields without erroring, because it errors upon construction
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct FileEntry {
     /// Name of the file
     pub name: String,
-    /// Localtime this file was modified, in format
-    pub created: String,
+    /// Time this file was created, or modified if that isn't known
+    pub created: DateTime<Utc>,
+    /// Last modification time, in seconds since the unix epoch
+    pub modified: i64,
+    /// Permission bits, only known on unix
+    pub mode: Option<u32>,
+    /// Owner and group ids, only known on unix
+    #[serde(default)]
+    pub uid: Option<u32>,
+    #[serde(default)]
+    pub gid: Option<u32>,
     /// Size of this file, if this is a file, already formatted
     /// Size of all children, if this is a directory
     pub size: u64,
+    /// Whether this is a symlink that's shown but not followed
+    #[serde(default)]
+    pub link: bool,
+}
+
+#[cfg(unix)]
+fn permission_bits(meta: &Metadata) -> Option<u32> {
+    use std::os::unix::fs::PermissionsExt as _;
+    Some(meta.permissions().mode() & 0o7777)
+}
+
+#[cfg(not(unix))]
+const fn permission_bits(_: &Metadata) -> Option<u32> {
+    None
+}
+
+#[cfg(unix)]
+fn ownership(meta: &Metadata) -> (Option<u32>, Option<u32>) {
+    use std::os::unix::fs::MetadataExt as _;
+    (Some(meta.uid()), Some(meta.gid()))
+}
+
+#[cfg(not(unix))]
+const fn ownership(_: &Metadata) -> (Option<u32>, Option<u32>) {
+    (None, None)
 }
 
-impl TryFrom<std::fs::DirEntry> for CacheEntry {
-    type Error = Report;
+/// Identifies a directory no matter which path it was reached through, so symlinks that loop
+/// back to a directory they're in can be found
+#[cfg(unix)]
+type DirId = (u64, u64);
 
-    fn try_from(value: std::fs::DirEntry) -> Result<Self> {
-        let name = value
+#[cfg(unix)]
+fn dir_id(meta: &Metadata) -> Option<DirId> {
+    use std::os::unix::fs::MetadataExt as _;
+    Some((meta.dev(), meta.ino()))
+}
+
+#[cfg(not(unix))]
+type DirId = ();
+
+#[cfg(not(unix))]
+const fn dir_id(_: &Metadata) -> Option<DirId> {
+    None
+}
+
+/// What to do with symlinks inside the data dir
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SymlinkPolicy {
+    /// Treat them like what they point to, as long as it's inside the data dir and doesn't make
+    /// a loop
+    Follow,
+    /// Show them, marked as links, but don't follow them
+    #[default]
+    Display,
+    /// Leave them out of the cache entirely
+    Ignore,
+}
+
+impl FromStr for SymlinkPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "follow" => Ok(Self::Follow),
+            "display" => Ok(Self::Display),
+            "ignore" => Ok(Self::Ignore),
+            s => Err(format!(
+                "Invalid symlink policy {s}, expected `follow`, `display` or `ignore`"
+            )),
+        }
+    }
+}
+
+/// How the data dir is read
+#[derive(Debug, Clone)]
+pub struct ScanOptions {
+    pub symlinks: SymlinkPolicy,
+    pub excludes: Excludes,
+    /// Followed symlinks have to point inside of one of them
+    canonical_data_dirs: Vec<PathBuf>,
+}
+
+impl ScanOptions {
+    pub fn new(roots: &DataRoots, symlinks: SymlinkPolicy, excludes: Excludes) -> Result<Self> {
+        let canonical_data_dirs = roots
+            .dirs()
+            .into_iter()
+            .map(|(_, dir)| {
+                dir.canonicalize()
+                    .wrap_err_with(|| format!("Failed to canonicalize data dir {dir}"))
+            })
+            .collect::<Result<_>>()?;
+        Ok(Self {
+            symlinks,
+            excludes,
+            canonical_data_dirs,
+        })
+    }
+
+    fn in_data_dirs(&self, path: &Path) -> bool {
+        self.canonical_data_dirs.iter().any(|d| path.starts_with(d))
+    }
+
+    /// Metadata of what the symlink at `path` points to, which has to be inside the data dirs
+    fn follow(&self, path: &Path) -> Result<Metadata> {
+        let target = path.canonicalize().wrap_err("Failed to resolve symlink")?;
+        ensure!(
+            self.in_data_dirs(&target),
+            "Symlink points outside of the data dirs, to {}",
+            target.display()
+        );
+        std::fs::metadata(&target).wrap_err("Failed to get metadata for symlink target")
+    }
+
+    /// Makes sure `path` can be downloaded, which is only possible through symlinks if they're
+    /// followed, and then only if it ends up inside the data dirs
+    pub fn check_download(&self, roots: &DataRoots, path: &Utf8Path) -> Result<()> {
+        self.download_target(roots, path).map(|_| ())
+    }
+
+    /// Opens `path` after checking it like [`Self::check_download`]
+    ///
+    /// What's opened is where the path led when it was checked, without going through it again,
+    /// and it's checked again after opening it, in case anything on the way was swapped for a
+    /// symlink in between
+    pub fn open_download(&self, roots: &DataRoots, path: &Utf8Path) -> Result<File> {
+        let target = self.download_target(roots, path)?;
+        let file = File::open(&target).wrap_err_with(|| format!("Failed to open {path}"))?;
+        ensure!(
+            is_opened_from(&file, &target),
+            "Path {path} changed while it was being opened"
+        );
+        Ok(file)
+    }
+
+    /// Where `path` leads, once it's made sure it can be downloaded
+    fn download_target(&self, roots: &DataRoots, path: &Utf8Path) -> Result<PathBuf> {
+        let (data_dir, path) = roots
+            .resolve(path)
+            .wrap_err_with(|| format!("Path {path} is not inside a data dir"))?;
+        // Whatever the path goes through, what's opened has to be inside the data dirs
+        let target = data_dir
+            .join(path)
+            .canonicalize()
+            .wrap_err_with(|| format!("Failed to resolve {path}"))?;
+        ensure!(
+            self.in_data_dirs(&target),
+            "Path {path} is outside of the data dirs"
+        );
+        if self.symlinks != SymlinkPolicy::Follow {
+            let mut current = data_dir.to_path_buf();
+            for component in path.components() {
+                current.push(component);
+                let meta = std::fs::symlink_metadata(&current)
+                    .wrap_err_with(|| format!("Failed to get metadata for {current}"))?;
+                ensure!(!meta.is_symlink(), "Path {path} goes through a symlink");
+            }
+        }
+        Ok(target)
+    }
+}
+
+/// Whether `file` was opened from `path`, which has no symlinks in it, going by where the kernel
+/// says the file it opened is
+#[cfg(target_os = "linux")]
+fn is_opened_from(file: &File, path: &Path) -> bool {
+    use std::os::unix::io::AsRawFd as _;
+    std::fs::read_link(format!("/proc/self/fd/{}", file.as_raw_fd())).is_ok_and(|p| p == path)
+}
+
+/// Whether `file` is the same one that's at `path` now, which is the best that can be done
+/// without asking the kernel where it is
+#[cfg(all(unix, not(target_os = "linux")))]
+fn is_opened_from(file: &File, path: &Path) -> bool {
+    let (Ok(opened), Ok(at_path)) = (file.metadata(), std::fs::symlink_metadata(path)) else {
+        return false;
+    };
+    dir_id(&opened) == dir_id(&at_path)
+}
+
+#[cfg(not(unix))]
+const fn is_opened_from(_: &File, _: &Path) -> bool {
+    true
+}
+
+/// Directories the path being read is inside of, outermost first
+#[derive(Debug, Default)]
+struct Ancestors {
+    ids: Vec<Option<DirId>>,
+    /// Patterns from the ignore file of each one, if they have one
+    dir_patterns: Vec<Option<Gitignore>>,
+}
+
+impl Ancestors {
+    /// The data dir and every directory on the way to `path`, not counting itself
+    fn of(data_dir: &Utf8Path, path: &Utf8Path) -> Self {
+        let mut ancestors = Self::default();
+        let mut current = data_dir.to_path_buf();
+        let meta = std::fs::metadata(&current).ok();
+        ancestors.push(current.as_std_path(), meta.as_ref().and_then(dir_id));
+        if let Some(parent) = path.parent() {
+            for component in parent.components() {
+                current.push(component);
+                let meta = std::fs::metadata(&current).ok();
+                ancestors.push(current.as_std_path(), meta.as_ref().and_then(dir_id));
+            }
+        }
+        ancestors
+    }
+
+    fn contains(&self, id: &DirId) -> bool {
+        self.ids.iter().flatten().any(|i| i == id)
+    }
+
+    fn push(&mut self, dir: &Path, id: Option<DirId>) {
+        self.ids.push(id);
+        self.dir_patterns.push(Excludes::dir_patterns(dir));
+    }
+
+    fn pop(&mut self) {
+        self.ids.pop();
+        self.dir_patterns.pop();
+    }
+}
+
+/// Entry that was left out of the cache because it couldn't be read
+#[derive(Debug, Clone, Serialize)]
+pub struct IndexError {
+    pub path: String,
+    pub error: String,
+}
+
+impl CacheEntry {
+    /// Builds the entry for the file or directory at `path`, along with its children if it's a
+    /// directory, down to `depth` levels of directories below it
+    ///
+    /// `ancestors` has every directory `path` is inside of. Children that can't be read are
+    /// skipped and recorded in `errors`, so only failing to read `path` itself is an error
+    fn from_path(
+        path: &Path,
+        depth: Option<usize>,
+        options: &ScanOptions,
+        ancestors: &mut Ancestors,
+        errors: &mut Vec<IndexError>,
+    ) -> Result<Self> {
+        let file_name = path
             .file_name()
+            .with_context(|| format!("Path {} has no file name", path.display()))?;
+        let name = file_name
             .to_str()
-            .with_context(|| format!("File name for {:?} was invalid unicode", value.file_name()))?
+            .with_context(|| format!("File name for {file_name:?} was invalid unicode"))?
             .to_owned();
 
-        let is_dir = value
-            .file_type()
-            .wrap_err_with(|| format!("Could not get filetype for {name}"))?
-            .is_dir();
-
-        let meta = value
-            .metadata()
+        let meta = std::fs::symlink_metadata(path)
             .wrap_err_with(|| format!("Failed to get metadata for {name}"))?;
+        let (meta, link) = if meta.is_symlink() {
+            match options.symlinks {
+                SymlinkPolicy::Follow => (
+                    options
+                        .follow(path)
+                        .wrap_err_with(|| format!("Failed to follow symlink {name}"))?,
+                    false,
+                ),
+                SymlinkPolicy::Display | SymlinkPolicy::Ignore => (meta, true),
+            }
+        } else {
+            (meta, false)
+        };
+        let is_dir = meta.is_dir();
 
+        // Not every filesystem keeps the creation time
         let created: DateTime<Utc> = meta
             .created()
+            .or_else(|_| meta.modified())
             .wrap_err_with(|| format!("Failed to get creation time for {name}"))?
             .into();
-        let created = created.format("%Y-%m-%d [%H:%M:%S]").to_string();
+        let modified = meta
+            .modified()
+            .map_or(created, DateTime::<Utc>::from)
+            .timestamp();
+        let mode = permission_bits(&meta);
+        let (uid, gid) = ownership(&meta);
 
         if is_dir {
-            let children: Vec<Self> = {
-                let entries = value
-                    .path()
-                    .read_dir()
-                    .wrap_err_with(|| format!("Failed to read children for directory {name}"))?;
-                let mut children = vec![];
-                for e in entries {
-                    let e = e?;
-                    children.push(
-                        e.try_into()
-                            .wrap_err_with(|| format!("Failed to get child for {name}"))?,
-                    );
-                }
+            let id = dir_id(&meta);
+            if let Some(id) = id {
+                ensure!(
+                    !ancestors.contains(&id),
+                    "Symlink {name} loops back to a directory it's inside of"
+                );
+            }
+
+            let unread = depth == Some(0);
+            let children = if unread {
+                DirContents::default()
+            } else {
+                ancestors.push(path, id);
+                let children =
+                    read_children(path, depth.map(|d| d - 1), options, ancestors, errors);
+                ancestors.pop();
                 children
+                    .wrap_err_with(|| format!("Failed to read children for directory {name}"))?
+                    .into()
             };
-            Ok(Self::Dir(DirEntry {
+            let mut entry = DirEntry {
                 name,
                 created,
+                modified,
+                mode,
+                uid,
+                gid,
                 children,
-            }))
+                size: 0,
+                file_count: 0,
+                unread,
+            };
+            entry.update_totals();
+            Ok(Self::Dir(entry))
         } else {
             let size = meta.len();
             Ok(Self::File(FileEntry {
                 name,
                 created,
+                modified,
+                mode,
+                uid,
+                gid,
                 size,
+                link,
             }))
         }
     }
 }
+
+/// Reads every entry inside the data dirs, recursively, or only down to `depth` levels of
+/// directories below the root, leaving deeper ones unread
+///
+/// Entries that can't be read are skipped and recorded in `errors`, so only failing to list the
+/// data dir itself is an error. When serving several, the ones that can't be read are skipped too
+pub fn read_entries(
+    roots: &DataRoots,
+    depth: Option<usize>,
+    options: &ScanOptions,
+    errors: &mut Vec<IndexError>,
+) -> Result<Vec<CacheEntry>> {
+    match roots {
+        DataRoots::Single(data_dir) => {
+            let mut ancestors = Ancestors::of(data_dir, Utf8Path::new(""));
+            read_children(
+                data_dir.as_std_path(),
+                depth,
+                options,
+                &mut ancestors,
+                errors,
+            )
+        }
+        DataRoots::Mounts(mounts) => {
+            let mut res = vec![];
+            for mount in mounts {
+                match read_mount(mount, depth, options, errors) {
+                    Ok(entry) => res.push(entry),
+                    Err(e) => errors.push(IndexError {
+                        path: mount.path.to_string(),
+                        error: format!("{e:#}"),
+                    }),
+                }
+            }
+            Ok(res)
+        }
+    }
+}
+
+/// Reads the data dir in `mount` as a top-level directory
+fn read_mount(
+    mount: &Mount,
+    depth: Option<usize>,
+    options: &ScanOptions,
+    errors: &mut Vec<IndexError>,
+) -> Result<CacheEntry> {
+    let mut entry = CacheEntry::from_path(
+        mount.path.as_std_path(),
+        depth,
+        options,
+        &mut Ancestors::default(),
+        errors,
+    )?;
+    match &mut entry {
+        CacheEntry::Dir(d) => d.name.clone_from(&mount.name),
+        CacheEntry::File(_) => bail!("Data dir {} is not a directory", mount.path),
+    }
+    Ok(entry)
+}
+
+/// Reads the entry at `path` down to `depth` levels of directories below it
+pub fn read_entry(
+    roots: &DataRoots,
+    path: &Utf8Path,
+    depth: Option<usize>,
+    options: &ScanOptions,
+    errors: &mut Vec<IndexError>,
+) -> Result<CacheEntry> {
+    let (data_dir, rest) = roots
+        .resolve(path)
+        .wrap_err_with(|| format!("Path {path} is not inside a data dir"))?;
+    if let (DataRoots::Mounts(mounts), "") = (roots, rest.as_str()) {
+        let mount = mounts
+            .iter()
+            .find(|m| m.name == path.as_str())
+            .wrap_err_with(|| format!("Path {path} is not a data dir"))?;
+        return read_mount(mount, depth, options, errors);
+    }
+    let mut ancestors = Ancestors::of(data_dir, rest);
+    CacheEntry::from_path(
+        data_dir.join(rest).as_std_path(),
+        depth,
+        options,
+        &mut ancestors,
+        errors,
+    )
+}
+
+fn read_children(
+    dir: &Path,
+    depth: Option<usize>,
+    options: &ScanOptions,
+    ancestors: &mut Ancestors,
+    errors: &mut Vec<IndexError>,
+) -> Result<Vec<CacheEntry>> {
+    let entries = dir
+        .read_dir()
+        .wrap_err_with(|| format!("Failed to read contents of {}", dir.display()))?;
+
+    let mut res = vec![];
+    for e in entries {
+        let e = match e {
+            Ok(e) => e,
+            Err(e) => {
+                errors.push(IndexError {
+                    path: dir.display().to_string(),
+                    error: format!("Failed to read entry: {e}"),
+                });
+                continue;
+            }
+        };
+        if options.symlinks == SymlinkPolicy::Ignore && e.file_type().is_ok_and(|t| t.is_symlink())
+        {
+            continue;
+        }
+        let path = e.path();
+        let is_dir = std::fs::metadata(&path).is_ok_and(|m| m.is_dir());
+        if options
+            .excludes
+            .is_excluded(&ancestors.dir_patterns, &path, is_dir)
+        {
+            continue;
+        }
+        match CacheEntry::from_path(&path, depth, options, ancestors, errors) {
+            Ok(entry) => res.push(entry),
+            Err(e) => errors.push(IndexError {
+                path: path.display().to_string(),
+                error: format!("{e:#}"),
+            }),
+        }
+    }
+    Ok(res)
+}
+
+/// Children of the directory at `path`, if it's in `entries`
+fn dir_children<'a>(entries: &'a DirContents, path: &Utf8Path) -> Option<&'a DirContents> {
+    let mut children = entries;
+    for component in path.components() {
+        let Utf8Component::Normal(name) = component else {
+            return None;
+        };
+        let CacheEntry::Dir(d) = children.get(name)? else {
+            return None;
+        };
+        children = &d.children;
+    }
+    Some(children)
+}
+
+/// Children of the directory at `path`, if it's in `entries`
+fn dir_children_mut<'a>(
+    entries: &'a mut DirContents,
+    path: &Utf8Path,
+) -> Option<&'a mut DirContents> {
+    let mut children = entries;
+    for component in path.components() {
+        let Utf8Component::Normal(name) = component else {
+            return None;
+        };
+        let CacheEntry::Dir(d) = children.get_mut(name)? else {
+            return None;
+        };
+        children = &mut d.children;
+    }
+    Some(children)
+}
+
+/// First directory on the way to `path`, or `path` itself, whose children weren't read yet
+pub fn first_unread(entries: &DirContents, path: &Utf8Path) -> Option<Utf8PathBuf> {
+    let mut children = entries;
+    let mut current = Utf8PathBuf::new();
+    for component in path.components() {
+        let CacheEntry::Dir(d) = children.get(component.as_str())? else {
+            return None;
+        };
+        current.push(&d.name);
+        if d.unread {
+            return Some(current);
+        }
+        children = &d.children;
+    }
+    None
+}
+
+/// Whether any directory in `entries` wasn't read yet
+pub fn has_unread(entries: &[CacheEntry]) -> bool {
+    entries.iter().any(|e| match e {
+        CacheEntry::File(_) => false,
+        CacheEntry::Dir(d) => d.unread || has_unread(&d.children),
+    })
+}
+
+/// Puts `entry` in place of the one at `path`, if the directory it's in is still in `entries`
+pub fn replace_entry(entries: &mut DirContents, path: &Utf8Path, entry: CacheEntry) {
+    let parent = path.parent().unwrap_or_else(|| Utf8Path::new(""));
+    if let Some(siblings) = dir_children_mut(entries, parent) {
+        siblings.insert(entry);
+        update_totals(entries, parent);
+    }
+}
+
+/// Computes the totals of every directory on the way to `path`, and `path` itself, again, after
+/// something inside of it changed
+fn update_totals(entries: &mut DirContents, path: &Utf8Path) {
+    let mut components = path.components();
+    let Some(first) = components.next() else {
+        return;
+    };
+    if let Some(CacheEntry::Dir(d)) = entries.get_mut(first.as_str()) {
+        update_totals(&mut d.children, components.as_path());
+        d.update_totals();
+    }
+}
+
+/// What's on disk now at a path that changed, read with [`read_change`] without holding the cache,
+/// then put in it with [`apply_change`]
+pub enum Change {
+    /// Everything inside the data dirs
+    All(Vec<CacheEntry>),
+    /// The entry at the path, or `None` if it doesn't exist anymore
+    Entry(Utf8PathBuf, Option<CacheEntry>),
+}
+
+impl Change {
+    /// Path that was read
+    pub fn path(&self) -> &Utf8Path {
+        match self {
+            Change::All(_) => Utf8Path::new(""),
+            Change::Entry(path, _) => path,
+        }
+    }
+}
+
+/// Path that has to be read again after `path` changed
+///
+/// If the directory it's in isn't in `entries`, that directory has to be read instead, and so on
+pub fn changed_path(entries: &DirContents, path: &Utf8Path) -> Utf8PathBuf {
+    let Some(name) = path.file_name() else {
+        return Utf8PathBuf::new();
+    };
+    let parent = path.parent().unwrap_or_else(|| Utf8Path::new(""));
+    // Changing the patterns can change anything in the directory
+    if name == IGNORE_FILE || dir_children(entries, parent).is_none() {
+        return changed_path(entries, parent);
+    }
+    path.to_owned()
+}
+
+/// Reads the entry at `path` again, as returned by [`changed_path`], with directories read down to
+/// `depth` levels below the root
+pub fn read_change(
+    roots: &DataRoots,
+    path: &Utf8Path,
+    depth: Option<usize>,
+    options: &ScanOptions,
+    errors: &mut Vec<IndexError>,
+) -> Result<Change> {
+    let Some(parent) = path.parent() else {
+        return Ok(Change::All(read_entries(roots, depth, options, errors)?));
+    };
+    // Directories that were read when browsed into are deeper than the cache depth
+    let entry_depth = depth.map(|d| d.saturating_sub(parent.components().count()));
+
+    // Not inside of any data dir, so it's gone
+    let Some(full_path) = roots.fs_path(path) else {
+        return Ok(Change::Entry(path.to_owned(), None));
+    };
+    let entry = match std::fs::symlink_metadata(&full_path) {
+        Err(e) if e.kind() == io::ErrorKind::NotFound => None,
+        Ok(meta) if meta.is_symlink() && options.symlinks == SymlinkPolicy::Ignore => None,
+        _ if options.excludes.excludes_path(roots, path) => None,
+        _ => match read_entry(roots, path, entry_depth, options, errors) {
+            Ok(entry) => Some(entry),
+            Err(e) => {
+                errors.push(IndexError {
+                    path: full_path.to_string(),
+                    error: format!("{e:#}"),
+                });
+                None
+            }
+        },
+    };
+    Ok(Change::Entry(path.to_owned(), entry))
+}
+
+/// Puts what was read with [`read_change`] in `entries`, replacing or removing the entry that was
+/// there. Nothing changes if the directory it's in was removed from `entries` since
+pub fn apply_change(entries: &mut DirContents, change: Change) {
+    let (path, entry) = match change {
+        Change::All(all) => {
+            *entries = all.into();
+            return;
+        }
+        Change::Entry(path, entry) => (path, entry),
+    };
+    let (Some(name), Some(parent)) = (path.file_name(), path.parent()) else {
+        return;
+    };
+    let Some(siblings) = dir_children_mut(entries, parent) else {
+        return;
+    };
+    siblings.remove(name);
+    if let Some(entry) = entry {
+        siblings.insert(entry);
+    }
+    update_totals(entries, parent);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::roots::DataDir;
+
+    fn read_tree(dir: &Path) -> DirContents {
+        let roots = DataRoots::new(&[DataDir {
+            name: None,
+            path: Utf8Path::from_path(dir)
+                .expect("tempdir is utf-8")
+                .to_owned(),
+            hidden: false,
+            preview_only: false,
+        }])
+        .expect("valid data dir");
+        let excludes = Excludes::new(&roots, &[]).expect("no patterns to fail");
+        let options =
+            ScanOptions::new(&roots, SymlinkPolicy::Display, excludes).expect("valid scan options");
+        let mut errors = vec![];
+        let entries = read_entries(&roots, None, &options, &mut errors).expect("data dir is read");
+        assert!(errors.is_empty(), "{errors:?}");
+        entries.into()
+    }
+
+    #[test]
+    fn entries_are_found_by_name_at_any_depth() {
+        let data = tempfile::tempdir().expect("could not create tempdir for data");
+        let deep: Utf8PathBuf = (0..64).map(|i| format!("d{i}")).collect();
+        std::fs::create_dir_all(data.path().join(&deep)).expect("failed creating dirs");
+        std::fs::write(data.path().join(deep.join("file.txt")), "deep")
+            .expect("failed writing file");
+        std::fs::create_dir(data.path().join("big")).expect("failed creating dir");
+        for i in 0..1000 {
+            std::fs::write(data.path().join(format!("big/{i}")), "").expect("failed writing file");
+        }
+
+        let mut entries = read_tree(data.path());
+        let children = dir_children(&entries, &deep).expect("deep dir was read");
+        assert!(children.get("file.txt").is_some_and(|e| !e.is_dir()));
+        assert!(dir_children(&entries, &deep.join("file.txt")).is_none());
+        assert!(dir_children(&entries, &deep.join("missing")).is_none());
+
+        let big = dir_children_mut(&mut entries, Utf8Path::new("big")).expect("dir was read");
+        for i in (0..1000).step_by(3) {
+            big.remove(&i.to_string()).expect("entry was there");
+        }
+        // Whatever was moved around by removing the others can still be found
+        for i in 0..1000 {
+            assert_eq!(big.get(&i.to_string()).is_some(), i % 3 != 0, "{i}");
+        }
+        let replaced = big.get("1").expect("entry was there").clone();
+        big.insert(replaced);
+        assert_eq!(big.len(), 666);
+        assert!(big.iter().all(|e| big.get(e.name()).is_some()));
+    }
+
+    #[test]
+    fn index_by_name_follows_inserts_and_removals() {
+        let file = |name: String| {
+            CacheEntry::File(FileEntry {
+                name,
+                created: DateTime::default(),
+                modified: 0,
+                mode: None,
+                uid: None,
+                gid: None,
+                size: 0,
+                link: false,
+            })
+        };
+
+        // Starts empty and grows one entry at a time, removing some on the way
+        let mut contents = DirContents::default();
+        assert!(contents.get("0").is_none());
+        for i in 0..5000 {
+            contents.insert(file(i.to_string()));
+            if i % 5 == 4 {
+                contents
+                    .remove(&(i / 2).to_string())
+                    .expect("entry was there");
+            }
+        }
+        let removed: Vec<_> = (0..5000).filter(|i| i % 5 == 4).map(|i| i / 2).collect();
+        for i in 0..5000 {
+            let name = i.to_string();
+            let entry = contents.get(&name);
+            assert_eq!(entry.is_some(), !removed.contains(&i), "{i}");
+            assert!(entry.map_or(true, |e| e.name() == name));
+        }
+        assert_eq!(contents.len(), 4000);
+
+        // A copy keeps the old entries when the original is changed
+        let copy = contents.clone();
+        for e in copy.iter() {
+            contents.remove(e.name()).expect("entry was there");
+        }
+        assert!(contents.is_empty());
+        assert!(copy.iter().all(|e| copy.get(e.name()).is_some()));
+        contents.insert(file("again".to_owned()));
+        assert!(contents.get("again").is_some());
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn symlink_swapped_in_after_checking_is_refused() {
+        let data = tempfile::tempdir().expect("could not create tempdir for data");
+        let outside = tempfile::tempdir().expect("could not create tempdir outside of it");
+        std::fs::create_dir(data.path().join("sub")).expect("failed creating dir");
+        std::fs::write(data.path().join("sub/a.txt"), "inside").expect("failed writing file");
+        std::fs::write(outside.path().join("a.txt"), "outside").expect("failed writing file");
+
+        let roots = DataRoots::new(&[DataDir {
+            name: None,
+            path: Utf8Path::from_path(data.path())
+                .expect("tempdir is utf-8")
+                .to_owned(),
+            hidden: false,
+            preview_only: false,
+        }])
+        .expect("valid data dir");
+        let excludes = Excludes::new(&roots, &[]).expect("no patterns to fail");
+        let options =
+            ScanOptions::new(&roots, SymlinkPolicy::Display, excludes).expect("valid scan options");
+        let path = Utf8Path::new("sub/a.txt");
+        let target = options
+            .download_target(&roots, path)
+            .expect("file can be downloaded");
+
+        // What the path goes through changes between checking it and opening it
+        std::fs::rename(data.path().join("sub"), data.path().join("old"))
+            .expect("failed moving dir");
+        std::os::unix::fs::symlink(outside.path(), data.path().join("sub"))
+            .expect("failed creating symlink");
+        let file = File::open(&target).expect("path still leads to a file");
+        assert!(!is_opened_from(&file, &target));
+
+        assert!(options.open_download(&roots, path).is_err());
+        let file = options
+            .open_download(&roots, Utf8Path::new("old/a.txt"))
+            .expect("file can be downloaded");
+        let moved = data
+            .path()
+            .join("old/a.txt")
+            .canonicalize()
+            .expect("file exists");
+        assert!(is_opened_from(&file, &moved));
+    }
+}
@@ -3,25 +3,47 @@ use axum::response::IntoResponse;
 use axum::{
     body::Body,
     extract::{self, Query, State},
-    http::{Response, StatusCode},
+    http::{header, HeaderMap, HeaderValue, Response, StatusCode},
     response::Redirect,
+    Json,
 };
 use camino::{Utf8Component, Utf8Path, Utf8PathBuf};
+use chrono::{DateTime, Utc};
 use color_eyre::{
     eyre::{bail, ensure, WrapErr},
     Result,
 };
-use serde::Deserialize;
+use globset::{Glob, GlobMatcher};
+use percent_encoding::{utf8_percent_encode, AsciiSet, CONTROLS};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::hash::{DefaultHasher, Hash, Hasher};
 use std::path::PathBuf;
+use std::sync::atomic::Ordering;
 use std::sync::Arc;
 use tracing::{debug, info};
-use url::Url;
+use url::{form_urlencoded, Url};
 
 use askama::Template;
 
-use crate::{dir_cache::CacheEntry, utils::cmp_ignore_case_utf8, AppState};
+use crate::{
+    access::Visitor,
+    archive_browse::can_browse,
+    assets::Branding,
+    checksums::Algorithm,
+    color_scheme::ColorScheme,
+    dir_cache::{CacheEntry, DirContents},
+    download::not_modified,
+    i18n::{Locale, Messages},
+    mime,
+    owners::{format_mode, Owners},
+    readme::render_readme,
+    utils::{cmp_ignore_case_utf8, DateFormat, SizeUnits},
+    view::{can_preview, opens_as_page},
+    AppState,
+};
 
-#[derive(Deserialize, Debug)]
+#[derive(Deserialize, Debug, Hash)]
 pub struct FetchQuery {
     #[serde(rename = "ord")]
     #[serde(default)]
@@ -30,15 +52,79 @@ pub struct FetchQuery {
     #[serde(default)]
     sort_key: SortKey,
     aria2: Option<String>,
+    /// Shell script downloading every file with `wget`
+    wget: Option<String>,
+    /// Shell script downloading every file with `curl`
+    curl: Option<String>,
+    /// Metalink of every file, with their checksums
+    metalink: Option<String>,
+    /// Compare names ignoring their case, instead of putting every uppercase name first
+    #[serde(rename = "icase")]
+    ignore_case: Option<String>,
+    /// Only show the entries with this in their name, ignoring case
+    filter: Option<String>,
+    /// `json` to list the entries as JSON instead of HTML
+    format: Option<String>,
+    #[serde(default)]
+    view: ViewMode,
+    /// How times are shown, instead of how the config says
+    times: Option<TimeFormat>,
+    /// Only render the listing, for the page to swap it in without loading all of it again
+    fragment: Option<String>,
+    /// Levels of the tree put in download lists, 1 being only the files directly in the directory
+    max_depth: Option<usize>,
+    /// Glob the paths of the files put in download lists have to match, relative to the directory
+    include: Option<String>,
 }
 
 impl FetchQuery {
-    pub const fn aria2(&self) -> bool {
-        self.aria2.is_some()
+    /// Download list of every file in the directory that was asked for instead of the listing
+    const fn list_format(&self) -> Option<ListFormat> {
+        if self.aria2.is_some() {
+            Some(ListFormat::Aria2)
+        } else if self.wget.is_some() {
+            Some(ListFormat::Wget)
+        } else if self.curl.is_some() {
+            Some(ListFormat::Curl)
+        } else if self.metalink.is_some() {
+            Some(ListFormat::Metalink)
+        } else {
+            None
+        }
+    }
+
+    pub const fn ignore_case(&self) -> bool {
+        self.ignore_case.is_some()
+    }
+
+    pub const fn fragment(&self) -> bool {
+        self.fragment.is_some()
+    }
+
+    /// Whether the entries are listed as JSON, because it was asked for in the query or the
+    /// `Accept` header
+    fn json(&self, headers: &HeaderMap) -> bool {
+        match self.format.as_deref() {
+            Some(format) => format == "json",
+            None => headers
+                .get(header::ACCEPT)
+                .and_then(|accept| accept.to_str().ok())
+                .is_some_and(|accept| accept.contains("application/json")),
+        }
+    }
+
+    /// Whether times are shown as how long ago they were, `default` unless the query says
+    fn relative_times(&self, default: bool) -> bool {
+        self.times
+            .map_or(default, |times| times == TimeFormat::Relative)
+    }
+
+    fn filter(&self) -> Option<&str> {
+        self.filter.as_deref().filter(|f| !f.is_empty())
     }
 }
 
-#[derive(Deserialize, Debug, PartialEq, Eq)]
+#[derive(Deserialize, Debug, PartialEq, Eq, Hash)]
 #[serde(rename_all = "snake_case")]
 enum SortDirection {
     #[serde(rename = "asc")]
@@ -53,7 +139,7 @@ impl Default for SortDirection {
     }
 }
 
-#[derive(Deserialize, Debug, PartialEq, Eq)]
+#[derive(Deserialize, Debug, PartialEq, Eq, Hash)]
 #[serde(rename_all = "snake_case")]
 enum SortKey {
     Name,
@@ -68,26 +154,153 @@ impl Default for SortKey {
     }
 }
 
+/// How the entries are laid out in the directory view
+#[derive(Deserialize, Debug, PartialEq, Eq, Hash, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+enum ViewMode {
+    Table,
+    /// Images as a grid of thumbnails, and the other entries as a list
+    Gallery,
+}
+
+impl Default for ViewMode {
+    fn default() -> Self {
+        Self::Table
+    }
+}
+
+/// How times are shown in the directory view
+#[derive(Deserialize, Debug, PartialEq, Eq, Hash, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+enum TimeFormat {
+    /// The date and time, in the format and timezone of the config
+    Exact,
+    /// How long ago it was, with the exact time when hovering over it
+    Relative,
+}
+
+/// Width of the thumbnail shown in link previews, big enough for the ones that fill the width
+const PREVIEW_IMAGE_WIDTH: u32 = 512;
+
+/// Characters that are encoded in a path segment of a url
+/// <https://url.spec.whatwg.org/#path-percent-encode-set>, and `/` since it's a single segment
+const PATH_SEGMENT: &AsciiSet = &CONTROLS
+    .add(b' ')
+    .add(b'"')
+    .add(b'#')
+    .add(b'<')
+    .add(b'>')
+    .add(b'?')
+    .add(b'`')
+    .add(b'{')
+    .add(b'}')
+    .add(b'/')
+    .add(b'%');
+
+/// Link to a directory on the way to the current one
+#[derive(Serialize)]
+pub struct Breadcrumb {
+    /// Name of the directory, escaped by the template
+    name: String,
+    /// Where it's browsed, already percent-encoded
+    href: String,
+}
+
+/// Links to every directory on the way to `dirname`, from the root
+pub fn breadcrumbs(dirname: &str) -> Vec<Breadcrumb> {
+    let mut href = String::from("/browse");
+    dirname
+        .split('/')
+        .filter(|s| !s.is_empty() && *s != ".")
+        .map(|name| {
+            href.push('/');
+            href.extend(utf8_percent_encode(name, PATH_SEGMENT));
+            Breadcrumb {
+                name: name.to_owned(),
+                href: href.clone(),
+            }
+        })
+        .collect()
+}
+
 // FIXME: Minify this!
 #[derive(Template)]
 #[template(path = "dir_view.html")]
-pub struct DirectoryViewTemplate {
+pub struct DirectoryViewTemplate<'a> {
     /// String pointing to parent directory of current directory, used to traverse up
     parent_directory: Option<String>,
-    /// List of dirnames with anchor tags used to browse up in the view
-    /// For directory "Some dir/dir1"
-    /// "<a href="/browse/Some%20dir">Some dir</a> / <a href="/browse/Some%20dir/dir1">dir1</a>"
-    list_of_anchors: String,
+    /// Every directory on the way to the current one, used to browse up in the view
+    breadcrumbs: Vec<Breadcrumb>,
     /// Name of the current directory being browsed
     display_dirname: String,
     /// Directory name urlencoded
     encoded_dirname: String,
     /// List of every entry in the current directory
-    entries: Vec<CacheEntry>,
+    entries: Vec<&'a CacheEntry>,
     /// Direction to sort by
     sort_direction: SortDirection,
     /// What value to sort by
     sort_key: SortKey,
+    /// Whether names are compared ignoring their case
+    ignore_case: bool,
+    /// What the names of the entries were filtered by
+    filter: Option<String>,
+    view: ViewMode,
+    /// How times were asked to be shown in the query, if they were
+    times: Option<TimeFormat>,
+    dates: Arc<DateFormat>,
+    /// Whether times are shown as how long ago they were
+    relative_times: bool,
+    /// Absolute url of this view, for link previews
+    page_url: String,
+    /// Absolute url of a thumbnail of the first picture in the directory, for link previews
+    preview_image: Option<String>,
+    size_units: SizeUnits,
+    /// Completed downloads of every file in this directory, if they're shown
+    downloads: Option<HashMap<String, u64>>,
+    /// SHA-256 of every file in this directory that was already hashed, if they're shown
+    checksums: Option<HashMap<String, String>>,
+    /// To make the links that are copied absolute
+    base_url: Arc<Url>,
+    /// Names of the owners of the entries, if they're shown
+    owners: Option<Arc<Owners>>,
+    /// Readme of the directory, already rendered and sanitized
+    readme: Option<String>,
+    branding: Arc<Branding>,
+    color_scheme: ColorScheme,
+    /// Space left on the filesystem of the directory, if it's shown
+    free_space: Option<String>,
+    locale: Locale,
+    t: &'static Messages,
+    /// Whether only the listing is rendered, without the rest of the page around it
+    fragment: bool,
+}
+
+/// What a `dir_view.html` from the template dir is rendered with, made of plain data since it
+/// can't call the methods of the embedded template
+#[derive(Serialize)]
+struct ThemeDirectoryView<'a> {
+    site_title: &'a str,
+    tagline: Option<&'a str>,
+    /// Whether `/logo` has a picture
+    has_logo: bool,
+    /// Path of the directory, ending in `/` unless it's the root
+    dirname: &'a str,
+    parent_directory: Option<&'a str>,
+    breadcrumbs: &'a [Breadcrumb],
+    /// Already rendered and sanitized, so it has to be marked `safe`
+    readme: Option<&'a str>,
+    color_scheme: ColorScheme,
+    /// Space left on the filesystem of the directory, if it's shown
+    free_space: Option<&'a str>,
+    locale: Locale,
+    /// Every string of the pages, in the language of the locale
+    t: &'static Messages,
+    /// Whether only the listing was asked for, with `?fragment`
+    fragment: bool,
+    /// The same listing `?format=json` returns
+    #[serde(flatten)]
+    listing: JsonListing,
 }
 
 pub fn normalise_path(path: &Utf8Path) -> Result<Utf8PathBuf> {
@@ -113,12 +326,9 @@ pub fn normalise_path(path: &Utf8Path) -> Result<Utf8PathBuf> {
     }
 }
 
-pub fn path_contents_from_cache(
-    path: &Utf8Path,
-    v: &[CacheEntry],
-) -> Result<Option<Vec<CacheEntry>>> {
+pub fn path_contents_from_cache(path: &Utf8Path, v: &DirContents) -> Result<Option<DirContents>> {
     if path == Utf8Path::new("") {
-        return Ok(Some(v.to_vec()));
+        return Ok(Some(v.clone()));
     }
 
     let mut components = path.components();
@@ -130,15 +340,59 @@ pub fn path_contents_from_cache(
         bail!("Found component of type not normal in path {path:?}");
     };
 
-    let Some(c) = v.iter().find(|c| c.is_dir() && *c.as_dir().name == *s) else {
+    let Some(c) = v.get(s).filter(|c| c.is_dir()) else {
         return Ok(None);
     };
 
     return path_contents_from_cache(components.as_path(), &c.as_dir().children);
 }
 
-impl DirectoryViewTemplate {
-    pub fn new(data_dir: &Utf8Path, mut entries: Vec<CacheEntry>, query: FetchQuery) -> Self {
+/// Finds the entry at `path`, which can be either a file or a directory
+pub fn entry_from_cache<'a>(path: &Utf8Path, v: &'a DirContents) -> Option<&'a CacheEntry> {
+    let mut components = path.components();
+    let Some(Utf8Component::Normal(name)) = components.next() else {
+        return None;
+    };
+    let entry = v.get(name)?;
+
+    let rest = components.as_path();
+    if rest == Utf8Path::new("") {
+        Some(entry)
+    } else if entry.is_dir() {
+        entry_from_cache(rest, &entry.as_dir().children)
+    } else {
+        None
+    }
+}
+
+impl<'a> DirectoryViewTemplate<'a> {
+    fn theme_context(&self, state: &AppState, dir: &Utf8Path) -> ThemeDirectoryView<'_> {
+        ThemeDirectoryView {
+            site_title: &self.branding.title,
+            tagline: self.branding.tagline.as_deref(),
+            has_logo: self.branding.has_logo(),
+            dirname: &self.display_dirname,
+            parent_directory: self.parent_directory.as_deref(),
+            breadcrumbs: &self.breadcrumbs,
+            readme: self.readme.as_deref(),
+            color_scheme: self.color_scheme,
+            free_space: self.free_space.as_deref(),
+            locale: self.locale,
+            t: self.t,
+            fragment: self.fragment,
+            listing: JsonListing::new(state, dir, &self.entries),
+        }
+    }
+
+    pub fn new(
+        state: &AppState,
+        data_dir: &Utf8Path,
+        entries: &'a DirContents,
+        query: FetchQuery,
+        color_scheme: ColorScheme,
+        locale: Locale,
+    ) -> Self {
+        let base_url = &state.base_url;
         let parent_directory = if data_dir == Utf8Path::new(".") {
             None
         } else {
@@ -152,159 +406,663 @@ impl DirectoryViewTemplate {
         let dirname = dirname;
 
         let encoded_dirname = urlencode(&dirname).expect("TODO: Handle dirnames not urlencodable");
+        let page_url = base_url
+            .join(&format!("browse/{encoded_dirname}"))
+            .map_or_else(|_| base_url.to_string(), String::from);
 
-        let list_of_anchors = match dirname.as_str() {
-            "." => String::new(),
-            s => {
-                let mut accumulated = String::new();
-                let mut anchor_tags = vec![];
-                for dirname in s.split('/').filter(|s| !s.is_empty()) {
-                    let encoded_dirname =
-                        urlencode(dirname).expect("TODO: Handle invalid url charaters in filename");
-                    let current_relative_dirname = format!("{accumulated}/{encoded_dirname}");
-                    // The / is added by the above line
-                    let anchor_tag = format!(
-                        "<a href=\"/browse{current_relative_dirname}\"><strong>{dirname}</strong></a>"
-                    );
-                    accumulated = current_relative_dirname.clone();
-                    anchor_tags.push(anchor_tag);
-                }
-                anchor_tags.into_iter().intersperse(" / ".into()).collect()
-            }
-        };
+        let breadcrumbs = breadcrumbs(&dirname);
 
-        entries.sort_by(|e1, e2| {
-            let ord = match query.sort_key {
-                SortKey::Name => e1.name().cmp(e2.name()),
-                SortKey::Date => match e1.created().cmp(e2.created()) {
-                    std::cmp::Ordering::Equal => e1.name().cmp(e2.name()),
-                    o => o,
-                },
-                SortKey::Size => match e1.size().cmp(&e2.size()) {
-                    std::cmp::Ordering::Equal => e1.name().cmp(e2.name()),
-                    o => o,
-                },
-                SortKey::ChildrenCount => {
-                    let o = if e1.is_dir() && e2.is_dir() {
-                        e1.as_dir()
-                            .children_count()
-                            .cmp(&e2.as_dir().children_count())
-                    } else if e1.is_dir() && !e2.is_dir() {
-                        std::cmp::Ordering::Greater
-                    } else if !e1.is_dir() && e2.is_dir() {
-                        std::cmp::Ordering::Less
-                    } else {
-                        std::cmp::Ordering::Equal
-                    };
+        let readme = state
+            .render_readme
+            .then(|| render_readme(&state.roots, data_dir, entries))
+            .flatten();
+        let entries = sorted_entries(entries, &query);
+        let ignore_case = query.ignore_case();
+        let preview_image = entries.iter().find(|e| shown_as_image(e)).and_then(|e| {
+            let mut url = base_url
+                .join(&format!("thumb/{encoded_dirname}{}", e.name_url_encoded()))
+                .ok()?;
+            url.set_query(Some(&format!("w={PREVIEW_IMAGE_WIDTH}")));
+            Some(url.to_string())
+        });
+        let fragment = query.fragment();
 
-                    match o {
-                        std::cmp::Ordering::Equal => e1.name().cmp(e2.name()),
-                        o => o,
-                    }
-                }
-            };
-            if query.sort_direction == SortDirection::Descending {
-                ord.reverse()
-            } else {
-                ord
-            }
+        let downloads = state.show_download_counts.then(|| {
+            entries
+                .iter()
+                .filter(|e| e.is_file())
+                .filter_map(|e| {
+                    let path = normalise_path(&data_dir.join(e.name())).ok()?;
+                    let completed = state.transfers.get(path.as_str())?.completed;
+                    Some((e.name().to_owned(), completed))
+                })
+                .collect()
+        });
+
+        let checksums = state.show_checksums.then(|| {
+            entries
+                .iter()
+                .filter(|e| e.is_file() && !e.as_file().link)
+                .filter_map(|e| {
+                    let path = normalise_path(&data_dir.join(e.name())).ok()?;
+                    let fs_path = state.roots.fs_path(&path)?;
+                    let checksums = state.checksums.as_ref()?;
+                    let sha256 = checksums.sha256(&fs_path, e.size(), e.modified())?;
+                    Some((e.name().to_owned(), sha256))
+                })
+                .collect()
         });
 
         Self {
             parent_directory,
-            list_of_anchors,
+            breadcrumbs,
             // FIXME: Display the directory properly in the title
             display_dirname: dirname,
             encoded_dirname,
             entries,
             sort_direction: query.sort_direction,
             sort_key: query.sort_key,
+            ignore_case,
+            filter: query.filter().map(str::to_owned),
+            view: query.view,
+            times: query.times,
+            dates: Arc::clone(&state.dates),
+            relative_times: query.relative_times(state.relative_times),
+            page_url,
+            preview_image,
+            size_units: state.size_units,
+            downloads,
+            checksums,
+            base_url: Arc::clone(&state.base_url),
+            owners: state.owners.clone(),
+            readme,
+            branding: Arc::clone(&state.branding),
+            color_scheme,
+            free_space: state
+                .free_space
+                .as_ref()
+                .and_then(|free_space| free_space.of(&state.roots, data_dir))
+                .map(|free| state.size_units.format(free)),
+            locale,
+            t: locale.messages(),
+            fragment,
+        }
+    }
+
+    /// Query parameters to keep the case sensitivity and filter when changing the sort
+    fn extra_query(&self) -> String {
+        let mut query = String::new();
+        if self.ignore_case {
+            query.push_str("&icase");
+        }
+        if let Some(filter) = &self.filter {
+            query.push_str("&filter=");
+            query.extend(form_urlencoded::byte_serialize(filter.as_bytes()));
+        }
+        if self.view == ViewMode::Gallery {
+            query.push_str("&view=gallery");
+        }
+        match self.times {
+            Some(TimeFormat::Exact) => query.push_str("&times=exact"),
+            Some(TimeFormat::Relative) => query.push_str("&times=relative"),
+            None => {}
+        }
+        query
+    }
+
+    /// Files that are shown as pictures in the gallery, in the order they're sorted in
+    fn images(&self) -> Vec<&CacheEntry> {
+        self.entries
+            .iter()
+            .filter(|e| shown_as_image(e))
+            .copied()
+            .collect()
+    }
+
+    /// Entries that are listed under the pictures in the gallery
+    fn non_images(&self) -> Vec<&CacheEntry> {
+        self.entries
+            .iter()
+            .filter(|e| !shown_as_image(e))
+            .copied()
+            .collect()
+    }
+
+    /// Route that opens the file, `view` for documentation and media or `dl` for anything else
+    fn file_route(&self, entry: &CacheEntry) -> &'static str {
+        if opens_as_page(entry.name()) {
+            "view"
+        } else {
+            "dl"
+        }
+    }
+
+    /// Whether the file is downloaded when opened but can also be shown as a page
+    fn has_page_view(&self, entry: &CacheEntry) -> bool {
+        entry.is_file()
+            && !entry.as_file().link
+            && can_preview(entry.name())
+            && !opens_as_page(entry.name())
+    }
+
+    /// Whether the file is an archive whose contents can be listed
+    fn can_browse(&self, entry: &CacheEntry) -> bool {
+        entry.is_file() && !entry.as_file().link && can_browse(entry.name())
+    }
+
+    /// Whether the file has a page with all of its details
+    fn has_info(&self, entry: &CacheEntry) -> bool {
+        entry.is_file() && !entry.as_file().link
+    }
+
+    /// How many directories and files are listed, and the size of everything inside them, which
+    /// the cache already added up for the directories
+    fn summary(&self) -> String {
+        let dirs = self.entries.iter().filter(|e| e.is_dir()).count();
+        let files = self.entries.len() - dirs;
+        let size: u64 = self.entries.iter().map(|e| e.size()).sum();
+        self.t.summary(dirs, files, &self.size_units.format(size))
+    }
+
+    fn entry_size(&self, entry: &CacheEntry) -> String {
+        self.size_units.format(entry.size())
+    }
+
+    fn entry_downloads(&self, entry: &CacheEntry) -> u64 {
+        self.downloads
+            .as_ref()
+            .and_then(|d| d.get(entry.name()))
+            .copied()
+            .unwrap_or(0)
+    }
+
+    fn entry_checksum(&self, entry: &CacheEntry) -> Option<&str> {
+        self.checksums
+            .as_ref()
+            .and_then(|c| c.get(entry.name()))
+            .map(String::as_str)
+    }
+
+    /// Absolute url of the entry to copy, its listing for directories and its download for files
+    fn direct_link(&self, entry: &CacheEntry) -> String {
+        let path = if entry.is_dir() {
+            format!(
+                "browse/{}{}/",
+                self.encoded_dirname,
+                entry.name_url_encoded()
+            )
+        } else {
+            format!("dl/{}{}", self.encoded_dirname, entry.name_url_encoded())
+        };
+        self.base_url
+            .join(&path)
+            .map_or_else(|_| self.base_url.to_string(), String::from)
+    }
+
+    fn entry_owner(&self, entry: &CacheEntry) -> String {
+        self.owners
+            .as_ref()
+            .map_or_else(String::new, |o| o.user(entry.uid()))
+    }
+
+    fn entry_group(&self, entry: &CacheEntry) -> String {
+        self.owners
+            .as_ref()
+            .map_or_else(String::new, |o| o.group(entry.gid()))
+    }
+
+    fn entry_permissions(&self, entry: &CacheEntry) -> String {
+        format_mode(entry.mode(), entry.is_dir())
+    }
+}
+
+/// Directory view for scripts, from `?format=json`
+///
+/// Its fields are only ever added to, so mirroring tools can rely on it: every file has its size,
+/// modification time and checksums, to only download what changed and check what was
+#[derive(Serialize, Debug)]
+pub struct JsonListing {
+    /// Path of the directory, relative to the root
+    path: Utf8PathBuf,
+    entries: Vec<JsonEntry>,
+}
+
+#[derive(Serialize, Debug)]
+struct JsonEntry {
+    name: String,
+    /// `file`, `dir` or `symlink`, for symlinks that are shown but not followed
+    #[serde(rename = "type")]
+    kind: &'static str,
+    /// Size in bytes, of every file inside it for directories
+    size: u64,
+    created: DateTime<Utc>,
+    modified: Option<DateTime<Utc>>,
+    /// Entries directly inside it, only for directories
+    children_count: Option<usize>,
+    /// Where to download it, as a file or a ZIP archive, if it can be
+    download_url: Option<String>,
+    /// Where to list it, only for directories
+    browse_url: Option<String>,
+    /// Only for files, and `null` until the checksum thread gets to them
+    sha256: Option<String>,
+    md5: Option<String>,
+}
+
+impl JsonListing {
+    fn new(state: &AppState, dir: &Utf8Path, entries: &[&CacheEntry]) -> Self {
+        let base_url = &state.base_url;
+        let url_for = |route: &str, name: &str| {
+            let mut url = base_url.clone();
+            url.path_segments_mut()
+                .expect("Base url provided is a base")
+                .pop_if_empty()
+                .push(route)
+                .extend(dir.components().map(|c| c.as_str()))
+                .push(name);
+            url.to_string()
+        };
+        let entries = entries
+            .iter()
+            .map(|entry| {
+                let link = entry.is_file() && entry.as_file().link;
+                let checksum = |algorithm| {
+                    if !entry.is_file() || link {
+                        return None;
+                    }
+                    let path = normalise_path(&dir.join(entry.name())).ok()?;
+                    let fs_path = state.roots.fs_path(&path)?;
+                    state.checksums.as_ref()?.get(
+                        algorithm,
+                        &fs_path,
+                        entry.size(),
+                        entry.modified(),
+                    )
+                };
+                JsonEntry {
+                    name: entry.name().to_owned(),
+                    kind: if entry.is_dir() {
+                        "dir"
+                    } else if link {
+                        "symlink"
+                    } else {
+                        "file"
+                    },
+                    size: entry.size(),
+                    created: entry.created(),
+                    modified: DateTime::from_timestamp(entry.modified(), 0),
+                    children_count: entry.is_dir().then(|| entry.as_dir().children_count()),
+                    download_url: if entry.is_dir() {
+                        Some(url_for("arc", entry.name()))
+                    } else {
+                        (!link).then(|| url_for("dl", entry.name()))
+                    },
+                    browse_url: entry
+                        .is_dir()
+                        .then(|| url_for("browse", entry.name()) + "/"),
+                    sha256: checksum(Algorithm::Sha256),
+                    md5: checksum(Algorithm::Md5),
+                }
+            })
+            .collect();
+        Self {
+            path: dir.to_path_buf(),
+            entries,
+        }
+    }
+
+    /// Whether every file in it was already hashed, or they never will be, so it won't change
+    /// until the cache does
+    fn hashed(&self, state: &AppState) -> bool {
+        state.checksums.is_none()
+            || self
+                .entries
+                .iter()
+                .all(|e| e.kind != "file" || e.sha256.is_some())
+    }
+}
+
+/// Whether the entry is a picture in the gallery
+fn shown_as_image(entry: &CacheEntry) -> bool {
+    entry.is_file() && !entry.as_file().link && mime::is_image(entry.name())
+}
+
+/// Entries of a directory view, filtered and sorted like `query` says
+fn sorted_entries<'a>(entries: &'a DirContents, query: &FetchQuery) -> Vec<&'a CacheEntry> {
+    let ignore_case = query.ignore_case();
+    let cmp_names = |n1: &str, n2: &str| {
+        if ignore_case {
+            // Names that only differ in case still get a stable order
+            cmp_ignore_case_utf8(n1, n2).then_with(|| n1.cmp(n2))
+        } else {
+            n1.cmp(n2)
+        }
+    };
+    let filter = query.filter().map(str::to_lowercase);
+    let mut entries: Vec<_> = entries
+        .iter()
+        .filter(|e| {
+            filter
+                .as_ref()
+                .map_or(true, |f| e.name().to_lowercase().contains(f))
+        })
+        .collect();
+    entries.sort_by(|e1, e2| {
+        let ord = match query.sort_key {
+            SortKey::Name => cmp_names(e1.name(), e2.name()),
+            SortKey::Date => match e1.created().cmp(&e2.created()) {
+                std::cmp::Ordering::Equal => cmp_names(e1.name(), e2.name()),
+                o => o,
+            },
+            SortKey::Size => match e1.size().cmp(&e2.size()) {
+                std::cmp::Ordering::Equal => cmp_names(e1.name(), e2.name()),
+                o => o,
+            },
+            SortKey::ChildrenCount => {
+                let o = if e1.is_dir() && e2.is_dir() {
+                    e1.as_dir()
+                        .children_count()
+                        .cmp(&e2.as_dir().children_count())
+                } else if e1.is_dir() && !e2.is_dir() {
+                    std::cmp::Ordering::Greater
+                } else if !e1.is_dir() && e2.is_dir() {
+                    std::cmp::Ordering::Less
+                } else {
+                    std::cmp::Ordering::Equal
+                };
+
+                match o {
+                    std::cmp::Ordering::Equal => cmp_names(e1.name(), e2.name()),
+                    o => o,
+                }
+            }
+        };
+        if query.sort_direction == SortDirection::Descending {
+            ord.reverse()
+        } else {
+            ord
+        }
+    });
+    entries
+}
+
+/// Which files of a directory tree go in a download list, like the aria2 input file
+#[derive(Debug)]
+struct ListFilter {
+    max_depth: Option<usize>,
+    include: Option<GlobMatcher>,
+}
+
+impl ListFilter {
+    fn new(query: &FetchQuery) -> Result<Self> {
+        let include = query
+            .include
+            .as_deref()
+            .filter(|pattern| !pattern.is_empty())
+            .map(|pattern| {
+                Glob::new(pattern)
+                    .map(|glob| glob.compile_matcher())
+                    .wrap_err_with(|| format!("Invalid include pattern {pattern}"))
+            })
+            .transpose()?;
+        Ok(Self {
+            max_depth: query.max_depth,
+            include,
+        })
+    }
+
+    /// Whether the entries `depth` levels down are listed, 1 being the ones directly in the
+    /// directory
+    fn descends(&self, depth: usize) -> bool {
+        self.max_depth.map_or(true, |max_depth| depth <= max_depth)
+    }
+
+    /// Whether the file at `path`, relative to the directory, is listed
+    fn includes(&self, path: &Utf8Path) -> bool {
+        self.include
+            .as_ref()
+            .map_or(true, |include| include.is_match(path))
+    }
+}
+
+/// File put in a download list
+struct ListedFile {
+    url: Url,
+    /// Directory it's downloaded to, `.` for the one the list is used in
+    dir: String,
+    name: String,
+    /// Path under the data dir, to find its checksum
+    path: Utf8PathBuf,
+    size: u64,
+    modified: i64,
+}
+
+impl ListedFile {
+    /// Path it's downloaded to, relative to where the list is used
+    fn out_path(&self) -> String {
+        if self.dir == "." {
+            self.name.clone()
+        } else {
+            format!("{}/{}", self.dir, self.name)
         }
     }
 }
 
-pub fn generate_aria2(base_url: &Url, entries: &[CacheEntry]) -> String {
-    fn generate_aria2_helper(
+/// Files in `entries` that pass `filter`, which are the contents of `dir`, each one going to
+/// where it is under the data dir, with the files of a directory before the ones of its
+/// subdirectories
+fn listed_files(
+    base_url: &Url,
+    dir: &Utf8Path,
+    entries: &[CacheEntry],
+    filter: &ListFilter,
+) -> Vec<ListedFile> {
+    fn listed_files_helper(
         base_url: &Url,
+        dir: &Utf8Path,
         fetch_dir: &Utf8Path,
         entries: &[CacheEntry],
-    ) -> String {
-        let mut file_list = String::new();
-        let mut subdir_list = String::new();
-        let aria2_dir = if fetch_dir == Utf8Path::new("") {
+        filter: &ListFilter,
+        depth: usize,
+        files: &mut Vec<ListedFile>,
+    ) {
+        if !filter.descends(depth) {
+            return;
+        }
+        let list_dir = if fetch_dir == Utf8Path::new("") {
             ".".to_string()
         } else {
             fetch_dir.as_str().trim_end_matches('/').to_string()
         };
-        let mut entries = entries.to_vec();
+        let mut entries: Vec<_> = entries.iter().collect();
         entries.sort_by(|e1, e2| cmp_ignore_case_utf8(e1.name(), e2.name()));
-        for entry in entries {
-            if entry.is_file() {
-                let mut entry_url = base_url.clone();
-                {
-                    let mut path_segments = entry_url
-                        .path_segments_mut()
-                        .expect("Base url provided is a base");
-                    path_segments.push("dl");
-
-                    fetch_dir.components().for_each(|c| {
-                        path_segments.push(c.as_ref());
-                    });
-
-                    path_segments.push(entry.name());
+        for entry in entries.iter().filter(|e| e.is_file()) {
+            let relative_path = fetch_dir.strip_prefix(dir).unwrap_or(fetch_dir);
+            if !filter.includes(&relative_path.join(entry.name())) {
+                continue;
+            }
+            let mut entry_url = base_url.clone();
+            {
+                let mut path_segments = entry_url
+                    .path_segments_mut()
+                    .expect("Base url provided is a base");
+                path_segments.push("dl");
+
+                fetch_dir.components().for_each(|c| {
+                    path_segments.push(c.as_ref());
+                });
+
+                path_segments.push(entry.name());
+            }
+            files.push(ListedFile {
+                url: entry_url,
+                dir: list_dir.clone(),
+                name: entry.name().to_owned(),
+                path: fetch_dir.join(entry.name()),
+                size: entry.size(),
+                modified: entry.modified(),
+            });
+        }
+        for entry in entries.iter().filter(|e| e.is_dir()) {
+            listed_files_helper(
+                base_url,
+                dir,
+                &fetch_dir.join(entry.name()),
+                &entry.as_dir().children,
+                filter,
+                depth + 1,
+                files,
+            );
+        }
+    }
+    let mut files = vec![];
+    listed_files_helper(base_url, dir, dir, entries, filter, 1, &mut files);
+    files
+}
+
+/// Program the download list of a directory is for
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ListFormat {
+    /// Input file for `aria2c -i`
+    Aria2,
+    /// Shell script calling `wget` for every file
+    Wget,
+    /// Shell script calling `curl` for every file
+    Curl,
+    /// Metalink 4 (RFC 5854) XML, with the sizes and checksums of the files
+    Metalink,
+}
+
+/// File in a metalink, see [`MetalinkTemplate`]
+struct MetalinkFile {
+    /// Path it's downloaded to
+    name: String,
+    size: u64,
+    url: String,
+    /// None if it wasn't hashed yet
+    sha256: Option<String>,
+}
+
+#[derive(Template)]
+#[template(path = "metalink.xml")]
+struct MetalinkTemplate {
+    files: Vec<MetalinkFile>,
+}
+
+/// `s` quoted for a POSIX shell
+fn shell_quote(s: &str) -> String {
+    format!("'{}'", s.replace('\'', "'\\''"))
+}
+
+/// Metalink of every file in `files`, with the checksums of the ones that were already hashed
+fn generate_metalink(state: &AppState, files: Vec<ListedFile>) -> askama::Result<String> {
+    let files = files
+        .into_iter()
+        .map(|file| MetalinkFile {
+            name: file.out_path(),
+            size: file.size,
+            sha256: state.checksums.as_ref().and_then(|checksums| {
+                let fs_path = state.roots.fs_path(&file.path)?;
+                checksums.sha256(&fs_path, file.size, file.modified)
+            }),
+            url: file.url.into(),
+        })
+        .collect();
+    MetalinkTemplate { files }.render()
+}
+
+/// Download list in `format` of every file in `files`, which recreates the directories they are
+/// in
+fn generate_list(format: ListFormat, files: &[ListedFile]) -> String {
+    let mut list = String::new();
+    if format != ListFormat::Aria2 {
+        list.push_str("#!/bin/sh\nset -e\n\n");
+    }
+    let mut made_dirs = HashSet::new();
+    for file in files {
+        match format {
+            ListFormat::Metalink => unreachable!("Metalinks are made with generate_metalink"),
+            ListFormat::Aria2 => {
+                list.push_str(&format!(
+                    "{url}\n  dir={dir}\n  out={name}\n\n",
+                    url = file.url,
+                    dir = file.dir,
+                    name = file.name
+                ));
+            }
+            ListFormat::Wget => {
+                if made_dirs.insert(&file.dir) {
+                    list.push_str(&format!("mkdir -p {}\n", shell_quote(&file.dir)));
                 }
-                let mut entry_str = String::new();
-                entry_str.push_str(entry_url.as_str());
-                entry_str.push('\n');
-                entry_str.push_str(&' '.to_string().repeat(2));
-                entry_str.push_str(&format!("dir={aria2_dir}"));
-                entry_str.push('\n');
-                entry_str.push_str(&' '.to_string().repeat(2));
-                entry_str.push_str(&format!("out={name}", name = entry.name()));
-                entry_str.push('\n');
-                entry_str.push('\n');
-                file_list.push_str(&entry_str);
-            } else if entry.is_dir() {
-                let entry_path = {
-                    let mut fetch_dir = fetch_dir.to_path_buf();
-                    fetch_dir.push(entry.name());
-                    fetch_dir
-                };
-                subdir_list.push_str(&generate_aria2_helper(
-                    base_url,
-                    &entry_path,
-                    &entry.as_dir().children,
+                list.push_str(&format!(
+                    "wget -O {} {}\n",
+                    shell_quote(&file.out_path()),
+                    shell_quote(file.url.as_str())
+                ));
+            }
+            ListFormat::Curl => {
+                list.push_str(&format!(
+                    "curl -f --create-dirs -o {} {}\n",
+                    shell_quote(&file.out_path()),
+                    shell_quote(file.url.as_str())
                 ));
             }
         }
-        file_list.push_str(&subdir_list);
-        file_list
     }
-    generate_aria2_helper(base_url, "".into(), entries)
+    list
 }
 
 pub async fn root_directory_view(
     State(state): State<AppState>,
     Query(query): Query<FetchQuery>,
+    color_scheme: ColorScheme,
+    locale: Locale,
+    visitor: Visitor,
+    headers: HeaderMap,
 ) -> impl IntoResponse {
-    view_for_path(Utf8Path::new("."), &state, query)
+    view_for_path(
+        Utf8Path::new("."),
+        &state,
+        query,
+        color_scheme,
+        locale,
+        &visitor,
+        &headers,
+    )
+    .await
 }
 
 pub async fn serve_path_view(
     extract::Path(path): extract::Path<PathBuf>,
     State(state): State<AppState>,
     Query(query): Query<FetchQuery>,
+    color_scheme: ColorScheme,
+    locale: Locale,
+    visitor: Visitor,
+    headers: HeaderMap,
 ) -> Result<Response<Body>, (StatusCode, String)> {
     // FIXME: nicer errors?
     let path = Utf8PathBuf::from_path_buf(path)
         .map_err(|p| (StatusCode::BAD_REQUEST, format!("Path {p:?} was not UTF-8")))?;
-    view_for_path(&path, &state, query)
+    view_for_path(
+        &path,
+        &state,
+        query,
+        color_scheme,
+        locale,
+        &visitor,
+        &headers,
+    )
+    .await
 }
 
-pub fn view_for_path(
+pub async fn view_for_path(
     path_for_view: &Utf8Path,
     state: &AppState,
     query: FetchQuery,
+    color_scheme: ColorScheme,
+    locale: Locale,
+    visitor: &Visitor,
+    headers: &HeaderMap,
 ) -> Result<Response<Body>, (StatusCode, String)> {
     let cache = Arc::clone(&state.cache);
 
@@ -318,43 +1076,139 @@ pub fn view_for_path(
     let normalised_path = normalise_path(path_for_view)
         .wrap_err_with(|| format!("Failed making path {path_for_view:?} goody"))
         .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
-
-    let lock = cache.read();
-    let max_depth = lock
-        .iter()
-        .filter(|c| c.is_dir())
-        .map(|d| d.as_dir().max_depth())
-        .max()
-        .unwrap_or(0);
-    // Allow displaying the dir view for an empty directory, as empty
-    if path_for_view.components().count() > max_depth + 1 {
-        return Err((
-            StatusCode::BAD_REQUEST,
-            "Path had more components than maximum depth of data".to_string(),
-        ));
-    }
-    drop(lock);
+    state.load_path(&normalised_path, false).await;
 
     let lock = cache.read();
     let path_entries = path_contents_from_cache(&normalised_path, &lock)
         .wrap_err_with(|| format!("Failed fetching contents of path {normalised_path:?}"))
         .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    // Paths that aren't directories are either files, which are downloaded, or don't exist
+    let is_file = path_entries.is_none()
+        && entry_from_cache(&normalised_path, &lock).is_some_and(CacheEntry::is_file);
     drop(lock);
 
-    // If we have no dir entries, user tried to browse a file
     let Some(dir_entries) = path_entries else {
-        return Ok(Redirect::permanent(&format!("/dl/{normalised_path}")).into_response());
+        if is_file {
+            return Ok(Redirect::permanent(&format!("/dl/{normalised_path}")).into_response());
+        }
+        return Err((
+            StatusCode::NOT_FOUND,
+            format!("Directory {normalised_path:?} does not exist"),
+        ));
     };
+    // Everyone sees the same entries unless some of them can't be reached
+    let pruned = visitor.prune(state, &normalised_path, &dir_entries);
+    let hidden = pruned.is_some();
+    let dir_entries = pruned.unwrap_or(dir_entries);
 
-    if query.aria2() {
+    let json = query.json(headers).then(|| {
+        JsonListing::new(
+            state,
+            &normalised_path,
+            &sorted_entries(&dir_entries, &query),
+        )
+    });
+    // Download counts, how long ago things were and checksums change without the cache changing,
+    // so the view can't be cached then
+    let changing = hidden
+        || match &json {
+            Some(listing) => !listing.hashed(state),
+            None => {
+                state.show_download_counts
+                    || state.show_checksums
+                    || query.relative_times(state.relative_times)
+                    || (query.list_format() == Some(ListFormat::Metalink)
+                        && state.checksums.is_some())
+            }
+        };
+    let etag = (!changing).then(|| {
+        view_etag(
+            state,
+            &normalised_path,
+            &query,
+            color_scheme,
+            locale,
+            json.is_some(),
+        )
+    });
+    if let Some(etag) = &etag {
+        if not_modified(headers, Some(etag), None) {
+            return Response::builder()
+                .status(StatusCode::NOT_MODIFIED)
+                .header(header::ETAG, etag)
+                .body(Body::empty())
+                .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()));
+        }
+    }
+
+    let mut response = if let Some(listing) = json {
+        Json(listing).into_response()
+    } else if let Some(format) = query.list_format() {
         // FIXME: Should this go in /dl instead of /browse?
-        let base_url = &state.base_url;
+        let filter =
+            ListFilter::new(&query).map_err(|e| (StatusCode::BAD_REQUEST, format!("{e:#}")))?;
+        let files = listed_files(&state.base_url, &normalised_path, &dir_entries, &filter);
+        let (content_type, body) = if format == ListFormat::Metalink {
+            let metalink = generate_metalink(state, files)
+                .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+            ("application/metalink4+xml", metalink)
+        } else {
+            ("text/plain", generate_list(format, &files))
+        };
         Response::builder()
-            .header("Content-Type", "text/plain")
-            .body(Body::new(generate_aria2(base_url, &dir_entries)))
-            .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))
+            .header("Content-Type", content_type)
+            .body(Body::new(body))
+            .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?
     } else {
         // TODO: Minify this
-        Ok(DirectoryViewTemplate::new(&normalised_path, dir_entries, query).into_response())
+        let page = DirectoryViewTemplate::new(
+            state,
+            &normalised_path,
+            &dir_entries,
+            query,
+            color_scheme,
+            locale,
+        );
+        state
+            .theme
+            .render("dir_view.html", || {
+                page.theme_context(state, &normalised_path)
+            })
+            .unwrap_or_else(|| page.into_response())
+    };
+    // The format can depend on the Accept header, the language on Accept-Language, the colors
+    // on the cookie and the entries on the token
+    response.headers_mut().insert(
+        header::VARY,
+        HeaderValue::from_static("Accept, Accept-Language, Authorization, Cookie"),
+    );
+    if let Some(etag) = etag.and_then(|etag| HeaderValue::try_from(etag).ok()) {
+        if response.status().is_success() {
+            response.headers_mut().insert(header::ETAG, etag);
+        }
     }
+    Ok(response)
+}
+
+/// Changes whenever the cache does, and is different for every path, format, way of sorting it,
+/// color scheme and locale
+fn view_etag(
+    state: &AppState,
+    path: &Utf8Path,
+    query: &FetchQuery,
+    color_scheme: ColorScheme,
+    locale: Locale,
+    json: bool,
+) -> String {
+    let mut hasher = DefaultHasher::new();
+    path.hash(&mut hasher);
+    query.hash(&mut hasher);
+    color_scheme.hash(&mut hasher);
+    locale.hash(&mut hasher);
+    json.hash(&mut hasher);
+    format!(
+        "W/\"{:x}-{:x}\"",
+        state.generation.load(Ordering::Acquire),
+        hasher.finish()
+    )
 }
@@ -1,158 +1,621 @@
+use askama::filters::urlencode;
 use axum::{
     body::Body,
-    extract::{self, Query, State},
-    http::{HeaderMap, Response, StatusCode},
+    extract::{self, Query, RawQuery, State},
+    http::{response::Builder, HeaderMap, Response, StatusCode},
+    response::{IntoResponse as _, Redirect},
 };
+use bytes::Bytes;
 use camino::{Utf8Path, Utf8PathBuf};
+use chrono::{DateTime, Utc};
 use color_eyre::{
     eyre::{ensure, ContextCompat, WrapErr},
     Result,
 };
-use std::{collections::HashMap, io::SeekFrom, path::PathBuf};
-use tokio::io::{AsyncSeekExt as _, BufReader};
-use tracing::{debug, info};
+use futures_util::{Stream, StreamExt as _, TryStreamExt as _};
+use serde::Deserialize;
+use std::{
+    fs::Metadata,
+    io::{self, SeekFrom},
+    ops::Range,
+    path::PathBuf,
+    str::FromStr,
+    time::{SystemTime, UNIX_EPOCH},
+};
+use tokio::io::{AsyncReadExt as _, AsyncSeekExt as _, BufReader};
+use tracing::{debug, info, warn};
+use url::form_urlencoded;
 
 type Ranges = Vec<(Option<u64>, Option<u64>)>;
 
-use crate::utils::content_type_from_extension;
+use crate::access::Visitor;
+use crate::archive::{self, ArchiveEntry, ArchiveFormat};
+use crate::archive_cache::ArchiveKey;
+use crate::checksums::Algorithm;
+use crate::digest::Digests;
+use crate::dir_view::{entry_from_cache, normalise_path, path_contents_from_cache};
+use crate::mime;
+use crate::stats::Transfer;
+use crate::utils::deserialize_flag;
 use crate::AppState;
 
+/// Whether `/dl` asks the browser to save a file or to display it
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Disposition {
+    #[default]
+    Attachment,
+    Inline,
+}
+
+impl Disposition {
+    const fn as_str(self) -> &'static str {
+        match self {
+            Self::Attachment => "attachment",
+            Self::Inline => "inline",
+        }
+    }
+
+    pub fn header_value(self, file_name: &str) -> String {
+        format!("{}; filename=\"{file_name}\"", self.as_str())
+    }
+}
+
+impl FromStr for Disposition {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "attachment" => Ok(Self::Attachment),
+            "inline" => Ok(Self::Inline),
+            s => Err(format!(
+                "Invalid disposition {s}, expected `attachment` or `inline`"
+            )),
+        }
+    }
+}
+
+/// Overrides the default [`Disposition`] for a class of content types
+///
+/// Parsed from `<class>=<disposition>`, where class is either a top level type like `image`, or
+/// a full content type like `application/pdf`
+#[derive(Debug, Clone)]
+pub struct DispositionOverride {
+    pub class: String,
+    pub disposition: Disposition,
+}
+
+impl DispositionOverride {
+    fn matches(&self, content_type: &str) -> bool {
+        if self.class.contains('/') {
+            content_type == self.class
+        } else {
+            content_type.split('/').next() == Some(self.class.as_str())
+        }
+    }
+}
+
+impl FromStr for DispositionOverride {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let Some((class, disposition)) = s.split_once('=') else {
+            return Err(format!(
+                "Disposition override should be `<class>=<disposition>`, was {s}"
+            ));
+        };
+        Ok(Self {
+            class: class.to_owned(),
+            disposition: disposition.parse()?,
+        })
+    }
+}
+
+/// Picks the disposition for `content_type`, full content type overrides win over top level ones
+fn disposition_for(state: &AppState, content_type: &str) -> Disposition {
+    let overrides = &state.disposition_overrides;
+    overrides
+        .iter()
+        .find(|o| o.class == content_type)
+        .or_else(|| overrides.iter().find(|o| o.matches(content_type)))
+        .map_or(state.disposition, |o| o.disposition)
+}
+
+/// Weak ETag made only from the size and modification time of a file, so that it stays the same
+/// across restarts for as long as the file does
+fn etag_for(metadata: &Metadata) -> Option<String> {
+    let modified = metadata.modified().ok()?.duration_since(UNIX_EPOCH).ok()?;
+    Some(format!(
+        "W/\"{:x}-{:x}.{:x}\"",
+        metadata.len(),
+        modified.as_secs(),
+        modified.subsec_nanos()
+    ))
+}
+
+/// Whether the client already has the file with `etag` that was last modified at `modified`,
+/// going by its conditional headers
+pub fn not_modified(
+    headers: &HeaderMap,
+    etag: Option<&str>,
+    modified: Option<DateTime<Utc>>,
+) -> bool {
+    // If-Modified-Since is ignored when both are sent
+    if let Some(if_none_match) = headers.get("If-None-Match") {
+        let (Some(etag), Ok(if_none_match)) = (etag, if_none_match.to_str()) else {
+            return false;
+        };
+        // Weak comparison, so the W/ prefixes don't matter
+        let etag = etag.trim_start_matches("W/");
+        return if_none_match
+            .split(',')
+            .map(str::trim)
+            .any(|tag| tag == "*" || tag.trim_start_matches("W/") == etag);
+    }
+
+    let since = headers
+        .get("If-Modified-Since")
+        .and_then(|since| since.to_str().ok())
+        .and_then(|since| DateTime::parse_from_rfc2822(since).ok());
+    match (since, modified) {
+        // HTTP dates don't have fractions of a second
+        (Some(since), Some(modified)) => modified.timestamp() <= since.timestamp(),
+        _ => false,
+    }
+}
+
+/// Whether a range request with `headers` can be answered for the representation with `etag`
+/// that was last modified at `modified`, going by its `If-Range`
+///
+/// Only strong tags and exact dates match, anything else gets the whole representation
+fn if_range_matches(headers: &HeaderMap, etag: &str, modified: DateTime<Utc>) -> bool {
+    let Some(if_range) = headers.get("If-Range") else {
+        return true;
+    };
+    let Ok(if_range) = if_range.to_str() else {
+        return false;
+    };
+    let if_range = if_range.trim();
+    if if_range.starts_with('"') || if_range.starts_with("W/") {
+        return !etag.starts_with("W/") && if_range == etag;
+    }
+    DateTime::parse_from_rfc2822(if_range)
+        .is_ok_and(|date| date.timestamp() == modified.timestamp())
+}
+
+/// Streams the rest of `file`, which is `len` bytes long, accounting for them in `transfer`
+fn stream_file(file: tokio::fs::File, len: u64, mut transfer: Transfer) -> Body {
+    transfer.expect(len);
+    let buffered_file = BufReader::new(file);
+    let stream = tokio_util::io::ReaderStream::new(buffered_file).inspect(move |chunk| {
+        if let Ok(bytes) = chunk {
+            transfer.add(bytes.len());
+        }
+    });
+    Body::from_stream(stream)
+}
+
+/// Most parts a multipart range response can have, after joining the ones that overlap
+const MAX_RANGE_PARTS: usize = 16;
+
+/// Turns the parsed `ranges` into the byte ranges of a `file_len` long file they select, leaving
+/// out the ones that are outside of it
+///
+/// They're sorted, and the ones that overlap or are right after each other are joined, so no
+/// byte is sent twice
+fn resolve_ranges(ranges: &Ranges, file_len: u64) -> Vec<Range<u64>> {
+    let mut ranges: Vec<_> = ranges
+        .iter()
+        .filter_map(|&range| match range {
+            (Some(start), end) => {
+                // Ends are inclusive
+                let end = end.map_or(file_len, |e| e.saturating_add(1).min(file_len));
+                Some(start..end)
+            }
+            // The last `len` bytes
+            (None, Some(len)) => Some(file_len.saturating_sub(len)..file_len),
+            (None, None) => None,
+        })
+        .filter(|r| r.start < r.end)
+        .collect();
+    ranges.sort_unstable_by_key(|r| r.start);
+
+    let mut joined: Vec<Range<u64>> = Vec::with_capacity(ranges.len());
+    for range in ranges {
+        match joined.last_mut() {
+            Some(last) if range.start <= last.end => last.end = last.end.max(range.end),
+            _ => joined.push(range),
+        }
+    }
+    joined
+}
+
+/// Streams `range` of `file`, which shares its position with every other handle to it, so only
+/// one range can be streamed at a time
+fn stream_range(
+    file: io::Result<std::fs::File>,
+    range: Range<u64>,
+) -> impl Stream<Item = io::Result<Bytes>> {
+    futures_util::stream::once(async move {
+        let mut file = tokio::fs::File::from_std(file?);
+        file.seek(SeekFrom::Start(range.start)).await?;
+        let file = BufReader::new(file).take(range.end - range.start);
+        Ok::<_, io::Error>(tokio_util::io::ReaderStream::new(file))
+    })
+    .try_flatten()
+}
+
+/// `response` should already have the headers shared with full downloads set
 pub async fn dl_range(
-    path_relative_to_data: &Utf8Path,
-    file_name: &str,
+    file: std::fs::File,
     file_len: u64,
     ranges: Ranges,
-    content_type: &str,
+    mut response: Builder,
+    mut transfer: Transfer,
 ) -> Result<Response<Body>, (StatusCode, String)> {
     debug!("User made a range request");
     debug!(?ranges);
 
-    let start = if ranges.is_empty() {
+    if ranges.is_empty() {
         return Err((StatusCode::RANGE_NOT_SATISFIABLE, "You shouldn't send a range request without an actual range. That's bad for the environment".to_string()));
-    } else if ranges.len() > 1 {
+    }
+    let ranges = resolve_ranges(&ranges, file_len);
+    if ranges.is_empty() {
         return Err((
             StatusCode::RANGE_NOT_SATISFIABLE,
-            "Do not support multiple ranges in Range request".to_string(),
+            "The ranges were all past the end of the file".to_string(),
         ));
-    } else {
-        let start = ranges[0]
-            .0
-            .context("Range without starting range not supported")
-            .map_err(|e| (StatusCode::RANGE_NOT_SATISFIABLE, e.to_string()))?;
-
-        if start > file_len {
-            Err((
-                StatusCode::RANGE_NOT_SATISFIABLE,
-                "The range start was past the end of the file".to_string(),
-            ))
-        } else {
-            Ok(start)
-        }
-    }?;
+    }
+    // That many parts would only be asked for to have the file sent many times over, so it's sent
+    // once instead, like RFC 9110 allows ignoring the range
+    if ranges.len() > MAX_RANGE_PARTS {
+        debug!(
+            parts = ranges.len(),
+            "Sending whole file for too many ranges"
+        );
+        let stream = stream_file(tokio::fs::File::from_std(file), file_len, transfer);
+        return response
+            .status(200)
+            .header("Content-Length", file_len)
+            .body(stream)
+            .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()));
+    }
 
-    let mut file = tokio::fs::File::open(&path_relative_to_data)
-        .await
-        .map_err(|e| (StatusCode::NOT_FOUND, e.to_string()))?;
+    if let [range] = &ranges[..] {
+        let range = range.clone();
+        let mut file = tokio::fs::File::from_std(file);
+        file.seek(SeekFrom::Start(range.start)).await.map_err(|e| {
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                format!("Failed to seek file to {} bytes: {e}", range.start),
+            )
+        })?;
 
-    let start_i64 = start.try_into().map_err(|_| {
-        (
-            StatusCode::RANGE_NOT_SATISFIABLE,
-            "Range value too big to fit into i64 (9 exabytes)".to_string(),
-        )
-    })?;
-    file.seek(SeekFrom::Current(start_i64)).await.map_err(|e| {
-        (
-            StatusCode::INTERNAL_SERVER_ERROR,
-            format!("Failed to seek file to {start} bytes: {e}"),
-        )
-    })?;
+        let sent_len = range.end - range.start;
+        let stream = stream_file(file, sent_len, transfer);
+        return response
+            .status(206)
+            .header(
+                "Content-Range",
+                format!("bytes {}-{}/{file_len}", range.start, range.end - 1),
+            )
+            .header("Content-Length", sent_len)
+            .body(stream)
+            .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()));
+    }
 
-    let buffered_file = BufReader::new(file);
-    let stream = tokio_util::io::ReaderStream::new(buffered_file);
-    let stream = axum::body::Body::from_stream(stream);
-    let sent_len = file_len - start;
-    let end = file_len - 1;
-    // FIXME: Transfer-Encoding maybe?
-    Response::builder()
+    // Every part has the content type of the file, and the response itself is multipart
+    let content_type = response
+        .headers_ref()
+        .and_then(|h| h.get("Content-Type"))
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or("application/octet-stream")
+        .to_owned();
+    let boundary = format!(
+        "sfsb-{:x}",
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_nanos()
+    );
+    if let Some(headers) = response.headers_mut() {
+        headers.remove("Content-Type");
+    }
+
+    let mut sent_len = 0;
+    let mut parts = vec![];
+    for range in ranges {
+        let header = format!(
+            "--{boundary}\r\nContent-Type: {content_type}\r\nContent-Range: bytes {}-{}/{file_len}\r\n\r\n",
+            range.start,
+            range.end - 1
+        );
+        sent_len += header.len() as u64 + (range.end - range.start) + 2;
+        parts.push((Bytes::from(header), range));
+    }
+    let closing = Bytes::from(format!("--{boundary}--\r\n"));
+    sent_len += closing.len() as u64;
+
+    transfer.expect(sent_len);
+    let stream = futures_util::stream::iter(parts)
+        .flat_map(move |(header, range)| {
+            // The parts are streamed one after the other, so they can share the position
+            futures_util::stream::iter([Ok(header)])
+                .chain(stream_range(file.try_clone(), range))
+                .chain(futures_util::stream::iter([Ok(Bytes::from_static(
+                    b"\r\n",
+                ))]))
+        })
+        .chain(futures_util::stream::iter([Ok(closing)]))
+        .inspect(move |chunk| {
+            if let Ok(bytes) = chunk {
+                transfer.add(bytes.len());
+            }
+        });
+
+    response
         .status(206)
-        .header("Content-Range", format!("bytes {start}-{end}/{file_len}"))
-        .header("Content-Length", sent_len)
-        .header("Content-Type", content_type)
         .header(
-            "Content-Disposition",
-            format!("attachment; filename=\"{file_name}\""),
+            "Content-Type",
+            format!("multipart/byteranges; boundary={boundary}"),
         )
-        .body(stream)
+        .header("Content-Length", sent_len)
+        .body(Body::from_stream(stream))
         .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))
 }
 
+/// Pre-compressed versions of files that are served instead of them, most preferred first, as
+/// their encoding and the extension added to the file name
+const PRECOMPRESSED: [(&str, &str); 2] = [("br", "br"), ("gzip", "gz")];
+
+fn accepts_encoding(headers: &HeaderMap, encoding: &str) -> bool {
+    headers
+        .get_all("Accept-Encoding")
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .any(|item| {
+            let mut params = item.split(';').map(str::trim);
+            let name = params.next().unwrap_or_default();
+            let refused = params.any(|p| {
+                p.strip_prefix("q=")
+                    .and_then(|q| q.parse::<f32>().ok())
+                    .is_some_and(|q| q <= 0.0)
+            });
+            (name.eq_ignore_ascii_case(encoding) || name == "*") && !refused
+        })
+}
+
+/// Whether both are the metadata of the same file
+#[cfg(unix)]
+fn same_file(a: &Metadata, b: &Metadata) -> bool {
+    use std::os::unix::fs::MetadataExt as _;
+    (a.dev(), a.ino()) == (b.dev(), b.ino())
+}
+
+#[cfg(not(unix))]
+fn same_file(a: &Metadata, b: &Metadata) -> bool {
+    a.len() == b.len() && a.modified().ok() == b.modified().ok()
+}
+
+/// Finds a pre-compressed version of the file at `path` in the data dir that the client accepts,
+/// returning its encoding and the opened file with its metadata. Also returns whether there are
+/// any versions at all, since then the response depends on `Accept-Encoding`
+///
+/// Variants go through the same checks as the file itself, so excluded ones are never served.
+/// Looking for them is blocking, so it's meant for a blocking thread
+fn precompressed_variant(
+    state: &AppState,
+    headers: &HeaderMap,
+    path: &Utf8Path,
+) -> (Option<(&'static str, std::fs::File, Metadata)>, bool) {
+    let mut any = false;
+    for (encoding, ext) in PRECOMPRESSED {
+        let variant = Utf8PathBuf::from(format!("{path}.{ext}"));
+        if state
+            .scan_options
+            .excludes
+            .excludes_path(&state.roots, &variant)
+        {
+            continue;
+        }
+        let Some(fs_path) = state.roots.fs_path(&variant) else {
+            continue;
+        };
+        // Variants aren't in the cache, so they're never followed if they're symlinks, which
+        // could point anywhere
+        let Ok(metadata) = std::fs::symlink_metadata(&fs_path) else {
+            continue;
+        };
+        if !metadata.is_file() {
+            continue;
+        }
+        any = true;
+        if !accepts_encoding(headers, encoding) {
+            continue;
+        }
+        let file = match state.scan_options.open_download(&state.roots, &variant) {
+            Ok(file) => file,
+            Err(e) => {
+                debug!(?variant, "Not serving pre-compressed variant: {e:#}");
+                continue;
+            }
+        };
+        // Only if it's still the file that was found, and not a symlink swapped in since
+        if file.metadata().is_ok_and(|m| same_file(&m, &metadata)) {
+            return (Some((encoding, file, metadata)), true);
+        }
+    }
+    (None, any)
+}
+
+#[derive(Deserialize, Debug)]
+pub struct DownloadQuery {
+    /// Show the file in the browser, whatever the configured disposition for it is
+    #[serde(default, deserialize_with = "deserialize_flag")]
+    inline: bool,
+}
+
 pub async fn dl_path(
     extract::Path(fetched_path): extract::Path<PathBuf>,
     State(state): State<AppState>,
     headers: HeaderMap,
+    Query(query): Query<DownloadQuery>,
 ) -> Result<Response<Body>, (StatusCode, String)> {
     let fetched_path = Utf8PathBuf::from_path_buf(fetched_path)
         .map_err(|p| (StatusCode::BAD_REQUEST, format!("Path {p:?} was not UTF-8")))?;
+    let fetched_path =
+        normalise_path(&fetched_path).map_err(|e| (StatusCode::BAD_REQUEST, e.to_string()))?;
     info!(?fetched_path, "Downloading path");
 
-    let path_relative_to_data = {
-        let data_dir = &state.data_dir;
-        let mut p = data_dir.to_path_buf();
-        p.push(&fetched_path);
-        p
-    };
+    // Only what's in the cache can be downloaded, so nothing that was left out of it can be
+    // reached by guessing its path
+    state.load_path(&fetched_path, false).await;
+    if !fetched_path.as_str().is_empty()
+        && entry_from_cache(&fetched_path, &state.cache.read()).is_none()
+    {
+        return Err((
+            StatusCode::NOT_FOUND,
+            format!("Path {fetched_path:?} does not exist"),
+        ));
+    }
 
-    let metadata = {
-        let metadata = tokio::fs::metadata(&path_relative_to_data)
-            .await
-            .map_err(|e| (StatusCode::NOT_FOUND, e.to_string()))?;
+    let path_relative_to_data = state.roots.fs_path(&fetched_path).ok_or_else(|| {
+        (
+            StatusCode::NOT_FOUND,
+            format!("Path {fetched_path:?} is not inside a data dir"),
+        )
+    })?;
 
-        if metadata.is_dir() {
-            return Err((
-                StatusCode::UNSUPPORTED_MEDIA_TYPE,
-                format!("TODO: Cannot download folders yet: requested {fetched_path:?}"),
-            ));
-        }
-        metadata
+    if state
+        .scan_options
+        .excludes
+        .excludes_path(&state.roots, &fetched_path)
+    {
+        return Err((
+            StatusCode::NOT_FOUND,
+            format!("Path {fetched_path:?} does not exist"),
+        ));
+    }
+    if !query.inline && state.roots.is_preview_only(&fetched_path) {
+        return Err((
+            StatusCode::FORBIDDEN,
+            format!("Path {fetched_path:?} can only be previewed, not downloaded"),
+        ));
+    }
+    // Only what was checked is read, even if the path changes after this. Checking it walks
+    // the path, so it's done on a blocking thread
+    let opened = {
+        let state = state.clone();
+        let path = fetched_path.clone();
+        tokio::task::spawn_blocking(move || {
+            let file = state
+                .scan_options
+                .open_download(&state.roots, &path)
+                .map_err(|e| (StatusCode::FORBIDDEN, format!("{e:#}")))?;
+            let metadata = file
+                .metadata()
+                .map_err(|e| (StatusCode::NOT_FOUND, e.to_string()))?;
+            Ok::<_, (StatusCode, String)>((file, metadata))
+        })
     };
+    let (file, metadata) = opened
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))??;
 
-    let file_len = metadata.len();
-    let ext = fetched_path.extension();
-    let content_type = content_type_from_extension(ext);
+    if metadata.is_dir() {
+        let encoded_path = urlencode(fetched_path.as_str())
+            .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+        return Ok(Redirect::temporary(&format!("/arc/{encoded_path}")).into_response());
+    }
+
+    let content_type = mime::detect(
+        &state.mime_overrides,
+        state.unknown_content_type,
+        &path_relative_to_data,
+    )
+    .await;
     let file_name = path_relative_to_data
         .file_name()
         .expect("File name should be some since it is validated");
+    let disposition = if query.inline {
+        Disposition::Inline
+    } else {
+        disposition_for(&state, &content_type)
+    };
+    let content_disposition = disposition.header_value(file_name);
+
+    let (variant, has_variants) = {
+        let state = state.clone();
+        let headers = headers.clone();
+        let path = fetched_path.clone();
+        tokio::task::spawn_blocking(move || precompressed_variant(&state, &headers, &path))
+            .await
+            .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?
+    };
+    let (file, metadata, content_encoding) = match variant {
+        Some((encoding, file, metadata)) => (file, metadata, Some(encoding)),
+        None => (file, metadata, None),
+    };
+
+    let file_len = metadata.len();
+    let etag = etag_for(&metadata);
+    let modified = metadata.modified().ok().map(DateTime::<Utc>::from);
+
+    let mut response = Response::builder();
+    if has_variants {
+        response = response.header("Vary", "Accept-Encoding");
+    }
+    if let Some(etag) = &etag {
+        response = response.header("ETag", etag);
+    }
+    if let Some(modified) = modified {
+        response = response.header(
+            "Last-Modified",
+            modified.format("%a, %d %b %Y %H:%M:%S GMT").to_string(),
+        );
+    }
+    if not_modified(&headers, etag.as_deref(), modified) {
+        return response
+            .status(StatusCode::NOT_MODIFIED)
+            .body(Body::empty())
+            .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()));
+    }
+
+    let mut response = response
+        .header("Accept-Ranges", "bytes")
+        .header("Content-Type", mime::with_charset(&content_type))
+        .header("Content-Disposition", content_disposition);
+    if let Some(encoding) = content_encoding {
+        response = response.header("Content-Encoding", encoding);
+    }
+    let digests = match (&state.checksums, content_encoding, modified) {
+        // Pre-compressed versions aren't in the cache, so they're never hashed
+        (Some(checksums), None, Some(modified)) => Digests::get(
+            checksums,
+            &path_relative_to_data,
+            file_len,
+            modified.timestamp(),
+        ),
+        _ => None,
+    };
+    let whole = !headers.contains_key("Range");
+    for (name, value) in digests.iter().flat_map(|d| d.headers(&headers, whole)) {
+        response = response.header(name, value);
+    }
+    let transfer = Transfer::new(&state.transfers, fetched_path.as_str());
 
     if let Some(ranges) = headers.get("Range") {
         let ranges = ranges
             .to_str()
             .map_err(|e| (StatusCode::BAD_REQUEST, e.to_string()))?;
         let ranges = parse_ranges(ranges).map_err(|e| (StatusCode::BAD_REQUEST, e.to_string()))?;
-        dl_range(
-            &path_relative_to_data,
-            file_name,
-            file_len,
-            ranges,
-            content_type,
-        )
-        .await
+        dl_range(file, file_len, ranges, response, transfer).await
     } else {
-        let file = tokio::fs::File::open(&path_relative_to_data)
-            .await
-            .map_err(|e| (StatusCode::NOT_FOUND, e.to_string()))?;
-        let buffered_file = BufReader::new(file);
-        let stream = tokio_util::io::ReaderStream::new(buffered_file);
-        let stream = axum::body::Body::from_stream(stream);
+        let stream = stream_file(tokio::fs::File::from_std(file), file_len, transfer);
 
-        Response::builder()
+        response
             .status(200)
-            .header("Accept-Ranges", "bytes")
             .header("Content-Length", file_len)
-            .header("Content-Type", content_type)
-            .header(
-                "Content-Disposition",
-                format!("attachment; filename=\"{file_name}\""),
-            )
             .body(stream)
             .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))
     }
@@ -207,12 +670,310 @@ pub fn parse_ranges(range: &str) -> Result<Ranges> {
     Ok(res)
 }
 
+#[derive(Deserialize, Debug)]
+pub struct ArchiveQuery {
+    #[serde(default)]
+    format: ArchiveFormat,
+    /// Don't compress files, which lets the length be known up front. Only for ZIP archives
+    #[serde(default, deserialize_with = "deserialize_flag")]
+    store: bool,
+    /// Add a `SHA256SUMS` file with the digests of every file
+    #[serde(default, deserialize_with = "deserialize_flag")]
+    checksums: bool,
+}
+
+/// Values of every `files` parameter, which can't be parsed by [`Query`] since it's repeated
+fn selected_files(query: Option<&str>) -> Vec<String> {
+    query
+        .map(|q| {
+            form_urlencoded::parse(q.as_bytes())
+                .filter(|(key, _)| key == "files")
+                .map(|(_, value)| value.into_owned())
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+pub async fn root_archive(
+    State(state): State<AppState>,
+    visitor: Visitor,
+    headers: HeaderMap,
+    query: Query<ArchiveQuery>,
+    raw_query: RawQuery,
+) -> Result<Response<Body>, (StatusCode, String)> {
+    dl_archive(
+        extract::Path(PathBuf::from(".")),
+        State(state),
+        visitor,
+        headers,
+        query,
+        raw_query,
+    )
+    .await
+}
+
 pub async fn dl_archive(
     extract::Path(fetched_path): extract::Path<PathBuf>,
-    State(_): State<AppState>,
-    _: HeaderMap,
-    Query(query): Query<HashMap<String, Option<Vec<String>>>>,
-) -> Result<Response<Body>, String> {
+    State(state): State<AppState>,
+    visitor: Visitor,
+    headers: HeaderMap,
+    Query(query): Query<ArchiveQuery>,
+    RawQuery(raw_query): RawQuery,
+) -> Result<Response<Body>, (StatusCode, String)> {
+    let fetched_path = Utf8PathBuf::from_path_buf(fetched_path)
+        .map_err(|p| (StatusCode::BAD_REQUEST, format!("Path {p:?} was not UTF-8")))?;
     info!(?fetched_path, ?query, "Downloading archive from path");
-    todo!()
+
+    let normalised_path =
+        normalise_path(&fetched_path).map_err(|e| (StatusCode::BAD_REQUEST, e.to_string()))?;
+    if state.roots.is_preview_only(&normalised_path) {
+        return Err((
+            StatusCode::FORBIDDEN,
+            format!("Path {normalised_path:?} can only be previewed, not downloaded"),
+        ));
+    }
+
+    state.load_path(&normalised_path, true).await;
+    let dir_entries = {
+        let lock = state.cache.read();
+        path_contents_from_cache(&normalised_path, &lock)
+            .wrap_err_with(|| format!("Failed fetching contents of path {normalised_path:?}"))
+            .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?
+    };
+    let Some(dir_entries) = dir_entries else {
+        return Err((
+            StatusCode::NOT_FOUND,
+            format!("Path {normalised_path:?} is not a directory"),
+        ));
+    };
+
+    let archive_name = normalised_path.file_name().unwrap_or("root");
+    let prefix = format!("{archive_name}/");
+    let mut entries = vec![];
+
+    let selected = selected_files(raw_query.as_deref());
+    let whole_dir = selected.is_empty();
+    if whole_dir {
+        archive::collect_entries(
+            &prefix,
+            &state.roots,
+            &normalised_path,
+            &dir_entries,
+            &mut entries,
+        );
+    } else {
+        let mut selected = selected
+            .iter()
+            .map(|f| normalise_path(Utf8Path::new(f)))
+            .collect::<Result<Vec<_>>>()
+            .map_err(|e| (StatusCode::BAD_REQUEST, e.to_string()))?;
+        // Parents sort before their children, so anything inside an already selected directory
+        // can be skipped
+        selected.sort();
+        let mut added: Vec<Utf8PathBuf> = vec![];
+        for path in selected {
+            if added.iter().any(|a| path.starts_with(a)) {
+                continue;
+            }
+            let entry = entry_from_cache(&path, &dir_entries).ok_or_else(|| {
+                (
+                    StatusCode::BAD_REQUEST,
+                    format!("Selected file {path:?} is not inside {normalised_path:?}"),
+                )
+            })?;
+
+            let parent = path.parent().unwrap_or_else(|| Utf8Path::new(""));
+            let entry_prefix = if parent.as_str().is_empty() {
+                prefix.clone()
+            } else {
+                format!("{prefix}{parent}/")
+            };
+            archive::collect_entry(
+                &entry_prefix,
+                &state.roots,
+                &normalised_path.join(parent),
+                entry,
+                &mut entries,
+            );
+            added.push(path);
+        }
+    }
+    let hidden = visitor.retain_reachable(&state, &normalised_path, &prefix, &mut entries);
+    if normalised_path.as_str().is_empty() {
+        // Mounts are only in the root, and the ones that can only be previewed are left out for
+        // everyone, so the archive can still be cached
+        entries.retain(|e| {
+            let path = e.name.strip_prefix(&prefix).unwrap_or(&e.name);
+            !state.roots.is_preview_only(Utf8Path::new(path))
+        });
+    }
+
+    if let Some(max) = state.max_archive_entries {
+        if entries.len() > max {
+            return Err((
+                StatusCode::PAYLOAD_TOO_LARGE,
+                format!(
+                    "Archive would have {} entries, the most allowed is {max}",
+                    entries.len()
+                ),
+            ));
+        }
+    }
+    if let Some(max) = state.max_archive_bytes {
+        let size: u64 = entries.iter().filter_map(|e| e.size).sum();
+        if size > max {
+            return Err((
+                StatusCode::PAYLOAD_TOO_LARGE,
+                format!("Archive would have {size} bytes of files, the most allowed is {max}"),
+            ));
+        }
+    }
+
+    if query.checksums {
+        let checksums = state.checksums.as_ref().ok_or_else(|| {
+            (
+                StatusCode::BAD_REQUEST,
+                "Files aren't hashed on this server, so archives can't have checksums".to_string(),
+            )
+        })?;
+        let manifest = checksums
+            .manifest(Algorithm::Sha256, &prefix, &entries)
+            .ok_or_else(|| {
+                (
+                    StatusCode::SERVICE_UNAVAILABLE,
+                    "Some files haven't been hashed yet, try again later".to_string(),
+                )
+            })?;
+        // Newest time in the archive, so it doesn't change between downloads
+        let modified = entries.iter().map(|e| e.modified).max().unwrap_or(0);
+        entries.push(ArchiveEntry::generated(
+            format!("{prefix}{}", Algorithm::Sha256.manifest_name()),
+            manifest.into(),
+            modified,
+        ));
+    }
+
+    let format = query.format;
+    let response = Response::builder()
+        .header("Content-Type", format.content_type())
+        .header(
+            "Content-Disposition",
+            format!(
+                "attachment; filename=\"{archive_name}.{}\"",
+                format.extension()
+            ),
+        );
+
+    if query.store {
+        if format != ArchiveFormat::Zip {
+            return Err((
+                StatusCode::BAD_REQUEST,
+                "Only ZIP archives can be stored uncompressed".to_string(),
+            ));
+        }
+        return stored_zip(&state, &headers, entries, response);
+    }
+
+    // Selections are unlikely to be asked for again, so only whole directories are cached
+    let spool = match &state.archive_cache {
+        // Archives missing what the visitor can't reach are only for them
+        Some(archive_cache) if whole_dir && !hidden => {
+            let key = ArchiveKey {
+                path: normalised_path.clone(),
+                format,
+                checksums: query.checksums,
+            };
+            if let Some(cached) = archive_cache.get(&key) {
+                match tokio::fs::File::open(&cached.path).await {
+                    Ok(file) => {
+                        debug!(?key, "Serving cached archive");
+                        return response
+                            .status(200)
+                            .header("Content-Length", cached.len)
+                            .body(Body::from_stream(tokio_util::io::ReaderStream::new(file)))
+                            .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()));
+                    }
+                    // It was thrown away after being looked up
+                    Err(e) => debug!("Failed opening cached archive: {e}"),
+                }
+            }
+            match archive_cache.spool(key).await {
+                Ok(spool) => Some(spool),
+                Err(e) => {
+                    warn!("Failed creating file to cache archive in: {e}");
+                    None
+                }
+            }
+        }
+        _ => None,
+    };
+
+    response
+        .status(200)
+        .body(archive::stream(format, entries, state.zstd_level, spool))
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))
+}
+
+/// Uncompressed ZIPs have a known length, so they can have a `Content-Length` and be resumed
+///
+/// They're the same bytes as long as the entries don't change, so they have a strong ETag that
+/// `If-Range` is checked against, and the newest time in them as `Last-Modified`
+fn stored_zip(
+    state: &AppState,
+    headers: &HeaderMap,
+    entries: Vec<ArchiveEntry>,
+    response: Builder,
+) -> Result<Response<Body>, (StatusCode, String)> {
+    let len = archive::stored_zip_len(&entries)
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    let etag = archive::stored_zip_etag(&entries);
+    let modified = entries.iter().map(|e| e.modified).max().unwrap_or(0);
+    let modified = DateTime::from_timestamp(modified, 0).unwrap_or_default();
+    let response = response
+        .header("Accept-Ranges", "bytes")
+        .header("ETag", &etag)
+        .header(
+            "Last-Modified",
+            modified.format("%a, %d %b %Y %H:%M:%S GMT").to_string(),
+        );
+    let checksums = state.checksums.clone();
+
+    let ranges = headers
+        .get("Range")
+        // A range of an archive that changed since would be spliced into the old one
+        .filter(|_| if_range_matches(headers, &etag, modified));
+    let Some(ranges) = ranges else {
+        return response
+            .status(200)
+            .header("Content-Length", len)
+            .body(archive::stream_stored_zip(entries, 0..len, checksums))
+            .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()));
+    };
+
+    let ranges = ranges
+        .to_str()
+        .map_err(|e| (StatusCode::BAD_REQUEST, e.to_string()))?;
+    let ranges = parse_ranges(ranges).map_err(|e| (StatusCode::BAD_REQUEST, e.to_string()))?;
+    debug!(?ranges, "User made a range request for an archive");
+    let [(Some(start), end)] = ranges[..] else {
+        return Err((
+            StatusCode::RANGE_NOT_SATISFIABLE,
+            "Only a single range with a start is supported for archives".to_string(),
+        ));
+    };
+    // Range ends are inclusive
+    let end = end.map_or(len, |end| end.saturating_add(1).min(len));
+    if start >= end {
+        return Err((
+            StatusCode::RANGE_NOT_SATISFIABLE,
+            "The range start was past the end of the archive".to_string(),
+        ));
+    }
+
+    response
+        .status(206)
+        .header("Content-Range", format!("bytes {start}-{}/{len}", end - 1))
+        .header("Content-Length", end - start)
+        .body(archive::stream_stored_zip(entries, start..end, checksums))
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))
 }
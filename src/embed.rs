@@ -0,0 +1,90 @@
+use axum::{
+    extract::{Query, State},
+    http::StatusCode,
+    Json,
+};
+use camino::Utf8PathBuf;
+use percent_encoding::percent_decode_str;
+use serde::{Deserialize, Serialize};
+use url::Url;
+
+use crate::{dir_view::entry_from_cache, AppState};
+
+#[derive(Deserialize, Debug)]
+pub struct OEmbedQuery {
+    url: String,
+    format: Option<String>,
+}
+
+/// <https://oembed.com/#section2.3>
+#[derive(Serialize, Debug)]
+pub struct OEmbed {
+    version: &'static str,
+    #[serde(rename = "type")]
+    kind: &'static str,
+    title: String,
+    provider_name: String,
+    provider_url: String,
+}
+
+/// oEmbed provider for `/browse`, `/dl` and `/view` urls of this instance
+pub async fn oembed(
+    State(state): State<AppState>,
+    Query(query): Query<OEmbedQuery>,
+) -> Result<Json<OEmbed>, (StatusCode, String)> {
+    if query.format.as_deref().is_some_and(|f| f != "json") {
+        return Err((
+            StatusCode::NOT_IMPLEMENTED,
+            "Only the json oEmbed format is supported".to_string(),
+        ));
+    }
+
+    let url = Url::parse(&query.url).map_err(|e| (StatusCode::BAD_REQUEST, e.to_string()))?;
+    if url.origin() != state.base_url.origin() {
+        return Err((
+            StatusCode::NOT_FOUND,
+            format!("Url {url} is not served by this instance"),
+        ));
+    }
+
+    let mut segments = url
+        .path_segments()
+        .into_iter()
+        .flatten()
+        .filter(|s| !s.is_empty());
+    if !matches!(segments.next(), Some("browse" | "dl" | "view")) {
+        return Err((
+            StatusCode::NOT_FOUND,
+            format!("Url {url} does not point to a file or directory"),
+        ));
+    }
+    let path: Utf8PathBuf = segments
+        .map(|s| percent_decode_str(s).decode_utf8().map(|s| s.into_owned()))
+        .collect::<Result<_, _>>()
+        .map_err(|e| (StatusCode::BAD_REQUEST, e.to_string()))?;
+
+    let title = if path.components().next().is_none() {
+        "Root".to_string()
+    } else {
+        let lock = state.cache.read();
+        let entry = entry_from_cache(&path, &lock).ok_or_else(|| {
+            (
+                StatusCode::NOT_FOUND,
+                format!("Path {path:?} does not exist"),
+            )
+        })?;
+        format!(
+            "{} ({})",
+            entry.name(),
+            state.size_units.format(entry.size())
+        )
+    };
+
+    Ok(Json(OEmbed {
+        version: "1.0",
+        kind: "link",
+        title,
+        provider_name: state.web_app.name.clone(),
+        provider_url: state.base_url.to_string(),
+    }))
+}
@@ -0,0 +1,150 @@
+use askama::Template;
+use axum::{
+    body::to_bytes,
+    extract::{Request, State},
+    http::{header, HeaderMap},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use percent_encoding::percent_decode_str;
+use serde::Serialize;
+use tracing::error;
+
+use crate::{
+    color_scheme::ColorScheme,
+    dir_view::{breadcrumbs, Breadcrumb},
+    i18n::{Locale, Messages},
+    AppState,
+};
+
+/// Most of an error body that's read to be shown in the page
+const MAX_MESSAGE_BYTES: usize = 64 * 1024;
+
+/// Routes whose paths are of files and directories, so the page can link to where they are
+pub const PATH_ROUTES: [&str; 11] = [
+    "browse",
+    "dl",
+    "arc",
+    "tree",
+    "view",
+    "thumb",
+    "info",
+    "torrent",
+    "sha256sum",
+    "md5sum",
+    "zsync",
+];
+
+#[derive(Template, Serialize)]
+#[template(path = "error.html")]
+pub struct ErrorTemplate {
+    /// Code and reason, like `404 Not Found`
+    status: String,
+    /// What went wrong, none if it's hidden or the handler didn't say
+    message: Option<String>,
+    /// Whether what went wrong is only in the logs
+    hidden: bool,
+    /// Links to the directories on the way to what the request was for
+    breadcrumbs: Vec<Breadcrumb>,
+    site_title: String,
+    tagline: Option<String>,
+    /// Whether `/logo` has a picture
+    has_logo: bool,
+    color_scheme: ColorScheme,
+    locale: Locale,
+    /// Every string of the page, in the language of the locale
+    t: &'static Messages,
+}
+
+/// Whether the request comes from a browser, which wants a page instead of plain text
+fn wants_html(headers: &HeaderMap) -> bool {
+    headers
+        .get(header::ACCEPT)
+        .and_then(|accept| accept.to_str().ok())
+        .is_some_and(|accept| accept.contains("text/html"))
+}
+
+/// Directory the file or directory at the url path is in, if the route is of one
+fn parent_dirname(path: &str) -> Option<String> {
+    let path = percent_decode_str(path).decode_utf8().ok()?;
+    let mut segments = path.split('/').filter(|s| !s.is_empty());
+    if !PATH_ROUTES.contains(&segments.next()?) {
+        return None;
+    }
+    let mut segments: Vec<_> = segments.collect();
+    segments.pop();
+    Some(segments.join("/"))
+}
+
+/// Middleware that shows errors to browsers in a page with the rest of the site around it, and
+/// only logs what went wrong in server errors unless the config says to show it
+pub async fn error_pages(
+    State(state): State<AppState>,
+    color_scheme: ColorScheme,
+    locale: Locale,
+    request: Request,
+    next: Next,
+) -> Response {
+    let html = wants_html(request.headers());
+    let path = request.uri().path().to_owned();
+    let response = next.run(request).await;
+
+    let status = response.status();
+    // Errors from the handlers are plain text, or empty when there's nothing to say
+    let plain = response
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .map_or(true, |c| c.as_bytes().starts_with(b"text/plain"));
+    let hidden = status.is_server_error() && !state.show_error_details;
+    if !(status.is_client_error() || status.is_server_error()) || !plain || !(html || hidden) {
+        return response;
+    }
+
+    let (parts, body) = response.into_parts();
+    let message = to_bytes(body, MAX_MESSAGE_BYTES)
+        .await
+        .ok()
+        .map(|bytes| String::from_utf8_lossy(&bytes).into_owned())
+        .filter(|message| !message.is_empty());
+    let t = locale.messages();
+    let message = if hidden {
+        error!(%status, ?path, details = ?message, "Request failed");
+        None
+    } else {
+        message
+    };
+
+    let mut page = if html {
+        let page = ErrorTemplate {
+            status: format!(
+                "{} {}",
+                status.as_str(),
+                status.canonical_reason().unwrap_or_default()
+            ),
+            message,
+            hidden,
+            breadcrumbs: parent_dirname(&path)
+                .map(|dirname| breadcrumbs(&dirname))
+                .unwrap_or_default(),
+            site_title: state.branding.title.clone(),
+            tagline: state.branding.tagline.clone(),
+            has_logo: state.branding.has_logo(),
+            color_scheme,
+            locale,
+            t,
+        };
+        state.theme.page("error.html", page)
+    } else {
+        t.error_hidden.into_response()
+    };
+    *page.status_mut() = status;
+    *page.extensions_mut() = parts.extensions;
+    // The other headers of the error are kept, like the `Content-Range` of unsatisfiable ranges
+    let own: Vec<_> = page.headers().keys().cloned().collect();
+    for (name, value) in &parts.headers {
+        if !own.contains(name) && name != header::CONTENT_LENGTH {
+            page.headers_mut().append(name, value.clone());
+        }
+    }
+    page
+}
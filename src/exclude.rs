@@ -0,0 +1,96 @@
+use camino::Utf8Path;
+use color_eyre::{eyre::WrapErr, Result};
+use ignore::{
+    gitignore::{Gitignore, GitignoreBuilder},
+    Match,
+};
+use std::path::Path;
+use tracing::warn;
+
+use crate::roots::DataRoots;
+
+/// Files with patterns, in gitignore syntax, of paths to leave out of the directory they're in
+pub const IGNORE_FILE: &str = ".sfsbignore";
+
+/// Paths that are left out of the cache, and can't be downloaded
+#[derive(Debug, Clone)]
+pub struct Excludes {
+    /// Patterns from the config, for each data dir, relative to it
+    global: Vec<Gitignore>,
+}
+
+impl Excludes {
+    pub fn new(roots: &DataRoots, patterns: &[String]) -> Result<Self> {
+        let mut global = vec![];
+        for (_, data_dir) in roots.dirs() {
+            let mut builder = GitignoreBuilder::new(data_dir);
+            for pattern in patterns {
+                builder
+                    .add_line(None, pattern)
+                    .wrap_err_with(|| format!("Invalid exclude pattern {pattern}"))?;
+            }
+            global.push(
+                builder
+                    .build()
+                    .wrap_err("Failed building exclude patterns")?,
+            );
+        }
+        Ok(Self { global })
+    }
+
+    /// Patterns in the ignore file of `dir`, if it has one
+    pub fn dir_patterns(dir: &Path) -> Option<Gitignore> {
+        let path = dir.join(IGNORE_FILE);
+        if !path.is_file() {
+            return None;
+        }
+        let (patterns, error) = Gitignore::new(&path);
+        if let Some(e) = error {
+            warn!("Failed reading some patterns from {}: {e}", path.display());
+        }
+        Some(patterns)
+    }
+
+    /// Whether `path` is excluded, with `dir_patterns` being the ones from the ignore files of
+    /// the directories it's in, outermost first
+    pub fn is_excluded(
+        &self,
+        dir_patterns: &[Option<Gitignore>],
+        path: &Path,
+        is_dir: bool,
+    ) -> bool {
+        if path.file_name().is_some_and(|n| n == IGNORE_FILE) {
+            return true;
+        }
+        // Patterns closer to the path win, like in git
+        for patterns in dir_patterns.iter().rev().flatten() {
+            match patterns.matched(path, is_dir) {
+                Match::Ignore(_) => return true,
+                Match::Whitelist(_) => return false,
+                Match::None => {}
+            }
+        }
+        self.global
+            .iter()
+            .filter(|g| path.starts_with(g.path()))
+            .any(|g| g.matched(path, is_dir).is_ignore())
+    }
+
+    /// Whether `path` or any directory it's in is excluded, reading the ignore files on the way to
+    /// it
+    pub fn excludes_path(&self, roots: &DataRoots, path: &Utf8Path) -> bool {
+        let Some((data_dir, path)) = roots.resolve(path) else {
+            return false;
+        };
+        let mut dir_patterns = vec![];
+        let mut current = data_dir.to_path_buf();
+        for component in path.components() {
+            dir_patterns.push(Self::dir_patterns(current.as_std_path()));
+            current.push(component);
+            if self.is_excluded(&dir_patterns, current.as_std_path(), current.is_dir()) {
+                return true;
+            }
+        }
+        false
+    }
+}
@@ -0,0 +1,45 @@
+use camino::{Utf8Path, Utf8PathBuf};
+use parking_lot::RwLock;
+use std::collections::HashMap;
+use tracing::warn;
+
+use crate::roots::DataRoots;
+
+/// Space left on the filesystems of the data dirs, read again whenever the cache changes since
+/// that's when it's likely to have changed too
+#[derive(Debug, Default)]
+pub struct FreeSpace {
+    /// Bytes available to the server, by data dir
+    available: RwLock<HashMap<Utf8PathBuf, u64>>,
+}
+
+impl FreeSpace {
+    pub fn new(roots: &DataRoots) -> Self {
+        let free_space = Self::default();
+        free_space.update(roots);
+        free_space
+    }
+
+    /// Asks the filesystems of every data dir how much space they have left
+    pub fn update(&self, roots: &DataRoots) {
+        let available = roots
+            .dirs()
+            .into_iter()
+            .filter_map(|(_, dir)| match fs4::available_space(dir) {
+                Ok(available) => Some((dir.to_owned(), available)),
+                Err(e) => {
+                    warn!(%dir, "Failed to read free space of data dir: {e}");
+                    None
+                }
+            })
+            .collect();
+        *self.available.write() = available;
+    }
+
+    /// Space left on the filesystem `path` is on, which isn't known for the root when serving
+    /// several data dirs
+    pub fn of(&self, roots: &DataRoots, path: &Utf8Path) -> Option<u64> {
+        let (dir, _) = roots.resolve(path)?;
+        self.available.read().get(dir).copied()
+    }
+}
@@ -0,0 +1,539 @@
+use axum::{
+    async_trait,
+    extract::FromRequestParts,
+    http::{header, request::Parts, HeaderMap},
+};
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use std::{borrow::Borrow, convert::Infallible, str::FromStr};
+
+use crate::AppState;
+
+/// Language the pages are shown in
+#[derive(Serialize, Debug, Default, Clone, Copy, PartialEq, Eq, Hash)]
+#[serde(rename_all = "lowercase")]
+pub enum Locale {
+    #[default]
+    En,
+    Es,
+    De,
+    Fr,
+}
+
+impl Locale {
+    const ALL: [Self; 4] = [Self::En, Self::Es, Self::De, Self::Fr];
+
+    /// Language tag of the locale, for the `lang` of the pages
+    pub const fn code(self) -> &'static str {
+        match self {
+            Self::En => "en",
+            Self::Es => "es",
+            Self::De => "de",
+            Self::Fr => "fr",
+        }
+    }
+
+    pub const fn messages(self) -> &'static Messages {
+        match self {
+            Self::En => &EN,
+            Self::Es => &ES,
+            Self::De => &DE,
+            Self::Fr => &FR,
+        }
+    }
+
+    /// The locale the browser likes the most out of the ones there are, going by the
+    /// `Accept-Language` header
+    fn from_accept_language(headers: &HeaderMap) -> Option<Self> {
+        let mut languages: Vec<(&str, f32)> = headers
+            .get_all(header::ACCEPT_LANGUAGE)
+            .iter()
+            .filter_map(|h| h.to_str().ok())
+            .flat_map(|h| h.split(','))
+            .filter_map(|language| {
+                let mut parts = language.split(';');
+                let tag = parts.next()?.trim();
+                let quality = parts
+                    .find_map(|p| p.trim().strip_prefix("q="))
+                    .map_or(Some(1.0), |q| q.parse().ok())?;
+                Some((tag, quality))
+            })
+            .filter(|(_, quality)| *quality > 0.0)
+            .collect();
+        // Stable, so languages with the same quality keep their order
+        languages.sort_by(|(_, q1), (_, q2)| q2.total_cmp(q1));
+        languages.into_iter().find_map(|(tag, _)| {
+            let primary = tag.split('-').next().unwrap_or(tag);
+            primary.parse().ok()
+        })
+    }
+}
+
+impl FromStr for Locale {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::ALL
+            .into_iter()
+            .find(|locale| s.eq_ignore_ascii_case(locale.code()))
+            .ok_or_else(|| {
+                let codes: Vec<_> = Self::ALL.iter().map(|l| l.code()).collect();
+                format!("Unknown locale {s}, should be one of {}", codes.join(", "))
+            })
+    }
+}
+
+/// The locale of the config if there's one, or the one the browser asks for
+#[async_trait]
+impl FromRequestParts<AppState> for Locale {
+    type Rejection = Infallible;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &AppState,
+    ) -> Result<Self, Self::Rejection> {
+        Ok(state
+            .locale
+            .or_else(|| Self::from_accept_language(&parts.headers))
+            .unwrap_or_default())
+    }
+}
+
+/// Replaces the `{}` in a message with `value`
+fn fill(message: &str, value: impl ToString) -> String {
+    message.replacen("{}", &value.to_string(), 1)
+}
+
+/// Every string shown in the pages, in a single language
+///
+/// Messages with `{}` have something put in their place, and the ones that are pairs are the
+/// singular and plural of it
+#[derive(Serialize, Debug)]
+pub struct Messages {
+    /// Whether zero is counted in the singular, like in French
+    #[serde(skip)]
+    singular_zero: bool,
+    pub root: &'static str,
+    pub search: &'static str,
+    pub recently_added: &'static str,
+    pub download_as_zip: &'static str,
+    pub tree: &'static str,
+    pub match_case: &'static str,
+    pub ignore_case: &'static str,
+    pub table: &'static str,
+    pub gallery: &'static str,
+    pub exact_times: &'static str,
+    pub relative_times: &'static str,
+    pub light: &'static str,
+    pub dark: &'static str,
+    pub auto: &'static str,
+    pub filter_by_name: &'static str,
+    pub filter: &'static str,
+    pub nothing_matches: &'static str,
+    pub empty_directory: &'static str,
+    pub symlink: &'static str,
+    pub name: &'static str,
+    pub creation_time: &'static str,
+    pub size: &'static str,
+    pub owner: &'static str,
+    pub group: &'static str,
+    pub permissions: &'static str,
+    pub children_count: &'static str,
+    pub downloads: &'static str,
+    pub archive: &'static str,
+    pub view: &'static str,
+    pub browse: &'static str,
+    pub modified: &'static str,
+    /// Short labels of the columns, for narrow screens
+    pub created: &'static str,
+    pub children: &'static str,
+    pub download_selected: &'static str,
+    pub path: &'static str,
+    pub search_placeholder: &'static str,
+    pub results_truncated: &'static str,
+    pub back_to_listing: &'static str,
+    pub download: &'static str,
+    pub qr_code: &'static str,
+    pub torrent: &'static str,
+    pub info: &'static str,
+    pub content_type: &'static str,
+    pub dimensions: &'static str,
+    pub share_link: &'static str,
+    pub copy: &'static str,
+    pub copy_link: &'static str,
+    pub copied: &'static str,
+    pub not_hashed_yet: &'static str,
+    pub too_big: &'static str,
+    pub just_now: &'static str,
+    pub error_hidden: &'static str,
+    free: &'static str,
+    directories: [&'static str; 2],
+    files: [&'static str; 2],
+    bytes: [&'static str; 2],
+    total: &'static str,
+    results: [&'static str; 2],
+    tree_of: &'static str,
+    download_named: &'static str,
+    created_on: &'static str,
+    ago: &'static str,
+    years: [&'static str; 2],
+    months: [&'static str; 2],
+    weeks: [&'static str; 2],
+    days: [&'static str; 2],
+    hours: [&'static str; 2],
+    minutes: [&'static str; 2],
+}
+
+impl Messages {
+    fn count(&self, n: u64, forms: [&str; 2]) -> String {
+        let singular = n == 1 || (n == 0 && self.singular_zero);
+        fill(forms[usize::from(!singular)], n)
+    }
+
+    pub fn free(&self, size: &str) -> String {
+        fill(self.free, size)
+    }
+
+    /// Exact size of a file, like `1024 bytes`
+    pub fn bytes(&self, size: u64) -> String {
+        self.count(size, self.bytes)
+    }
+
+    pub fn results(&self, count: usize) -> String {
+        self.count(count as u64, self.results)
+    }
+
+    pub fn tree_of(&self, dir: &str) -> String {
+        fill(self.tree_of, dir)
+    }
+
+    pub fn download_named(&self, name: &str) -> String {
+        fill(self.download_named, name)
+    }
+
+    pub fn created_on(&self, date: &str) -> String {
+        fill(self.created_on, date)
+    }
+
+    /// Directories and files in a listing, and how much is in them
+    pub fn summary(&self, dirs: usize, files: usize, size: &str) -> String {
+        format!(
+            "{}, {}, {}",
+            self.count(dirs as u64, self.directories),
+            self.count(files as u64, self.files),
+            fill(self.total, size)
+        )
+    }
+
+    /// How long before now a time from the directory cache was, like `3 days ago`
+    pub fn ago(&self, time: impl Borrow<DateTime<Utc>>) -> String {
+        let seconds = (Utc::now() - *time.borrow()).num_seconds();
+        // Clocks of other machines can be a bit ahead
+        if seconds < 60 {
+            return self.just_now.to_owned();
+        }
+        let (length, forms) = [
+            (365 * 24 * 60 * 60, self.years),
+            (30 * 24 * 60 * 60, self.months),
+            (7 * 24 * 60 * 60, self.weeks),
+            (24 * 60 * 60, self.days),
+            (60 * 60, self.hours),
+        ]
+        .into_iter()
+        .find(|(length, _)| seconds >= *length)
+        .unwrap_or((60, self.minutes));
+        fill(
+            self.ago,
+            self.count((seconds / length).unsigned_abs(), forms),
+        )
+    }
+}
+
+static EN: Messages = Messages {
+    singular_zero: false,
+    root: "Root",
+    search: "Search",
+    recently_added: "Recently added",
+    download_as_zip: "Download as ZIP",
+    tree: "Tree",
+    match_case: "Match case",
+    ignore_case: "Ignore case",
+    table: "Table",
+    gallery: "Gallery",
+    exact_times: "Exact times",
+    relative_times: "Relative times",
+    light: "Light",
+    dark: "Dark",
+    auto: "Auto",
+    filter_by_name: "Filter by name",
+    filter: "Filter",
+    nothing_matches: "Nothing in this directory matches the filter",
+    empty_directory: "This directory is empty",
+    symlink: "symlink",
+    name: "Name",
+    creation_time: "Creation Time",
+    size: "Size",
+    owner: "Owner",
+    group: "Group",
+    permissions: "Permissions",
+    children_count: "Children Count",
+    downloads: "Downloads",
+    archive: "Archive",
+    view: "View",
+    browse: "Browse",
+    modified: "Modified",
+    created: "Created",
+    children: "Children",
+    download_selected: "Download selected as ZIP",
+    path: "Path",
+    search_placeholder: "Name or glob, like *.txt",
+    results_truncated: "only the first ones are shown",
+    back_to_listing: "Back to listing",
+    download: "Download",
+    qr_code: "QR code",
+    torrent: "Torrent",
+    info: "Info",
+    content_type: "Type",
+    dimensions: "Dimensions",
+    share_link: "Share link",
+    copy: "Copy",
+    copy_link: "Copy link",
+    copied: "Copied",
+    not_hashed_yet: "Not computed yet, try again in a while",
+    too_big: "The file is too big to show, only its start is shown",
+    just_now: "just now",
+    error_hidden: "Something went wrong, the details are in the logs of the server",
+    free: "{} free",
+    directories: ["{} directory", "{} directories"],
+    files: ["{} file", "{} files"],
+    bytes: ["{} byte", "{} bytes"],
+    total: "{} total",
+    results: ["{} result", "{} results"],
+    tree_of: "Tree of {}",
+    download_named: "Download {}",
+    created_on: "created {}",
+    ago: "{} ago",
+    years: ["{} year", "{} years"],
+    months: ["{} month", "{} months"],
+    weeks: ["{} week", "{} weeks"],
+    days: ["{} day", "{} days"],
+    hours: ["{} hour", "{} hours"],
+    minutes: ["{} minute", "{} minutes"],
+};
+
+static ES: Messages = Messages {
+    singular_zero: false,
+    root: "Raíz",
+    search: "Buscar",
+    recently_added: "Añadidos recientemente",
+    download_as_zip: "Descargar como ZIP",
+    tree: "Árbol",
+    match_case: "Distinguir mayúsculas",
+    ignore_case: "Ignorar mayúsculas",
+    table: "Tabla",
+    gallery: "Galería",
+    exact_times: "Fechas exactas",
+    relative_times: "Fechas relativas",
+    light: "Claro",
+    dark: "Oscuro",
+    auto: "Automático",
+    filter_by_name: "Filtrar por nombre",
+    filter: "Filtrar",
+    nothing_matches: "Nada en este directorio coincide con el filtro",
+    empty_directory: "Este directorio está vacío",
+    symlink: "enlace simbólico",
+    name: "Nombre",
+    creation_time: "Fecha de creación",
+    size: "Tamaño",
+    owner: "Propietario",
+    group: "Grupo",
+    permissions: "Permisos",
+    children_count: "Elementos",
+    downloads: "Descargas",
+    archive: "Archivo",
+    view: "Ver",
+    browse: "Explorar",
+    modified: "Modificado",
+    created: "Creado",
+    children: "Elementos",
+    download_selected: "Descargar seleccionados como ZIP",
+    path: "Ruta",
+    search_placeholder: "Nombre o patrón, como *.txt",
+    results_truncated: "solo se muestran los primeros",
+    back_to_listing: "Volver al listado",
+    download: "Descargar",
+    qr_code: "Código QR",
+    torrent: "Torrent",
+    info: "Información",
+    content_type: "Tipo",
+    dimensions: "Dimensiones",
+    share_link: "Enlace para compartir",
+    copy: "Copiar",
+    copy_link: "Copiar enlace",
+    copied: "Copiado",
+    not_hashed_yet: "Aún sin calcular, vuelve a intentarlo en un rato",
+    too_big: "El archivo es demasiado grande, solo se muestra su principio",
+    just_now: "ahora mismo",
+    error_hidden: "Algo salió mal, los detalles están en los registros del servidor",
+    free: "{} libres",
+    directories: ["{} directorio", "{} directorios"],
+    files: ["{} archivo", "{} archivos"],
+    bytes: ["{} byte", "{} bytes"],
+    total: "{} en total",
+    results: ["{} resultado", "{} resultados"],
+    tree_of: "Árbol de {}",
+    download_named: "Descargar {}",
+    created_on: "creado el {}",
+    ago: "hace {}",
+    years: ["{} año", "{} años"],
+    months: ["{} mes", "{} meses"],
+    weeks: ["{} semana", "{} semanas"],
+    days: ["{} día", "{} días"],
+    hours: ["{} hora", "{} horas"],
+    minutes: ["{} minuto", "{} minutos"],
+};
+
+static DE: Messages = Messages {
+    singular_zero: false,
+    root: "Start",
+    search: "Suchen",
+    recently_added: "Zuletzt hinzugefügt",
+    download_as_zip: "Als ZIP herunterladen",
+    tree: "Baum",
+    match_case: "Groß-/Kleinschreibung beachten",
+    ignore_case: "Groß-/Kleinschreibung ignorieren",
+    table: "Tabelle",
+    gallery: "Galerie",
+    exact_times: "Genaue Zeiten",
+    relative_times: "Relative Zeiten",
+    light: "Hell",
+    dark: "Dunkel",
+    auto: "Automatisch",
+    filter_by_name: "Nach Name filtern",
+    filter: "Filtern",
+    nothing_matches: "Nichts in diesem Verzeichnis passt zum Filter",
+    empty_directory: "Dieses Verzeichnis ist leer",
+    symlink: "symbolischer Link",
+    name: "Name",
+    creation_time: "Erstellt am",
+    size: "Größe",
+    owner: "Besitzer",
+    group: "Gruppe",
+    permissions: "Rechte",
+    children_count: "Einträge",
+    downloads: "Downloads",
+    archive: "Archiv",
+    view: "Ansehen",
+    browse: "Durchsuchen",
+    modified: "Geändert",
+    created: "Erstellt",
+    children: "Einträge",
+    download_selected: "Auswahl als ZIP herunterladen",
+    path: "Pfad",
+    search_placeholder: "Name oder Muster, wie *.txt",
+    results_truncated: "nur die ersten werden angezeigt",
+    back_to_listing: "Zurück zur Liste",
+    download: "Herunterladen",
+    qr_code: "QR-Code",
+    torrent: "Torrent",
+    info: "Details",
+    content_type: "Typ",
+    dimensions: "Abmessungen",
+    share_link: "Link zum Teilen",
+    copy: "Kopieren",
+    copy_link: "Link kopieren",
+    copied: "Kopiert",
+    not_hashed_yet: "Noch nicht berechnet, versuche es später erneut",
+    too_big: "Die Datei ist zu groß, nur ihr Anfang wird angezeigt",
+    just_now: "gerade eben",
+    error_hidden: "Etwas ist schiefgelaufen, die Details stehen in den Logs des Servers",
+    free: "{} frei",
+    directories: ["{} Verzeichnis", "{} Verzeichnisse"],
+    files: ["{} Datei", "{} Dateien"],
+    bytes: ["{} Byte", "{} Bytes"],
+    total: "{} insgesamt",
+    results: ["{} Ergebnis", "{} Ergebnisse"],
+    tree_of: "Baum von {}",
+    download_named: "{} herunterladen",
+    created_on: "erstellt am {}",
+    ago: "vor {}",
+    // After `vor`, so in the dative
+    years: ["{} Jahr", "{} Jahren"],
+    months: ["{} Monat", "{} Monaten"],
+    weeks: ["{} Woche", "{} Wochen"],
+    days: ["{} Tag", "{} Tagen"],
+    hours: ["{} Stunde", "{} Stunden"],
+    minutes: ["{} Minute", "{} Minuten"],
+};
+
+static FR: Messages = Messages {
+    singular_zero: true,
+    root: "Racine",
+    search: "Rechercher",
+    recently_added: "Ajouts récents",
+    download_as_zip: "Télécharger en ZIP",
+    tree: "Arborescence",
+    match_case: "Respecter la casse",
+    ignore_case: "Ignorer la casse",
+    table: "Tableau",
+    gallery: "Galerie",
+    exact_times: "Dates exactes",
+    relative_times: "Dates relatives",
+    light: "Clair",
+    dark: "Sombre",
+    auto: "Automatique",
+    filter_by_name: "Filtrer par nom",
+    filter: "Filtrer",
+    nothing_matches: "Rien dans ce dossier ne correspond au filtre",
+    empty_directory: "Ce dossier est vide",
+    symlink: "lien symbolique",
+    name: "Nom",
+    creation_time: "Date de création",
+    size: "Taille",
+    owner: "Propriétaire",
+    group: "Groupe",
+    permissions: "Permissions",
+    children_count: "Éléments",
+    downloads: "Téléchargements",
+    archive: "Archive",
+    view: "Voir",
+    browse: "Parcourir",
+    modified: "Modifié",
+    created: "Créé",
+    children: "Éléments",
+    download_selected: "Télécharger la sélection en ZIP",
+    path: "Chemin",
+    search_placeholder: "Nom ou motif, comme *.txt",
+    results_truncated: "seuls les premiers sont affichés",
+    back_to_listing: "Retour à la liste",
+    download: "Télécharger",
+    qr_code: "Code QR",
+    torrent: "Torrent",
+    info: "Infos",
+    content_type: "Type",
+    dimensions: "Dimensions",
+    share_link: "Lien de partage",
+    copy: "Copier",
+    copy_link: "Copier le lien",
+    copied: "Copié",
+    not_hashed_yet: "Pas encore calculé, réessayez dans un moment",
+    too_big: "Le fichier est trop gros, seul son début est affiché",
+    just_now: "à l’instant",
+    error_hidden: "Une erreur s’est produite, les détails sont dans les journaux du serveur",
+    free: "{} libres",
+    directories: ["{} dossier", "{} dossiers"],
+    files: ["{} fichier", "{} fichiers"],
+    bytes: ["{} octet", "{} octets"],
+    total: "{} au total",
+    results: ["{} résultat", "{} résultats"],
+    tree_of: "Arborescence de {}",
+    download_named: "Télécharger {}",
+    created_on: "créé le {}",
+    ago: "il y a {}",
+    years: ["{} an", "{} ans"],
+    months: ["{} mois", "{} mois"],
+    weeks: ["{} semaine", "{} semaines"],
+    days: ["{} jour", "{} jours"],
+    hours: ["{} heure", "{} heures"],
+    minutes: ["{} minute", "{} minutes"],
+};
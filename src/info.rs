@@ -0,0 +1,142 @@
+use askama::{filters::urlencode, Template};
+use axum::{
+    extract::{self, State},
+    http::StatusCode,
+    response::Response,
+};
+use camino::{Utf8Path, Utf8PathBuf};
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use std::path::PathBuf;
+use std::sync::Arc;
+use tracing::{debug, info};
+
+use crate::{
+    color_scheme::ColorScheme,
+    dir_view::{entry_from_cache, normalise_path},
+    i18n::{Locale, Messages},
+    mime,
+    utils::DateFormat,
+    view::can_preview,
+    AppState,
+};
+
+#[derive(Template, Serialize)]
+#[template(path = "info.html")]
+pub struct InfoTemplate {
+    /// Name of the file
+    name: String,
+    /// Path of the file urlencoded, to download it
+    encoded_path: String,
+    /// Directory the file is in urlencoded, ending in `/` unless it's the root
+    encoded_dirname: String,
+    /// Already formatted with the configured units
+    size: String,
+    /// Size in bytes
+    bytes: u64,
+    created: DateTime<Utc>,
+    modified: DateTime<Utc>,
+    /// Content type it's downloaded as
+    content_type: String,
+    /// None until the checksum thread gets to it
+    sha256: Option<String>,
+    /// Width and height, only for pictures
+    dimensions: Option<String>,
+    /// Absolute url to download the file, to share it
+    download_url: String,
+    /// Absolute url of the page of the file, if it can be shown as one
+    view_url: Option<String>,
+    #[serde(skip)]
+    dates: Arc<DateFormat>,
+    color_scheme: ColorScheme,
+    locale: Locale,
+    /// Every string of the page, in the language of the locale
+    t: &'static Messages,
+}
+
+/// Every detail known about a file, like its exact size, checksum or content type, with links to
+/// share it
+pub async fn serve_file_info(
+    extract::Path(path): extract::Path<PathBuf>,
+    State(state): State<AppState>,
+    color_scheme: ColorScheme,
+    locale: Locale,
+) -> Result<Response, (StatusCode, String)> {
+    let path = Utf8PathBuf::from_path_buf(path)
+        .map_err(|p| (StatusCode::BAD_REQUEST, format!("Path {p:?} was not UTF-8")))?;
+    let path = normalise_path(&path).map_err(|e| (StatusCode::BAD_REQUEST, e.to_string()))?;
+    info!(?path, "Displaying file info");
+
+    let not_found = || {
+        (
+            StatusCode::NOT_FOUND,
+            format!("File {path:?} does not exist"),
+        )
+    };
+    state.load_path(&path, false).await;
+    let (bytes, created, modified) = {
+        let lock = state.cache.read();
+        let entry = entry_from_cache(&path, &lock).ok_or_else(not_found)?;
+        if !entry.is_file() || entry.as_file().link {
+            return Err(not_found());
+        }
+        (entry.size(), entry.created(), entry.modified())
+    };
+    state
+        .scan_options
+        .check_download(&state.roots, &path)
+        .map_err(|e| (StatusCode::FORBIDDEN, format!("{e:#}")))?;
+    let fs_path = state.roots.fs_path(&path).ok_or_else(not_found)?;
+
+    let content_type =
+        mime::detect(&state.mime_overrides, state.unknown_content_type, &fs_path).await;
+    let sha256 = state
+        .checksums
+        .as_ref()
+        .and_then(|c| c.sha256(&fs_path, bytes, modified));
+    let dimensions = if content_type.starts_with("image/") {
+        // Only the header is read, but that's still blocking
+        tokio::task::spawn_blocking(move || image::image_dimensions(&fs_path))
+            .await
+            .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?
+            .map_err(|e| debug!(?path, "Failed reading picture dimensions: {e}"))
+            .ok()
+            .map(|(width, height)| format!("{width} × {height}"))
+    } else {
+        None
+    };
+
+    let encoded_path =
+        urlencode(path.as_str()).map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    let name = path.file_name().unwrap_or_default().to_owned();
+    let mut dirname = path.parent().map(Utf8Path::to_string).unwrap_or_default();
+    if !dirname.is_empty() {
+        dirname.push('/');
+    }
+    let absolute_url = |path: &str| {
+        state
+            .base_url
+            .join(path)
+            .map_or_else(|_| state.base_url.to_string(), String::from)
+    };
+    let page = InfoTemplate {
+        encoded_dirname: urlencode(&dirname)
+            .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?,
+        download_url: absolute_url(&format!("dl/{encoded_path}")),
+        view_url: can_preview(&name).then(|| absolute_url(&format!("view/{encoded_path}"))),
+        name,
+        encoded_path,
+        size: state.size_units.format(bytes),
+        bytes,
+        created,
+        modified: DateTime::from_timestamp(modified, 0).unwrap_or_default(),
+        content_type,
+        sha256,
+        dimensions,
+        dates: Arc::clone(&state.dates),
+        color_scheme,
+        locale,
+        t: locale.messages(),
+    };
+    Ok(state.theme.page("info.html", page))
+}
@@ -0,0 +1,71 @@
+use camino::{Utf8Path, Utf8PathBuf};
+use parking_lot::Mutex;
+use std::{collections::HashMap, io, sync::Arc};
+use tracing::warn;
+
+enum Job<S, T> {
+    Running(S),
+    Done(S, Arc<T>),
+}
+
+/// Results of jobs that read whole files, like hashing the pieces of a torrent, which are too
+/// slow to do in a request, so they're done in the background and kept until the files they were
+/// made from change
+pub struct Jobs<S, T> {
+    /// Indexed by the path of the file or directory, not including the data dir
+    jobs: Mutex<HashMap<Utf8PathBuf, Job<S, T>>>,
+}
+
+impl<S, T> Default for Jobs<S, T> {
+    fn default() -> Self {
+        Self {
+            jobs: Mutex::default(),
+        }
+    }
+}
+
+impl<S, T> Jobs<S, T>
+where
+    S: Clone + PartialEq + Send + 'static,
+    T: Send + Sync + 'static,
+{
+    /// Result of `job` for `path` made from `source`, like the sizes and modification times of
+    /// the files, or `None` if it's still running, starting it if it never ran or `source`
+    /// changed
+    pub fn get(
+        self: &Arc<Self>,
+        path: &Utf8Path,
+        source: S,
+        job: impl FnOnce(&S) -> io::Result<T> + Send + 'static,
+    ) -> Option<Arc<T>> {
+        let mut jobs = self.jobs.lock();
+        match jobs.get(path) {
+            Some(Job::Done(done, result)) if *done == source => return Some(Arc::clone(result)),
+            Some(Job::Running(running)) if *running == source => return None,
+            _ => {}
+        }
+        jobs.insert(path.to_owned(), Job::Running(source.clone()));
+        drop(jobs);
+
+        let this = Arc::clone(self);
+        let path = path.to_owned();
+        tokio::task::spawn_blocking(move || {
+            let result = job(&source);
+            let mut jobs = this.jobs.lock();
+            // The files changed while it ran, so another job is running for them
+            if !matches!(jobs.get(&path), Some(Job::Running(running)) if *running == source) {
+                return;
+            }
+            match result {
+                Ok(result) => {
+                    jobs.insert(path, Job::Done(source, result.into()));
+                }
+                Err(e) => {
+                    warn!(?path, "Background job failed: {e}");
+                    jobs.remove(&path);
+                }
+            }
+        });
+        None
+    }
+}
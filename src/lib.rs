@@ -1,112 +1,867 @@
-#![feature(iter_intersperse)]
-
 use camino::{Utf8Path, Utf8PathBuf};
 use color_eyre::{eyre::Context as _, Result};
-use notify::{RecursiveMode, Watcher as _};
-use parking_lot::RwLock;
+use futures_util::{
+    future::{BoxFuture, Shared},
+    FutureExt as _,
+};
+use notify::{PollWatcher, RecommendedWatcher, RecursiveMode, Watcher};
+use notify_debouncer_full::{Debouncer, FileIdMap};
+use parking_lot::{Mutex, RwLock};
+use serde::Serialize;
+use std::any::Any;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
-use std::time::Duration;
-use tracing::{error, info, warn};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tracing::{debug, error, info, warn};
 use url::Url;
 
-mod dir_cache;
+/// How often download stats and token usage are saved to disk
+const STATS_SAVE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// How often paths that can't be watched are polled, if there's no poll interval configured
+const FALLBACK_POLL_INTERVAL: Duration = Duration::from_secs(30);
+
+mod access;
+mod archive;
+mod archive_browse;
+mod archive_cache;
+mod assets;
+mod audit;
+mod auth;
+mod cache_control;
+mod checksums;
+mod color_scheme;
+mod cors;
+mod digest;
+pub mod dir_cache;
 mod dir_view;
 mod download;
+mod embed;
+mod error_page;
+mod exclude;
+mod free_space;
+mod i18n;
+mod info;
+mod jobs;
+mod limit;
+mod mime;
+mod owners;
+mod proxy;
+mod qr;
+mod quota;
+mod readme;
+mod recent;
+mod roots;
+mod search;
+mod security_headers;
+mod stats;
+mod sums;
+mod theme;
+mod thumbnails;
+mod torrent;
+mod tree;
 mod utils;
-use axum::{response::Redirect, routing::get, Router};
-use dir_cache::CacheEntry;
-use dir_view::{root_directory_view, serve_path_view};
-use download::{dl_archive, dl_path};
+mod view;
+mod zsync;
+use archive_browse::browse_archive;
+use archive_cache::ArchiveCache;
+use assets::{Branding, WebApp};
+use audit::AuditLog;
+use axum::{
+    http::{HeaderName, HeaderValue},
+    middleware,
+    response::Redirect,
+    routing::get,
+    Router,
+};
+use checksums::Checksums;
+use dir_cache::{DirContents, IndexError, ScanOptions};
+use dir_view::{entry_from_cache, root_directory_view, serve_path_view};
+use download::{dl_archive, dl_path, root_archive};
+use exclude::Excludes;
+use free_space::FreeSpace;
+use info::serve_file_info;
+use limit::DownloadLimiter;
+use owners::Owners;
+use qr::qr_code;
+use quota::{Quota, TokenUsages};
+use recent::recent_files;
+use roots::DataRoots;
+use search::search;
+use stats::{cache_status, file_stats, TransferStats};
+use sums::{md5sum, root_md5sum, root_sha256sum, sha256sum};
+use theme::{serve_theme_file, Theme};
+use thumbnails::{serve_thumbnail, Thumbnails};
 use tokio::sync::oneshot;
+use torrent::{serve_torrent, Torrents};
+use tree::{root_tree_view, serve_tree_view};
+use utils::DateFormat;
+use view::serve_file_view;
+use zsync::{serve_zsync, ZsyncFiles};
+
+pub use access::AccessRule;
+pub use auth::ApiToken;
+pub use cache_control::CacheControlRule;
+pub use chrono_tz::Tz;
+pub use cors::Cors;
+pub use dir_cache::SymlinkPolicy;
+pub use download::{Disposition, DispositionOverride};
+pub use i18n::Locale;
+pub use mime::{MimeOverride, UnknownContentType};
+pub use proxy::TrustedProxy;
+pub use roots::DataDir;
+pub use security_headers::SecurityHeaders;
+pub use utils::SizeUnits;
 
 pub struct AppConfig {
     pub base_url: Url,
-    pub data_dir: Utf8PathBuf,
+    /// Directories to serve, a single one is the root, several are shown as top-level
+    /// directories
+    pub data_dirs: Vec<DataDir>,
     pub listener: tokio::net::TcpListener,
     pub shutdown: Option<oneshot::Receiver<()>>,
+    /// Default `Content-Disposition` for files served from `/dl`
+    pub disposition: Disposition,
+    pub disposition_overrides: Vec<DispositionOverride>,
+    /// Content types for extensions, which win over the detected ones
+    pub mime_overrides: Vec<MimeOverride>,
+    /// What to serve files whose type couldn't be detected as
+    pub unknown_content_type: UnknownContentType,
+    /// Name used when installing the site as a web app
+    pub app_name: String,
+    pub theme_color: String,
+    /// PNG used as the web app icon instead of the default one
+    pub app_icon: Option<Utf8PathBuf>,
+    /// Shown at the top of the directory view and in its title, the app name if not set
+    pub site_title: Option<String>,
+    /// Shown under the site title
+    pub tagline: Option<String>,
+    /// Picture shown next to the site title
+    pub logo: Option<Utf8PathBuf>,
+    /// Templates replacing the embedded ones with the same name, and files they link to
+    pub template_dir: Option<Utf8PathBuf>,
+    pub size_units: SizeUnits,
+    /// How times are shown, in the syntax of `strftime`
+    pub date_format: String,
+    /// Timezone times are shown in
+    pub timezone: Tz,
+    /// Compression level for `.tar.zst` archives
+    pub zstd_level: i32,
+    /// Where generated archives are kept to be served again, until the data dir changes
+    pub archive_cache_dir: Option<Utf8PathBuf>,
+    /// Directory where thumbnails are kept, they're made again for every request if not set
+    pub thumbnail_cache_dir: Option<Utf8PathBuf>,
+    /// Most thumbnails that are made at the same time
+    pub max_thumbnail_jobs: usize,
+    /// Archives with more bytes than this in their files are refused
+    pub max_archive_bytes: Option<u64>,
+    /// Archives with more files and directories than this are refused
+    pub max_archive_entries: Option<usize>,
+    /// How many downloads and archives can be sent at the same time
+    pub max_downloads: Option<usize>,
+    /// How long downloads wait for a free slot before being refused
+    pub download_queue_timeout: Duration,
+    /// Where download stats are kept between restarts
+    pub stats_file: Option<Utf8PathBuf>,
+    /// Whether the directory view shows how many times each file was downloaded
+    pub show_download_counts: bool,
+    /// Whether the directory view shows the SHA-256 of every file that was already hashed
+    pub show_checksums: bool,
+    /// Hash every file in the background, for `/sha256sum`, `/md5sum`, archives with
+    /// checksums, metalinks, the digest headers of downloads and the checksums of listings.
+    /// Always on if they're shown
+    pub hash_files: bool,
+    /// Where digests are kept between restarts, so files that didn't change aren't hashed again
+    pub checksums_file: Option<Utf8PathBuf>,
+    /// Show the owner, group and permissions of every entry in the directory view
+    pub show_ownership: bool,
+    /// Render the `README.md` of directories above their entries
+    pub render_readme: bool,
+    /// Show how much space is left on the filesystem of the data dir in the directory view
+    pub show_free_space: bool,
+    /// Show times as how long ago they were, with the exact time when hovering over them
+    pub relative_times: bool,
+    /// Language of the pages, the one the browser asks for if not set
+    pub locale: Option<Locale>,
+    /// Show what went wrong in server errors, instead of only logging it
+    pub show_error_details: bool,
+    /// `Cache-Control` for paths, the first one that matches is used
+    pub cache_control: Vec<CacheControlRule>,
+    /// Tokens every request needs one of, except for the icons and styles, nothing needs one if
+    /// empty
+    pub api_tokens: Vec<ApiToken>,
+    /// Bytes of downloads and archives each token can get in a day in UTC, refused with a 429
+    /// after that
+    pub token_daily_bytes: Option<u64>,
+    /// Bytes of downloads and archives each token can ever get, refused with a 403 after that
+    pub token_total_bytes: Option<u64>,
+    /// Where how much each token downloaded is kept between restarts
+    pub token_usage_file: Option<Utf8PathBuf>,
+    /// Who can reach each directory, the rule with the longest path wins
+    pub access_rules: Vec<AccessRule>,
+    /// Reverse proxies whose forwarding headers say who the client is, instead of the address of
+    /// the connection
+    pub trusted_proxies: Vec<TrustedProxy>,
+    /// Headers sent with pages, like the `Content-Security-Policy`
+    pub security_headers: SecurityHeaders,
+    /// Other sites that can call the listings and downloads from the browser
+    pub cors: Cors,
+    /// File a line of JSON is appended to for every `/dl` and `/arc` response, with who got what
+    /// and how much of it was sent
+    pub audit_log: Option<Utf8PathBuf>,
+    /// Size past which the audit log is moved to the same name with `.1` after it
+    pub audit_log_max_bytes: u64,
+    /// How many levels of directories below the data dir are read on startup, deeper ones are
+    /// read the first time they're needed
+    pub cache_depth: Option<usize>,
+    pub symlinks: SymlinkPolicy,
+    /// Paths left out of the cache and downloads, in gitignore syntax, on top of the ones in
+    /// `.sfsbignore` files
+    pub exclude: Vec<String>,
+    /// Check the data dir for changes this often instead of relying on filesystem events, which
+    /// network filesystems usually don't send
+    pub poll_interval: Option<Duration>,
+    /// How long to wait for changes to settle before updating the cache
+    pub debounce: Duration,
+    /// Whether changes in subdirectories are watched, or only the ones in the data dir itself
+    pub recursive_watch: bool,
+    /// Read the whole data dir again this often, whether anything changed or not
+    pub refresh_interval: Option<Duration>,
 }
 
+/// Read of the unread directories of a path that every request for it at the time waits for
+type PathLoad = Shared<BoxFuture<'static, ()>>;
+
 #[derive(Clone)]
 struct AppState {
     base_url: Arc<Url>,
-    data_dir: Arc<Utf8Path>,
-    cache: Arc<RwLock<Vec<CacheEntry>>>,
+    roots: Arc<DataRoots>,
+    cache: Arc<RwLock<DirContents>>,
+    /// Entries that were left out of the cache on the last refresh
+    index_errors: Arc<RwLock<Vec<IndexError>>>,
+    /// Every error from the watchers since startup
+    watch_errors: Arc<RwLock<Vec<WatchError>>>,
+    /// Bumped every time the cache changes, starting from the time the app started so it's
+    /// different after restarts too
+    generation: Arc<AtomicU64>,
+    disposition: Disposition,
+    disposition_overrides: Arc<[DispositionOverride]>,
+    mime_overrides: Arc<[MimeOverride]>,
+    unknown_content_type: UnknownContentType,
+    transfers: Arc<TransferStats>,
+    show_download_counts: bool,
+    show_checksums: bool,
+    /// Only loaded if ownership is shown
+    owners: Option<Arc<Owners>>,
+    render_readme: bool,
+    relative_times: bool,
+    locale: Option<Locale>,
+    show_error_details: bool,
+    /// Only read if it's shown
+    free_space: Option<Arc<FreeSpace>>,
+    web_app: Arc<WebApp>,
+    branding: Arc<Branding>,
+    theme: Arc<Theme>,
+    size_units: SizeUnits,
+    dates: Arc<DateFormat>,
+    zstd_level: i32,
+    archive_cache: Option<Arc<ArchiveCache>>,
+    thumbnails: Arc<Thumbnails>,
+    max_archive_bytes: Option<u64>,
+    max_archive_entries: Option<usize>,
+    /// `None` if files aren't hashed
+    checksums: Option<Arc<Checksums>>,
+    torrents: Arc<Torrents>,
+    zsync_files: Arc<ZsyncFiles>,
+    download_limiter: Option<Arc<DownloadLimiter>>,
+    cache_control: Arc<[CacheControlRule]>,
+    api_tokens: Arc<[ApiToken]>,
+    quota: Quota,
+    token_usage: Arc<TokenUsages>,
+    access_rules: Arc<[AccessRule]>,
+    trusted_proxies: Arc<[TrustedProxy]>,
+    security_headers: Arc<[(HeaderName, HeaderValue)]>,
+    audit_log: Option<Arc<AuditLog>>,
+    cache_depth: Option<usize>,
+    /// Paths being read because of the cache depth, and whether their whole subtree is
+    loads: Arc<Mutex<HashMap<(Utf8PathBuf, bool), PathLoad>>>,
+    scan_options: Arc<ScanOptions>,
 }
 
 impl AppState {
-    fn from_config(config: &AppConfig) -> Self {
-        Self {
+    fn from_config(config: &AppConfig) -> Result<Self> {
+        let web_app = WebApp::new(
+            &config.app_name,
+            &config.theme_color,
+            config.app_icon.as_deref(),
+        )?;
+        let archive_cache = config
+            .archive_cache_dir
+            .as_deref()
+            .map(ArchiveCache::new)
+            .transpose()?;
+        let transfers = config
+            .stats_file
+            .as_deref()
+            .map(TransferStats::load)
+            .transpose()?
+            .unwrap_or_default();
+        let token_usage = config
+            .token_usage_file
+            .as_deref()
+            .map(TokenUsages::load)
+            .transpose()?
+            .unwrap_or_default();
+        let roots: Arc<DataRoots> = DataRoots::new(&config.data_dirs)?.into();
+        let cache = Arc::default();
+        let checksums = (config.hash_files || config.show_checksums)
+            .then(|| {
+                Checksums::start(
+                    Arc::clone(&cache),
+                    Arc::clone(&roots),
+                    config.checksums_file.clone(),
+                )
+            })
+            .transpose()?;
+        let free_space = config
+            .show_free_space
+            .then(|| FreeSpace::new(&roots).into());
+
+        Ok(Self {
             base_url: config.base_url.clone().into(),
-            data_dir: config.data_dir.clone().into(),
-            cache: Arc::default(),
+            scan_options: ScanOptions::new(
+                &roots,
+                config.symlinks,
+                Excludes::new(&roots, &config.exclude)?,
+            )?
+            .into(),
+            roots,
+            cache,
+            index_errors: Arc::default(),
+            watch_errors: Arc::default(),
+            generation: Arc::new(AtomicU64::new(
+                SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .map_or(0, |d| d.as_millis() as u64),
+            )),
+            disposition: config.disposition,
+            disposition_overrides: config.disposition_overrides.clone().into(),
+            mime_overrides: config.mime_overrides.clone().into(),
+            unknown_content_type: config.unknown_content_type,
+            transfers: transfers.into(),
+            show_download_counts: config.show_download_counts,
+            show_checksums: config.show_checksums,
+            owners: config.show_ownership.then(|| Owners::load().into()),
+            render_readme: config.render_readme,
+            relative_times: config.relative_times,
+            locale: config.locale,
+            show_error_details: config.show_error_details,
+            free_space,
+            web_app: web_app.into(),
+            branding: Branding::new(
+                config.site_title.as_deref().unwrap_or(&config.app_name),
+                config.tagline.as_deref(),
+                config.logo.as_deref(),
+            )?
+            .into(),
+            theme: Theme::new(config.template_dir.as_deref())?.into(),
+            size_units: config.size_units,
+            dates: DateFormat::new(&config.date_format, config.timezone)?.into(),
+            zstd_level: config.zstd_level,
+            archive_cache: archive_cache.map(Arc::new),
+            thumbnails: Thumbnails::new(
+                config.thumbnail_cache_dir.as_deref(),
+                config.max_thumbnail_jobs,
+            )?
+            .into(),
+            max_archive_bytes: config.max_archive_bytes,
+            max_archive_entries: config.max_archive_entries,
+            checksums,
+            torrents: Arc::default(),
+            zsync_files: Arc::default(),
+            download_limiter: config
+                .max_downloads
+                .map(|max| Arc::new(DownloadLimiter::new(max, config.download_queue_timeout))),
+            cache_control: config.cache_control.clone().into(),
+            api_tokens: config.api_tokens.clone().into(),
+            quota: Quota {
+                daily_bytes: config.token_daily_bytes,
+                total_bytes: config.token_total_bytes,
+            },
+            token_usage: token_usage.into(),
+            access_rules: config.access_rules.clone().into(),
+            trusted_proxies: config.trusted_proxies.clone().into(),
+            security_headers: config.security_headers.headers()?.into(),
+            audit_log: config
+                .audit_log
+                .as_deref()
+                .map(|path| AuditLog::start(path, config.audit_log_max_bytes))
+                .transpose()?
+                .map(Arc::new),
+            cache_depth: config.cache_depth,
+            loads: Arc::default(),
+        })
+    }
+
+    /// Reads the directories on the way to `path` that were left unread because of the cache
+    /// depth, so the contents of `path` and the levels below it are in the cache, or everything
+    /// inside it if `whole_subtree`
+    ///
+    /// They're read on a blocking thread, and requests for the same path while it's being read
+    /// wait for that read instead of starting another one
+    async fn load_path(&self, path: &Utf8Path, whole_subtree: bool) {
+        if self.cache_depth.is_none()
+            || (dir_cache::first_unread(&self.cache.read(), path).is_none()
+                && !(whole_subtree && self.subtree_unread(path)))
+        {
+            return;
         }
+
+        let key = (path.to_path_buf(), whole_subtree);
+        let load = self
+            .loads
+            .lock()
+            .entry(key.clone())
+            .or_insert_with(|| {
+                let state = self.clone();
+                async move {
+                    let (path, whole_subtree) = key.clone();
+                    let task_state = state.clone();
+                    let read = tokio::task::spawn_blocking(move || {
+                        task_state.read_unread(&path, whole_subtree);
+                    });
+                    if let Err(e) = read.await {
+                        error!(path = ?key.0, "Failed reading directories: {e}");
+                    }
+                    state.loads.lock().remove(&key);
+                }
+                .boxed()
+                .shared()
+            })
+            .clone();
+        load.await;
+    }
+
+    /// Whether anything inside the directory at `path` was left unread
+    fn subtree_unread(&self, path: &Utf8Path) -> bool {
+        let lock = self.cache.read();
+        if path.as_str().is_empty() {
+            dir_cache::has_unread(&lock)
+        } else {
+            entry_from_cache(path, &lock)
+                .is_some_and(|e| e.is_dir() && dir_cache::has_unread(&e.as_dir().children))
+        }
+    }
+
+    /// Blocking part of [`Self::load_path`], which checks what's unread again since another
+    /// load could have read it already
+    fn read_unread(&self, path: &Utf8Path, whole_subtree: bool) {
+        let Some(cache_depth) = self.cache_depth else {
+            return;
+        };
+
+        let unread = dir_cache::first_unread(&self.cache.read(), path);
+        if let Some(unread) = unread {
+            let depth = if whole_subtree {
+                None
+            } else {
+                let below = path.components().count() - unread.components().count();
+                Some(below + 1 + cache_depth)
+            };
+            self.read_entry(&unread, depth);
+        }
+
+        if whole_subtree && self.subtree_unread(path) {
+            self.read_entry(path, None);
+        }
+    }
+
+    /// Reads the entry at `path` down to `depth` levels below it, and puts it in the cache
+    fn read_entry(&self, path: &Utf8Path, depth: Option<usize>) {
+        debug!(?path, ?depth, "Reading directory that wasn't in the cache");
+        let mut errors = vec![];
+        if path.as_str().is_empty() {
+            match dir_cache::read_entries(&self.roots, depth, &self.scan_options, &mut errors) {
+                Ok(entries) => *self.cache.write() = entries.into(),
+                Err(e) => warn!("Failed reading data dir: {e:#}"),
+            }
+        } else {
+            match dir_cache::read_entry(&self.roots, path, depth, &self.scan_options, &mut errors) {
+                Ok(entry) => dir_cache::replace_entry(&mut self.cache.write(), path, entry),
+                Err(e) => warn!("Failed reading {path}: {e:#}"),
+            }
+        }
+        for e in &errors {
+            warn!(
+                path = %e.path,
+                "Left entry out of the directory cache: {}", e.error
+            );
+        }
+        self.index_errors.write().extend(errors);
+        self.cache_changed();
+    }
+
+    /// Lets everything that depends on the cache know that it changed
+    fn cache_changed(&self) {
+        self.generation.fetch_add(1, Ordering::AcqRel);
+        if let Some(checksums) = &self.checksums {
+            checksums.update();
+        }
+        if let Some(free_space) = &self.free_space {
+            free_space.update(&self.roots);
+        }
+    }
+
+    /// Like [`Self::cache_changed`], but also for changes to files that were already in it
+    fn files_changed(&self) {
+        if let Some(archive_cache) = &self.archive_cache {
+            archive_cache.invalidate();
+        }
+        self.cache_changed();
+    }
+
+    /// Reads the whole data dir again
+    fn refresh_cache(&self) -> Result<()> {
+        let mut errors = vec![];
+        let entries = dir_cache::read_entries(
+            &self.roots,
+            self.cache_depth,
+            &self.scan_options,
+            &mut errors,
+        )
+        .wrap_err("Failed to read contents of data dir")?;
+        for e in &errors {
+            warn!(
+                path = %e.path,
+                "Left entry out of the directory cache: {}", e.error
+            );
+        }
+        *self.index_errors.write() = errors;
+
+        let empty = {
+            let mut lock = self.cache.write();
+            let empty = lock.is_empty();
+            *lock = entries.into();
+            empty
+        };
+        self.files_changed();
+        if empty {
+            info!("Generated directory cache");
+        } else {
+            info!("Updated directory cache after fs event");
+        }
+
+        Ok(())
+    }
+
+    /// Reads only the entries at `paths` again, instead of the whole data dir
+    fn update_cache(&self, paths: &[Utf8PathBuf]) -> Result<()> {
+        for path in paths {
+            let mut errors = vec![];
+            let changed = dir_cache::changed_path(&self.cache.read(), path);
+            // Read from disk without holding the cache, like the first time it's read
+            let change = dir_cache::read_change(
+                &self.roots,
+                &changed,
+                self.cache_depth,
+                &self.scan_options,
+                &mut errors,
+            )
+            .wrap_err_with(|| format!("Failed to update {path} in the directory cache"))?;
+            let updated = change.path().to_owned();
+            dir_cache::apply_change(&mut self.cache.write(), change);
+            for e in &errors {
+                warn!(
+                    path = %e.path,
+                    "Left entry out of the directory cache: {}", e.error
+                );
+            }
+
+            let mut index_errors = self.index_errors.write();
+            // Everything was read again if it's not inside a single data dir
+            match self.roots.fs_path(&updated) {
+                Some(updated) => {
+                    index_errors.retain(|e| !Utf8Path::new(&e.path).starts_with(&updated));
+                }
+                None => index_errors.clear(),
+            }
+            index_errors.extend(errors);
+        }
+
+        self.files_changed();
+        info!("Updated {} paths in the directory cache", paths.len());
+
+        Ok(())
     }
 }
 
-fn refresh_cache(cache: &RwLock<Vec<CacheEntry>>, data_dir: &Utf8Path) -> Result<()> {
-    let entries = data_dir
-        .read_dir()
-        .wrap_err_with(|| format!("Failed to read contents of data dir {data_dir}"))?;
-    let entries: Result<Vec<CacheEntry>> = entries.map(|e| e?.try_into()).collect();
-    let entries =
-        entries.wrap_err_with(|| format!("Failed to parse contents of data dir {data_dir}"))?;
-    let empty = {
-        let mut lock = cache.write();
-        let empty = lock.is_empty();
-        *lock = entries;
-        empty
-    };
-    if empty {
-        info!("Generated directory cache");
-    } else {
-        info!("Updated directory cache after fs event");
+/// Paths inside `data_dir` that changed, relative to it, or `None` if the whole data dir has to be
+/// read again because the events don't say exactly what changed
+fn changed_paths(
+    event: notify_debouncer_full::DebounceEventResult,
+    data_dir: &Utf8Path,
+) -> Option<Vec<Utf8PathBuf>> {
+    let events = event.ok()?;
+    // The watcher might report absolute paths even if the data dir isn't
+    let canonical_data_dir = data_dir.canonicalize_utf8().ok();
+
+    let mut paths = vec![];
+    for event in events {
+        if event.need_rescan() || event.paths.is_empty() {
+            return None;
+        }
+        for path in &event.paths {
+            let path = Utf8Path::from_path(path)?;
+            let relative = path.strip_prefix(data_dir).ok().or_else(|| {
+                canonical_data_dir
+                    .as_deref()
+                    .and_then(|d| path.strip_prefix(d).ok())
+            })?;
+            paths.push(relative.to_owned());
+        }
     }
 
-    Ok(())
+    // Parents sort before their children, which are read again along with them
+    paths.sort();
+    paths.dedup();
+    let mut roots: Vec<Utf8PathBuf> = vec![];
+    for path in paths {
+        if !roots.iter().any(|r| path.starts_with(r)) {
+            roots.push(path);
+        }
+    }
+    Some(roots)
 }
 
 enum DataUpdateEvent {
-    FsNotify(notify_debouncer_full::DebounceEventResult),
+    /// Events from the watcher of the data dir at this path in the cache
+    FsNotify(Utf8PathBuf, notify_debouncer_full::DebounceEventResult),
+    /// The refresh interval passed
+    Refresh,
     Shutdown,
 }
 
+/// Starts watching `data_dir`, which is at `prefix` in the cache, with the watcher `T`, sending
+/// its events to `tx` until it's dropped
+fn watch_data_dir<T: Watcher>(
+    data_dir: &Utf8Path,
+    prefix: Utf8PathBuf,
+    debounce: Duration,
+    mode: RecursiveMode,
+    config: notify::Config,
+    tx: tokio::sync::mpsc::Sender<DataUpdateEvent>,
+) -> notify::Result<Debouncer<T, FileIdMap>> {
+    let mut debouncer = notify_debouncer_full::new_debouncer_opt(
+        debounce,
+        None,
+        move |ev| match tx.blocking_send(DataUpdateEvent::FsNotify(prefix.clone(), ev)) {
+            Ok(()) => {}
+            Err(e) => error!("Failed sending DataUpdateEvent after notify event: {e}"),
+        },
+        FileIdMap::new(),
+        config,
+    )?;
+    debouncer.watcher().watch(data_dir.as_std_path(), mode)?;
+    Ok(debouncer)
+}
+
+/// Path that couldn't be watched, or stopped being watched
+#[derive(Debug, Clone, Serialize)]
+struct WatchError {
+    path: String,
+    error: String,
+}
+
+/// Watchers of every data dir, which fall back to polling the paths that can't be watched
+/// because the system ran out of watches
+struct Watchers {
+    tx: tokio::sync::mpsc::Sender<DataUpdateEvent>,
+    debounce: Duration,
+    mode: RecursiveMode,
+    poll_interval: Option<Duration>,
+    /// How often paths that can't be watched are polled, if there's no poll interval configured
+    fallback_interval: Duration,
+    errors: Arc<RwLock<Vec<WatchError>>>,
+    /// Only kept around so they keep watching
+    watchers: Vec<Box<dyn Any>>,
+    /// Paths polled because they couldn't be watched
+    polled: Vec<Utf8PathBuf>,
+}
+
+impl Watchers {
+    /// Starts watching `path`, inside the data dir at `prefix` in the cache
+    fn watch(&mut self, path: &Utf8Path, prefix: &Utf8Path) {
+        let res = match self.poll_interval {
+            Some(interval) => self.poll(path, prefix, interval),
+            None => watch_data_dir::<RecommendedWatcher>(
+                path,
+                prefix.to_owned(),
+                self.debounce,
+                self.mode,
+                notify::Config::default(),
+                self.tx.clone(),
+            )
+            .map(|w| Box::new(w) as Box<dyn Any>),
+        };
+        match res {
+            Ok(watcher) => self.watchers.push(watcher),
+            Err(e) if self.poll_interval.is_none() && is_out_of_watches(&e) => {
+                self.record(path, &e);
+                self.fall_back(path, prefix);
+            }
+            Err(e) => panic!("Failed watching data dir {path}: {e}"),
+        }
+    }
+
+    fn poll(
+        &self,
+        path: &Utf8Path,
+        prefix: &Utf8Path,
+        interval: Duration,
+    ) -> notify::Result<Box<dyn Any>> {
+        let config = notify::Config::default().with_poll_interval(interval);
+        let watcher = watch_data_dir::<PollWatcher>(
+            path,
+            prefix.to_owned(),
+            self.debounce,
+            self.mode,
+            config,
+            self.tx.clone(),
+        )?;
+        Ok(Box::new(watcher))
+    }
+
+    /// Polls `path` for changes, since it can't be watched
+    fn fall_back(&mut self, path: &Utf8Path, prefix: &Utf8Path) {
+        if self.polled.iter().any(|p| path.starts_with(p)) {
+            return;
+        }
+        let interval = self.poll_interval.unwrap_or(self.fallback_interval);
+        warn!(
+            ?interval,
+            "Polling {path} for changes, since it can't be watched"
+        );
+        match self.poll(path, prefix, interval) {
+            Ok(watcher) => {
+                self.watchers.push(watcher);
+                self.polled.push(path.to_owned());
+            }
+            Err(e) => error!("Failed polling {path}: {e}"),
+        }
+    }
+
+    fn record(&self, path: &Utf8Path, error: &notify::Error) {
+        error!("Failed watching {path}: {error}");
+        self.errors.write().push(WatchError {
+            path: path.to_string(),
+            error: error.to_string(),
+        });
+    }
+
+    /// Handles the errors from the watcher of `data_dir`, which is at `prefix` in the cache
+    fn handle_errors(&mut self, data_dir: &Utf8Path, prefix: &Utf8Path, errors: &[notify::Error]) {
+        for e in errors {
+            let mut paths: Vec<Utf8PathBuf> = e
+                .paths
+                .iter()
+                .filter_map(|p| Utf8Path::from_path(p).map(Utf8Path::to_owned))
+                .collect();
+            if paths.is_empty() {
+                paths.push(data_dir.to_owned());
+            }
+            for path in &paths {
+                self.record(path, e);
+                if is_out_of_watches(e) {
+                    self.fall_back(path, prefix);
+                }
+            }
+        }
+    }
+}
+
+const fn is_out_of_watches(error: &notify::Error) -> bool {
+    matches!(error.kind, notify::ErrorKind::MaxFilesWatch)
+}
+
 pub async fn run_app(config: AppConfig) -> Result<()> {
-    let state = AppState::from_config(&config);
+    let state = AppState::from_config(&config)?;
 
-    let data_dir = Arc::clone(&state.data_dir);
-    let cache = Arc::clone(&state.cache);
+    let task_state = state.clone();
+    let transfers = Arc::clone(&state.transfers);
+    let token_usage = Arc::clone(&state.token_usage);
+    let token_usage_file = config.token_usage_file.clone();
+    let watch_errors = Arc::clone(&state.watch_errors);
+    let stats_file = config.stats_file.clone();
+    let poll_interval = config.poll_interval;
+    let debounce = config.debounce;
+    let watch_mode = if config.recursive_watch {
+        RecursiveMode::Recursive
+    } else {
+        RecursiveMode::NonRecursive
+    };
 
     let (data_update_tx, mut data_update_rx) = tokio::sync::mpsc::channel(2);
 
-    refresh_cache(&cache, &data_dir).expect("Failed refreshing cache");
+    state.refresh_cache().expect("Failed refreshing cache");
     let task_tx = data_update_tx.clone();
     tokio::task::spawn_blocking(move || {
-        let data_dir = Arc::clone(&data_dir);
-
-        let mut watcher =
-            notify_debouncer_full::new_debouncer(Duration::from_secs(1), None, move |ev| {
-                match task_tx.blocking_send(DataUpdateEvent::FsNotify(ev)) {
-                    Ok(()) => {}
-                    Err(e) => error!("Failed sending DataUpdateEvent after notify event: {e}"),
-                }
-            })
-            .expect("Failed creating watcher for data dir");
-
-        watcher
-            .watcher()
-            .watch(data_dir.as_std_path(), RecursiveMode::Recursive)
-            .expect("Failed watching data dir");
+        let state = task_state;
+        if let Some(interval) = poll_interval {
+            info!(?interval, "Polling data dir for changes");
+        }
+        let mut watchers = Watchers {
+            tx: task_tx,
+            debounce,
+            mode: watch_mode,
+            poll_interval,
+            fallback_interval: FALLBACK_POLL_INTERVAL,
+            errors: watch_errors,
+            watchers: vec![],
+            polled: vec![],
+        };
+        for (prefix, data_dir) in state.roots.dirs() {
+            watchers.watch(data_dir, prefix);
+        }
 
         loop {
             match data_update_rx.blocking_recv() {
                 // FIXME: Should this crash the program if the update fails?
-                Some(DataUpdateEvent::FsNotify(_)) => {
-                    info!("Refreshing data directory cache after event");
-                    match refresh_cache(&cache, &data_dir) {
+                Some(DataUpdateEvent::FsNotify(prefix, ev)) => {
+                    let Some((data_dir, _)) = state.roots.resolve(&prefix) else {
+                        continue;
+                    };
+                    if let Err(errors) = &ev {
+                        watchers.handle_errors(data_dir, &prefix, errors);
+                    }
+                    let changed = changed_paths(ev, data_dir);
+                    let res = match changed {
+                        Some(paths) => {
+                            let paths: Vec<_> = paths.iter().map(|p| prefix.join(p)).collect();
+                            state.update_cache(&paths)
+                        }
+                        // Only that data dir has to be read again
+                        None if !prefix.as_str().is_empty() => {
+                            info!("Refreshing data dir {prefix} after event");
+                            state.update_cache(&[prefix])
+                        }
+                        None => {
+                            info!("Refreshing data directory cache after event");
+                            state.refresh_cache()
+                        }
+                    };
+                    match res {
                         Ok(_) => {}
                         Err(e) => error!("Failed refreshing cache: {}", e),
                     }
                 }
+                Some(DataUpdateEvent::Refresh) => {
+                    info!("Refreshing data directory cache on timer");
+                    if let Err(e) = state.refresh_cache() {
+                        error!("Failed refreshing cache: {}", e);
+                    }
+                }
                 Some(DataUpdateEvent::Shutdown) => {
                     warn!("Aborting data refresh task");
                     break;
@@ -119,14 +874,116 @@ pub async fn run_app(config: AppConfig) -> Result<()> {
         }
     });
 
-    let app = Router::new()
+    let refresher = config.refresh_interval.map(|refresh_interval| {
+        let tx = data_update_tx.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(refresh_interval);
+            // The first tick is immediate, and the cache was just read
+            interval.tick().await;
+            loop {
+                interval.tick().await;
+                if tx.send(DataUpdateEvent::Refresh).await.is_err() {
+                    break;
+                }
+            }
+        })
+    });
+
+    let stats_saver = (stats_file.is_some() || token_usage_file.is_some()).then(|| {
+        let transfers = Arc::clone(&transfers);
+        let token_usage = Arc::clone(&token_usage);
+        let stats_file = stats_file.clone();
+        let token_usage_file = token_usage_file.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(STATS_SAVE_INTERVAL);
+            loop {
+                interval.tick().await;
+                if let Some(stats_file) = &stats_file {
+                    if let Err(e) = transfers.save(stats_file) {
+                        error!("Failed saving download stats: {e}");
+                    }
+                }
+                if let Some(token_usage_file) = &token_usage_file {
+                    if let Err(e) = token_usage.save(token_usage_file) {
+                        error!("Failed saving token usage: {e}");
+                    }
+                }
+            }
+        })
+    });
+
+    let cors = config.cors.layer()?;
+    let limit_downloads = middleware::from_fn_with_state(state.clone(), limit::limit_downloads);
+    let mut app = Router::new()
         .route("/", get(|| async { Redirect::permanent("/browse/") }))
         .route("/browse", get(root_directory_view))
         .route("/browse/", get(root_directory_view))
         .route("/browse/*path", get(serve_path_view))
-        .route("/dl/*path", get(dl_path))
-        .route("/arc/*path", get(dl_archive))
+        .route("/dl/*path", get(dl_path).layer(limit_downloads.clone()))
+        .route("/arc", get(root_archive).layer(limit_downloads.clone()))
+        .route("/arc/", get(root_archive).layer(limit_downloads.clone()))
+        .route("/arc/*path", get(dl_archive).layer(limit_downloads.clone()))
+        .route(
+            "/browse-archive/*path",
+            get(browse_archive).layer(limit_downloads),
+        )
+        .route("/tree", get(root_tree_view))
+        .route("/tree/", get(root_tree_view))
+        .route("/tree/*path", get(serve_tree_view))
+        .route("/view/*path", get(serve_file_view))
+        .route("/info/*path", get(serve_file_info))
+        .route("/torrent/*path", get(serve_torrent))
+        .route("/zsync/*path", get(serve_zsync))
+        .route("/sha256sum", get(root_sha256sum))
+        .route("/sha256sum/", get(root_sha256sum))
+        .route("/sha256sum/*path", get(sha256sum))
+        .route("/md5sum", get(root_md5sum))
+        .route("/md5sum/", get(root_md5sum))
+        .route("/md5sum/*path", get(md5sum))
+        .route("/thumb/*path", get(serve_thumbnail))
+        .route("/search", get(search))
+        .route("/recent", get(recent_files))
+        .route("/api/stats/files", get(file_stats))
+        .route("/api/cache", get(cache_status))
+        .route("/favicon.ico", get(assets::favicon_ico))
+        .route("/favicon.svg", get(assets::favicon_svg))
+        .route("/static/:name", get(assets::serve_static))
+        .route("/icon-192.png", get(assets::icon_192))
+        .route("/icon-512.png", get(assets::icon_512))
+        .route("/icon.png", get(assets::custom_icon))
+        .route("/logo", get(assets::logo))
+        .route("/theme/:name", get(serve_theme_file))
+        .route("/manifest.json", get(assets::manifest))
+        .route("/oembed", get(embed::oembed))
+        .route("/qr", get(qr_code))
+        .layer(middleware::from_fn_with_state(
+            state.clone(),
+            auth::check_access,
+        ))
+        .layer(middleware::from_fn_with_state(
+            state.clone(),
+            error_page::error_pages,
+        ))
+        // Outside of the access checks and error pages, so refused downloads are recorded as
+        // they were sent
+        .layer(middleware::from_fn_with_state(
+            state.clone(),
+            audit::audit_downloads,
+        ))
+        .layer(middleware::from_fn_with_state(
+            state.clone(),
+            security_headers::add_security_headers,
+        ))
+        .layer(middleware::from_fn_with_state(
+            state.clone(),
+            cache_control::add_cache_control,
+        ))
+        .layer(middleware::from_fn(color_scheme::remember_color_scheme))
         .with_state(state);
+    // Outside everything else so preflight requests are answered without a token
+    if let Some(cors) = cors {
+        app = app.layer(cors);
+    }
 
     // Tokio doesn't follow this for some reason
     #[allow(clippy::redundant_pub_crate)]
@@ -174,9 +1031,97 @@ pub async fn run_app(config: AppConfig) -> Result<()> {
     };
 
     info!("Server listening on {}", config.listener.local_addr()?);
-    axum::serve(config.listener, app)
-        .with_graceful_shutdown(quit_sig)
-        .await?;
+    // The address of clients is logged when they send invalid tokens
+    axum::serve(
+        config.listener,
+        app.into_make_service_with_connect_info::<SocketAddr>(),
+    )
+    .with_graceful_shutdown(quit_sig)
+    .await?;
+
+    if let Some(refresher) = refresher {
+        refresher.abort();
+    }
+    if let Some(stats_saver) = stats_saver {
+        stats_saver.abort();
+    }
+    if let Some(stats_file) = &stats_file {
+        transfers.save(stats_file)?;
+    }
+    if let Some(token_usage_file) = &token_usage_file {
+        token_usage.save(token_usage_file)?;
+    }
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn paths_out_of_watches_are_polled() {
+        let dir = tempfile::tempdir().expect("could not create tempdir for data");
+        std::fs::create_dir(dir.path().join("sub")).expect("failed creating dir");
+        let data_dir = Utf8Path::from_path(dir.path())
+            .expect("tempdir is utf-8")
+            .to_owned();
+        let sub = data_dir.join("sub");
+
+        let (tx, mut rx) = tokio::sync::mpsc::channel(16);
+        let errors = Arc::default();
+        let mut watchers = Watchers {
+            tx,
+            debounce: Duration::from_millis(50),
+            mode: RecursiveMode::Recursive,
+            poll_interval: None,
+            fallback_interval: Duration::from_millis(100),
+            errors: Arc::clone(&errors),
+            watchers: vec![],
+            polled: vec![],
+        };
+        let out_of_watches = |path: &Utf8Path| {
+            notify::Error::new(notify::ErrorKind::MaxFilesWatch)
+                .add_path(path.as_std_path().to_owned())
+        };
+        let other = notify::Error::generic("something else")
+            .add_path(data_dir.join("a").into_std_path_buf());
+        watchers.handle_errors(
+            &data_dir,
+            Utf8Path::new(""),
+            &[
+                out_of_watches(&sub),
+                out_of_watches(&sub.join("nested")),
+                other,
+            ],
+        );
+
+        let recorded: Vec<_> = errors.read().iter().map(|e| e.path.clone()).collect();
+        assert_eq!(
+            recorded,
+            [
+                sub.to_string(),
+                sub.join("nested").to_string(),
+                data_dir.join("a").to_string()
+            ]
+        );
+        // Only once, since what's inside of it is already polled with it
+        assert_eq!(watchers.polled, [sub.clone()]);
+
+        std::fs::write(sub.join("a.txt"), "first file").expect("failed writing file");
+        let mut tries = 0;
+        loop {
+            if let Ok(DataUpdateEvent::FsNotify(_, Ok(events))) = rx.try_recv() {
+                if events
+                    .iter()
+                    .any(|e| e.paths.iter().any(|p| p.ends_with("sub/a.txt")))
+                {
+                    break;
+                }
+            }
+            tries += 1;
+            assert!(tries < 50, "change in polled path was not found");
+            std::thread::sleep(Duration::from_millis(100));
+        }
+    }
+}
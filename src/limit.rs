@@ -0,0 +1,72 @@
+use axum::{
+    body::Body,
+    extract::{Request, State},
+    http::StatusCode,
+    middleware::Next,
+    response::{IntoResponse as _, Response},
+};
+use futures_util::StreamExt as _;
+use std::{sync::Arc, time::Duration};
+use tokio::sync::Semaphore;
+use tracing::debug;
+
+use crate::AppState;
+
+/// Seconds clients are told to wait before trying again when every download slot is taken
+const RETRY_AFTER_SECS: u64 = 5;
+
+/// Limits how many `/dl` and `/arc` responses can be sent at the same time
+#[derive(Debug)]
+pub struct DownloadLimiter {
+    permits: Arc<Semaphore>,
+    /// How long a request waits for a slot before giving up
+    queue_timeout: Duration,
+}
+
+impl DownloadLimiter {
+    pub fn new(max: usize, queue_timeout: Duration) -> Self {
+        Self {
+            permits: Arc::new(Semaphore::new(max)),
+            queue_timeout,
+        }
+    }
+}
+
+/// Middleware that holds a download slot until the whole response body was sent, or the client
+/// went away
+pub async fn limit_downloads(
+    State(state): State<AppState>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let Some(limiter) = &state.download_limiter else {
+        return next.run(request).await;
+    };
+
+    let permit = match Arc::clone(&limiter.permits).try_acquire_owned() {
+        Ok(permit) => Some(permit),
+        Err(_) => {
+            let acquire = Arc::clone(&limiter.permits).acquire_owned();
+            tokio::time::timeout(limiter.queue_timeout, acquire)
+                .await
+                .ok()
+                .and_then(Result::ok)
+        }
+    };
+    let Some(permit) = permit else {
+        debug!(uri = %request.uri(), "Every download slot is taken");
+        return (
+            StatusCode::SERVICE_UNAVAILABLE,
+            [("Retry-After", RETRY_AFTER_SECS.to_string())],
+            "Too many downloads in progress, try again later",
+        )
+            .into_response();
+    };
+
+    let (parts, body) = next.run(request).await.into_parts();
+    let body = body.into_data_stream().map(move |chunk| {
+        let _permit = &permit;
+        chunk
+    });
+    Response::from_parts(parts, Body::from_stream(body))
+}
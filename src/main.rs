@@ -8,7 +8,13 @@
 use camino::Utf8PathBuf;
 use clap::Parser;
 use color_eyre::Result;
+use sfsb::{
+    AccessRule, ApiToken, CacheControlRule, Cors, DataDir, Disposition, DispositionOverride,
+    Locale, MimeOverride, SecurityHeaders, SizeUnits, SymlinkPolicy, TrustedProxy, Tz,
+    UnknownContentType,
+};
 use std::net::{IpAddr, Ipv4Addr};
+use std::time::Duration;
 use tracing::info;
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
 use url::Url;
@@ -19,8 +25,11 @@ struct RawConfig {
     #[arg(env = "SFSB_BASE_URL")]
     base_url: Url,
 
+    /// Directory to serve, as `<path>` or `<name>=<path>`, the name is only used when serving
+    /// several. When serving several, the name can have `:hidden` to leave it out of the
+    /// listings of the root, and `:preview-only` so its files can be viewed but not downloaded
     #[arg(env = "SFSB_DATA_DIR")]
-    data_dir: Utf8PathBuf,
+    data_dir: DataDir,
 
     #[arg(env = "SFSB_LISTEN_ADDRESS", default_value_t = IpAddr::V4(Ipv4Addr::new(0,0,0,0)))]
     listen_address: IpAddr,
@@ -30,15 +39,346 @@ struct RawConfig {
 
     #[arg(env = "SFSB_PORT", default_value_t = 0)]
     threads: usize,
+
+    /// Whether downloads default to being saved (`attachment`) or displayed (`inline`)
+    #[arg(long, env = "SFSB_DISPOSITION", default_value = "attachment")]
+    disposition: Disposition,
+
+    /// Per content type overrides for the disposition, like `image=inline,application/pdf=inline`
+    #[arg(long, env = "SFSB_DISPOSITION_OVERRIDES", value_delimiter = ',')]
+    disposition_overrides: Vec<DispositionOverride>,
+
+    /// Content types to serve files with some extensions as, like `md=text/markdown,log=text/plain`
+    #[arg(long, env = "SFSB_MIME_TYPES", value_delimiter = ',')]
+    mime_types: Vec<MimeOverride>,
+
+    /// What to serve files with an unknown type as, `text`, `binary`, or `sniff` to check whether
+    /// they look like text
+    #[arg(long, env = "SFSB_UNKNOWN_CONTENT_TYPE", default_value = "sniff")]
+    unknown_content_type: UnknownContentType,
+
+    /// Name shown when installing the site as a web app
+    #[arg(long, env = "SFSB_APP_NAME", default_value = "sfsb")]
+    app_name: String,
+
+    #[arg(long, env = "SFSB_THEME_COLOR", default_value = "#2b6cb0")]
+    theme_color: String,
+
+    /// PNG to use as the web app icon instead of the embedded one
+    #[arg(long, env = "SFSB_APP_ICON")]
+    app_icon: Option<Utf8PathBuf>,
+
+    /// Title shown at the top of every directory and in the page title, the app name if not set
+    #[arg(long, env = "SFSB_SITE_TITLE")]
+    site_title: Option<String>,
+
+    /// Line shown under the site title
+    #[arg(long, env = "SFSB_TAGLINE")]
+    tagline: Option<String>,
+
+    /// Picture shown next to the site title
+    #[arg(long, env = "SFSB_LOGO")]
+    logo: Option<Utf8PathBuf>,
+
+    /// Directory with templates that replace the embedded ones with the same name, like
+    /// `dir_view.html`, rendered with minijinja. Other files in it are served from `/theme/`
+    #[arg(long, env = "SFSB_TEMPLATE_DIR")]
+    template_dir: Option<Utf8PathBuf>,
+
+    /// Show sizes in powers of 1024 (`binary`) or 1000 (`si`)
+    #[arg(long, env = "SFSB_SIZE_UNITS", default_value = "binary")]
+    size_units: SizeUnits,
+
+    /// How times are shown, in the syntax of `strftime`
+    #[arg(long, env = "SFSB_DATE_FORMAT", default_value = "%Y-%m-%d [%H:%M:%S]")]
+    date_format: String,
+
+    /// Timezone times are shown in, like `Europe/Madrid`
+    #[arg(long, env = "SFSB_TIMEZONE", default_value = "UTC")]
+    timezone: Tz,
+
+    /// Compression level for `.tar.zst` archives
+    #[arg(long, env = "SFSB_ZSTD_LEVEL", default_value_t = 3, value_parser = clap::value_parser!(i32).range(-7..=22))]
+    zstd_level: i32,
+
+    /// Directory where generated archives are kept, so they don't have to be compressed again
+    /// until something in the data dir changes
+    #[arg(long, env = "SFSB_ARCHIVE_CACHE_DIR")]
+    archive_cache_dir: Option<Utf8PathBuf>,
+
+    /// Directory where thumbnails are kept, so they're only made once for every version of a
+    /// picture
+    #[arg(long, env = "SFSB_THUMBNAIL_CACHE_DIR")]
+    thumbnail_cache_dir: Option<Utf8PathBuf>,
+
+    /// Most thumbnails that are made at the same time, the rest wait for their turn
+    #[arg(long, env = "SFSB_MAX_THUMBNAIL_JOBS", default_value_t = 4)]
+    max_thumbnail_jobs: usize,
+
+    /// Refuse to build archives whose files add up to more than this many bytes
+    #[arg(long, env = "SFSB_MAX_ARCHIVE_BYTES")]
+    max_archive_bytes: Option<u64>,
+
+    /// Refuse to build archives with more than this many files and directories
+    #[arg(long, env = "SFSB_MAX_ARCHIVE_ENTRIES")]
+    max_archive_entries: Option<usize>,
+
+    /// Most downloads and archives that can be sent at the same time, the rest wait for a slot
+    #[arg(long, env = "SFSB_MAX_DOWNLOADS")]
+    max_downloads: Option<usize>,
+
+    /// Seconds a download waits for a slot before it's refused with a 503
+    #[arg(long, env = "SFSB_DOWNLOAD_QUEUE_SECS", default_value_t = 2)]
+    download_queue_secs: u64,
+
+    /// File where download stats are saved, so they're kept between restarts
+    #[arg(long, env = "SFSB_STATS_FILE")]
+    stats_file: Option<Utf8PathBuf>,
+
+    /// Show how many times each file was downloaded in the directory view
+    #[arg(long, env = "SFSB_SHOW_DOWNLOAD_COUNTS")]
+    show_download_counts: bool,
+
+    /// Show the SHA-256 of every file in the directory view, once it was hashed in the background,
+    /// with buttons to copy it
+    #[arg(long, env = "SFSB_SHOW_CHECKSUMS")]
+    show_checksums: bool,
+
+    /// Hash every file in the background, which `/sha256sum`, `/md5sum`, `?checksums` archives,
+    /// `Repr-Digest` headers and the checksums in metalinks and JSON listings need. Always on
+    /// with `--show-checksums`
+    #[arg(long, env = "SFSB_HASH_FILES")]
+    hash_files: bool,
+
+    /// File where digests are saved, so files that didn't change aren't read again after a
+    /// restart
+    #[arg(long, env = "SFSB_CHECKSUMS_FILE")]
+    checksums_file: Option<Utf8PathBuf>,
+
+    /// Show the owner, group and permissions of every entry in the directory view
+    #[arg(long, env = "SFSB_SHOW_OWNERSHIP")]
+    show_ownership: bool,
+
+    /// Don't render the `README.md`, `README.txt` or `index.md` of directories above their entries
+    #[arg(long, env = "SFSB_NO_README")]
+    no_readme: bool,
+
+    /// Show how much space is left on the filesystem of the data dir in the directory view
+    #[arg(long, env = "SFSB_SHOW_FREE_SPACE")]
+    show_free_space: bool,
+
+    /// Show times as how long ago they were, like `3 days ago`, with the exact time when hovering
+    /// over them. `?times=exact` or `?times=relative` changes it for a single view
+    #[arg(long, env = "SFSB_RELATIVE_TIMES")]
+    relative_times: bool,
+
+    /// Language of the pages, `en`, `es`, `de` or `fr`. The one the browser asks for in
+    /// `Accept-Language` if not set
+    #[arg(long, env = "SFSB_LOCALE")]
+    locale: Option<Locale>,
+
+    /// Show what went wrong in server errors, like the paths and errors of files that couldn't be
+    /// read, instead of only logging it
+    #[arg(long, env = "SFSB_SHOW_ERROR_DETAILS")]
+    show_error_details: bool,
+
+    /// `Cache-Control` for paths matching a pattern, separated by `;`, like
+    /// `*.iso => public, max-age=86400; /browse/* => no-cache`
+    #[arg(long, env = "SFSB_CACHE_CONTROL", value_delimiter = ';')]
+    cache_control: Vec<CacheControlRule>,
+
+    /// Tokens every request needs one of, separated by `;`, in an `Authorization: Bearer` header
+    /// or a `?token=` query. A token followed by `:<path>` can only reach what's under that path,
+    /// like `secret; photos-token:photos/2024`
+    #[arg(long, env = "SFSB_API_TOKENS", value_delimiter = ';')]
+    api_tokens: Vec<ApiToken>,
+
+    /// Most bytes of `/dl` and `/arc` each token can download in a day in UTC, after which it
+    /// gets a 429 until the next day. Downloads that started under it are finished
+    #[arg(long, env = "SFSB_TOKEN_DAILY_BYTES")]
+    token_daily_bytes: Option<u64>,
+
+    /// Most bytes of `/dl` and `/arc` each token can ever download, after which it gets a 403
+    #[arg(long, env = "SFSB_TOKEN_TOTAL_BYTES")]
+    token_total_bytes: Option<u64>,
+
+    /// File where how much each token downloaded is saved, so quotas are kept between restarts.
+    /// Tokens are only in it as the start of their SHA-256
+    #[arg(long, env = "SFSB_TOKEN_USAGE_FILE")]
+    token_usage_file: Option<Utf8PathBuf>,
+
+    /// Who can reach directories, separated by `;`, like
+    /// `/ => public; private => admin-token, other-token; shared => *`. The rule with the longest
+    /// path wins, `*` is any token, and paths without a rule need a token if there are any
+    #[arg(long, env = "SFSB_ACCESS_RULES", value_delimiter = ';')]
+    access_rules: Vec<AccessRule>,
+
+    /// Reverse proxies, separated by `,`, like `127.0.0.1, 10.0.0.0/8`. Requests from them are
+    /// logged with the client in their `Forwarded` or `X-Forwarded-For` headers instead
+    #[arg(
+        long = "trusted-proxy",
+        env = "SFSB_TRUSTED_PROXIES",
+        value_delimiter = ','
+    )]
+    trusted_proxies: Vec<TrustedProxy>,
+
+    /// `Content-Security-Policy` of pages, instead of one only allowing what's on this site.
+    /// Empty to not send one
+    #[arg(long, env = "SFSB_CONTENT_SECURITY_POLICY")]
+    content_security_policy: Option<String>,
+
+    /// `Referrer-Policy` of pages, instead of `same-origin`. Empty to not send one
+    #[arg(long, env = "SFSB_REFERRER_POLICY")]
+    referrer_policy: Option<String>,
+
+    /// `X-Frame-Options` of pages, instead of `DENY`. Empty to not send one
+    #[arg(long, env = "SFSB_FRAME_OPTIONS")]
+    frame_options: Option<String>,
+
+    /// Other sites that can call the listings and downloads from the browser, separated by `,`,
+    /// like `https://example.com`, or `*` for any
+    #[arg(long, env = "SFSB_CORS_ORIGINS", value_delimiter = ',')]
+    cors_origins: Vec<String>,
+
+    /// Methods those sites can use, separated by `,`
+    #[arg(
+        long,
+        env = "SFSB_CORS_METHODS",
+        value_delimiter = ',',
+        default_value = "GET,HEAD"
+    )]
+    cors_methods: Vec<String>,
+
+    /// Headers those sites can send, separated by `,`
+    #[arg(
+        long,
+        env = "SFSB_CORS_HEADERS",
+        value_delimiter = ',',
+        default_value = "Authorization,Range"
+    )]
+    cors_headers: Vec<String>,
+
+    /// File a line of JSON is appended to for every download, with when, who, the path, status,
+    /// range and how many bytes were sent
+    #[arg(long, env = "SFSB_AUDIT_LOG")]
+    audit_log: Option<Utf8PathBuf>,
+
+    /// Move the audit log to the same name with `.1` after it once it gets past this many bytes,
+    /// replacing the one that was there
+    #[arg(long, env = "SFSB_AUDIT_LOG_MAX_BYTES", default_value_t = 100 * 1024 * 1024)]
+    audit_log_max_bytes: u64,
+
+    /// Only read this many levels of directories below the data dir on startup, deeper ones are
+    /// read the first time they're browsed
+    #[arg(long, env = "SFSB_CACHE_DEPTH")]
+    cache_depth: Option<usize>,
+
+    /// Whether symlinks are followed (`follow`), shown without following them (`display`), or
+    /// left out (`ignore`)
+    #[arg(long, env = "SFSB_SYMLINKS", default_value = "display")]
+    symlinks: SymlinkPolicy,
+
+    /// Patterns of paths to leave out, in gitignore syntax, separated by `,`. `.sfsbignore` files
+    /// inside the data dir can add more
+    #[arg(long, env = "SFSB_EXCLUDE", value_delimiter = ',')]
+    exclude: Vec<String>,
+
+    /// Check the data dir for changes every this many seconds instead of waiting for filesystem
+    /// events, for network filesystems that don't send them
+    #[arg(long, env = "SFSB_POLL_INTERVAL_SECS")]
+    poll_interval_secs: Option<u64>,
+
+    /// Wait this many milliseconds for changes to settle before updating the cache
+    #[arg(long, env = "SFSB_DEBOUNCE_MS", default_value_t = 1000)]
+    debounce_ms: u64,
+
+    /// Only watch for changes in the data dir itself, not in its subdirectories
+    #[arg(long, env = "SFSB_NO_RECURSIVE_WATCH")]
+    no_recursive_watch: bool,
+
+    /// Read the whole data dir again every this many seconds, whether anything changed or not
+    #[arg(long, env = "SFSB_REFRESH_INTERVAL_SECS")]
+    refresh_interval_secs: Option<u64>,
+
+    /// More directories to serve, separated by `,`, like the data dir. Each one is shown as a
+    /// top-level directory, the data dir included
+    #[arg(long, env = "SFSB_EXTRA_DATA_DIRS", value_delimiter = ',')]
+    extra_data_dirs: Vec<DataDir>,
 }
 
 impl RawConfig {
     fn convert(self, listener: tokio::net::TcpListener) -> sfsb::AppConfig {
         sfsb::AppConfig {
             listener,
-            data_dir: self.data_dir,
+            data_dirs: std::iter::once(self.data_dir)
+                .chain(self.extra_data_dirs)
+                .collect(),
             base_url: self.base_url,
             shutdown: None,
+            disposition: self.disposition,
+            disposition_overrides: self.disposition_overrides,
+            mime_overrides: self.mime_types,
+            unknown_content_type: self.unknown_content_type,
+            app_name: self.app_name,
+            theme_color: self.theme_color,
+            app_icon: self.app_icon,
+            site_title: self.site_title,
+            tagline: self.tagline,
+            logo: self.logo,
+            template_dir: self.template_dir,
+            size_units: self.size_units,
+            date_format: self.date_format,
+            timezone: self.timezone,
+            zstd_level: self.zstd_level,
+            archive_cache_dir: self.archive_cache_dir,
+            thumbnail_cache_dir: self.thumbnail_cache_dir,
+            max_thumbnail_jobs: self.max_thumbnail_jobs,
+            max_archive_bytes: self.max_archive_bytes,
+            max_archive_entries: self.max_archive_entries,
+            max_downloads: self.max_downloads,
+            download_queue_timeout: Duration::from_secs(self.download_queue_secs),
+            stats_file: self.stats_file,
+            show_download_counts: self.show_download_counts,
+            show_checksums: self.show_checksums,
+            hash_files: self.hash_files,
+            checksums_file: self.checksums_file,
+            show_ownership: self.show_ownership,
+            render_readme: !self.no_readme,
+            show_free_space: self.show_free_space,
+            relative_times: self.relative_times,
+            locale: self.locale,
+            show_error_details: self.show_error_details,
+            cache_control: self.cache_control,
+            api_tokens: self.api_tokens,
+            token_daily_bytes: self.token_daily_bytes,
+            token_total_bytes: self.token_total_bytes,
+            token_usage_file: self.token_usage_file,
+            access_rules: self.access_rules,
+            trusted_proxies: self.trusted_proxies,
+            security_headers: {
+                let defaults = SecurityHeaders::default();
+                SecurityHeaders {
+                    content_security_policy: self
+                        .content_security_policy
+                        .unwrap_or(defaults.content_security_policy),
+                    referrer_policy: self.referrer_policy.unwrap_or(defaults.referrer_policy),
+                    frame_options: self.frame_options.unwrap_or(defaults.frame_options),
+                }
+            },
+            cors: Cors {
+                origins: self.cors_origins,
+                methods: self.cors_methods,
+                headers: self.cors_headers,
+            },
+            audit_log: self.audit_log,
+            audit_log_max_bytes: self.audit_log_max_bytes,
+            cache_depth: self.cache_depth,
+            symlinks: self.symlinks,
+            exclude: self.exclude,
+            poll_interval: self.poll_interval_secs.map(Duration::from_secs),
+            debounce: Duration::from_millis(self.debounce_ms),
+            recursive_watch: !self.no_recursive_watch,
+            refresh_interval: self.refresh_interval_secs.map(Duration::from_secs),
         }
     }
 }
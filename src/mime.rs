@@ -0,0 +1,218 @@
+use camino::Utf8Path;
+use std::str::FromStr;
+use tokio::io::AsyncReadExt as _;
+
+/// How much of the start of a file is read to sniff its type
+const SNIFF_LEN: u64 = 8 * 1024;
+
+/// Content types that aren't `text/*` but are still text, and so get a charset
+const TEXT_CONTENT_TYPES: &[&str] = &["application/json", "application/xml", "image/svg+xml"];
+
+/// What to serve files as when neither their extension nor their magic bytes give a type
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum UnknownContentType {
+    /// `text/plain` if the start of the file looks like UTF-8 text, `application/octet-stream`
+    /// otherwise
+    #[default]
+    Sniff,
+    Text,
+    Binary,
+}
+
+impl FromStr for UnknownContentType {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "sniff" => Ok(Self::Sniff),
+            "text" => Ok(Self::Text),
+            "binary" => Ok(Self::Binary),
+            s => Err(format!(
+                "Invalid unknown content type policy {s}, expected `sniff`, `text` or `binary`"
+            )),
+        }
+    }
+}
+
+/// Content type to serve files with some extension as, instead of the detected one
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MimeOverride {
+    /// Lowercase, without the dot
+    pub extension: String,
+    pub content_type: String,
+}
+
+impl FromStr for MimeOverride {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let Some((extension, content_type)) = s.split_once('=') else {
+            return Err(format!(
+                "Content type override should be `<extension>=<content type>`, was {s}"
+            ));
+        };
+        if !content_type.contains('/') {
+            return Err(format!("Invalid content type {content_type}"));
+        }
+        Ok(Self {
+            extension: extension.trim_start_matches('.').to_ascii_lowercase(),
+            content_type: content_type.to_owned(),
+        })
+    }
+}
+
+/// Whether the file called `name` is a picture, going by its extension
+pub fn is_image(name: &str) -> bool {
+    Utf8Path::new(name)
+        .extension()
+        .and_then(|ext| from_extension(&ext.to_ascii_lowercase()))
+        .is_some_and(|content_type| content_type.starts_with("image/"))
+}
+
+/// `ext` should be lowercase, without the dot
+pub fn from_extension(ext: &str) -> Option<&'static str> {
+    // https://developer.mozilla.org/en-US/docs/Web/HTTP/Basics_of_HTTP/MIME_types/Common_types
+    match ext {
+        "aac" => Some("audio/aac"),
+        "abw" => Some("application/x-abiword"),
+        "apng" => Some("image/apng"),
+        "arc" => Some("application/x-freearc"),
+        "avif" => Some("image/avif"),
+        "avi" => Some("video/x-msvideo"),
+        "azw" => Some("application/vnd.amazon.ebook"),
+        "bin" => Some("application/octet-stream"),
+        "bmp" => Some("image/bmp"),
+        "bz" => Some("application/x-bzip"),
+        "bz2" => Some("application/x-bzip2"),
+        "cda" => Some("application/x-cdf"),
+        "csh" => Some("application/x-csh"),
+        "css" => Some("text/css"),
+        "csv" => Some("text/csv"),
+        "doc" => Some("application/msword"),
+        "docx" => Some("application/vnd.openxmlformats-officedocument.wordprocessingml.document"),
+        "eot" => Some("application/vnd.ms-fontobject"),
+        "epub" => Some("application/epub+zip"),
+        "gz" => Some("application/gzip"),
+        "gif" => Some("image/gif"),
+        "htm" | "html" => Some("text/html"),
+        "ico" => Some("image/vnd.microsoft.icon"),
+        "ics" => Some("text/calendar"),
+        "jar" => Some("application/java-archive"),
+        "jpeg" | "jpg" => Some("image/jpeg"),
+        "mjs" | "js" => Some("text/javascript"),
+        "json" => Some("application/json"),
+        "jsonld" => Some("application/ld+json"),
+        "mid" | "midi" => Some("audio/midi"),
+        "mp3" => Some("audio/mpeg"),
+        "mp4" => Some("video/mp4"),
+        "mpeg" => Some("video/mpeg"),
+        "mpkg" => Some("application/vnd.apple.installer+xml"),
+        "odp" => Some("application/vnd.oasis.opendocument.presentation"),
+        "ods" => Some("application/vnd.oasis.opendocument.spreadsheet"),
+        "odt" => Some("application/vnd.oasis.opendocument.text"),
+        "oga" => Some("audio/ogg"),
+        "ogv" => Some("video/ogg"),
+        "ogx" => Some("application/ogg"),
+        "opus" => Some("audio/opus"),
+        "otf" => Some("font/otf"),
+        "png" => Some("image/png"),
+        "pdf" => Some("application/pdf"),
+        "php" => Some("application/x-httpd-php"),
+        "ppt" => Some("application/vnd.ms-powerpoint"),
+        "pptx" => Some("application/vnd.openxmlformats-officedocument.presentationml.presentation"),
+        "rar" => Some("application/vnd.rar"),
+        "rtf" => Some("application/rtf"),
+        "sh" => Some("application/x-sh"),
+        "svg" => Some("image/svg+xml"),
+        "tar" => Some("application/x-tar"),
+        "tif" | "tiff" => Some("image/tiff"),
+        "ts" => Some("video/mp2t"),
+        "ttf" => Some("font/ttf"),
+        "vsd" => Some("application/vnd.visio"),
+        "wav" => Some("audio/wav"),
+        "weba" => Some("audio/webm"),
+        "webm" => Some("video/webm"),
+        "webp" => Some("image/webp"),
+        "woff" => Some("font/woff"),
+        "woff2" => Some("font/woff2"),
+        "xhtml" => Some("application/xhtml+xml"),
+        "xls" => Some("application/vnd.ms-excel"),
+        "xlsx" => Some("application/vnd.openxmlformats-officedocument.spreadsheetml.sheet"),
+        "xml" => Some("application/xml"),
+        "xul" => Some("application/vnd.mozilla.xul+xml"),
+        "zip" => Some("application/zip"),
+        "3gp" => Some("video/3gpp"),
+        "3g2" => Some("video/3gpp2"),
+        "7z" => Some("application/x-7z-compressed"),
+        "txt" => Some("text/plain"),
+        _ => None,
+    }
+}
+
+/// Guesses the type of a file from the magic bytes at its `start`
+pub fn sniff(start: &[u8]) -> Option<&'static str> {
+    infer::get(start).map(|t| t.mime_type())
+}
+
+async fn read_start(path: &Utf8Path) -> std::io::Result<Vec<u8>> {
+    let file = tokio::fs::File::open(path).await?;
+    let mut start = vec![];
+    file.take(SNIFF_LEN).read_to_end(&mut start).await?;
+    Ok(start)
+}
+
+/// Whether `start` looks like the start of a UTF-8 text file
+fn looks_like_text(start: &[u8]) -> bool {
+    if start.contains(&0) {
+        return false;
+    }
+    match std::str::from_utf8(start) {
+        Ok(_) => true,
+        // The read might have stopped in the middle of a character
+        Err(e) => e.error_len().is_none(),
+    }
+}
+
+/// Adds `charset=utf-8` to text content types that don't have a charset yet
+pub fn with_charset(content_type: &str) -> String {
+    let is_text = content_type.starts_with("text/") || TEXT_CONTENT_TYPES.contains(&content_type);
+    if is_text && !content_type.contains(';') {
+        format!("{content_type}; charset=utf-8")
+    } else {
+        content_type.to_owned()
+    }
+}
+
+/// Content type of the file at `path`, from the overrides, its extension, or its contents, in
+/// that order, without a charset
+pub async fn detect(
+    overrides: &[MimeOverride],
+    unknown: UnknownContentType,
+    path: &Utf8Path,
+) -> String {
+    let ext = path.extension().map(str::to_ascii_lowercase);
+    if let Some(ext) = &ext {
+        if let Some(o) = overrides.iter().find(|o| &o.extension == ext) {
+            return o.content_type.clone();
+        }
+        if let Some(content_type) = from_extension(ext) {
+            return content_type.to_owned();
+        }
+    }
+
+    // Failing to read it will also fail when it's downloaded, so it doesn't matter here
+    let start = read_start(path).await.unwrap_or_default();
+    if let Some(content_type) = sniff(&start) {
+        return content_type.to_owned();
+    }
+    let is_text = match unknown {
+        UnknownContentType::Sniff => looks_like_text(&start),
+        UnknownContentType::Text => true,
+        UnknownContentType::Binary => false,
+    };
+    if is_text {
+        "text/plain".to_owned()
+    } else {
+        "application/octet-stream".to_owned()
+    }
+}
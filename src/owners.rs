@@ -0,0 +1,72 @@
+use std::collections::HashMap;
+
+/// Names of the users and groups of the system, to show who owns each entry
+#[derive(Debug, Default)]
+pub struct Owners {
+    users: HashMap<u32, String>,
+    groups: HashMap<u32, String>,
+}
+
+impl Owners {
+    /// Reads the names from `/etc/passwd` and `/etc/group`, ids without one are shown as numbers
+    pub fn load() -> Self {
+        let read = |path| {
+            std::fs::read_to_string(path)
+                .map(|contents| parse_names(&contents))
+                .unwrap_or_default()
+        };
+        Self {
+            users: read("/etc/passwd"),
+            groups: read("/etc/group"),
+        }
+    }
+
+    pub fn user(&self, uid: Option<u32>) -> String {
+        name_or_id(&self.users, uid)
+    }
+
+    pub fn group(&self, gid: Option<u32>) -> String {
+        name_or_id(&self.groups, gid)
+    }
+}
+
+fn name_or_id(names: &HashMap<u32, String>, id: Option<u32>) -> String {
+    match id {
+        Some(id) => names.get(&id).cloned().unwrap_or_else(|| id.to_string()),
+        None => "-".to_owned(),
+    }
+}
+
+/// Names of the ids in a file like `/etc/passwd`, with lines like `<name>:<password>:<id>:...`
+fn parse_names(contents: &str) -> HashMap<u32, String> {
+    contents
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.split(':');
+            let name = fields.next()?;
+            let id = fields.nth(1)?.parse().ok()?;
+            Some((id, name.to_owned()))
+        })
+        .collect()
+}
+
+/// Permission bits like `ls -l` shows them, `drwxr-xr-x`
+pub fn format_mode(mode: Option<u32>, is_dir: bool) -> String {
+    let Some(mode) = mode else {
+        return "-".to_owned();
+    };
+    let mut s = String::with_capacity(10);
+    s.push(if is_dir { 'd' } else { '-' });
+    for (shift, special, special_char) in [(6, 0o4000, 's'), (3, 0o2000, 's'), (0, 0o1000, 't')] {
+        let bits = (mode >> shift) & 0o7;
+        s.push(if bits & 0o4 == 0 { '-' } else { 'r' });
+        s.push(if bits & 0o2 == 0 { '-' } else { 'w' });
+        s.push(match (bits & 0o1 != 0, mode & special != 0) {
+            (true, true) => special_char,
+            (false, true) => special_char.to_ascii_uppercase(),
+            (true, false) => 'x',
+            (false, false) => '-',
+        });
+    }
+    s
+}
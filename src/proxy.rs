@@ -0,0 +1,120 @@
+use axum::{
+    extract::{ConnectInfo, Request},
+    http::HeaderMap,
+};
+use std::{
+    net::{IpAddr, SocketAddr},
+    str::FromStr,
+};
+
+use crate::AppState;
+
+/// Addresses of reverse proxies whose `Forwarded` and `X-Forwarded-For` headers are believed,
+/// parsed from a CIDR like `10.0.0.0/8` or a single address
+#[derive(Debug, Clone, Copy)]
+pub struct TrustedProxy {
+    addr: IpAddr,
+    prefix_len: u8,
+}
+
+impl FromStr for TrustedProxy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        let (addr, prefix_len) = s.split_once('/').unwrap_or((s, ""));
+        let addr: IpAddr = addr
+            .parse()
+            .map_err(|e| format!("Invalid address for trusted proxy {s}: {e}"))?;
+        let max_len = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix_len = if prefix_len.is_empty() {
+            max_len
+        } else {
+            prefix_len
+                .parse()
+                .ok()
+                .filter(|&len| len <= max_len)
+                .ok_or_else(|| format!("Invalid prefix length for trusted proxy {s}"))?
+        };
+        Ok(Self { addr, prefix_len })
+    }
+}
+
+impl TrustedProxy {
+    fn contains(&self, addr: IpAddr) -> bool {
+        let (addr, network, bits) = match (addr.to_canonical(), self.addr) {
+            (IpAddr::V4(a), IpAddr::V4(n)) => (u32::from(a).into(), u32::from(n).into(), 32),
+            (IpAddr::V6(a), IpAddr::V6(n)) => (u128::from(a), u128::from(n), 128),
+            _ => return false,
+        };
+        let shift = bits - u32::from(self.prefix_len);
+        // Shifting by all the bits would overflow, and a prefix of 0 matches everything anyway
+        shift == bits || addr >> shift == network >> shift
+    }
+}
+
+/// Address in a `for=` of a `Forwarded` header, which can have a port and be in quotes or
+/// brackets, like `"[2001:db8::1]:4711"`
+fn forwarded_for(node: &str) -> Option<IpAddr> {
+    let node = node.trim().trim_matches('"');
+    if let Some(v6) = node.strip_prefix('[') {
+        return v6.split(']').next()?.parse().ok();
+    }
+    node.parse()
+        .ok()
+        .or_else(|| node.rsplit_once(':')?.0.parse().ok())
+}
+
+/// Addresses each proxy in the way says it got the request from, the nearest one last
+///
+/// `None` if the headers have an address that can't be read, like an obfuscated one
+fn forwarded_chain(headers: &HeaderMap) -> Option<Vec<IpAddr>> {
+    let forwarded: Vec<_> = headers
+        .get_all("forwarded")
+        .iter()
+        .filter_map(|h| h.to_str().ok())
+        .flat_map(|h| h.split(','))
+        .filter_map(|element| {
+            element.split(';').find_map(|pair| {
+                let (name, value) = pair.split_once('=')?;
+                name.trim().eq_ignore_ascii_case("for").then_some(value)
+            })
+        })
+        .collect();
+    if !forwarded.is_empty() {
+        return forwarded.into_iter().map(forwarded_for).collect();
+    }
+    headers
+        .get_all("x-forwarded-for")
+        .iter()
+        .filter_map(|h| h.to_str().ok())
+        .flat_map(|h| h.split(','))
+        .map(|addr| addr.trim().parse().ok())
+        .collect()
+}
+
+/// Address of whoever made `request`
+///
+/// It's the address of the connection, unless that's a trusted proxy, in which case it's the
+/// nearest address its forwarding headers have that isn't another trusted proxy
+pub fn client_addr(state: &AppState, request: &Request) -> Option<IpAddr> {
+    let peer = request
+        .extensions()
+        .get::<ConnectInfo<SocketAddr>>()
+        .map(|info| info.0.ip().to_canonical())?;
+    let is_trusted = |addr| state.trusted_proxies.iter().any(|p| p.contains(addr));
+    if !is_trusted(peer) {
+        return Some(peer);
+    }
+    let Some(chain) = forwarded_chain(request.headers()) else {
+        return Some(peer);
+    };
+    let mut client = peer;
+    for addr in chain.into_iter().rev() {
+        client = addr.to_canonical();
+        if !is_trusted(client) {
+            break;
+        }
+    }
+    Some(client)
+}
@@ -0,0 +1,58 @@
+use axum::{
+    extract::{Query, State},
+    http::{header, StatusCode},
+    response::IntoResponse,
+};
+use qrcode::{render::svg, QrCode};
+use serde::Deserialize;
+
+use crate::AppState;
+
+/// Smallest size of the code, so it can be read from across a table
+const MIN_SIZE: u32 = 200;
+
+#[derive(Deserialize, Debug)]
+pub struct QrQuery {
+    /// `/browse` or `/dl` url of this instance, or its path
+    target: String,
+}
+
+/// QR code of a `/browse` or `/dl` url as an SVG, to open it on a phone without typing it
+pub async fn qr_code(
+    State(state): State<AppState>,
+    Query(query): Query<QrQuery>,
+) -> Result<impl IntoResponse, (StatusCode, String)> {
+    let url = state
+        .base_url
+        .join(query.target.trim_start_matches('/'))
+        .map_err(|e| (StatusCode::BAD_REQUEST, e.to_string()))?;
+    if url.origin() != state.base_url.origin() {
+        return Err((
+            StatusCode::NOT_FOUND,
+            format!("Url {url} is not served by this instance"),
+        ));
+    }
+    let route = url
+        .path_segments()
+        .into_iter()
+        .flatten()
+        .find(|s| !s.is_empty());
+    if !matches!(route, Some("browse" | "dl")) {
+        return Err((
+            StatusCode::NOT_FOUND,
+            format!("Url {url} does not point to a file or directory"),
+        ));
+    }
+
+    let svg = QrCode::new(url.as_str())
+        .map_err(|e| {
+            (
+                StatusCode::BAD_REQUEST,
+                format!("Url {url} can't be a QR code: {e}"),
+            )
+        })?
+        .render::<svg::Color<'_>>()
+        .min_dimensions(MIN_SIZE, MIN_SIZE)
+        .build();
+    Ok(([(header::CONTENT_TYPE, "image/svg+xml")], svg))
+}
@@ -0,0 +1,161 @@
+use axum::{
+    body::Body,
+    http::StatusCode,
+    response::{IntoResponse, Response},
+};
+use camino::Utf8Path;
+use chrono::Utc;
+use color_eyre::{eyre::WrapErr, Result};
+use futures_util::StreamExt as _;
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+};
+
+const SECS_PER_DAY: i64 = 24 * 60 * 60;
+
+/// Bytes downloaded with a single token
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct TokenUsage {
+    /// Every byte sent since its usage started being kept
+    pub total_bytes: u64,
+    /// Day `day_bytes` are of, in days since the unix epoch, in UTC
+    pub day: i64,
+    pub day_bytes: u64,
+}
+
+/// Current day, in days since the unix epoch, in UTC
+fn today() -> i64 {
+    Utc::now().timestamp().div_euclid(SECS_PER_DAY)
+}
+
+/// How many bytes of `/dl` and `/arc` each token can download, counting every byte of a
+/// download that started before going over
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Quota {
+    /// Until the end of the day in UTC, after which it's refused with a 429
+    pub daily_bytes: Option<u64>,
+    /// Ever, after which it's refused with a 403
+    pub total_bytes: Option<u64>,
+}
+
+impl Quota {
+    pub const fn is_set(&self) -> bool {
+        self.daily_bytes.is_some() || self.total_bytes.is_some()
+    }
+
+    /// Refusal for a download with a token that already used `usage`, if it's over a limit
+    pub fn refusal(&self, usage: &TokenUsage) -> Option<Response> {
+        if self.total_bytes.is_some_and(|max| usage.total_bytes >= max) {
+            return Some(
+                (
+                    StatusCode::FORBIDDEN,
+                    "The token used up every byte it could download",
+                )
+                    .into_response(),
+            );
+        }
+        if self.daily_bytes.is_some_and(|max| usage.day_bytes >= max) {
+            let tomorrow = (today() + 1) * SECS_PER_DAY;
+            let retry_after = tomorrow - Utc::now().timestamp();
+            return Some(
+                (
+                    StatusCode::TOO_MANY_REQUESTS,
+                    [("Retry-After", retry_after.to_string())],
+                    "The token used up what it could download today",
+                )
+                    .into_response(),
+            );
+        }
+        None
+    }
+}
+
+/// Bytes downloaded with each token, by the fingerprint of the token so they can be saved
+/// without the tokens
+#[derive(Debug, Default)]
+pub struct TokenUsages {
+    tokens: Mutex<HashMap<String, TokenUsage>>,
+    /// Whether anything was recorded since the last time they were saved
+    dirty: AtomicBool,
+}
+
+impl TokenUsages {
+    /// Loads the usage saved to `path`, or starts with none if it doesn't exist yet
+    pub fn load(path: &Utf8Path) -> Result<Self> {
+        let tokens = match std::fs::read(path) {
+            Ok(contents) => serde_json::from_slice(&contents)
+                .wrap_err_with(|| format!("Failed parsing token usage from {path}"))?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => HashMap::new(),
+            Err(e) => {
+                return Err(e).wrap_err_with(|| format!("Failed reading token usage from {path}"))
+            }
+        };
+        Ok(Self {
+            tokens: Mutex::new(tokens),
+            dirty: AtomicBool::new(false),
+        })
+    }
+
+    /// Writes the usage to `path` if anything changed since it was last saved
+    pub fn save(&self, path: &Utf8Path) -> Result<()> {
+        if !self.dirty.swap(false, Ordering::AcqRel) {
+            return Ok(());
+        }
+        let contents = serde_json::to_vec(&*self.tokens.lock())?;
+        // Written next to it and renamed, so a crash can't leave half of a file behind
+        let tmp_path = path.with_extension("tmp");
+        std::fs::write(&tmp_path, contents)
+            .wrap_err_with(|| format!("Failed writing token usage to {tmp_path}"))?;
+        std::fs::rename(&tmp_path, path)
+            .wrap_err_with(|| format!("Failed moving token usage to {path}"))?;
+        Ok(())
+    }
+
+    /// Usage of the token with `fingerprint`, with nothing for today if it last downloaded on
+    /// another day
+    pub fn get(&self, fingerprint: &str) -> TokenUsage {
+        let mut usage = self
+            .tokens
+            .lock()
+            .get(fingerprint)
+            .cloned()
+            .unwrap_or_default();
+        let today = today();
+        if usage.day != today {
+            usage.day = today;
+            usage.day_bytes = 0;
+        }
+        usage
+    }
+
+    fn add(&self, fingerprint: &str, bytes: u64) {
+        let today = today();
+        let mut tokens = self.tokens.lock();
+        let usage = tokens.entry(fingerprint.to_owned()).or_default();
+        if usage.day != today {
+            usage.day = today;
+            usage.day_bytes = 0;
+        }
+        usage.day_bytes += bytes;
+        usage.total_bytes += bytes;
+        self.dirty.store(true, Ordering::Release);
+    }
+}
+
+/// Counts the bytes of the body of `response` against the token with `fingerprint` as they're
+/// sent, so downloads that are dropped early only count what was sent
+pub fn count_body(usages: Arc<TokenUsages>, fingerprint: String, response: Response) -> Response {
+    let (parts, body) = response.into_parts();
+    let stream = body.into_data_stream().inspect(move |chunk| {
+        if let Ok(bytes) = chunk {
+            usages.add(&fingerprint, bytes.len() as u64);
+        }
+    });
+    Response::from_parts(parts, Body::from_stream(stream))
+}
@@ -0,0 +1,52 @@
+use camino::Utf8Path;
+use pulldown_cmark::{html, Options, Parser};
+use std::io::Read;
+use tracing::warn;
+
+use crate::{dir_cache::DirContents, roots::DataRoots};
+
+/// Files rendered above the entries of the directory they're in, the first one found is used
+const README_NAMES: [&str; 3] = ["README.md", "README.txt", "index.md"];
+/// Anything longer than this is cut short
+pub const MAX_TEXT_BYTES: u64 = 1024 * 1024;
+
+/// Reads the text file at `path`, only up to [`MAX_TEXT_BYTES`], replacing anything that isn't
+/// UTF-8
+pub fn read_text(path: &Utf8Path) -> std::io::Result<String> {
+    let mut contents = vec![];
+    std::fs::File::open(path)?
+        .take(MAX_TEXT_BYTES)
+        .read_to_end(&mut contents)?;
+    Ok(String::from_utf8_lossy(&contents).into_owned())
+}
+
+/// Markdown rendered as sanitized HTML
+pub fn render_markdown(markdown: &str) -> String {
+    let mut rendered = String::new();
+    html::push_html(&mut rendered, Parser::new_ext(markdown, Options::all()));
+    ammonia::clean(&rendered)
+}
+
+/// The readme of `dir`, if it has one, as sanitized HTML
+pub fn render_readme(roots: &DataRoots, dir: &Utf8Path, entries: &DirContents) -> Option<String> {
+    let name = README_NAMES.into_iter().find(|name| {
+        entries
+            .get(name)
+            .is_some_and(|e| e.is_file() && !e.as_file().link)
+    })?;
+    let path = roots.fs_path(&dir.join(name))?;
+
+    let contents = match read_text(&path) {
+        Ok(contents) => contents,
+        Err(e) => {
+            warn!(%path, "Failed reading readme: {e}");
+            return None;
+        }
+    };
+
+    if name.ends_with(".md") {
+        Some(render_markdown(&contents))
+    } else {
+        Some(format!("<pre>{}</pre>", ammonia::clean_text(&contents)))
+    }
+}
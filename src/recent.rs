@@ -0,0 +1,128 @@
+use askama::{filters::urlencode, Template};
+use axum::{
+    extract::{Query, State},
+    response::Response,
+};
+use camino::{Utf8Path, Utf8PathBuf};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use std::sync::Arc;
+
+use crate::{
+    access::Visitor,
+    color_scheme::ColorScheme,
+    dir_cache::DirContents,
+    i18n::{Locale, Messages},
+    utils::DateFormat,
+    AppState,
+};
+
+/// Files shown if the query doesn't say how many
+const DEFAULT_RECENT_FILES: usize = 50;
+/// Most files that can be shown, so the whole tree isn't rendered
+const MAX_RECENT_FILES: usize = 1000;
+
+#[derive(Deserialize, Debug)]
+pub struct RecentQuery {
+    /// How many files to show
+    count: Option<usize>,
+}
+
+/// File in the list of recently added ones
+#[derive(Serialize)]
+pub struct RecentFile {
+    /// Path relative to the root
+    path: Utf8PathBuf,
+    /// Path urlencoded
+    encoded_path: String,
+    created: DateTime<Utc>,
+    /// Whether this is a symlink that's shown but not followed, so it can't be downloaded
+    link: bool,
+    size: String,
+}
+
+#[derive(Template, Serialize)]
+#[template(path = "recent.html")]
+pub struct RecentTemplate {
+    files: Vec<RecentFile>,
+    /// Whether times are shown as how long ago they were
+    relative_times: bool,
+    #[serde(skip)]
+    dates: Arc<DateFormat>,
+    color_scheme: ColorScheme,
+    locale: Locale,
+    /// Every string of the page, in the language of the locale
+    t: &'static Messages,
+}
+
+fn collect_files(
+    state: &AppState,
+    dir: &Utf8Path,
+    entries: &DirContents,
+    out: &mut Vec<RecentFile>,
+) {
+    for entry in entries.iter() {
+        let path = dir.join(entry.name());
+        if entry.is_dir() {
+            collect_files(state, &path, &entry.as_dir().children, out);
+        } else {
+            out.push(RecentFile {
+                encoded_path: urlencode(path.as_str()).expect("TODO: Handle invalid chars in name"),
+                path,
+                created: entry.created(),
+                link: entry.as_file().link,
+                size: state.size_units.format(entry.size()),
+            });
+        }
+    }
+}
+
+/// The newest files anywhere in the cache, going by when they were created
+pub async fn recent_files(
+    State(state): State<AppState>,
+    Query(query): Query<RecentQuery>,
+    color_scheme: ColorScheme,
+    locale: Locale,
+    visitor: Visitor,
+) -> Response {
+    let count = query
+        .count
+        .unwrap_or(DEFAULT_RECENT_FILES)
+        .min(MAX_RECENT_FILES);
+
+    let mut files = vec![];
+    {
+        let cache = state.cache.read();
+        let pruned = visitor.prune(&state, Utf8Path::new(""), &cache);
+        collect_files(
+            &state,
+            Utf8Path::new(""),
+            pruned.as_ref().unwrap_or(&cache),
+            &mut files,
+        );
+    }
+    let newest_first = |f1: &RecentFile, f2: &RecentFile| {
+        f2.created
+            .cmp(&f1.created)
+            .then_with(|| f1.path.cmp(&f2.path))
+    };
+    // Only the newest ones have to be sorted
+    if files.len() > count && count > 0 {
+        files.select_nth_unstable_by(count - 1, newest_first);
+    }
+    files.truncate(count);
+    files.sort_unstable_by(newest_first);
+
+    state.theme.page(
+        "recent.html",
+        RecentTemplate {
+            files,
+            relative_times: state.relative_times,
+            dates: Arc::clone(&state.dates),
+            color_scheme,
+            locale,
+            t: locale.messages(),
+        },
+    )
+}
@@ -0,0 +1,172 @@
+use camino::{Utf8Path, Utf8PathBuf};
+use color_eyre::{
+    eyre::{bail, ensure, WrapErr},
+    Result,
+};
+use std::str::FromStr;
+
+/// Directory to serve, parsed from `<name>=<path>` or just `<path>`, with flags after the name
+/// like `<name>:hidden:preview-only=<path>`
+#[derive(Debug, Clone)]
+pub struct DataDir {
+    /// Name of its top-level directory when serving several, its own name if not set
+    pub name: Option<String>,
+    pub path: Utf8PathBuf,
+    /// Left out of the listings, archives and searches of the root, like an unlisted access rule
+    pub hidden: bool,
+    /// Its files can be previewed in the browser, but `/dl` only serves them inline and `/arc`
+    /// leaves them out
+    pub preview_only: bool,
+}
+
+impl FromStr for DataDir {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut dir = Self {
+            name: None,
+            path: s.into(),
+            hidden: false,
+            preview_only: false,
+        };
+        let Some((name, path)) = s.split_once('=').filter(|(name, _)| !name.contains('/')) else {
+            return Ok(dir);
+        };
+        let mut parts = name.split(':');
+        let name = parts.next().unwrap_or_default();
+        for flag in parts {
+            match flag {
+                "hidden" => dir.hidden = true,
+                "preview-only" => dir.preview_only = true,
+                flag => {
+                    return Err(format!(
+                        "Unknown flag {flag:?} for data dir, should be `hidden` or `preview-only`"
+                    ))
+                }
+            }
+        }
+        dir.name = (!name.is_empty()).then(|| name.to_owned());
+        dir.path = path.into();
+        Ok(dir)
+    }
+}
+
+/// Directory shown as a top-level directory when serving several
+#[derive(Debug, Clone)]
+pub struct Mount {
+    pub name: String,
+    /// Canonical path of the directory
+    pub path: Utf8PathBuf,
+    pub hidden: bool,
+    pub preview_only: bool,
+}
+
+/// Where the paths in the directory cache are on disk
+#[derive(Debug, Clone)]
+pub enum DataRoots {
+    /// A single directory is the root
+    Single(Utf8PathBuf),
+    /// Every directory is inside the root, which only exists in the cache
+    Mounts(Vec<Mount>),
+}
+
+impl DataRoots {
+    pub fn new(dirs: &[DataDir]) -> Result<Self> {
+        match dirs {
+            [] => bail!("No data dir was given"),
+            [dir] => {
+                ensure!(
+                    !dir.hidden && !dir.preview_only,
+                    "Flags only apply to data dirs served next to others, not to {}",
+                    dir.path
+                );
+                Ok(Self::Single(dir.path.clone()))
+            }
+            dirs => {
+                let mut mounts: Vec<Mount> = vec![];
+                for dir in dirs {
+                    let name = match &dir.name {
+                        Some(name) => name.clone(),
+                        None => match dir.path.file_name() {
+                            Some(name) => name.to_owned(),
+                            None => bail!("Data dir {} needs a name", dir.path),
+                        },
+                    };
+                    ensure!(
+                        !name.is_empty() && name != "." && name != "..",
+                        "Invalid name {name:?} for data dir {}",
+                        dir.path
+                    );
+                    ensure!(
+                        mounts.iter().all(|m| m.name != name),
+                        "Several data dirs are named {name}"
+                    );
+                    // Read through symlinks, which are only about what's inside of it
+                    let path = dir.path.canonicalize_utf8().wrap_err_with(|| {
+                        format!("Failed to canonicalize data dir {}", dir.path)
+                    })?;
+                    mounts.push(Mount {
+                        name,
+                        path,
+                        hidden: dir.hidden,
+                        preview_only: dir.preview_only,
+                    });
+                }
+                Ok(Self::Mounts(mounts))
+            }
+        }
+    }
+
+    /// Every directory on disk, along with the path it's at in the cache
+    pub fn dirs(&self) -> Vec<(&Utf8Path, &Utf8Path)> {
+        match self {
+            Self::Single(dir) => vec![(Utf8Path::new(""), dir)],
+            Self::Mounts(mounts) => mounts
+                .iter()
+                .map(|m| (Utf8Path::new(&m.name), m.path.as_path()))
+                .collect(),
+        }
+    }
+
+    /// Directory on disk `path` is inside of, and where it is inside of it, or `None` if it's not
+    /// inside any of them, like the root when serving several
+    pub fn resolve<'a>(&'a self, path: &'a Utf8Path) -> Option<(&'a Utf8Path, &'a Utf8Path)> {
+        match self {
+            Self::Single(dir) => Some((dir, path)),
+            Self::Mounts(mounts) => {
+                let name = path.components().next()?.as_str();
+                let mount = mounts.iter().find(|m| m.name == name)?;
+                let rest = path.strip_prefix(name).ok()?;
+                Some((&mount.path, rest))
+            }
+        }
+    }
+
+    /// Paths in the cache of the mounts that are left out of the listings of the root
+    pub fn hidden_mounts(&self) -> impl Iterator<Item = &Utf8Path> {
+        let mounts = match self {
+            Self::Single(_) => &[][..],
+            Self::Mounts(mounts) => mounts,
+        };
+        mounts
+            .iter()
+            .filter(|m| m.hidden)
+            .map(|m| Utf8Path::new(&m.name))
+    }
+
+    /// Whether `path` is inside a mount whose files can't be downloaded, only previewed
+    pub fn is_preview_only(&self, path: &Utf8Path) -> bool {
+        let Self::Mounts(mounts) = self else {
+            return false;
+        };
+        path.components()
+            .next()
+            .and_then(|name| mounts.iter().find(|m| m.name == name.as_str()))
+            .is_some_and(|m| m.preview_only)
+    }
+
+    /// Where `path`, relative to the root, is on disk
+    pub fn fs_path(&self, path: &Utf8Path) -> Option<Utf8PathBuf> {
+        self.resolve(path).map(|(dir, rest)| dir.join(rest))
+    }
+}
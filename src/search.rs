@@ -0,0 +1,129 @@
+use askama::{filters::urlencode, Template};
+use axum::{
+    extract::{Query, State},
+    response::Response,
+};
+use camino::{Utf8Path, Utf8PathBuf};
+use serde::{Deserialize, Serialize};
+use tracing::info;
+
+use crate::{
+    access::Visitor,
+    color_scheme::ColorScheme,
+    dir_cache::DirContents,
+    i18n::{Locale, Messages},
+    utils::glob_match,
+    AppState,
+};
+
+/// Most results shown for a search, so searching for `*` doesn't render the whole tree
+const MAX_SEARCH_RESULTS: usize = 1000;
+
+#[derive(Deserialize, Debug)]
+pub struct SearchQuery {
+    q: Option<String>,
+}
+
+/// Entry whose name matched the search
+#[derive(Serialize)]
+pub struct SearchResult {
+    /// Path relative to the root
+    path: Utf8PathBuf,
+    /// Path urlencoded
+    encoded_path: String,
+    is_dir: bool,
+    /// Whether this is a symlink that's shown but not followed, so it can't be downloaded
+    link: bool,
+    size: String,
+}
+
+#[derive(Template, Serialize)]
+#[template(path = "search.html")]
+pub struct SearchTemplate {
+    /// What was searched for, empty if nothing was
+    query: String,
+    results: Vec<SearchResult>,
+    /// Whether there were more results than the ones shown
+    truncated: bool,
+    color_scheme: ColorScheme,
+    locale: Locale,
+    /// Every string of the page, in the language of the locale
+    t: &'static Messages,
+}
+
+/// Whether `name` matches `query`, a glob if it has wildcards or a substring if not, ignoring case
+fn matches(query: &str, name: &str) -> bool {
+    let name = name.to_lowercase();
+    if query.contains(['*', '?']) {
+        glob_match(query, &name)
+    } else {
+        name.contains(query)
+    }
+}
+
+fn search_entries(
+    state: &AppState,
+    query: &str,
+    dir: &Utf8Path,
+    entries: &DirContents,
+    results: &mut Vec<SearchResult>,
+) {
+    for entry in entries.iter() {
+        // One past the maximum, to know that some were left out
+        if results.len() > MAX_SEARCH_RESULTS {
+            return;
+        }
+        let path = dir.join(entry.name());
+        if matches(query, entry.name()) {
+            results.push(SearchResult {
+                encoded_path: urlencode(path.as_str()).expect("TODO: Handle invalid chars in name"),
+                path: path.clone(),
+                is_dir: entry.is_dir(),
+                link: entry.is_file() && entry.as_file().link,
+                size: state.size_units.format(entry.size()),
+            });
+        }
+        if entry.is_dir() {
+            search_entries(state, query, &path, &entry.as_dir().children, results);
+        }
+    }
+}
+
+/// Finds the entries anywhere in the cache whose name matches the query, which only includes
+/// the directories that were already read when the cache is shallow
+pub async fn search(
+    State(state): State<AppState>,
+    Query(query): Query<SearchQuery>,
+    color_scheme: ColorScheme,
+    locale: Locale,
+    visitor: Visitor,
+) -> Response {
+    let query = query.q.unwrap_or_default();
+    let mut results = vec![];
+    if !query.is_empty() {
+        info!(query, "Searching directory cache");
+        let cache = state.cache.read();
+        let pruned = visitor.prune(&state, Utf8Path::new(""), &cache);
+        search_entries(
+            &state,
+            &query.to_lowercase(),
+            Utf8Path::new(""),
+            pruned.as_ref().unwrap_or(&cache),
+            &mut results,
+        );
+    }
+    let truncated = results.len() > MAX_SEARCH_RESULTS;
+    results.truncate(MAX_SEARCH_RESULTS);
+
+    state.theme.page(
+        "search.html",
+        SearchTemplate {
+            query,
+            results,
+            truncated,
+            color_scheme,
+            locale,
+            t: locale.messages(),
+        },
+    )
+}
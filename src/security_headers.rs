@@ -0,0 +1,83 @@
+use axum::{
+    extract::{Request, State},
+    http::{header, HeaderName, HeaderValue},
+    middleware::Next,
+    response::Response,
+};
+use color_eyre::{eyre::WrapErr, Result};
+
+use crate::AppState;
+
+/// Only what's on this site, and inline styles since highlighted code is colored with them
+const DEFAULT_CONTENT_SECURITY_POLICY: &str = "default-src 'self'; style-src 'self' \
+    'unsafe-inline'; img-src 'self' data:; object-src 'none'; base-uri 'none'; \
+    frame-ancestors 'none'";
+const DEFAULT_REFERRER_POLICY: &str = "same-origin";
+const DEFAULT_FRAME_OPTIONS: &str = "DENY";
+
+/// Headers sent with pages so browsers limit what they let them do, each one left out if empty
+#[derive(Debug, Clone)]
+pub struct SecurityHeaders {
+    pub content_security_policy: String,
+    pub referrer_policy: String,
+    /// `X-Frame-Options`, for browsers that don't know `frame-ancestors`
+    pub frame_options: String,
+}
+
+impl Default for SecurityHeaders {
+    fn default() -> Self {
+        Self {
+            content_security_policy: DEFAULT_CONTENT_SECURITY_POLICY.to_owned(),
+            referrer_policy: DEFAULT_REFERRER_POLICY.to_owned(),
+            frame_options: DEFAULT_FRAME_OPTIONS.to_owned(),
+        }
+    }
+}
+
+impl SecurityHeaders {
+    /// The ones that aren't empty, failing if any isn't a valid header value
+    pub fn headers(&self) -> Result<Vec<(HeaderName, HeaderValue)>> {
+        [
+            (
+                header::CONTENT_SECURITY_POLICY,
+                &self.content_security_policy,
+            ),
+            (header::REFERRER_POLICY, &self.referrer_policy),
+            (header::X_FRAME_OPTIONS, &self.frame_options),
+        ]
+        .into_iter()
+        .filter(|(_, value)| !value.trim().is_empty())
+        .map(|(name, value)| {
+            let value = HeaderValue::from_str(value.trim())
+                .wrap_err_with(|| format!("Invalid {name} {value:?}"))?;
+            Ok((name, value))
+        })
+        .collect()
+    }
+}
+
+/// Middleware that adds the security headers to pages that don't have them yet, and
+/// `X-Content-Type-Options: nosniff` to everything, so files are never shown as something other
+/// than their content type says
+pub async fn add_security_headers(
+    State(state): State<AppState>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let mut response = next.run(request).await;
+    let headers = response.headers_mut();
+    headers
+        .entry(header::X_CONTENT_TYPE_OPTIONS)
+        .or_insert(HeaderValue::from_static("nosniff"));
+
+    let is_html = headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|t| t.to_str().ok())
+        .is_some_and(|t| t.starts_with("text/html"));
+    if is_html {
+        for (name, value) in state.security_headers.iter() {
+            headers.entry(name).or_insert_with(|| value.clone());
+        }
+    }
+    response
+}
@@ -0,0 +1,172 @@
+use axum::{extract::State, Json};
+use camino::Utf8Path;
+use color_eyre::{eyre::WrapErr, Result};
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+};
+
+use crate::{
+    dir_cache::{CacheEntry, IndexError},
+    AppState, WatchError,
+};
+
+/// Transfers of a single file through `/dl`
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct FileTransferStats {
+    /// Transfers that sent every byte that was asked for
+    pub completed: u64,
+    /// Transfers that were dropped before sending everything, usually because the client left
+    pub aborted: u64,
+    /// Bytes that were actually sent, including the ones from aborted transfers
+    pub bytes_sent: u64,
+}
+
+#[derive(Debug, Default)]
+pub struct TransferStats {
+    files: Mutex<HashMap<String, FileTransferStats>>,
+    /// Whether anything was recorded since the last time they were saved
+    dirty: AtomicBool,
+}
+
+impl TransferStats {
+    /// Loads the stats saved to `path`, or starts with none if it doesn't exist yet
+    pub fn load(path: &Utf8Path) -> Result<Self> {
+        let files = match std::fs::read(path) {
+            Ok(contents) => serde_json::from_slice(&contents)
+                .wrap_err_with(|| format!("Failed parsing download stats from {path}"))?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => HashMap::new(),
+            Err(e) => {
+                return Err(e)
+                    .wrap_err_with(|| format!("Failed reading download stats from {path}"))
+            }
+        };
+        Ok(Self {
+            files: Mutex::new(files),
+            dirty: AtomicBool::new(false),
+        })
+    }
+
+    /// Writes the stats to `path` if anything changed since they were last saved
+    pub fn save(&self, path: &Utf8Path) -> Result<()> {
+        if !self.dirty.swap(false, Ordering::AcqRel) {
+            return Ok(());
+        }
+        let contents = serde_json::to_vec(&self.files())?;
+        // Written next to it and renamed, so a crash can't leave half of a file behind
+        let tmp_path = path.with_extension("tmp");
+        std::fs::write(&tmp_path, contents)
+            .wrap_err_with(|| format!("Failed writing download stats to {tmp_path}"))?;
+        std::fs::rename(&tmp_path, path)
+            .wrap_err_with(|| format!("Failed moving download stats to {path}"))?;
+        Ok(())
+    }
+
+    pub fn get(&self, path: &str) -> Option<FileTransferStats> {
+        self.files.lock().get(path).cloned()
+    }
+
+    fn record(&self, path: &str, bytes_sent: u64, completed: bool) {
+        let mut files = self.files.lock();
+        let stats = files.entry(path.to_owned()).or_default();
+        if completed {
+            stats.completed += 1;
+        } else {
+            stats.aborted += 1;
+        }
+        stats.bytes_sent += bytes_sent;
+        self.dirty.store(true, Ordering::Release);
+    }
+
+    pub fn files(&self) -> BTreeMap<String, FileTransferStats> {
+        self.files
+            .lock()
+            .iter()
+            .map(|(path, stats)| (path.clone(), stats.clone()))
+            .collect()
+    }
+}
+
+/// Counts the bytes sent for a single transfer, and records them once it's dropped, which happens
+/// both when the body finishes and when the connection is closed early
+///
+/// Transfers that never started sending, because the request failed before [`Transfer::expect`]
+/// was called, aren't recorded
+#[derive(Debug)]
+pub struct Transfer {
+    stats: Arc<TransferStats>,
+    path: String,
+    expected: Option<u64>,
+    sent: u64,
+}
+
+impl Transfer {
+    pub fn new(stats: &Arc<TransferStats>, path: impl Into<String>) -> Self {
+        Self {
+            stats: Arc::clone(stats),
+            path: path.into(),
+            expected: None,
+            sent: 0,
+        }
+    }
+
+    /// Sets how many bytes have to be sent for the transfer to count as completed
+    pub fn expect(&mut self, len: u64) {
+        self.expected = Some(len);
+    }
+
+    pub fn add(&mut self, len: usize) {
+        self.sent += len as u64;
+    }
+}
+
+impl Drop for Transfer {
+    fn drop(&mut self) {
+        if let Some(expected) = self.expected {
+            self.stats
+                .record(&self.path, self.sent, self.sent >= expected);
+        }
+    }
+}
+
+pub async fn file_stats(
+    State(state): State<AppState>,
+) -> Json<BTreeMap<String, FileTransferStats>> {
+    Json(state.transfers.files())
+}
+
+#[derive(Debug, Serialize)]
+pub struct CacheStatus {
+    /// Entries in the cache, counting the ones inside every directory
+    entries: usize,
+    /// Entries left out of the cache because they couldn't be read
+    errors: Vec<IndexError>,
+    /// Paths that couldn't be watched for changes
+    watch_errors: Vec<WatchError>,
+}
+
+fn count_entries(entries: &[CacheEntry]) -> usize {
+    entries
+        .iter()
+        .map(|e| match e {
+            CacheEntry::File(_) => 1,
+            CacheEntry::Dir(d) => 1 + count_entries(&d.children),
+        })
+        .sum()
+}
+
+pub async fn cache_status(State(state): State<AppState>) -> Json<CacheStatus> {
+    let entries = count_entries(&state.cache.read());
+    let errors = state.index_errors.read().clone();
+    let watch_errors = state.watch_errors.read().clone();
+    Json(CacheStatus {
+        entries,
+        errors,
+        watch_errors,
+    })
+}
@@ -0,0 +1,111 @@
+use axum::{
+    extract::{self, State},
+    http::{header, StatusCode},
+    response::{IntoResponse, Response},
+};
+use camino::{Utf8Path, Utf8PathBuf};
+use std::path::PathBuf;
+use tracing::info;
+
+use crate::{
+    access::Visitor,
+    archive,
+    checksums::Algorithm,
+    dir_view::{entry_from_cache, normalise_path},
+    AppState,
+};
+
+/// Manifest in the format `sha256sum` and `md5sum` check, of the file at `path` or every file
+/// under it, with paths relative to it
+async fn manifest(
+    algorithm: Algorithm,
+    path: PathBuf,
+    state: &AppState,
+    visitor: &Visitor,
+) -> Result<Response, (StatusCode, String)> {
+    let path = Utf8PathBuf::from_path_buf(path)
+        .map_err(|p| (StatusCode::BAD_REQUEST, format!("Path {p:?} was not UTF-8")))?;
+    let path = normalise_path(&path).map_err(|e| (StatusCode::BAD_REQUEST, e.to_string()))?;
+    info!(?path, ?algorithm, "Serving checksum manifest");
+
+    state.load_path(&path, true).await;
+    let mut entries = vec![];
+    let mut dir = path.as_path();
+    {
+        let lock = state.cache.read();
+        if path.as_str().is_empty() {
+            archive::collect_entries("", &state.roots, &path, &lock, &mut entries);
+        } else {
+            let entry = entry_from_cache(&path, &lock).ok_or_else(|| {
+                (
+                    StatusCode::NOT_FOUND,
+                    format!("Path {path:?} does not exist"),
+                )
+            })?;
+            if entry.is_dir() {
+                let children = &entry.as_dir().children;
+                archive::collect_entries("", &state.roots, &path, children, &mut entries);
+            } else {
+                dir = path.parent().unwrap_or_else(|| Utf8Path::new(""));
+                archive::collect_entry("", &state.roots, dir, entry, &mut entries);
+            }
+        }
+    }
+    visitor.retain_reachable(state, dir, "", &mut entries);
+    if !path.as_str().is_empty() {
+        state
+            .scan_options
+            .check_download(&state.roots, &path)
+            .map_err(|e| (StatusCode::FORBIDDEN, format!("{e:#}")))?;
+    }
+
+    let checksums = state.checksums.as_ref().ok_or_else(|| {
+        (
+            StatusCode::NOT_FOUND,
+            "Files aren't hashed on this server".to_string(),
+        )
+    })?;
+    let manifest = checksums.manifest(algorithm, "", &entries).ok_or_else(|| {
+        (
+            StatusCode::SERVICE_UNAVAILABLE,
+            "Some files haven't been hashed yet, try again later".to_string(),
+        )
+    })?;
+    Ok((
+        [(header::CONTENT_TYPE, "text/plain; charset=utf-8")],
+        manifest,
+    )
+        .into_response())
+}
+
+/// `SHA256SUMS` of everything under a directory, or of a single file
+pub async fn sha256sum(
+    extract::Path(path): extract::Path<PathBuf>,
+    State(state): State<AppState>,
+    visitor: Visitor,
+) -> Result<Response, (StatusCode, String)> {
+    manifest(Algorithm::Sha256, path, &state, &visitor).await
+}
+
+pub async fn root_sha256sum(
+    State(state): State<AppState>,
+    visitor: Visitor,
+) -> Result<Response, (StatusCode, String)> {
+    manifest(Algorithm::Sha256, PathBuf::from("."), &state, &visitor).await
+}
+
+/// `MD5SUMS` of everything under a directory, or of a single file
+pub async fn md5sum(
+    extract::Path(path): extract::Path<PathBuf>,
+    State(state): State<AppState>,
+    visitor: Visitor,
+) -> Result<Response, (StatusCode, String)> {
+    manifest(Algorithm::Md5, path, &state, &visitor).await
+}
+
+pub async fn root_md5sum(
+    State(state): State<AppState>,
+    visitor: Visitor,
+) -> Result<Response, (StatusCode, String)> {
+    manifest(Algorithm::Md5, PathBuf::from("."), &state, &visitor).await
+}
@@ -0,0 +1,107 @@
+use axum::{
+    extract::{self, State},
+    http::{header, StatusCode},
+    response::{Html, IntoResponse, Response},
+};
+use camino::Utf8Path;
+use color_eyre::{eyre::WrapErr, Result};
+use minijinja::Environment;
+use serde::Serialize;
+use std::collections::HashMap;
+use tracing::{info, warn};
+
+use crate::{mime, AppState};
+
+/// Templates and files from the template dir, which replace the embedded ones so the UI can be
+/// changed without building it again
+#[derive(Debug)]
+pub struct Theme {
+    /// Pages with a template here, by the name of the embedded one, are rendered with it instead
+    templates: Environment<'static>,
+    /// Everything else in the dir, like stylesheets, served from `/theme/` with its content type
+    files: HashMap<String, (Vec<u8>, &'static str)>,
+}
+
+impl Theme {
+    /// Reads every file in `dir`, so broken templates are found on startup and not on a request
+    pub fn new(dir: Option<&Utf8Path>) -> Result<Self> {
+        let mut templates = Environment::new();
+        let mut files = HashMap::new();
+
+        for entry in dir
+            .map(|dir| {
+                dir.read_dir_utf8()
+                    .wrap_err_with(|| format!("Failed to read template dir {dir}"))
+            })
+            .transpose()?
+            .into_iter()
+            .flatten()
+        {
+            let entry = entry.wrap_err("Failed to read template dir entry")?;
+            let path = entry.path();
+            if !path.is_file() {
+                continue;
+            }
+            let name = entry.file_name().to_owned();
+            let contents =
+                std::fs::read(path).wrap_err_with(|| format!("Failed to read {path}"))?;
+
+            if path.extension() == Some("html") {
+                let source = String::from_utf8(contents)
+                    .wrap_err_with(|| format!("Template {path} is not UTF-8"))?;
+                templates
+                    .add_template_owned(name.clone(), source)
+                    .wrap_err_with(|| format!("Failed to parse template {path}"))?;
+                info!(name, "Using template from template dir");
+            } else {
+                let content_type = path
+                    .extension()
+                    .and_then(|ext| mime::from_extension(&ext.to_ascii_lowercase()))
+                    .unwrap_or("application/octet-stream");
+                files.insert(name, (contents, content_type));
+            }
+        }
+
+        Ok(Self { templates, files })
+    }
+
+    /// The page rendered with the template called `name` from the template dir, if there's one
+    pub fn render<S: Serialize>(
+        &self,
+        name: &str,
+        context: impl FnOnce() -> S,
+    ) -> Option<Response> {
+        let template = self.templates.get_template(name).ok()?;
+        Some(match template.render(context()) {
+            Ok(html) => Html(html).into_response(),
+            Err(e) => {
+                warn!(name, "Failed to render template: {e:#}");
+                (
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    format!("Failed to render template {name}: {e}"),
+                )
+                    .into_response()
+            }
+        })
+    }
+
+    /// `page` rendered with the template called `name` from the template dir, or with the
+    /// embedded one if there isn't one there
+    pub fn page<T: IntoResponse + Serialize>(&self, name: &str, page: T) -> Response {
+        self.render(name, || &page)
+            .unwrap_or_else(|| page.into_response())
+    }
+}
+
+/// Files from the template dir that aren't templates, for the templates to link to
+pub async fn serve_theme_file(
+    extract::Path(name): extract::Path<String>,
+    State(state): State<AppState>,
+) -> impl IntoResponse {
+    match state.theme.files.get(&name) {
+        Some((contents, content_type)) => {
+            Ok(([(header::CONTENT_TYPE, *content_type)], contents.clone()))
+        }
+        None => Err(StatusCode::NOT_FOUND),
+    }
+}
@@ -0,0 +1,231 @@
+use askama::filters::urlencode;
+use axum::{
+    extract::{self, Query, State},
+    http::{header, StatusCode},
+    response::{IntoResponse, Redirect, Response},
+};
+use camino::{Utf8Path, Utf8PathBuf};
+use color_eyre::{eyre::WrapErr, Result};
+use image::{
+    codecs::{jpeg::JpegEncoder, webp::WebPEncoder},
+    DynamicImage, ImageFormat, ImageReader,
+};
+use serde::Deserialize;
+use std::{
+    hash::{DefaultHasher, Hash, Hasher},
+    path::PathBuf,
+    time::UNIX_EPOCH,
+};
+use tokio::sync::Semaphore;
+use tracing::{debug, info, warn};
+
+use crate::{
+    dir_view::{entry_from_cache, normalise_path},
+    mime, AppState,
+};
+
+/// Width of thumbnails if the query doesn't say
+const DEFAULT_THUMBNAIL_WIDTH: u32 = 256;
+/// Widths are clamped to this, so thumbnails stay small and there aren't too many of them
+const MIN_THUMBNAIL_WIDTH: u32 = 16;
+const MAX_THUMBNAIL_WIDTH: u32 = 1024;
+const JPEG_QUALITY: u8 = 80;
+
+#[derive(Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq, Hash)]
+#[serde(rename_all = "snake_case")]
+enum ThumbnailFormat {
+    #[default]
+    Jpeg,
+    /// Lossless, so bigger than the JPEG ones
+    Webp,
+}
+
+impl ThumbnailFormat {
+    const fn content_type(self) -> &'static str {
+        match self {
+            Self::Jpeg => "image/jpeg",
+            Self::Webp => "image/webp",
+        }
+    }
+
+    const fn extension(self) -> &'static str {
+        match self {
+            Self::Jpeg => "jpg",
+            Self::Webp => "webp",
+        }
+    }
+}
+
+#[derive(Deserialize, Debug)]
+pub struct ThumbnailQuery {
+    /// Thumbnails fit in a square this wide
+    w: Option<u32>,
+    #[serde(default)]
+    format: ThumbnailFormat,
+}
+
+/// Generates thumbnails a few at a time, and keeps them on disk if there's a dir for them
+#[derive(Debug)]
+pub struct Thumbnails {
+    /// Thumbnails are named after what they're of, so the ones from previous runs are used too
+    dir: Option<Utf8PathBuf>,
+    /// Decoding big pictures takes a lot of memory and time, so only this many are at a time
+    permits: Semaphore,
+}
+
+impl Thumbnails {
+    pub fn new(dir: Option<&Utf8Path>, max_jobs: usize) -> Result<Self> {
+        if let Some(dir) = dir {
+            std::fs::create_dir_all(dir)
+                .wrap_err_with(|| format!("Failed to create thumbnail cache dir {dir}"))?;
+        }
+        Ok(Self {
+            dir: dir.map(Utf8Path::to_owned),
+            permits: Semaphore::new(max_jobs.max(1)),
+        })
+    }
+}
+
+/// Where the thumbnail is kept, changes whenever the file does
+fn cache_file(
+    dir: &Utf8Path,
+    path: &Utf8Path,
+    modified: u128,
+    len: u64,
+    width: u32,
+    format: ThumbnailFormat,
+) -> Utf8PathBuf {
+    let mut hasher = DefaultHasher::new();
+    (path, modified, len, width, format).hash(&mut hasher);
+    dir.join(format!("{:016x}.{}", hasher.finish(), format.extension()))
+}
+
+fn generate(
+    path: &Utf8Path,
+    image_format: ImageFormat,
+    width: u32,
+    format: ThumbnailFormat,
+) -> image::ImageResult<Vec<u8>> {
+    let mut reader = ImageReader::open(path)?.with_guessed_format()?;
+    // The extension is only a fallback, the contents might say something else
+    if reader.format().is_none() {
+        reader.set_format(image_format);
+    }
+    let image = reader.decode()?;
+    let thumbnail = image.thumbnail(width, width);
+
+    let mut out = vec![];
+    match format {
+        ThumbnailFormat::Jpeg => {
+            // JPEG has no transparency
+            let thumbnail = DynamicImage::ImageRgb8(thumbnail.to_rgb8());
+            thumbnail.write_with_encoder(JpegEncoder::new_with_quality(&mut out, JPEG_QUALITY))?;
+        }
+        ThumbnailFormat::Webp => {
+            let thumbnail = DynamicImage::ImageRgba8(thumbnail.to_rgba8());
+            thumbnail.write_with_encoder(WebPEncoder::new_lossless(&mut out))?;
+        }
+    }
+    Ok(out)
+}
+
+/// Smaller version of a picture, for galleries
+pub async fn serve_thumbnail(
+    extract::Path(path): extract::Path<PathBuf>,
+    State(state): State<AppState>,
+    Query(query): Query<ThumbnailQuery>,
+) -> Result<Response, (StatusCode, String)> {
+    let path = Utf8PathBuf::from_path_buf(path)
+        .map_err(|p| (StatusCode::BAD_REQUEST, format!("Path {p:?} was not UTF-8")))?;
+    let path = normalise_path(&path).map_err(|e| (StatusCode::BAD_REQUEST, e.to_string()))?;
+    let width = query
+        .w
+        .unwrap_or(DEFAULT_THUMBNAIL_WIDTH)
+        .clamp(MIN_THUMBNAIL_WIDTH, MAX_THUMBNAIL_WIDTH);
+    info!(?path, width, "Sending thumbnail");
+
+    let not_found = || {
+        (
+            StatusCode::NOT_FOUND,
+            format!("Picture {path:?} does not exist"),
+        )
+    };
+    let name = path.file_name().unwrap_or_default();
+    if !mime::is_image(name) {
+        return Err(not_found());
+    }
+    state.load_path(&path, false).await;
+    {
+        let lock = state.cache.read();
+        let entry = entry_from_cache(&path, &lock).ok_or_else(not_found)?;
+        if !entry.is_file() || entry.as_file().link {
+            return Err(not_found());
+        }
+    }
+    state
+        .scan_options
+        .check_download(&state.roots, &path)
+        .map_err(|e| (StatusCode::FORBIDDEN, format!("{e:#}")))?;
+    let fs_path = state.roots.fs_path(&path).ok_or_else(not_found)?;
+
+    // Formats that can't be decoded, like SVG, are small enough to be their own thumbnail
+    let Some(image_format) = path.extension().and_then(ImageFormat::from_extension) else {
+        let encoded_path = urlencode(path.as_str())
+            .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+        return Ok(Redirect::temporary(&format!("/dl/{encoded_path}?inline")).into_response());
+    };
+
+    let metadata = tokio::fs::metadata(&fs_path)
+        .await
+        .map_err(|e| (StatusCode::NOT_FOUND, e.to_string()))?;
+    let modified = metadata
+        .modified()
+        .ok()
+        .and_then(|m| m.duration_since(UNIX_EPOCH).ok())
+        .map_or(0, |m| m.as_nanos());
+    let cached = state
+        .thumbnails
+        .dir
+        .as_deref()
+        .map(|dir| cache_file(dir, &path, modified, metadata.len(), width, query.format));
+
+    let response = |data: Vec<u8>| {
+        ([(header::CONTENT_TYPE, query.format.content_type())], data).into_response()
+    };
+    if let Some(cached) = &cached {
+        if let Ok(data) = tokio::fs::read(cached).await {
+            debug!(%cached, "Thumbnail was cached");
+            return Ok(response(data));
+        }
+    }
+
+    let _permit = state
+        .thumbnails
+        .permits
+        .acquire()
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    let format = query.format;
+    let data = tokio::task::spawn_blocking(move || generate(&fs_path, image_format, width, format))
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?
+        .map_err(|e| {
+            (
+                StatusCode::UNPROCESSABLE_ENTITY,
+                format!("Failed to make a thumbnail of {path:?}: {e}"),
+            )
+        })?;
+
+    if let Some(cached) = cached {
+        // Written somewhere else first, so a thumbnail that's only half written is never read
+        let partial = cached.with_extension("partial");
+        let written = match tokio::fs::write(&partial, &data).await {
+            Ok(()) => tokio::fs::rename(&partial, &cached).await,
+            Err(e) => Err(e),
+        };
+        if let Err(e) = written {
+            warn!(%cached, "Failed to cache thumbnail: {e}");
+        }
+    }
+    Ok(response(data))
+}
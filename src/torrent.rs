@@ -0,0 +1,254 @@
+use askama::filters::urlencode;
+use axum::{
+    extract::{self, State},
+    http::{header, StatusCode},
+    response::{IntoResponse, Response},
+};
+use camino::{Utf8Path, Utf8PathBuf};
+use sha1::{Digest as _, Sha1};
+use std::{
+    io::{self, Read as _},
+    path::PathBuf,
+};
+use tracing::info;
+
+use crate::{
+    access::Visitor,
+    archive::{self, Source},
+    dir_view::{entry_from_cache, normalise_path},
+    download::Disposition,
+    jobs::Jobs,
+    AppState,
+};
+
+/// Smallest and biggest size of the pieces, the ones clients handle well
+const MIN_PIECE_LENGTH: u64 = 256 * 1024;
+const MAX_PIECE_LENGTH: u64 = 16 * 1024 * 1024;
+/// Pieces are made bigger until there are about this many, so the torrent stays small
+const TARGET_PIECES: u64 = 2000;
+/// Seconds clients are told to wait to ask again for a torrent that's being made
+const RETRY_AFTER_SECS: u64 = 10;
+
+/// File in a torrent, as it was in the cache when the torrent was asked for
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TorrentFile {
+    /// Path inside the directory the torrent is of, `/` separated, empty if it's of a single file
+    path: String,
+    fs_path: Utf8PathBuf,
+    size: u64,
+    modified: i64,
+}
+
+/// Info dictionaries of the torrents that were asked for, made in the background since every
+/// file has to be read to hash their pieces
+pub type Torrents = Jobs<Vec<TorrentFile>, Vec<u8>>;
+
+fn bencode_bytes(out: &mut Vec<u8>, bytes: &[u8]) {
+    out.extend_from_slice(format!("{}:", bytes.len()).as_bytes());
+    out.extend_from_slice(bytes);
+}
+
+fn bencode_int(out: &mut Vec<u8>, n: u64) {
+    out.extend_from_slice(format!("i{n}e").as_bytes());
+}
+
+/// Power of two size of the pieces of a torrent with `total` bytes
+fn piece_length(total: u64) -> u64 {
+    let mut length = MIN_PIECE_LENGTH;
+    while length < MAX_PIECE_LENGTH && total / length > TARGET_PIECES {
+        length *= 2;
+    }
+    length
+}
+
+/// SHA-1 of every piece of `files` one after the other, failing if any of them changed size
+fn hash_pieces(files: &[TorrentFile], piece_length: u64) -> io::Result<Vec<u8>> {
+    let mut pieces = vec![];
+    let mut piece = Vec::with_capacity(piece_length as usize);
+    for file in files {
+        let mut reader = std::fs::File::open(&file.fs_path)?.take(file.size);
+        let mut read = 0;
+        loop {
+            let remaining = piece_length - piece.len() as u64;
+            let n = (&mut reader).take(remaining).read_to_end(&mut piece)?;
+            read += n as u64;
+            if piece.len() as u64 == piece_length {
+                pieces.extend_from_slice(&Sha1::digest(&piece));
+                piece.clear();
+            }
+            if n == 0 {
+                break;
+            }
+        }
+        if read != file.size {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                format!("{} changed while it was hashed", file.fs_path),
+            ));
+        }
+    }
+    if !piece.is_empty() {
+        pieces.extend_from_slice(&Sha1::digest(&piece));
+    }
+    Ok(pieces)
+}
+
+/// Whether the torrent of `files` is of a single file instead of a directory
+fn is_single_file(files: &[TorrentFile]) -> bool {
+    matches!(files, [file] if file.path.is_empty())
+}
+
+/// Bencoded v1 info dictionary of a torrent called `name` with `files`
+fn info_dict(name: &str, files: &[TorrentFile]) -> io::Result<Vec<u8>> {
+    let total = files.iter().map(|f| f.size).sum();
+    let piece_length = piece_length(total);
+    let pieces = hash_pieces(files, piece_length)?;
+
+    // Keys of dictionaries have to be sorted
+    let mut info = vec![b'd'];
+    if is_single_file(files) {
+        bencode_bytes(&mut info, b"length");
+        bencode_int(&mut info, total);
+    } else {
+        bencode_bytes(&mut info, b"files");
+        info.push(b'l');
+        for file in files {
+            info.push(b'd');
+            bencode_bytes(&mut info, b"length");
+            bencode_int(&mut info, file.size);
+            bencode_bytes(&mut info, b"path");
+            info.push(b'l');
+            for component in file.path.split('/') {
+                bencode_bytes(&mut info, component.as_bytes());
+            }
+            info.push(b'e');
+            info.push(b'e');
+        }
+        info.push(b'e');
+    }
+    bencode_bytes(&mut info, b"name");
+    bencode_bytes(&mut info, name.as_bytes());
+    bencode_bytes(&mut info, b"piece length");
+    bencode_int(&mut info, piece_length);
+    bencode_bytes(&mut info, b"pieces");
+    bencode_bytes(&mut info, &pieces);
+    info.push(b'e');
+    Ok(info)
+}
+
+/// Torrent of a file or directory, with its `/dl` url as a web seed so it can be downloaded
+/// without any other peers
+///
+/// The pieces are hashed in the background the first time, until then it's `503` with a
+/// `Retry-After`
+pub async fn serve_torrent(
+    extract::Path(path): extract::Path<PathBuf>,
+    State(state): State<AppState>,
+    visitor: Visitor,
+) -> Result<Response, (StatusCode, String)> {
+    let path = Utf8PathBuf::from_path_buf(path)
+        .map_err(|p| (StatusCode::BAD_REQUEST, format!("Path {p:?} was not UTF-8")))?;
+    let path = normalise_path(&path).map_err(|e| (StatusCode::BAD_REQUEST, e.to_string()))?;
+    info!(?path, "Serving torrent");
+
+    let Some(name) = path.file_name().map(str::to_owned) else {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            "The whole data dir can't be a torrent, only what's inside it".to_string(),
+        ));
+    };
+    let not_found = || {
+        (
+            StatusCode::NOT_FOUND,
+            format!("Path {path:?} does not exist"),
+        )
+    };
+    state.load_path(&path, true).await;
+    let parent = path.parent().unwrap_or_else(|| Utf8Path::new(""));
+    let mut entries = vec![];
+    {
+        let lock = state.cache.read();
+        let entry = entry_from_cache(&path, &lock).ok_or_else(not_found)?;
+        archive::collect_entry("", &state.roots, parent, entry, &mut entries);
+    }
+    visitor.retain_reachable(&state, parent, "", &mut entries);
+    state
+        .scan_options
+        .check_download(&state.roots, &path)
+        .map_err(|e| (StatusCode::FORBIDDEN, format!("{e:#}")))?;
+
+    let prefix = format!("{name}/");
+    let files: Vec<_> = entries
+        .into_iter()
+        .filter_map(|e| {
+            let (Some(size), Source::Disk(fs_path)) = (e.size, e.source) else {
+                return None;
+            };
+            Some(TorrentFile {
+                path: e.name.strip_prefix(&prefix).unwrap_or_default().to_owned(),
+                fs_path,
+                size,
+                modified: e.modified,
+            })
+        })
+        .collect();
+    if files.is_empty() {
+        return Err((
+            StatusCode::NOT_FOUND,
+            format!("Path {path:?} has no files to share"),
+        ));
+    }
+    let single = is_single_file(&files);
+
+    let info = {
+        let name = name.clone();
+        state.torrents.get(&path, files, move |files| {
+            info!(?name, "Hashing torrent pieces");
+            info_dict(&name, files)
+        })
+    };
+    let Some(info) = info else {
+        return Ok((
+            StatusCode::SERVICE_UNAVAILABLE,
+            [(header::RETRY_AFTER, RETRY_AFTER_SECS.to_string())],
+            format!("The pieces of {path:?} are being hashed, try again in a while"),
+        )
+            .into_response());
+    };
+
+    // Clients add the name and path of each file to the web seed of directories
+    let seed_path = if single {
+        path.as_str().to_owned()
+    } else if parent.as_str().is_empty() {
+        String::new()
+    } else {
+        format!("{parent}/")
+    };
+    let seed_path =
+        urlencode(&seed_path).map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    let web_seed = state
+        .base_url
+        .join(&format!("dl/{seed_path}"))
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+
+    let mut torrent = vec![b'd'];
+    bencode_bytes(&mut torrent, b"created by");
+    bencode_bytes(&mut torrent, b"sfsb");
+    bencode_bytes(&mut torrent, b"info");
+    torrent.extend_from_slice(&info);
+    bencode_bytes(&mut torrent, b"url-list");
+    bencode_bytes(&mut torrent, web_seed.as_str().as_bytes());
+    torrent.push(b'e');
+
+    Ok((
+        [
+            (header::CONTENT_TYPE, "application/x-bittorrent".to_owned()),
+            (
+                header::CONTENT_DISPOSITION,
+                Disposition::Attachment.header_value(&format!("{name}.torrent")),
+            ),
+        ],
+        torrent,
+    )
+        .into_response())
+}
@@ -0,0 +1,140 @@
+use askama::{filters::urlencode, Template};
+use axum::{
+    extract::{self, State},
+    http::StatusCode,
+    response::Response,
+};
+use camino::{Utf8Path, Utf8PathBuf};
+use serde::Serialize;
+use std::path::PathBuf;
+use tracing::info;
+
+use crate::{
+    access::Visitor,
+    color_scheme::ColorScheme,
+    dir_cache::DirContents,
+    dir_view::{normalise_path, path_contents_from_cache},
+    i18n::{Locale, Messages},
+    AppState,
+};
+
+/// Entry in the tree
+#[derive(Serialize)]
+pub struct TreeItem {
+    name: String,
+    /// Path relative to the root, urlencoded
+    encoded_path: String,
+    /// Whether this is a symlink that's shown but not followed, so it can't be downloaded
+    link: bool,
+    size: String,
+}
+
+/// The tree, flattened so the template doesn't have to recurse, which it can't
+#[derive(Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum TreeNode {
+    /// Start of a directory, followed by everything inside it
+    DirStart(TreeItem),
+    /// End of the last directory that was started
+    DirEnd,
+    File(TreeItem),
+}
+
+#[derive(Template, Serialize)]
+#[template(path = "tree.html")]
+pub struct TreeTemplate {
+    /// Path of the directory the tree is of, urlencoded and ending in `/` unless it's the root
+    encoded_dirname: String,
+    /// Path of the directory, for display
+    display_dirname: String,
+    nodes: Vec<TreeNode>,
+    color_scheme: ColorScheme,
+    locale: Locale,
+    /// Every string of the page, in the language of the locale
+    t: &'static Messages,
+}
+
+fn collect_nodes(state: &AppState, dir: &Utf8Path, entries: &DirContents, out: &mut Vec<TreeNode>) {
+    for entry in entries.iter() {
+        let path = dir.join(entry.name());
+        let item = TreeItem {
+            name: entry.name().to_owned(),
+            encoded_path: urlencode(path.as_str()).expect("TODO: Handle invalid chars in name"),
+            link: entry.is_file() && entry.as_file().link,
+            size: state.size_units.format(entry.size()),
+        };
+        if entry.is_dir() {
+            out.push(TreeNode::DirStart(item));
+            collect_nodes(state, &path, &entry.as_dir().children, out);
+            out.push(TreeNode::DirEnd);
+        } else {
+            out.push(TreeNode::File(item));
+        }
+    }
+}
+
+async fn tree_for_path(
+    state: &AppState,
+    path: &Utf8Path,
+    color_scheme: ColorScheme,
+    locale: Locale,
+    visitor: &Visitor,
+) -> Result<Response, (StatusCode, String)> {
+    info!(?path, "Displaying tree view");
+    let path = normalise_path(path).map_err(|e| (StatusCode::BAD_REQUEST, e.to_string()))?;
+    state.load_path(&path, true).await;
+
+    let lock = state.cache.read();
+    let entries = path_contents_from_cache(&path, &lock)
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?
+        .ok_or_else(|| {
+            (
+                StatusCode::NOT_FOUND,
+                format!("Directory {path:?} does not exist"),
+            )
+        })?;
+    drop(lock);
+    let entries = visitor.prune(state, &path, &entries).unwrap_or(entries);
+
+    let mut nodes = vec![];
+    collect_nodes(state, &path, &entries, &mut nodes);
+
+    let mut display_dirname = path.to_string();
+    if !display_dirname.is_empty() {
+        display_dirname.push('/');
+    }
+    Ok(state.theme.page(
+        "tree.html",
+        TreeTemplate {
+            encoded_dirname: urlencode(&display_dirname)
+                .expect("TODO: Handle invalid chars in name"),
+            display_dirname,
+            nodes,
+            color_scheme,
+            locale,
+            t: locale.messages(),
+        },
+    ))
+}
+
+pub async fn root_tree_view(
+    State(state): State<AppState>,
+    color_scheme: ColorScheme,
+    locale: Locale,
+    visitor: Visitor,
+) -> Result<Response, (StatusCode, String)> {
+    tree_for_path(&state, Utf8Path::new("."), color_scheme, locale, &visitor).await
+}
+
+/// Everything under a directory as a nested list that can be expanded
+pub async fn serve_tree_view(
+    extract::Path(path): extract::Path<PathBuf>,
+    State(state): State<AppState>,
+    color_scheme: ColorScheme,
+    locale: Locale,
+    visitor: Visitor,
+) -> Result<Response, (StatusCode, String)> {
+    let path = Utf8PathBuf::from_path_buf(path)
+        .map_err(|p| (StatusCode::BAD_REQUEST, format!("Path {p:?} was not UTF-8")))?;
+    tree_for_path(&state, &path, color_scheme, locale, &visitor).await
+}
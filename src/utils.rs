@@ -1,90 +1,107 @@
+use chrono::{
+    format::{Item, StrftimeItems},
+    DateTime, Utc,
+};
+use chrono_tz::Tz;
+use color_eyre::{eyre::ensure, Result};
 use itertools::{
     EitherOrBoth::{Both, Left, Right},
     Itertools as _,
 };
-use std::cmp::Ordering;
+use serde::{de::Error as _, Deserialize as _, Deserializer};
+use std::{borrow::Borrow, cmp::Ordering, str::FromStr};
+
+/// Deserializes flags in queries, which can be set with `1`, `true` or `on`, like HTML checkboxes
+/// send
+pub fn deserialize_flag<'de, D: Deserializer<'de>>(deserializer: D) -> Result<bool, D::Error> {
+    let value = String::deserialize(deserializer)?;
+    match value.as_str() {
+        "1" | "true" | "on" => Ok(true),
+        "0" | "false" | "off" => Ok(false),
+        _ => Err(D::Error::custom(format!("Invalid flag value: {value}"))),
+    }
+}
+
+/// How times from the directory cache are shown
+#[derive(Debug, Clone)]
+pub struct DateFormat {
+    /// In the syntax of `strftime`
+    format: String,
+    timezone: Tz,
+}
+
+impl DateFormat {
+    pub fn new(format: &str, timezone: Tz) -> Result<Self> {
+        ensure!(
+            StrftimeItems::new(format).all(|item| item != Item::Error),
+            "Invalid date format {format:?}"
+        );
+        Ok(Self {
+            format: format.to_owned(),
+            timezone,
+        })
+    }
+
+    /// Takes a reference too, as templates borrow the fields they pass to methods
+    pub fn format(&self, time: impl Borrow<DateTime<Utc>>) -> String {
+        time.borrow()
+            .with_timezone(&self.timezone)
+            .format(&self.format)
+            .to_string()
+    }
+
+    /// Name of the timezone times are shown in, like `Europe/Madrid`
+    pub fn timezone(&self) -> &'static str {
+        self.timezone.name()
+    }
+}
+
+/// Which multiples to show file sizes with
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SizeUnits {
+    /// Powers of 1024, `KiB`, `MiB`...
+    #[default]
+    Binary,
+    /// Powers of 1000, `kB`, `MB`...
+    Si,
+}
+
+impl SizeUnits {
+    #[allow(clippy::cast_precision_loss)]
+    pub fn format(self, size: u64) -> String {
+        let (base, units) = match self {
+            Self::Binary => (1024, ["B", "KiB", "MiB", "GiB", "TiB", "PiB", "EiB"]),
+            Self::Si => (1000, ["B", "kB", "MB", "GB", "TB", "PB", "EB"]),
+        };
+
+        if size < base {
+            return format!("{size} B");
+        }
+        let mut size = size as f64;
+        let mut unit = 0;
+        // u64::MAX is 16 EiB, so this never runs out of units
+        while size >= base as f64 && unit < units.len() - 1 {
+            size /= base as f64;
+            unit += 1;
+        }
+        // Otherwise 1023.96 KiB would be shown as 1024.0 KiB
+        if (size * 10.0).round() / 10.0 >= base as f64 && unit < units.len() - 1 {
+            size /= base as f64;
+            unit += 1;
+        }
+        format!("{size:.1} {}", units[unit])
+    }
+}
+
+impl FromStr for SizeUnits {
+    type Err = String;
 
-pub fn content_type_from_extension(ext: Option<&str>) -> &str {
-    // https://developer.mozilla.org/en-US/docs/Web/HTTP/Basics_of_HTTP/MIME_types/Common_types
-    let Some(ext) = ext else {
-        // FIXME: text/plain or application/octet-stream?
-        return "text/plain";
-    };
-    #[allow(clippy::wildcard_in_or_patterns)]
-    match ext {
-        ".aac" => "audio/aac",
-        ".abw" => "application/x-abiword",
-        ".apng" => "image/apng",
-        ".arc" => "application/x-freearc",
-        ".avif" => "image/avif",
-        ".avi" => "video/x-msvideo",
-        ".azw" => "application/vnd.amazon.ebook",
-        ".bin" => "application/octet-stream",
-        ".bmp" => "image/bmp",
-        ".bz" => "application/x-bzip",
-        ".bz2" => "application/x-bzip2",
-        ".cda" => "application/x-cdf",
-        ".csh" => "application/x-csh",
-        ".css" => "text/css",
-        ".csv" => "text/csv",
-        ".doc" => "application/msword",
-        ".docx" => "application/vnd.openxmlformats-officedocument.wordprocessingml.document",
-        ".eot" => "application/vnd.ms-fontobject",
-        ".epub" => "application/epub+zip",
-        ".gz" => "application/gzip",
-        ".gif" => "image/gif",
-        ".htm" | ".html" => "text/html",
-        ".ico" => "image/vnd.microsoft.icon",
-        ".ics" => "text/calendar",
-        ".jar" => "application/java-archive",
-        ".jpeg" | ".jpg" => "image/jpeg",
-        ".mjs" | ".js" => "text/javascript",
-        ".json" => "application/json",
-        ".jsonld" => "application/ld+json",
-        ".mid," => "audio/midi",
-        ".mp3" => "audio/mpeg",
-        ".mp4" => "video/mp4",
-        ".mpeg" => "video/mpeg",
-        ".mpkg" => "application/vnd.apple.installer+xml",
-        ".odp" => "application/vnd.oasis.opendocument.presentation",
-        ".ods" => "application/vnd.oasis.opendocument.spreadsheet",
-        ".odt" => "application/vnd.oasis.opendocument.text",
-        ".oga" => "audio/ogg",
-        ".ogv" => "video/ogg",
-        ".ogx" => "application/ogg",
-        ".opus" => "audio/opus",
-        ".otf" => "font/otf",
-        ".png" => "image/png",
-        ".pdf" => "application/pdf",
-        ".php" => "application/x-httpd-php",
-        ".ppt" => "application/vnd.ms-powerpoint",
-        ".pptx" => "application/vnd.openxmlformats-officedocument.presentationml.presentation",
-        ".rar" => "application/vnd.rar",
-        ".rtf" => "application/rtf",
-        ".sh" => "application/x-sh",
-        ".svg" => "image/svg+xml",
-        ".tar" => "application/x-tar",
-        ".tif" | ".tiff" => "image/tiff",
-        ".ts" => "video/mp2t",
-        ".ttf" => "font/ttf",
-        ".vsd" => "application/vnd.visio",
-        ".wav" => "audio/wav",
-        ".weba" => "audio/webm",
-        ".webm" => "video/webm",
-        ".webp" => "image/webp",
-        ".woff" => "font/woff",
-        ".woff2" => "font/woff2",
-        ".xhtml" => "application/xhtml+xml",
-        ".xls" => "application/vnd.ms-excel",
-        ".xlsx" => "application/vnd.openxmlformats-officedocument.spreadsheetml.sheet",
-        ".xml" => "application/xml",
-        ".xul" => "application/vnd.mozilla.xul+xml",
-        ".zip" => "application/zip",
-        ".3gp" => "video/3gpp",
-        ".3g2" => "video/3gpp2",
-        ".7z" => "application/x-7z-compressed",
-        // FIXME: Same as above
-        ".txt" | _ => "text/plain",
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "binary" => Ok(Self::Binary),
+            "si" => Ok(Self::Si),
+            s => Err(format!("Invalid size units {s}, expected `binary` or `si`")),
+        }
     }
 }
 
@@ -101,3 +118,74 @@ pub fn cmp_ignore_case_utf8(a: &str, b: &str) -> Ordering {
         .find(|&ordering| ordering != Ordering::Equal)
         .unwrap_or(Ordering::Equal)
 }
+
+/// Whether `s` matches `pattern`, where `*` matches any run of characters and `?` any single one
+pub fn glob_match(pattern: &str, s: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let s: Vec<char> = s.chars().collect();
+    let (mut p, mut i) = (0, 0);
+    // Where the last `*` was, and where in `s` it started matching, to backtrack to
+    let mut star = None;
+    while i < s.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                star = Some((p, i));
+                p += 1;
+            }
+            Some(&c) if c == '?' || c == s[i] => {
+                p += 1;
+                i += 1;
+            }
+            _ => match star {
+                Some((star_p, star_i)) => {
+                    p = star_p + 1;
+                    i = star_i + 1;
+                    star = Some((star_p, star_i + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::SizeUnits;
+
+    #[test]
+    fn binary_sizes() {
+        for (size, formatted) in [
+            (0, "0 B"),
+            (999, "999 B"),
+            (1000, "1000 B"),
+            (1023, "1023 B"),
+            (1024, "1.0 KiB"),
+            (1536, "1.5 KiB"),
+            (1024 * 1024 - 1, "1.0 MiB"),
+            (1024 * 1024, "1.0 MiB"),
+            (1024u64.pow(4), "1.0 TiB"),
+            (5 * 1024u64.pow(5), "5.0 PiB"),
+            (u64::MAX, "16.0 EiB"),
+        ] {
+            assert_eq!(SizeUnits::Binary.format(size), formatted, "{size}");
+        }
+    }
+
+    #[test]
+    fn si_sizes() {
+        for (size, formatted) in [
+            (0, "0 B"),
+            (999, "999 B"),
+            (1000, "1.0 kB"),
+            (1023, "1.0 kB"),
+            (1024, "1.0 kB"),
+            (999_999, "1.0 MB"),
+            (1_500_000, "1.5 MB"),
+            (1000u64.pow(4), "1.0 TB"),
+            (u64::MAX, "18.4 EB"),
+        ] {
+            assert_eq!(SizeUnits::Si.format(size), formatted, "{size}");
+        }
+    }
+}
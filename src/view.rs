@@ -0,0 +1,259 @@
+use askama::{filters::urlencode, Template};
+use axum::{
+    extract::{self, State},
+    http::StatusCode,
+    response::{IntoResponse, Redirect, Response},
+};
+use camino::{Utf8Path, Utf8PathBuf};
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use std::path::PathBuf;
+use std::sync::{Arc, OnceLock};
+use syntect::{
+    easy::HighlightLines,
+    highlighting::{Theme, ThemeSet},
+    html::{styled_line_to_highlighted_html, IncludeBackground},
+    parsing::{SyntaxReference, SyntaxSet},
+    util::LinesWithEndings,
+};
+use tracing::info;
+
+use crate::{
+    color_scheme::ColorScheme,
+    dir_view::{entry_from_cache, normalise_path},
+    i18n::{Locale, Messages},
+    mime,
+    readme::{read_text, render_markdown, MAX_TEXT_BYTES},
+    utils::DateFormat,
+    AppState,
+};
+
+fn syntaxes() -> &'static SyntaxSet {
+    static SYNTAXES: OnceLock<SyntaxSet> = OnceLock::new();
+    SYNTAXES.get_or_init(SyntaxSet::load_defaults_newlines)
+}
+
+fn theme() -> &'static Theme {
+    static THEME: OnceLock<Theme> = OnceLock::new();
+    THEME.get_or_init(|| {
+        ThemeSet::load_defaults()
+            .themes
+            .remove("InspiredGitHub")
+            .unwrap_or_default()
+    })
+}
+
+/// Audio or video file, played in the page
+#[derive(Clone, Copy, Serialize)]
+pub struct Media {
+    /// Whether it's shown in a `<video>` instead of an `<audio>`
+    video: bool,
+    content_type: &'static str,
+}
+
+impl Media {
+    /// `video` or `audio`, as Open Graph calls them
+    pub const fn kind(self) -> &'static str {
+        if self.video {
+            "video"
+        } else {
+            "audio"
+        }
+    }
+}
+
+/// How a file is shown in `/view`
+#[derive(Clone, Copy)]
+enum Preview {
+    /// Rendered as HTML
+    Markdown,
+    /// Highlighted with its line numbers
+    Code(&'static SyntaxReference),
+    /// Played from `/dl`, which supports the ranges players ask for
+    Media(Media),
+}
+
+impl Preview {
+    fn for_name(name: &str) -> Option<Self> {
+        let ext = Utf8Path::new(name).extension();
+        if ext.is_some_and(|ext| ext.eq_ignore_ascii_case("md")) {
+            return Some(Self::Markdown);
+        }
+        // Some syntaxes are found by the whole name, like `Makefile`
+        let syntax = ext
+            .and_then(|ext| syntaxes().find_syntax_by_extension(ext))
+            .or_else(|| syntaxes().find_syntax_by_extension(name));
+        if let Some(syntax) = syntax {
+            return Some(Self::Code(syntax));
+        }
+        let content_type = mime::from_extension(&ext?.to_ascii_lowercase())?;
+        if content_type.starts_with("video/") || content_type.starts_with("audio/") {
+            Some(Self::Media(Media {
+                video: content_type.starts_with("video/"),
+                content_type,
+            }))
+        } else {
+            None
+        }
+    }
+
+    fn render(self, contents: &str) -> String {
+        match self {
+            Self::Markdown => render_markdown(contents),
+            Self::Code(syntax) => highlight(contents, syntax),
+            Self::Media(_) => String::new(),
+        }
+    }
+
+    /// Whether the page is a better way to open the file than downloading it
+    const fn opens_as_page(self) -> bool {
+        matches!(self, Self::Markdown | Self::Media(_))
+    }
+}
+
+/// Whether the file called `name` can be shown as a page in `/view`
+pub fn can_preview(name: &str) -> bool {
+    Preview::for_name(name).is_some()
+}
+
+/// Whether the file called `name` is opened in `/view` instead of being downloaded, like
+/// documentation or videos
+pub fn opens_as_page(name: &str) -> bool {
+    Preview::for_name(name).is_some_and(Preview::opens_as_page)
+}
+
+/// `contents` as HTML with the colors of `syntax` and line numbers, or just escaped for lines
+/// that couldn't be highlighted
+fn highlight(contents: &str, syntax: &SyntaxReference) -> String {
+    let mut highlighter = HighlightLines::new(syntax, theme());
+    let mut html = String::from("<pre class=\"code\"><code>");
+    for (number, line) in LinesWithEndings::from(contents).enumerate() {
+        let highlighted = highlighter
+            .highlight_line(line, syntaxes())
+            .and_then(|regions| styled_line_to_highlighted_html(&regions, IncludeBackground::No));
+        let line = match highlighted {
+            Ok(line) => line,
+            Err(_) => ammonia::clean_text(line),
+        };
+        html.push_str(&format!(
+            "<span class=\"line-number\">{}</span>{line}",
+            number + 1
+        ));
+    }
+    html.push_str("</code></pre>");
+    html
+}
+
+#[derive(Template, Serialize)]
+#[template(path = "view.html")]
+pub struct ViewTemplate {
+    /// Name of the file
+    name: String,
+    /// Path of the file urlencoded, to download it
+    encoded_path: String,
+    /// Directory the file is in urlencoded, ending in `/` unless it's the root
+    encoded_dirname: String,
+    /// Absolute url of this view, for link previews
+    page_url: String,
+    /// Absolute url the file is played from, for link previews of media
+    media_url: String,
+    /// Contents of the file, already rendered and sanitized, empty for media
+    body: String,
+    /// Whether only the start of the file is shown, because it's too big
+    truncated: bool,
+    /// Set if the file is played instead of shown
+    media: Option<Media>,
+    size: String,
+    created: DateTime<Utc>,
+    #[serde(skip)]
+    dates: Arc<DateFormat>,
+    color_scheme: ColorScheme,
+    locale: Locale,
+    /// Every string of the page, in the language of the locale
+    t: &'static Messages,
+}
+
+/// Shows files that can be read in the browser as a page, like markdown rendered as HTML,
+/// highlighted source code or a video player
+pub async fn serve_file_view(
+    extract::Path(path): extract::Path<PathBuf>,
+    State(state): State<AppState>,
+    color_scheme: ColorScheme,
+    locale: Locale,
+) -> Result<Response, (StatusCode, String)> {
+    let path = Utf8PathBuf::from_path_buf(path)
+        .map_err(|p| (StatusCode::BAD_REQUEST, format!("Path {p:?} was not UTF-8")))?;
+    let path = normalise_path(&path).map_err(|e| (StatusCode::BAD_REQUEST, e.to_string()))?;
+    info!(?path, "Displaying file view");
+
+    let encoded_path =
+        urlencode(path.as_str()).map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    let name = path.file_name().unwrap_or_default().to_owned();
+    let Some(preview) = Preview::for_name(&name) else {
+        return Ok(Redirect::temporary(&format!("/dl/{encoded_path}")).into_response());
+    };
+
+    let not_found = || {
+        (
+            StatusCode::NOT_FOUND,
+            format!("File {path:?} does not exist"),
+        )
+    };
+    state.load_path(&path, false).await;
+    let (size, created) = {
+        let lock = state.cache.read();
+        let entry = entry_from_cache(&path, &lock).ok_or_else(not_found)?;
+        if !entry.is_file() || entry.as_file().link {
+            return Err(not_found());
+        }
+        (entry.size(), entry.created())
+    };
+    state
+        .scan_options
+        .check_download(&state.roots, &path)
+        .map_err(|e| (StatusCode::FORBIDDEN, format!("{e:#}")))?;
+    let fs_path = state.roots.fs_path(&path).ok_or_else(not_found)?;
+
+    let (body, media) = match preview {
+        Preview::Media(media) => (String::new(), Some(media)),
+        // Highlighting takes a while for big files
+        preview => {
+            let body = tokio::task::spawn_blocking(move || {
+                read_text(&fs_path).map(|contents| preview.render(&contents))
+            })
+            .await
+            .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?
+            .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+            (body, None)
+        }
+    };
+
+    let mut dirname = path.parent().map(Utf8Path::to_string).unwrap_or_default();
+    if !dirname.is_empty() {
+        dirname.push('/');
+    }
+    let absolute_url = |path: &str| {
+        state
+            .base_url
+            .join(path)
+            .map_or_else(|_| state.base_url.to_string(), String::from)
+    };
+    let page = ViewTemplate {
+        name,
+        encoded_dirname: urlencode(&dirname)
+            .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?,
+        page_url: absolute_url(&format!("view/{encoded_path}")),
+        media_url: absolute_url(&format!("dl/{encoded_path}?inline")),
+        encoded_path,
+        body,
+        truncated: media.is_none() && size > MAX_TEXT_BYTES,
+        media,
+        size: state.size_units.format(size),
+        created,
+        dates: Arc::clone(&state.dates),
+        color_scheme,
+        locale,
+        t: locale.messages(),
+    };
+    Ok(state.theme.page("view.html", page))
+}
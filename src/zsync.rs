@@ -0,0 +1,211 @@
+use askama::filters::urlencode;
+use axum::{
+    extract::{self, State},
+    http::{header, StatusCode},
+    response::{IntoResponse, Response},
+};
+use camino::Utf8PathBuf;
+use chrono::DateTime;
+use md4::Md4;
+use sha1::{Digest as _, Sha1};
+use std::{
+    fmt::Write as _,
+    io::{self, Read as _},
+    path::PathBuf,
+};
+use tracing::info;
+
+use crate::{
+    dir_view::{entry_from_cache, normalise_path},
+    download::Disposition,
+    jobs::Jobs,
+    AppState,
+};
+
+/// Files at least this big have bigger blocks, like `zsyncmake` does
+const BIG_FILE: u64 = 100_000_000;
+/// Seconds clients are told to wait to ask again for a control file that's being made
+const RETRY_AFTER_SECS: u64 = 10;
+
+/// File a control file is made of, as it was in the cache when it was asked for
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ZsyncSource {
+    fs_path: Utf8PathBuf,
+    size: u64,
+    modified: i64,
+}
+
+/// Everything in a control file that needs the file to be read
+pub struct BlockSums {
+    blocksize: u64,
+    /// How many blocks have to match in a row, and the bytes kept of each block's rolling
+    /// checksum and MD4
+    hash_lengths: (usize, usize, usize),
+    sha1: String,
+    /// Rolling checksum and MD4 of every block, cut to `hash_lengths`
+    sums: Vec<u8>,
+}
+
+/// Control files of the files that were asked for, made in the background since the whole file
+/// has to be read
+pub type ZsyncFiles = Jobs<ZsyncSource, BlockSums>;
+
+/// Lengths of the hashes of the blocks, the same way `zsyncmake` picks them so the chance of
+/// false matches stays low for the size of the file
+fn hash_lengths(len: u64, blocksize: u64) -> (usize, usize, usize) {
+    let seq_matches = if len > blocksize { 2 } else { 1 };
+    let log2 = |n: f64| n.ln() / 2f64.ln();
+    let file = len.max(1) as f64;
+    let blocks = (1 + len / blocksize) as f64;
+    let rsum_len = ((log2(file) + log2(blocksize as f64) - 8.6) / f64::from(seq_matches) / 8.0)
+        .ceil()
+        .clamp(2.0, 4.0);
+    let checksum_len = ((20.0 + log2(file) + log2(blocks)) / f64::from(seq_matches) / 8.0).ceil();
+    let checksum_len2 = ((7.9 + 20.0 + log2(blocks)) / 8.0).trunc();
+    let checksum_len = checksum_len.max(checksum_len2).min(16.0);
+    (
+        seq_matches as usize,
+        rsum_len as usize,
+        checksum_len as usize,
+    )
+}
+
+/// Rolling checksum of a block, as the two 16 bit halves in network order
+fn rsum(block: &[u8]) -> [u8; 4] {
+    let mut a: u16 = 0;
+    let mut b: u16 = 0;
+    for (i, &c) in block.iter().enumerate() {
+        a = a.wrapping_add(c.into());
+        b = b.wrapping_add(((block.len() - i) as u16).wrapping_mul(c.into()));
+    }
+    let [a1, a2] = a.to_be_bytes();
+    let [b1, b2] = b.to_be_bytes();
+    [a1, a2, b1, b2]
+}
+
+fn block_sums(source: &ZsyncSource) -> io::Result<BlockSums> {
+    let blocksize = if source.size < BIG_FILE { 2048 } else { 4096 };
+    let hash_lengths = hash_lengths(source.size, blocksize);
+    let (_, rsum_len, checksum_len) = hash_lengths;
+
+    let mut reader = std::fs::File::open(&source.fs_path)?.take(source.size);
+    let mut sha1 = Sha1::new();
+    let mut sums = vec![];
+    let mut block = Vec::with_capacity(blocksize as usize);
+    let mut read = 0;
+    loop {
+        block.clear();
+        let n = (&mut reader).take(blocksize).read_to_end(&mut block)?;
+        if n == 0 {
+            break;
+        }
+        read += n as u64;
+        sha1.update(&block);
+        // The last block is padded with zeroes
+        block.resize(blocksize as usize, 0);
+        sums.extend_from_slice(&rsum(&block)[4 - rsum_len..]);
+        sums.extend_from_slice(&Md4::digest(&block)[..checksum_len]);
+    }
+    if read != source.size {
+        return Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            format!("{} changed while it was read", source.fs_path),
+        ));
+    }
+    Ok(BlockSums {
+        blocksize,
+        hash_lengths,
+        sha1: format!("{:x}", sha1.finalize()),
+        sums,
+    })
+}
+
+/// `.zsync` control file of a file, so clients that have an older or partial copy only download
+/// the blocks that differ from `/dl` with ranges
+///
+/// The file is read in the background the first time, until then it's `503` with a
+/// `Retry-After`
+pub async fn serve_zsync(
+    extract::Path(path): extract::Path<PathBuf>,
+    State(state): State<AppState>,
+) -> Result<Response, (StatusCode, String)> {
+    let path = Utf8PathBuf::from_path_buf(path)
+        .map_err(|p| (StatusCode::BAD_REQUEST, format!("Path {p:?} was not UTF-8")))?;
+    let path = normalise_path(&path).map_err(|e| (StatusCode::BAD_REQUEST, e.to_string()))?;
+    info!(?path, "Serving zsync control file");
+
+    let not_found = || {
+        (
+            StatusCode::NOT_FOUND,
+            format!("File {path:?} does not exist"),
+        )
+    };
+    state.load_path(&path, false).await;
+    let (size, modified) = {
+        let lock = state.cache.read();
+        let entry = entry_from_cache(&path, &lock).ok_or_else(not_found)?;
+        if !entry.is_file() || entry.as_file().link {
+            return Err(not_found());
+        }
+        (entry.size(), entry.modified())
+    };
+    state
+        .scan_options
+        .check_download(&state.roots, &path)
+        .map_err(|e| (StatusCode::FORBIDDEN, format!("{e:#}")))?;
+    let fs_path = state.roots.fs_path(&path).ok_or_else(not_found)?;
+
+    let source = ZsyncSource {
+        fs_path,
+        size,
+        modified,
+    };
+    let Some(sums) = state.zsync_files.get(&path, source, block_sums) else {
+        return Ok((
+            StatusCode::SERVICE_UNAVAILABLE,
+            [(header::RETRY_AFTER, RETRY_AFTER_SECS.to_string())],
+            format!("The blocks of {path:?} are being hashed, try again in a while"),
+        )
+            .into_response());
+    };
+
+    let name = path.file_name().unwrap_or_default();
+    let encoded_path =
+        urlencode(path.as_str()).map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    let url = state
+        .base_url
+        .join(&format!("dl/{encoded_path}"))
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    let mtime = DateTime::from_timestamp(modified, 0)
+        .unwrap_or_default()
+        .format("%a, %d %b %Y %H:%M:%S %z");
+    let (seq_matches, rsum_len, checksum_len) = sums.hash_lengths;
+
+    let mut control = String::new();
+    _ = writeln!(control, "zsync: 0.6.2");
+    _ = writeln!(control, "Filename: {name}");
+    _ = writeln!(control, "MTime: {mtime}");
+    _ = writeln!(control, "Blocksize: {}", sums.blocksize);
+    _ = writeln!(control, "Length: {size}");
+    _ = writeln!(
+        control,
+        "Hash-Lengths: {seq_matches},{rsum_len},{checksum_len}"
+    );
+    _ = writeln!(control, "URL: {url}");
+    _ = writeln!(control, "SHA-1: {}", sums.sha1);
+    control.push('\n');
+    let mut body = control.into_bytes();
+    body.extend_from_slice(&sums.sums);
+
+    Ok((
+        [
+            (header::CONTENT_TYPE, "application/x-zsync".to_owned()),
+            (
+                header::CONTENT_DISPOSITION,
+                Disposition::Attachment.header_value(&format!("{name}.zsync")),
+            ),
+        ],
+        body,
+    )
+        .into_response())
+}
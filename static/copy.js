@@ -0,0 +1,21 @@
+// Buttons with `data-copy` put its value in the clipboard, like the link or checksum of a file,
+// and say so for a moment with their `data-copied`. Listening on the document keeps them working
+// after the listing is swapped.
+
+document.addEventListener("click", async (event) => {
+	const button = event.target.closest("button[data-copy]");
+	if (!button) {
+		return;
+	}
+	try {
+		await navigator.clipboard.writeText(button.dataset.copy);
+	} catch (e) {
+		// The clipboard is only available in secure contexts
+		console.error(e);
+		prompt("", button.dataset.copy);
+		return;
+	}
+	const label = button.textContent;
+	button.textContent = button.dataset.copied;
+	setTimeout(() => button.textContent = label, 2000);
+});
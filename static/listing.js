@@ -0,0 +1,36 @@
+// Links marked with `data-fragment`, like sorting or opening a directory, only fetch the listing
+// and swap it in, so the rest of the page isn't downloaded and rendered again. Without this the
+// links load the whole page like any other.
+
+/// Replaces the listing with the one of `href`, loading the whole page instead if that fails
+async function showListing(href) {
+	const url = new URL(href);
+	url.searchParams.set("fragment", "");
+	try {
+		const res = await fetch(url);
+		if (!res.ok) {
+			throw new Error(`Fetching listing failed with ${res.status}`);
+		}
+		document.getElementById("listing").outerHTML = await res.text();
+		document.title = document.getElementById("listing").dataset.title;
+		return true;
+	} catch (e) {
+		console.error(e);
+		location.href = href;
+		return false;
+	}
+}
+
+document.addEventListener("click", async (event) => {
+	const link = event.target.closest("a[data-fragment]");
+	// Opening the link in a new tab or window is left to the browser
+	if (!link || event.button !== 0 || event.ctrlKey || event.metaKey || event.shiftKey || event.altKey) {
+		return;
+	}
+	event.preventDefault();
+	if (await showListing(link.href)) {
+		history.pushState(null, "", link.href);
+	}
+});
+
+window.addEventListener("popstate", () => showListing(location.href));
@@ -0,0 +1,177 @@
+// Not every test binary uses every helper
+#![allow(dead_code)]
+
+use camino::Utf8Path;
+use std::{
+    future::Future,
+    io,
+    net::{IpAddr, Ipv4Addr},
+    sync::{Arc, Mutex, OnceLock},
+};
+use tempfile::TempDir;
+use tokio::sync::oneshot;
+use url::Url;
+
+pub struct SpawnInfo {
+    pub url: Url,
+    pub dir: TempDir,
+    pub shutdown: oneshot::Sender<()>,
+}
+
+impl Drop for SpawnInfo {
+    fn drop(&mut self) {
+        let (tx, _) = oneshot::channel();
+        let old = std::mem::replace(&mut self.shutdown, tx);
+        old.send(()).unwrap();
+    }
+}
+
+pub async fn spawn_app_empty() -> SpawnInfo {
+    let dir = tempfile::tempdir().expect("could not create tempdir for data");
+    spawn_app(dir).await
+}
+
+/// Starts the app serving `dir`, which should already have all the files the test needs, since
+/// the cache is built on startup
+pub async fn spawn_app(dir: TempDir) -> SpawnInfo {
+    spawn_app_with(dir, |_| {}).await
+}
+
+/// Same as [`spawn_app`], letting `configure` change the defaults
+pub async fn spawn_app_with(
+    dir: TempDir,
+    configure: impl FnOnce(&mut sfsb::AppConfig),
+) -> SpawnInfo {
+    let data_dir = Utf8Path::from_path(dir.path())
+        .expect("temp path was not UTF-8")
+        .to_path_buf();
+    let listener = tokio::net::TcpListener::bind((IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 0))
+        .await
+        .expect("failed binding to port");
+    let addr = listener.local_addr().expect("had local addr");
+    let (tx, rx) = oneshot::channel();
+
+    let mut config = sfsb::AppConfig {
+        base_url: Url::parse("http://localhost").expect("valid url"),
+        data_dirs: vec![sfsb::DataDir {
+            name: None,
+            path: data_dir,
+            hidden: false,
+            preview_only: false,
+        }],
+        listener,
+        shutdown: Some(rx),
+        disposition: sfsb::Disposition::default(),
+        disposition_overrides: vec![],
+        mime_overrides: vec![],
+        unknown_content_type: sfsb::UnknownContentType::default(),
+        app_name: "sfsb".to_owned(),
+        theme_color: "#2b6cb0".to_owned(),
+        app_icon: None,
+        site_title: None,
+        tagline: None,
+        logo: None,
+        template_dir: None,
+        size_units: sfsb::SizeUnits::default(),
+        date_format: "%Y-%m-%d [%H:%M:%S]".to_owned(),
+        timezone: sfsb::Tz::UTC,
+        zstd_level: 3,
+        archive_cache_dir: None,
+        thumbnail_cache_dir: None,
+        max_thumbnail_jobs: 4,
+        max_archive_bytes: None,
+        max_archive_entries: None,
+        max_downloads: None,
+        download_queue_timeout: std::time::Duration::ZERO,
+        stats_file: None,
+        show_download_counts: false,
+        show_checksums: false,
+        hash_files: false,
+        checksums_file: None,
+        show_ownership: false,
+        render_readme: true,
+        show_free_space: false,
+        relative_times: false,
+        locale: None,
+        show_error_details: false,
+        cache_control: vec![],
+        api_tokens: vec![],
+        token_daily_bytes: None,
+        token_total_bytes: None,
+        token_usage_file: None,
+        trusted_proxies: vec![],
+        access_rules: vec![],
+        security_headers: sfsb::SecurityHeaders::default(),
+        cors: sfsb::Cors::default(),
+        audit_log: None,
+        audit_log_max_bytes: 100 * 1024 * 1024,
+        cache_depth: None,
+        symlinks: sfsb::SymlinkPolicy::default(),
+        exclude: vec![],
+        poll_interval: None,
+        debounce: std::time::Duration::from_secs(1),
+        recursive_watch: true,
+        refresh_interval: None,
+    };
+    configure(&mut config);
+
+    tokio::spawn(sfsb::run_app(config));
+    let port = addr.port();
+
+    SpawnInfo {
+        url: Url::parse(&format!("http://localhost:{port}")).expect("valid url"),
+        dir,
+        shutdown: tx,
+    }
+}
+
+/// Log lines the apps write through their logs
+#[derive(Clone)]
+struct LogWriter(Arc<Mutex<Vec<u8>>>);
+
+impl io::Write for LogWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut logs = self.0.lock().expect("no test panicked while logging");
+        io::Write::write(&mut *logs, buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Warnings and errors logged by every app started in this test binary, since the first call
+///
+/// The server handles requests on tasks of its own, so the logger has to be the global one,
+/// shared by the tests running at the same time
+pub fn captured_logs() -> String {
+    static LOGS: OnceLock<LogWriter> = OnceLock::new();
+    let logs = LOGS.get_or_init(|| {
+        let writer = LogWriter(Arc::default());
+        let make_writer = writer.clone();
+        tracing_subscriber::fmt()
+            .with_env_filter("sfsb=warn")
+            .with_ansi(false)
+            .with_writer(move || make_writer.clone())
+            .init();
+        writer
+    });
+    let logs = logs.0.lock().expect("no test panicked while logging");
+    String::from_utf8_lossy(&logs).into_owned()
+}
+
+// Every test of the app needs to be ran using the multi threaded runtime, because otherwise the
+// test task has to yield to the scheduler for the scheduler to poll the shutdown task, on the
+// event of a shutdown, which would involve manually adding a sleep, which I think is jankier and
+// more cumbersome than this workaround. However, the test can run on a single thread, so we just
+// have a single worker thread.
+pub fn start_test(func: impl Future<Output = ()>) {
+    {
+        return tokio::runtime::Builder::new_multi_thread()
+            .worker_threads(1usize)
+            .enable_all()
+            .build()
+            .expect("Failed building the Runtime")
+            .block_on(func);
+    }
+}
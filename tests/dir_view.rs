@@ -1,127 +1,2717 @@
-use camino::Utf8Path;
+use camino::{Utf8Path, Utf8PathBuf};
 use proptest::{prop_assume, proptest};
-use reqwest::StatusCode;
-use scraper::Html;
-use std::{
-    future::Future,
-    net::{IpAddr, Ipv4Addr},
-    path::{Path, PathBuf},
+use reqwest::{
+    header::{ACCEPT, ACCEPT_LANGUAGE, CACHE_CONTROL, CONTENT_TYPE, COOKIE, SET_COOKIE},
+    StatusCode,
 };
-use tempfile::TempDir;
-use tokio::sync::oneshot;
-use url::Url;
+use scraper::{Html, Selector};
+use std::path::{Path, PathBuf};
 
-struct SpawnInfo {
-    url: Url,
-    dir: TempDir,
-    shutdown: oneshot::Sender<()>,
+mod common;
+use common::{spawn_app, spawn_app_empty, spawn_app_with, start_test, SpawnInfo};
+
+async fn empty_view_produces_valid_html_impl() {
+    let SpawnInfo {
+        ref url,
+        dir: ref _tempdir,
+        shutdown: _,
+    } = spawn_app_empty().await;
+
+    let res = reqwest::get(url.clone())
+        .await
+        .expect("no error with reqwest");
+    assert_eq!(res.status(), StatusCode::OK);
+
+    let bytes = res.bytes().await.expect("no error receiving html");
+    let content: &[u8] = &bytes.slice(..);
+    let content = std::str::from_utf8(content).expect("response html was not UTF-8");
+
+    let parser = Html::parse_document(content);
+    // Force fully parsing the file
+    let _ = parser.html();
+    assert_eq!(parser.errors, Vec::<&str>::new());
+}
+
+#[test]
+fn empty_view_produces_valid_html() {
+    start_test(empty_view_produces_valid_html_impl());
+}
+
+async fn view_links_to_archives_impl() {
+    let dir = tempfile::tempdir().expect("could not create tempdir for data");
+    std::fs::create_dir_all(dir.path().join("sub")).expect("failed creating dirs");
+
+    let SpawnInfo {
+        ref url,
+        dir: ref _tempdir,
+        shutdown: _,
+    } = spawn_app(dir).await;
+
+    let res = reqwest::get(url.join("browse/").expect("valid url"))
+        .await
+        .expect("no error with reqwest");
+    assert_eq!(res.status(), StatusCode::OK);
+    let content = res.text().await.expect("no error receiving html");
+
+    let parser = Html::parse_document(&content);
+    for href in ["/arc/", "/arc/sub"] {
+        let selector = Selector::parse(&format!("a[href=\"{href}\"]")).expect("valid selector");
+        assert!(
+            parser.select(&selector).next().is_some(),
+            "missing link to {href}"
+        );
+    }
+}
+
+#[test]
+fn view_links_to_archives() {
+    start_test(view_links_to_archives_impl());
+}
+
+async fn view_shows_download_counts_impl() {
+    let dir = tempfile::tempdir().expect("could not create tempdir for data");
+    std::fs::write(dir.path().join("a.txt"), "first file").expect("failed writing file");
+
+    let SpawnInfo {
+        ref url,
+        dir: ref _tempdir,
+        shutdown: _,
+    } = spawn_app_with(dir, |config| config.show_download_counts = true).await;
+
+    let res = reqwest::get(url.join("dl/a.txt").expect("valid url"))
+        .await
+        .expect("no error with reqwest");
+    res.bytes().await.expect("no error receiving file");
+
+    let selector = Selector::parse("td.downloads-column").expect("valid selector");
+    // The download is only recorded once the server is done sending it
+    for _ in 0..50 {
+        let res = reqwest::get(url.join("browse/").expect("valid url"))
+            .await
+            .expect("no error with reqwest");
+        assert_eq!(res.status(), StatusCode::OK);
+        let content = res.text().await.expect("no error receiving html");
+        let parser = Html::parse_document(&content);
+        let count = parser
+            .select(&selector)
+            .next()
+            .expect("view has a downloads column")
+            .text()
+            .collect::<String>();
+        if count == "1" {
+            return;
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+    }
+    panic!("download was never counted");
+}
+
+#[test]
+fn view_shows_download_counts() {
+    start_test(view_shows_download_counts_impl());
+}
+
+#[cfg(unix)]
+async fn view_shows_ownership_impl() {
+    use std::os::unix::fs::PermissionsExt as _;
+
+    let dir = tempfile::tempdir().expect("could not create tempdir for data");
+    let path = dir.path().join("a.txt");
+    std::fs::write(&path, "first file").expect("failed writing file");
+    std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o640))
+        .expect("failed setting permissions");
+
+    let SpawnInfo {
+        ref url,
+        dir: ref _tempdir,
+        shutdown: _,
+    } = spawn_app_with(dir, |config| config.show_ownership = true).await;
+
+    let res = reqwest::get(url.join("browse/").expect("valid url"))
+        .await
+        .expect("no error with reqwest");
+    assert_eq!(res.status(), StatusCode::OK);
+    let content = res.text().await.expect("no error receiving html");
+    let parser = Html::parse_document(&content);
+    let cell = |class: &str| {
+        let selector = Selector::parse(&format!("td.{class}")).expect("valid selector");
+        parser
+            .select(&selector)
+            .next()
+            .expect("column is shown")
+            .text()
+            .collect::<String>()
+    };
+    assert_eq!(cell("permissions-column"), "-rw-r-----");
+    assert!(!cell("owner-column").is_empty());
+    assert!(!cell("group-column").is_empty());
+}
+
+#[cfg(unix)]
+#[test]
+fn view_shows_ownership() {
+    start_test(view_shows_ownership_impl());
+}
+
+/// Waits until the view of `path` has a link to `href`, or doesn't have one if `present` is false
+async fn wait_for_link(url: &reqwest::Url, path: &str, href: &str, present: bool) {
+    let selector = Selector::parse(&format!("a[href=\"{href}\"]")).expect("valid selector");
+    // Changes are only noticed after the watcher's debounce time
+    for _ in 0..50 {
+        let res = reqwest::get(url.join(path).expect("valid url"))
+            .await
+            .expect("no error with reqwest");
+        // The directory itself might not be in the cache yet either
+        let found = if res.status() == StatusCode::OK {
+            let content = res.text().await.expect("no error receiving html");
+            Html::parse_document(&content)
+                .select(&selector)
+                .next()
+                .is_some()
+        } else {
+            false
+        };
+        if found == present {
+            return;
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+    }
+    panic!("link to {href} in {path} never changed");
+}
+
+async fn view_follows_file_changes_impl() {
+    let dir = tempfile::tempdir().expect("could not create tempdir for data");
+    let data_dir = dir.path().to_owned();
+    std::fs::create_dir_all(data_dir.join("sub")).expect("failed creating dirs");
+    std::fs::write(data_dir.join("sub/a.txt"), "first file").expect("failed writing file");
+
+    let SpawnInfo {
+        ref url,
+        dir: ref _tempdir,
+        shutdown: _,
+    } = spawn_app(dir).await;
+    // Give the watcher time to start
+    tokio::time::sleep(std::time::Duration::from_millis(500)).await;
+
+    std::fs::write(data_dir.join("sub/b.txt"), "second file").expect("failed writing file");
+    wait_for_link(url, "browse/sub/", "/dl/sub/b.txt", true).await;
+
+    std::fs::remove_file(data_dir.join("sub/a.txt")).expect("failed removing file");
+    wait_for_link(url, "browse/sub/", "/dl/sub/a.txt", false).await;
+
+    std::fs::create_dir_all(data_dir.join("new/nested")).expect("failed creating dirs");
+    std::fs::write(data_dir.join("new/nested/c.txt"), "third file").expect("failed writing file");
+    wait_for_link(url, "browse/new/nested/", "/dl/new/nested/c.txt", true).await;
+}
+
+#[test]
+fn view_follows_file_changes() {
+    start_test(view_follows_file_changes_impl());
+}
+
+async fn dir_size(url: &reqwest::Url, name: &str) -> String {
+    let res = reqwest::get(url.join("browse/").expect("valid url"))
+        .await
+        .expect("no error with reqwest");
+    assert_eq!(res.status(), StatusCode::OK);
+    let content = res.text().await.expect("no error receiving html");
+    let selector =
+        Selector::parse(&format!("tr[id=\"{name}-row\"] td.size-column")).expect("valid selector");
+    Html::parse_document(&content)
+        .select(&selector)
+        .next()
+        .expect("directory is listed")
+        .text()
+        .collect()
+}
+
+async fn dir_size_follows_changes_impl() {
+    let dir = tempfile::tempdir().expect("could not create tempdir for data");
+    let data_dir = dir.path().to_owned();
+    std::fs::create_dir_all(data_dir.join("sub/nested")).expect("failed creating dirs");
+    std::fs::write(data_dir.join("sub/a.txt"), "0123456789").expect("failed writing file");
+
+    let SpawnInfo {
+        ref url,
+        dir: ref _tempdir,
+        shutdown: _,
+    } = spawn_app(dir).await;
+    assert_eq!(dir_size(url, "sub").await, "10 B");
+    // Give the watcher time to start
+    tokio::time::sleep(std::time::Duration::from_millis(500)).await;
+
+    std::fs::write(data_dir.join("sub/nested/b.txt"), "01234567890123456789")
+        .expect("failed writing file");
+    // Changes are only noticed after the watcher's debounce time
+    for _ in 0..50 {
+        if dir_size(url, "sub").await == "30 B" {
+            return;
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+    }
+    panic!("size of sub never changed");
+}
+
+#[test]
+fn dir_size_follows_changes() {
+    start_test(dir_size_follows_changes_impl());
+}
+
+async fn unchanged_view_is_not_sent_again_impl() {
+    let dir = tempfile::tempdir().expect("could not create tempdir for data");
+    let data_dir = dir.path().to_owned();
+    std::fs::write(data_dir.join("a.txt"), "first file").expect("failed writing file");
+
+    let SpawnInfo {
+        ref url,
+        dir: ref _tempdir,
+        shutdown: _,
+    } = spawn_app(dir).await;
+    let client = reqwest::Client::new();
+    let browse_url = url.join("browse/").expect("valid url");
+
+    let response = client
+        .get(browse_url.clone())
+        .send()
+        .await
+        .expect("failed fetching view");
+    assert_eq!(response.status(), StatusCode::OK);
+    let etag = response
+        .headers()
+        .get("ETag")
+        .expect("view had no etag")
+        .clone();
+
+    let response = client
+        .get(browse_url.clone())
+        .header("If-None-Match", etag.clone())
+        .send()
+        .await
+        .expect("failed fetching view");
+    assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+
+    // Other sorts are different views
+    let response = client
+        .get(url.join("browse/?sort=size").expect("valid url"))
+        .header("If-None-Match", etag.clone())
+        .send()
+        .await
+        .expect("failed fetching view");
+    assert_eq!(response.status(), StatusCode::OK);
+
+    // Give the watcher time to start
+    tokio::time::sleep(std::time::Duration::from_millis(500)).await;
+    std::fs::write(data_dir.join("b.txt"), "second file").expect("failed writing file");
+    // Changes are only noticed after the watcher's debounce time
+    for _ in 0..50 {
+        let response = client
+            .get(browse_url.clone())
+            .header("If-None-Match", etag.clone())
+            .send()
+            .await
+            .expect("failed fetching view");
+        if response.status() == StatusCode::OK {
+            assert_ne!(response.headers().get("ETag"), Some(&etag));
+            return;
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+    }
+    panic!("view was never sent again after a change");
+}
+
+#[test]
+fn unchanged_view_is_not_sent_again() {
+    start_test(unchanged_view_is_not_sent_again_impl());
+}
+
+async fn polling_watcher_finds_changes_impl() {
+    let dir = tempfile::tempdir().expect("could not create tempdir for data");
+    let data_dir = dir.path().to_owned();
+    std::fs::write(data_dir.join("a.txt"), "first file").expect("failed writing file");
+
+    let SpawnInfo {
+        ref url,
+        dir: ref _tempdir,
+        shutdown: _,
+    } = spawn_app_with(dir, |config| {
+        config.poll_interval = Some(std::time::Duration::from_millis(100));
+    })
+    .await;
+    // Give the watcher time to start
+    tokio::time::sleep(std::time::Duration::from_millis(500)).await;
+
+    std::fs::write(data_dir.join("b.txt"), "second file").expect("failed writing file");
+    wait_for_link(url, "browse/", "/dl/b.txt", true).await;
+
+    std::fs::remove_file(data_dir.join("a.txt")).expect("failed removing file");
+    wait_for_link(url, "browse/", "/dl/a.txt", false).await;
+}
+
+#[test]
+fn polling_watcher_finds_changes() {
+    start_test(polling_watcher_finds_changes_impl());
+}
+
+async fn watch_errors_are_reported_impl() {
+    let dir = tempfile::tempdir().expect("could not create tempdir for data");
+    let data_dir = dir.path().to_owned();
+    std::fs::write(data_dir.join("a.txt"), "first file").expect("failed writing file");
+
+    let SpawnInfo {
+        ref url,
+        dir: ref _tempdir,
+        shutdown: _,
+    } = spawn_app_with(dir, |config| {
+        config.poll_interval = Some(std::time::Duration::from_millis(100));
+    })
+    .await;
+    let status: serde_json::Value = reqwest::get(url.join("api/cache").expect("valid url"))
+        .await
+        .expect("no error with reqwest")
+        .json()
+        .await
+        .expect("cache status is json");
+    assert_eq!(status["watch_errors"], serde_json::json!([]), "{status}");
+
+    // The watcher can't scan it anymore
+    std::fs::remove_dir_all(&data_dir).expect("failed removing data dir");
+    let mut tries = 0;
+    let errors = loop {
+        let status: serde_json::Value = reqwest::get(url.join("api/cache").expect("valid url"))
+            .await
+            .expect("no error with reqwest")
+            .json()
+            .await
+            .expect("cache status is json");
+        let errors = status["watch_errors"]
+            .as_array()
+            .expect("watch errors are a list")
+            .clone();
+        if !errors.is_empty() {
+            break errors;
+        }
+        tries += 1;
+        assert!(tries < 50, "watch error was not reported");
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+    };
+    let path = errors[0]["path"].as_str().expect("error has a path");
+    assert!(
+        std::path::Path::new(path).starts_with(&data_dir),
+        "{errors:?}"
+    );
+    assert!(errors[0]["error"].as_str().is_some(), "{errors:?}");
+}
+
+#[test]
+fn watch_errors_are_reported() {
+    start_test(watch_errors_are_reported_impl());
+}
+
+async fn refresh_timer_finds_unwatched_changes_impl() {
+    let dir = tempfile::tempdir().expect("could not create tempdir for data");
+    let data_dir = dir.path().to_owned();
+    std::fs::create_dir_all(data_dir.join("sub")).expect("failed creating dirs");
+
+    let SpawnInfo {
+        ref url,
+        dir: ref _tempdir,
+        shutdown: _,
+    } = spawn_app_with(dir, |config| {
+        config.recursive_watch = false;
+        config.refresh_interval = Some(std::time::Duration::from_millis(200));
+    })
+    .await;
+
+    // Not watched, so only the timer notices it
+    std::fs::write(data_dir.join("sub/a.txt"), "first file").expect("failed writing file");
+    wait_for_link(url, "browse/sub/", "/dl/sub/a.txt", true).await;
+}
+
+#[test]
+fn refresh_timer_finds_unwatched_changes() {
+    start_test(refresh_timer_finds_unwatched_changes_impl());
+}
+
+async fn shallow_cache_reads_dirs_when_browsed_impl() {
+    let dir = tempfile::tempdir().expect("could not create tempdir for data");
+    std::fs::create_dir_all(dir.path().join("deep/a/b")).expect("failed creating dirs");
+    std::fs::write(dir.path().join("deep/a/b/c.txt"), "deep file").expect("failed writing file");
+
+    let SpawnInfo {
+        ref url,
+        dir: ref _tempdir,
+        shutdown: _,
+    } = spawn_app_with(dir, |config| config.cache_depth = Some(0)).await;
+
+    let res = reqwest::get(url.join("browse/deep/a/b/").expect("valid url"))
+        .await
+        .expect("no error with reqwest");
+    assert_eq!(res.status(), StatusCode::OK);
+    let content = res.text().await.expect("no error receiving html");
+
+    let parser = Html::parse_document(&content);
+    let selector = Selector::parse("a[href=\"/dl/deep/a/b/c.txt\"]").expect("valid selector");
+    assert!(parser.select(&selector).next().is_some());
+}
+
+#[test]
+fn shallow_cache_reads_dirs_when_browsed() {
+    start_test(shallow_cache_reads_dirs_when_browsed_impl());
+}
+
+async fn shallow_cache_reads_dirs_for_requests_at_once_impl() {
+    let dir = tempfile::tempdir().expect("could not create tempdir for data");
+    std::fs::create_dir_all(dir.path().join("deep/a/b")).expect("failed creating dirs");
+    for i in 0..100 {
+        std::fs::write(dir.path().join(format!("deep/a/b/{i}.txt")), "deep file")
+            .expect("failed writing file");
+    }
+
+    let SpawnInfo {
+        ref url,
+        dir: ref _tempdir,
+        shutdown: _,
+    } = spawn_app_with(dir, |config| config.cache_depth = Some(0)).await;
+
+    // They all wait for the same read, and none of them see the directory half read
+    let requests = (0..8).map(|_| async {
+        reqwest::get(url.join("browse/deep/a/b/").expect("valid url"))
+            .await
+            .expect("no error with reqwest")
+            .text()
+            .await
+            .expect("no error receiving html")
+    });
+    for content in futures_util::future::join_all(requests).await {
+        let parser = Html::parse_document(&content);
+        for i in 0..100 {
+            let selector = Selector::parse(&format!("a[href=\"/dl/deep/a/b/{i}.txt\"]"))
+                .expect("valid selector");
+            assert!(parser.select(&selector).next().is_some());
+        }
+    }
+}
+
+#[test]
+fn shallow_cache_reads_dirs_for_requests_at_once() {
+    start_test(shallow_cache_reads_dirs_for_requests_at_once_impl());
+}
+
+#[cfg(unix)]
+async fn displayed_symlinks_are_marked_impl() {
+    use std::os::unix::fs::symlink;
+
+    let dir = tempfile::tempdir().expect("could not create tempdir for data");
+    std::fs::write(dir.path().join("a.txt"), "first file").expect("failed writing file");
+    symlink(dir.path().join("a.txt"), dir.path().join("alias.txt"))
+        .expect("failed creating symlink");
+
+    let SpawnInfo {
+        ref url,
+        dir: ref _tempdir,
+        shutdown: _,
+    } = spawn_app(dir).await;
+
+    let res = reqwest::get(url.join("browse/").expect("valid url"))
+        .await
+        .expect("no error with reqwest");
+    assert_eq!(res.status(), StatusCode::OK);
+    let content = res.text().await.expect("no error receiving html");
+    let parser = Html::parse_document(&content);
+    let selector = Selector::parse("td.name-column").expect("valid selector");
+    let names: Vec<String> = parser
+        .select(&selector)
+        .map(|e| e.text().collect::<String>().trim().to_owned())
+        .collect();
+    assert!(names.contains(&"alias.txt (symlink)".to_owned()));
+
+    let res = reqwest::get(url.join("dl/alias.txt").expect("valid url"))
+        .await
+        .expect("no error with reqwest");
+    assert_eq!(res.status(), StatusCode::FORBIDDEN);
+}
+
+#[cfg(unix)]
+#[test]
+fn displayed_symlinks_are_marked() {
+    start_test(displayed_symlinks_are_marked_impl());
+}
+
+async fn excluded_paths_are_hidden_impl() {
+    let dir = tempfile::tempdir().expect("could not create tempdir for data");
+    std::fs::write(dir.path().join("a.txt"), "first file").expect("failed writing file");
+    std::fs::write(dir.path().join("private.txt"), "secret").expect("failed writing file");
+    std::fs::write(dir.path().join(".sfsbignore"), "private.txt\n").expect("failed writing file");
+    std::fs::create_dir(dir.path().join("node_modules")).expect("failed creating dir");
+    std::fs::write(dir.path().join("node_modules/b.txt"), "second file")
+        .expect("failed writing file");
+
+    let SpawnInfo {
+        ref url,
+        dir: ref _tempdir,
+        shutdown: _,
+    } = spawn_app_with(dir, |config| {
+        config.exclude = vec!["node_modules".to_owned()]
+    })
+    .await;
+
+    let res = reqwest::get(url.join("browse/").expect("valid url"))
+        .await
+        .expect("no error with reqwest");
+    assert_eq!(res.status(), StatusCode::OK);
+    let content = res.text().await.expect("no error receiving html");
+    let parser = Html::parse_document(&content);
+    let selector = Selector::parse("td.name-column").expect("valid selector");
+    let names: Vec<String> = parser
+        .select(&selector)
+        .map(|e| e.text().collect::<String>().trim().to_owned())
+        .collect();
+    assert_eq!(names, ["a.txt"]);
+
+    for path in ["dl/private.txt", "dl/node_modules/b.txt", "dl/.sfsbignore"] {
+        let res = reqwest::get(url.join(path).expect("valid url"))
+            .await
+            .expect("no error with reqwest");
+        assert_eq!(res.status(), StatusCode::NOT_FOUND, "{path}");
+    }
+}
+
+#[test]
+fn excluded_paths_are_hidden() {
+    start_test(excluded_paths_are_hidden_impl());
+}
+
+async fn names_can_be_sorted_ignoring_case_impl() {
+    let dir = tempfile::tempdir().expect("could not create tempdir for data");
+    for name in ["b.txt", "A.txt", "C.txt"] {
+        std::fs::write(dir.path().join(name), name).expect("failed writing file");
+    }
+
+    let SpawnInfo {
+        ref url,
+        dir: ref _tempdir,
+        shutdown: _,
+    } = spawn_app(dir).await;
+
+    for (query, expected) in [
+        ("", ["A.txt", "C.txt", "b.txt"]),
+        ("?icase", ["A.txt", "b.txt", "C.txt"]),
+        ("?sort=name&ord=desc&icase", ["C.txt", "b.txt", "A.txt"]),
+    ] {
+        let res = reqwest::get(url.join(&format!("browse/{query}")).expect("valid url"))
+            .await
+            .expect("no error with reqwest");
+        assert_eq!(res.status(), StatusCode::OK);
+        let content = res.text().await.expect("no error receiving html");
+        let parser = Html::parse_document(&content);
+        let selector = Selector::parse("td.name-column").expect("valid selector");
+        let names: Vec<String> = parser
+            .select(&selector)
+            .map(|e| e.text().collect::<String>().trim().to_owned())
+            .collect();
+        assert_eq!(names, expected, "{query}");
+    }
+}
+
+#[test]
+fn names_can_be_sorted_ignoring_case() {
+    start_test(names_can_be_sorted_ignoring_case_impl());
+}
+
+async fn entries_can_be_filtered_by_name_impl() {
+    let dir = tempfile::tempdir().expect("could not create tempdir for data");
+    for name in ["Report.pdf", "notes.txt", "old_report.txt"] {
+        std::fs::write(dir.path().join(name), name).expect("failed writing file");
+    }
+    std::fs::create_dir(dir.path().join("reports")).expect("failed creating dir");
+
+    let SpawnInfo {
+        ref url,
+        dir: ref _tempdir,
+        shutdown: _,
+    } = spawn_app(dir).await;
+
+    for (query, expected) in [
+        (
+            "?filter=report",
+            &["Report.pdf", "old_report.txt", "reports"][..],
+        ),
+        ("?filter=.TXT", &["notes.txt", "old_report.txt"][..]),
+        ("?filter=missing", &[][..]),
+        (
+            "?filter=",
+            &["Report.pdf", "notes.txt", "old_report.txt", "reports"][..],
+        ),
+    ] {
+        let res = reqwest::get(url.join(&format!("browse/{query}")).expect("valid url"))
+            .await
+            .expect("no error with reqwest");
+        assert_eq!(res.status(), StatusCode::OK);
+        let content = res.text().await.expect("no error receiving html");
+        let parser = Html::parse_document(&content);
+        let selector = Selector::parse("td.name-column").expect("valid selector");
+        let names: Vec<String> = parser
+            .select(&selector)
+            .map(|e| e.text().collect::<String>().trim().to_owned())
+            .collect();
+        assert_eq!(names, expected, "{query}");
+    }
+}
+
+#[test]
+fn entries_can_be_filtered_by_name() {
+    start_test(entries_can_be_filtered_by_name_impl());
+}
+
+async fn search_finds_nested_entries_impl() {
+    let dir = tempfile::tempdir().expect("could not create tempdir for data");
+    std::fs::create_dir_all(dir.path().join("docs/reports")).expect("failed creating dirs");
+    std::fs::write(dir.path().join("docs/reports/q1.pdf"), "q1").expect("failed writing file");
+    std::fs::write(dir.path().join("docs/Notes.txt"), "notes").expect("failed writing file");
+    std::fs::write(dir.path().join("report.txt"), "report").expect("failed writing file");
+
+    let SpawnInfo {
+        ref url,
+        dir: ref _tempdir,
+        shutdown: _,
+    } = spawn_app(dir).await;
+
+    for (query, expected) in [
+        ("report", &["/browse/docs/reports/", "/dl/report.txt"][..]),
+        ("*.TXT", &["/dl/docs/Notes.txt", "/dl/report.txt"][..]),
+        ("q?.pdf", &["/dl/docs/reports/q1.pdf"][..]),
+        ("missing", &[][..]),
+    ] {
+        let res = reqwest::get(url.join(&format!("search?q={query}")).expect("valid url"))
+            .await
+            .expect("no error with reqwest");
+        assert_eq!(res.status(), StatusCode::OK);
+        let content = res.text().await.expect("no error receiving html");
+        let parser = Html::parse_document(&content);
+        let selector = Selector::parse("td.path-column a").expect("valid selector");
+        let mut links: Vec<&str> = parser
+            .select(&selector)
+            .filter_map(|e| e.value().attr("href"))
+            .collect();
+        links.sort_unstable();
+        assert_eq!(links, expected, "{query}");
+    }
+}
+
+#[test]
+fn search_finds_nested_entries() {
+    start_test(search_finds_nested_entries_impl());
+}
+
+async fn listing_can_be_json_impl() {
+    let dir = tempfile::tempdir().expect("could not create tempdir for data");
+    std::fs::create_dir_all(dir.path().join("sub/nested")).expect("failed creating dirs");
+    std::fs::write(dir.path().join("sub/a b.txt"), "0123456789").expect("failed writing file");
+
+    let SpawnInfo {
+        ref url,
+        dir: ref _tempdir,
+        shutdown: _,
+    } = spawn_app(dir).await;
+
+    let client = reqwest::Client::new();
+    for request in [
+        client.get(url.join("browse/sub/?format=json").expect("valid url")),
+        client
+            .get(url.join("browse/sub/").expect("valid url"))
+            .header("Accept", "application/json"),
+    ] {
+        let res = request.send().await.expect("no error with reqwest");
+        assert_eq!(res.status(), StatusCode::OK);
+        let listing: serde_json::Value =
+            serde_json::from_str(&res.text().await.expect("no error receiving json"))
+                .expect("listing was not json");
+        assert_eq!(listing["path"], "sub");
+
+        let entries = listing["entries"]
+            .as_array()
+            .expect("entries were not a list");
+        assert_eq!(entries.len(), 2);
+        let file = &entries[0];
+        assert_eq!(file["name"], "a b.txt");
+        assert_eq!(file["type"], "file");
+        assert_eq!(file["size"], 10);
+        // Urls are made from the base url, not the address it was fetched from
+        assert_eq!(file["download_url"], "http://localhost/dl/sub/a%20b.txt");
+        let nested = &entries[1];
+        assert_eq!(nested["name"], "nested");
+        assert_eq!(nested["type"], "dir");
+        assert_eq!(nested["children_count"], 0);
+        assert_eq!(nested["browse_url"], "http://localhost/browse/sub/nested/");
+    }
+}
+
+#[test]
+fn listing_can_be_json() {
+    start_test(listing_can_be_json_impl());
+}
+
+async fn json_listing_has_checksums_impl() {
+    use md5::Md5;
+    use sha2::{Digest as _, Sha256};
+
+    let dir = tempfile::tempdir().expect("could not create tempdir for data");
+    std::fs::create_dir_all(dir.path().join("sub")).expect("failed creating dirs");
+    std::fs::write(dir.path().join("a.txt"), "first file").expect("failed writing file");
+
+    let SpawnInfo {
+        ref url,
+        dir: ref _tempdir,
+        shutdown: _,
+    } = spawn_app_with(dir, |config| config.hash_files = true).await;
+
+    // Files are hashed in the background after startup
+    let mut tries = 0;
+    let (etag, listing) = loop {
+        let res = reqwest::get(url.join("browse/?format=json").expect("valid url"))
+            .await
+            .expect("no error with reqwest");
+        assert_eq!(res.status(), StatusCode::OK);
+        let etag = res.headers().get("etag").cloned();
+        let listing: serde_json::Value =
+            serde_json::from_str(&res.text().await.expect("no error receiving json"))
+                .expect("listing was not json");
+        if listing["entries"][0]["sha256"].is_string() || tries == 50 {
+            break (etag, listing);
+        }
+        // Until then it changes without the cache changing
+        assert_eq!(etag, None);
+        tries += 1;
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+    };
+
+    let file = &listing["entries"][0];
+    assert_eq!(file["name"], "a.txt");
+    assert_eq!(
+        file["sha256"],
+        format!("{:x}", Sha256::digest("first file"))
+    );
+    assert_eq!(file["md5"], format!("{:x}", Md5::digest("first file")));
+    let sub = &listing["entries"][1];
+    assert_eq!(sub["name"], "sub");
+    assert_eq!(sub["sha256"], serde_json::Value::Null);
+    assert!(etag.is_some(), "hashed listings can be cached");
+}
+
+#[test]
+fn json_listing_has_checksums() {
+    start_test(json_listing_has_checksums_impl());
+}
+
+async fn recent_shows_newest_files_impl() {
+    let dir = tempfile::tempdir().expect("could not create tempdir for data");
+    std::fs::create_dir_all(dir.path().join("sub/nested")).expect("failed creating dirs");
+    for path in ["old.txt", "sub/middle.txt", "sub/nested/new.txt"] {
+        std::fs::write(dir.path().join(path), path).expect("failed writing file");
+        // So every file has a different creation time
+        std::thread::sleep(std::time::Duration::from_millis(50));
+    }
+
+    let SpawnInfo {
+        ref url,
+        dir: ref _tempdir,
+        shutdown: _,
+    } = spawn_app(dir).await;
+
+    for (query, expected) in [
+        (
+            "recent",
+            &["sub/nested/new.txt", "sub/middle.txt", "old.txt"][..],
+        ),
+        (
+            "recent?count=2",
+            &["sub/nested/new.txt", "sub/middle.txt"][..],
+        ),
+    ] {
+        let res = reqwest::get(url.join(query).expect("valid url"))
+            .await
+            .expect("no error with reqwest");
+        assert_eq!(res.status(), StatusCode::OK);
+        let content = res.text().await.expect("no error receiving html");
+        let parser = Html::parse_document(&content);
+        let selector = Selector::parse("td.path-column").expect("valid selector");
+        let paths: Vec<String> = parser
+            .select(&selector)
+            .map(|e| e.text().collect::<String>().trim().to_owned())
+            .collect();
+        assert_eq!(paths, expected, "{query}");
+    }
+}
+
+#[test]
+fn recent_shows_newest_files() {
+    start_test(recent_shows_newest_files_impl());
+}
+
+async fn tree_shows_everything_below_impl() {
+    let dir = tempfile::tempdir().expect("could not create tempdir for data");
+    std::fs::create_dir_all(dir.path().join("a/b/c")).expect("failed creating dirs");
+    std::fs::write(dir.path().join("a/b/c/deep.txt"), "deep").expect("failed writing file");
+    std::fs::write(dir.path().join("top.txt"), "top").expect("failed writing file");
+
+    let SpawnInfo {
+        ref url,
+        dir: ref _tempdir,
+        shutdown: _,
+    } = spawn_app(dir).await;
+
+    for (path, expected) in [
+        (
+            "tree/",
+            &[
+                "/browse/a/",
+                "/browse/a/b/",
+                "/browse/a/b/c/",
+                "/dl/a/b/c/deep.txt",
+                "/dl/top.txt",
+            ][..],
+        ),
+        ("tree/a/b/", &["/browse/a/b/c/", "/dl/a/b/c/deep.txt"][..]),
+    ] {
+        let res = reqwest::get(url.join(path).expect("valid url"))
+            .await
+            .expect("no error with reqwest");
+        assert_eq!(res.status(), StatusCode::OK);
+        let content = res.text().await.expect("no error receiving html");
+        let parser = Html::parse_document(&content);
+        let selector = Selector::parse("ul.tree a").expect("valid selector");
+        let links: Vec<&str> = parser
+            .select(&selector)
+            .filter_map(|e| e.value().attr("href"))
+            .collect();
+        assert_eq!(links, expected, "{path}");
+    }
+
+    for path in ["tree/top.txt", "tree/missing/"] {
+        let res = reqwest::get(url.join(path).expect("valid url"))
+            .await
+            .expect("no error with reqwest");
+        assert_eq!(res.status(), StatusCode::NOT_FOUND, "{path}");
+    }
+}
+
+#[test]
+fn tree_shows_everything_below() {
+    start_test(tree_shows_everything_below_impl());
+}
+
+async fn readme_is_rendered_impl(render_readme: bool) {
+    let dir = tempfile::tempdir().expect("could not create tempdir for data");
+    std::fs::write(
+        dir.path().join("README.md"),
+        "# Releases\n\nGet them here<script>alert(1)</script>\n",
+    )
+    .expect("failed writing file");
+
+    let SpawnInfo {
+        ref url,
+        dir: ref _tempdir,
+        shutdown: _,
+    } = spawn_app_with(dir, |config| config.render_readme = render_readme).await;
+
+    let res = reqwest::get(url.join("browse/").expect("valid url"))
+        .await
+        .expect("no error with reqwest");
+    assert_eq!(res.status(), StatusCode::OK);
+    let content = res.text().await.expect("no error receiving html");
+    let parser = Html::parse_document(&content);
+    let selector = Selector::parse("div.readme h1").expect("valid selector");
+    let headings: Vec<String> = parser
+        .select(&selector)
+        .map(|e| e.text().collect::<String>())
+        .collect();
+    if render_readme {
+        assert_eq!(headings, ["Releases"]);
+    } else {
+        assert!(headings.is_empty());
+    }
+    assert!(!content.contains("<script>"));
+}
+
+#[test]
+fn readme_is_rendered() {
+    start_test(readme_is_rendered_impl(true));
+}
+
+#[test]
+fn readme_is_not_rendered_when_disabled() {
+    start_test(readme_is_rendered_impl(false));
+}
+
+async fn site_is_branded_impl() {
+    let dir = tempfile::tempdir().expect("could not create tempdir for data");
+    let logo_dir = tempfile::tempdir().expect("could not create tempdir for logo");
+    let logo_path = logo_dir.path().join("logo.svg");
+    std::fs::write(&logo_path, "<svg></svg>").expect("failed writing file");
+    let logo_path = Utf8PathBuf::from_path_buf(logo_path).expect("tempdir is UTF-8");
+
+    let SpawnInfo {
+        ref url,
+        dir: ref _tempdir,
+        shutdown: _,
+    } = spawn_app_with(dir, |config| {
+        config.site_title = Some("Family files".to_owned());
+        config.tagline = Some("Photos & <videos>".to_owned());
+        config.logo = Some(logo_path);
+    })
+    .await;
+
+    let res = reqwest::get(url.join("browse/").expect("valid url"))
+        .await
+        .expect("no error with reqwest");
+    assert_eq!(res.status(), StatusCode::OK);
+    let content = res.text().await.expect("no error receiving html");
+    let parser = Html::parse_document(&content);
+    let text = |selector: &str| {
+        let selector = Selector::parse(selector).expect("valid selector");
+        parser
+            .select(&selector)
+            .map(|e| e.text().collect::<String>())
+            .collect::<Vec<_>>()
+    };
+    assert!(text("title")[0].starts_with("Family files - "));
+    assert_eq!(text(".site-title"), ["Family files"]);
+    assert_eq!(text(".tagline"), ["Photos & <videos>"]);
+
+    let res = reqwest::get(url.join("logo").expect("valid url"))
+        .await
+        .expect("no error with reqwest");
+    assert_eq!(res.status(), StatusCode::OK);
+    assert_eq!(res.headers()[CONTENT_TYPE], "image/svg+xml");
+    assert_eq!(
+        res.text().await.expect("no error receiving logo"),
+        "<svg></svg>"
+    );
+}
+
+#[test]
+fn site_is_branded() {
+    start_test(site_is_branded_impl());
+}
+
+async fn templates_can_be_replaced_impl() {
+    let dir = tempfile::tempdir().expect("could not create tempdir for data");
+    std::fs::write(dir.path().join("a&b.txt"), "first file").expect("failed writing file");
+    let template_dir = tempfile::tempdir().expect("could not create tempdir for templates");
+    std::fs::write(
+        template_dir.path().join("dir_view.html"),
+        "<title>{{ site_title }}</title><ul>{% for entry in entries %}\
+         <li class=\"entry\">{{ entry.name }} {{ entry.size }}</li>{% endfor %}</ul>",
+    )
+    .expect("failed writing file");
+    std::fs::write(
+        template_dir.path().join("style.css"),
+        "body { color: red; }",
+    )
+    .expect("failed writing file");
+    let template_dir =
+        Utf8PathBuf::from_path_buf(template_dir.path().to_owned()).expect("tempdir is UTF-8");
+
+    let SpawnInfo {
+        ref url,
+        dir: ref _tempdir,
+        shutdown: _,
+    } = spawn_app_with(dir, |config| config.template_dir = Some(template_dir)).await;
+
+    let res = reqwest::get(url.join("browse/").expect("valid url"))
+        .await
+        .expect("no error with reqwest");
+    assert_eq!(res.status(), StatusCode::OK);
+    let content = res.text().await.expect("no error receiving html");
+    let parser = Html::parse_document(&content);
+    let selector = Selector::parse("li.entry").expect("valid selector");
+    let entries: Vec<String> = parser
+        .select(&selector)
+        .map(|e| e.text().collect::<String>())
+        .collect();
+    assert_eq!(entries, ["a&b.txt 10"]);
+    assert!(content.starts_with("<title>sfsb</title>"));
+
+    // Pages without a template in the dir are still the embedded ones
+    let res = reqwest::get(url.join("search?q=txt").expect("valid url"))
+        .await
+        .expect("no error with reqwest");
+    assert_eq!(res.status(), StatusCode::OK);
+    let content = res.text().await.expect("no error receiving html");
+    assert!(content.contains("path-column"));
+
+    let res = reqwest::get(url.join("theme/style.css").expect("valid url"))
+        .await
+        .expect("no error with reqwest");
+    assert_eq!(res.status(), StatusCode::OK);
+    assert_eq!(res.headers()[CONTENT_TYPE], "text/css");
+    let res = reqwest::get(url.join("theme/dir_view.html").expect("valid url"))
+        .await
+        .expect("no error with reqwest");
+    assert_eq!(res.status(), StatusCode::NOT_FOUND);
+}
+
+#[test]
+fn templates_can_be_replaced() {
+    start_test(templates_can_be_replaced_impl());
+}
+
+async fn color_scheme_can_be_picked_impl() {
+    let SpawnInfo {
+        ref url,
+        dir: ref _tempdir,
+        shutdown: _,
+    } = spawn_app_empty().await;
+    let data_theme = |content: &str| {
+        let parser = Html::parse_document(content);
+        let selector = Selector::parse("html").expect("valid selector");
+        parser
+            .select(&selector)
+            .next()
+            .and_then(|e| e.value().attr("data-theme"))
+            .map(str::to_owned)
+    };
+
+    let res = reqwest::get(url.join("browse/").expect("valid url"))
+        .await
+        .expect("no error with reqwest");
+    assert!(res.headers().get(SET_COOKIE).is_none());
+    let content = res.text().await.expect("no error receiving html");
+    assert_eq!(data_theme(&content), None);
+    assert!(content.contains("/colors.css"));
+
+    let res = reqwest::get(url.join("browse/?theme=dark").expect("valid url"))
+        .await
+        .expect("no error with reqwest");
+    let cookie = res.headers()[SET_COOKIE]
+        .to_str()
+        .expect("cookie is text")
+        .to_owned();
+    assert!(cookie.starts_with("theme=dark;"));
+    let content = res.text().await.expect("no error receiving html");
+    assert_eq!(data_theme(&content).as_deref(), Some("dark"));
+
+    // The cookie keeps it for the other pages
+    let res = reqwest::Client::new()
+        .get(url.join("search").expect("valid url"))
+        .header(COOKIE, "theme=dark")
+        .send()
+        .await
+        .expect("no error with reqwest");
+    let content = res.text().await.expect("no error receiving html");
+    assert_eq!(data_theme(&content).as_deref(), Some("dark"));
+
+    let res = reqwest::get(url.join("static/colors.css").expect("valid url"))
+        .await
+        .expect("no error with reqwest");
+    assert_eq!(res.status(), StatusCode::OK);
+    assert_eq!(res.headers()[CONTENT_TYPE], "text/css");
+}
+
+#[test]
+fn color_scheme_can_be_picked() {
+    start_test(color_scheme_can_be_picked_impl());
+}
+
+async fn listing_is_summarised_impl() {
+    let dir = tempfile::tempdir().expect("could not create tempdir for data");
+    std::fs::create_dir_all(dir.path().join("docs/empty")).expect("failed creating dir");
+    std::fs::write(dir.path().join("docs/a.txt"), "first file").expect("failed writing file");
+    std::fs::create_dir(dir.path().join("docs/more")).expect("failed creating dir");
+    std::fs::write(dir.path().join("docs/more/b.txt"), "second").expect("failed writing file");
+
+    let SpawnInfo {
+        ref url,
+        dir: ref _tempdir,
+        shutdown: _,
+    } = spawn_app(dir).await;
+    let text = |content: &str, selector: &str| {
+        let parser = Html::parse_document(content);
+        let selector = Selector::parse(selector).expect("valid selector");
+        parser
+            .select(&selector)
+            .map(|e| e.text().collect::<String>().trim().to_owned())
+            .collect::<Vec<_>>()
+    };
+
+    let res = reqwest::get(url.join("browse/docs/").expect("valid url"))
+        .await
+        .expect("no error with reqwest");
+    let content = res.text().await.expect("no error receiving html");
+    assert_eq!(
+        text(&content, "footer.summary"),
+        ["2 directories, 1 file, 16 B total"]
+    );
+    assert!(text(&content, ".empty-listing").is_empty());
+
+    let res = reqwest::get(url.join("browse/docs/empty/").expect("valid url"))
+        .await
+        .expect("no error with reqwest");
+    let content = res.text().await.expect("no error receiving html");
+    assert_eq!(
+        text(&content, ".empty-listing"),
+        ["This directory is empty"]
+    );
+    assert!(text(&content, "footer.summary").is_empty());
+    assert!(text(&content, "table").is_empty());
+
+    let res = reqwest::get(url.join("browse/docs/?filter=missing").expect("valid url"))
+        .await
+        .expect("no error with reqwest");
+    let content = res.text().await.expect("no error receiving html");
+    assert_eq!(
+        text(&content, ".empty-listing"),
+        ["Nothing in this directory matches the filter"]
+    );
+}
+
+#[test]
+fn listing_is_summarised() {
+    start_test(listing_is_summarised_impl());
+}
+
+async fn free_space_is_shown_impl(show_free_space: bool) {
+    let dir = tempfile::tempdir().expect("could not create tempdir for data");
+    std::fs::write(dir.path().join("a.txt"), "first file").expect("failed writing file");
+
+    let SpawnInfo {
+        ref url,
+        dir: ref _tempdir,
+        shutdown: _,
+    } = spawn_app_with(dir, |config| config.show_free_space = show_free_space).await;
+
+    let res = reqwest::get(url.join("browse/").expect("valid url"))
+        .await
+        .expect("no error with reqwest");
+    let content = res.text().await.expect("no error receiving html");
+    let parser = Html::parse_document(&content);
+    let selector = Selector::parse(".free-space").expect("valid selector");
+    let free_space: Vec<String> = parser
+        .select(&selector)
+        .map(|e| e.text().collect::<String>())
+        .collect();
+    if show_free_space {
+        assert_eq!(free_space.len(), 1);
+        assert!(free_space[0].ends_with(" free"));
+    } else {
+        assert!(free_space.is_empty());
+    }
+}
+
+#[test]
+fn free_space_is_shown() {
+    start_test(free_space_is_shown_impl(true));
+}
+
+#[test]
+fn free_space_is_not_shown_by_default() {
+    start_test(free_space_is_shown_impl(false));
+}
+
+async fn times_can_be_relative_impl() {
+    let dir = tempfile::tempdir().expect("could not create tempdir for data");
+    std::fs::write(dir.path().join("a.txt"), "first file").expect("failed writing file");
+
+    let SpawnInfo {
+        ref url,
+        dir: ref _tempdir,
+        shutdown: _,
+    } = spawn_app_with(dir, |config| config.relative_times = true).await;
+    let times = |content: &str| {
+        let parser = Html::parse_document(content);
+        let selector = Selector::parse("td.creation-time-column").expect("valid selector");
+        parser
+            .select(&selector)
+            .map(|e| e.text().collect::<String>().trim().to_owned())
+            .collect::<Vec<_>>()
+    };
+
+    let res = reqwest::get(url.join("browse/").expect("valid url"))
+        .await
+        .expect("no error with reqwest");
+    assert!(res.headers().get("ETag").is_none());
+    let content = res.text().await.expect("no error receiving html");
+    assert_eq!(times(&content), ["just now"]);
+    let parser = Html::parse_document(&content);
+    let selector = Selector::parse("td.creation-time-column time").expect("valid selector");
+    let title = parser
+        .select(&selector)
+        .next()
+        .and_then(|e| e.value().attr("title"))
+        .expect("time has a title");
+    assert!(title.ends_with(" UTC"));
+
+    let res = reqwest::get(url.join("browse/?times=exact").expect("valid url"))
+        .await
+        .expect("no error with reqwest");
+    let content = res.text().await.expect("no error receiving html");
+    let times = times(&content);
+    assert_eq!(times.len(), 1);
+    assert!(times[0].ends_with(']'));
+}
+
+#[test]
+fn times_can_be_relative() {
+    start_test(times_can_be_relative_impl());
+}
+
+async fn date_format_can_be_configured_impl() {
+    let dir = tempfile::tempdir().expect("could not create tempdir for data");
+    std::fs::write(dir.path().join("a.txt"), "first file").expect("failed writing file");
+
+    let SpawnInfo {
+        ref url,
+        dir: ref _tempdir,
+        shutdown: _,
+    } = spawn_app_with(dir, |config| {
+        config.date_format = "%d/%m/%Y".to_owned();
+        config.timezone = sfsb::Tz::Europe__Madrid;
+    })
+    .await;
+
+    let res = reqwest::get(url.join("browse/").expect("valid url"))
+        .await
+        .expect("no error with reqwest");
+    let content = res.text().await.expect("no error receiving html");
+    let parser = Html::parse_document(&content);
+    let selector = Selector::parse("td.creation-time-column").expect("valid selector");
+    let time = parser
+        .select(&selector)
+        .map(|e| e.text().collect::<String>().trim().to_owned())
+        .next()
+        .expect("file has a creation time");
+    assert_eq!(time.len(), "01/01/2024".len());
+    assert_eq!(&time[2..3], "/");
+    assert_eq!(&time[5..6], "/");
+
+    let res = reqwest::get(url.join("browse/?times=relative").expect("valid url"))
+        .await
+        .expect("no error with reqwest");
+    let content = res.text().await.expect("no error receiving html");
+    let parser = Html::parse_document(&content);
+    let selector = Selector::parse("td.creation-time-column time").expect("valid selector");
+    let title = parser
+        .select(&selector)
+        .next()
+        .and_then(|e| e.value().attr("title"))
+        .expect("time has a title");
+    assert_eq!(title, format!("{time} Europe/Madrid"));
+}
+
+#[test]
+fn date_format_can_be_configured() {
+    start_test(date_format_can_be_configured_impl());
+}
+
+async fn markdown_files_are_viewed_as_pages_impl() {
+    let dir = tempfile::tempdir().expect("could not create tempdir for data");
+    std::fs::create_dir(dir.path().join("docs")).expect("failed creating dir");
+    std::fs::write(
+        dir.path().join("docs/guide.md"),
+        "# Guide\n\n<img src=x onerror=alert(1)>\n",
+    )
+    .expect("failed writing file");
+    std::fs::write(dir.path().join("docs/a.txt"), "first file").expect("failed writing file");
+    std::fs::write(dir.path().join("docs/data.bin"), [0, 1, 2]).expect("failed writing file");
+
+    let SpawnInfo {
+        ref url,
+        dir: ref _tempdir,
+        shutdown: _,
+    } = spawn_app(dir).await;
+
+    let res = reqwest::get(url.join("browse/docs/").expect("valid url"))
+        .await
+        .expect("no error with reqwest");
+    let content = res.text().await.expect("no error receiving html");
+    let parser = Html::parse_document(&content);
+    for href in ["/view/docs/guide.md", "/dl/docs/a.txt"] {
+        let selector = Selector::parse(&format!("a[href=\"{href}\"]")).expect("valid selector");
+        assert!(
+            parser.select(&selector).next().is_some(),
+            "no link to {href}"
+        );
+    }
+
+    let res = reqwest::get(url.join("view/docs/guide.md").expect("valid url"))
+        .await
+        .expect("no error with reqwest");
+    assert_eq!(res.status(), StatusCode::OK);
+    let content = res.text().await.expect("no error receiving html");
+    assert!(!content.contains("onerror"));
+    let parser = Html::parse_document(&content);
+    let selector = Selector::parse("main h1").expect("valid selector");
+    let headings: Vec<String> = parser
+        .select(&selector)
+        .map(|e| e.text().collect::<String>())
+        .collect();
+    assert_eq!(headings, ["Guide"]);
+
+    // Anything that can't be shown is downloaded
+    let res = reqwest::get(url.join("view/docs/data.bin").expect("valid url"))
+        .await
+        .expect("no error with reqwest");
+    assert_eq!(res.status(), StatusCode::OK);
+    assert_eq!(res.url().path(), "/dl/docs/data.bin");
+
+    let res = reqwest::get(url.join("view/docs/missing.md").expect("valid url"))
+        .await
+        .expect("no error with reqwest");
+    assert_eq!(res.status(), StatusCode::NOT_FOUND);
+}
+
+#[test]
+fn markdown_files_are_viewed_as_pages() {
+    start_test(markdown_files_are_viewed_as_pages_impl());
+}
+
+async fn code_files_are_highlighted_impl() {
+    let dir = tempfile::tempdir().expect("could not create tempdir for data");
+    std::fs::write(
+        dir.path().join("main.rs"),
+        "fn main() {\n    println!(\"<b>hi</b>\");\n}\n",
+    )
+    .expect("failed writing file");
+
+    let SpawnInfo {
+        ref url,
+        dir: ref _tempdir,
+        shutdown: _,
+    } = spawn_app(dir).await;
+
+    let res = reqwest::get(url.join("browse/").expect("valid url"))
+        .await
+        .expect("no error with reqwest");
+    let content = res.text().await.expect("no error receiving html");
+    let parser = Html::parse_document(&content);
+    for href in ["/dl/main.rs", "/view/main.rs"] {
+        let selector = Selector::parse(&format!("a[href=\"{href}\"]")).expect("valid selector");
+        assert!(
+            parser.select(&selector).next().is_some(),
+            "no link to {href}"
+        );
+    }
+
+    let res = reqwest::get(url.join("view/main.rs").expect("valid url"))
+        .await
+        .expect("no error with reqwest");
+    assert_eq!(res.status(), StatusCode::OK);
+    let content = res.text().await.expect("no error receiving html");
+    let parser = Html::parse_document(&content);
+    let selector = Selector::parse("pre.code .line-number").expect("valid selector");
+    let numbers: Vec<String> = parser
+        .select(&selector)
+        .map(|e| e.text().collect::<String>())
+        .collect();
+    assert_eq!(numbers, ["1", "2", "3"]);
+    let selector = Selector::parse("pre.code").expect("valid selector");
+    let code: String = parser.select(&selector).flat_map(|e| e.text()).collect();
+    assert!(code.contains("println!(\"<b>hi</b>\");"));
+    // Highlighted, so it's split into colored spans
+    let selector = Selector::parse("pre.code span[style]").expect("valid selector");
+    assert!(parser.select(&selector).next().is_some());
+}
+
+#[test]
+fn code_files_are_highlighted() {
+    start_test(code_files_are_highlighted_impl());
+}
+
+async fn media_files_are_played_impl() {
+    let dir = tempfile::tempdir().expect("could not create tempdir for data");
+    std::fs::write(dir.path().join("clip.mp4"), "not really a video").expect("failed writing file");
+    std::fs::write(dir.path().join("song.mp3"), "not really a song").expect("failed writing file");
+
+    let SpawnInfo {
+        ref url,
+        dir: ref _tempdir,
+        shutdown: _,
+    } = spawn_app(dir).await;
+
+    let res = reqwest::get(url.join("browse/").expect("valid url"))
+        .await
+        .expect("no error with reqwest");
+    let content = res.text().await.expect("no error receiving html");
+    let parser = Html::parse_document(&content);
+    for href in ["/view/clip.mp4", "/view/song.mp3"] {
+        let selector = Selector::parse(&format!("a[href=\"{href}\"]")).expect("valid selector");
+        assert!(
+            parser.select(&selector).next().is_some(),
+            "no link to {href}"
+        );
+    }
+
+    for (path, player, src) in [
+        ("view/clip.mp4", "video", "/dl/clip.mp4?inline"),
+        ("view/song.mp3", "audio", "/dl/song.mp3?inline"),
+    ] {
+        let res = reqwest::get(url.join(path).expect("valid url"))
+            .await
+            .expect("no error with reqwest");
+        assert_eq!(res.status(), StatusCode::OK);
+        let content = res.text().await.expect("no error receiving html");
+        let parser = Html::parse_document(&content);
+        let selector = Selector::parse(&format!("{player}.player")).expect("valid selector");
+        let element = parser.select(&selector).next().expect("no player");
+        assert_eq!(element.value().attr("src"), Some(src), "{path}");
+    }
+}
+
+#[test]
+fn media_files_are_played() {
+    start_test(media_files_are_played_impl());
+}
+
+#[cfg(unix)]
+async fn breadcrumbs_are_escaped_impl() {
+    let dir = tempfile::tempdir().expect("could not create tempdir for data");
+    std::fs::create_dir_all(dir.path().join("<em>bold/50% #1")).expect("failed creating dirs");
+    std::fs::write(dir.path().join("<em>bold/50% #1/a.txt"), "first file")
+        .expect("failed writing file");
+
+    let SpawnInfo {
+        ref url,
+        dir: ref _tempdir,
+        shutdown: _,
+    } = spawn_app(dir).await;
+
+    let res = reqwest::get(
+        url.join("browse/%3Cem%3Ebold/50%25%20%231/")
+            .expect("valid url"),
+    )
+    .await
+    .expect("no error with reqwest");
+    assert_eq!(res.status(), StatusCode::OK);
+    let content = res.text().await.expect("no error receiving html");
+    assert!(!content.contains("<em>bold"));
+    let parser = Html::parse_document(&content);
+    let selector = Selector::parse("a > strong").expect("valid selector");
+    let crumbs: Vec<(String, Option<&str>)> = parser
+        .select(&selector)
+        .filter_map(|e| {
+            let link = scraper::ElementRef::wrap(e.parent()?)?;
+            Some((e.text().collect(), link.value().attr("href")))
+        })
+        .collect();
+    assert_eq!(
+        crumbs,
+        [
+            ("<em>bold".to_owned(), Some("/browse/%3Cem%3Ebold")),
+            (
+                "50% #1".to_owned(),
+                Some("/browse/%3Cem%3Ebold/50%25%20%231")
+            ),
+        ]
+    );
+}
+
+#[cfg(unix)]
+#[test]
+fn breadcrumbs_are_escaped() {
+    start_test(breadcrumbs_are_escaped_impl());
+}
+
+async fn gallery_shows_images_as_thumbnails_impl() {
+    let dir = tempfile::tempdir().expect("could not create tempdir for data");
+    for name in ["b.PNG", "a.jpg", "notes.txt"] {
+        std::fs::write(dir.path().join(name), name).expect("failed writing file");
+    }
+    std::fs::create_dir(dir.path().join("more")).expect("failed creating dir");
+
+    let SpawnInfo {
+        ref url,
+        dir: ref _tempdir,
+        shutdown: _,
+    } = spawn_app(dir).await;
+
+    let res = reqwest::get(url.join("browse/?view=gallery").expect("valid url"))
+        .await
+        .expect("no error with reqwest");
+    assert_eq!(res.status(), StatusCode::OK);
+    let content = res.text().await.expect("no error receiving html");
+    let parser = Html::parse_document(&content);
+
+    let selector = Selector::parse(".gallery img").expect("valid selector");
+    let thumbnails: Vec<&str> = parser
+        .select(&selector)
+        .filter_map(|e| e.value().attr("src"))
+        .collect();
+    assert_eq!(thumbnails, ["/thumb/a.jpg?w=256", "/thumb/b.PNG?w=256"]);
+
+    // Every picture can be opened, and goes to the ones next to it
+    let selector = Selector::parse(".lightbox").expect("valid selector");
+    let ids: Vec<&str> = parser
+        .select(&selector)
+        .filter_map(|e| e.value().id())
+        .collect();
+    assert_eq!(ids, ["image-1", "image-2"]);
+    let selector = Selector::parse("#image-1 .lightbox-next").expect("valid selector");
+    let next = parser
+        .select(&selector)
+        .next()
+        .expect("no link to the next picture");
+    assert_eq!(next.value().attr("href"), Some("#image-2"));
+
+    let selector = Selector::parse(".gallery-others a").expect("valid selector");
+    let others: Vec<&str> = parser
+        .select(&selector)
+        .filter_map(|e| e.value().attr("href"))
+        .collect();
+    assert_eq!(others, ["/browse/more/?view=gallery", "/dl/notes.txt"]);
+}
+
+#[test]
+fn gallery_shows_images_as_thumbnails() {
+    start_test(gallery_shows_images_as_thumbnails_impl());
+}
+
+async fn several_data_dirs_are_top_level_dirs_impl() {
+    let dir = tempfile::tempdir().expect("could not create tempdir for data");
+    std::fs::write(dir.path().join("a.txt"), "first file").expect("failed writing file");
+    let other_dir = tempfile::tempdir().expect("could not create tempdir for data");
+    std::fs::write(other_dir.path().join("b.txt"), "second file").expect("failed writing file");
+    let other_path = Utf8Path::from_path(other_dir.path())
+        .expect("temp path was not UTF-8")
+        .to_path_buf();
+
+    let SpawnInfo {
+        ref url,
+        dir: ref _tempdir,
+        shutdown: _,
+    } = spawn_app_with(dir, |config| {
+        config.data_dirs[0].name = Some("movies".to_owned());
+        config.data_dirs.push(sfsb::DataDir {
+            name: Some("music".to_owned()),
+            path: other_path,
+            hidden: false,
+            preview_only: false,
+        });
+    })
+    .await;
+
+    let res = reqwest::get(url.join("browse/").expect("valid url"))
+        .await
+        .expect("no error with reqwest");
+    assert_eq!(res.status(), StatusCode::OK);
+    let content = res.text().await.expect("no error receiving html");
+    let parser = Html::parse_document(&content);
+    for href in ["/browse/movies/", "/browse/music/"] {
+        let selector = Selector::parse(&format!("a[href=\"{href}\"]")).expect("valid selector");
+        assert!(parser.select(&selector).next().is_some(), "{href}");
+    }
+
+    for (path, contents) in [
+        ("dl/movies/a.txt", "first file"),
+        ("dl/music/b.txt", "second file"),
+    ] {
+        let res = reqwest::get(url.join(path).expect("valid url"))
+            .await
+            .expect("no error with reqwest");
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(res.text().await.expect("no error receiving body"), contents);
+    }
+}
+
+#[test]
+fn several_data_dirs_are_top_level_dirs() {
+    start_test(several_data_dirs_are_top_level_dirs_impl());
+}
+
+async fn hidden_data_dirs_are_left_out_of_the_root_impl() {
+    let dir = tempfile::tempdir().expect("could not create tempdir for data");
+    std::fs::write(dir.path().join("a.txt"), "first file").expect("failed writing file");
+    let other_dir = tempfile::tempdir().expect("could not create tempdir for data");
+    std::fs::write(other_dir.path().join("b.txt"), "second file").expect("failed writing file");
+    let other_path = Utf8Path::from_path(other_dir.path())
+        .expect("temp path was not UTF-8")
+        .to_path_buf();
+    let hidden: sfsb::DataDir = format!("music:hidden={other_path}")
+        .parse()
+        .expect("valid data dir");
+    assert!(hidden.hidden && !hidden.preview_only);
+
+    let SpawnInfo {
+        ref url,
+        dir: ref _tempdir,
+        shutdown: _,
+    } = spawn_app_with(dir, |config| {
+        config.data_dirs[0].name = Some("movies".to_owned());
+        config.data_dirs.push(hidden);
+    })
+    .await;
+
+    let res = reqwest::get(url.join("browse/").expect("valid url"))
+        .await
+        .expect("no error with reqwest");
+    assert_eq!(res.status(), StatusCode::OK);
+    let content = res.text().await.expect("no error receiving html");
+    let parser = Html::parse_document(&content);
+    let selector = Selector::parse("a[href=\"/browse/movies/\"]").expect("valid selector");
+    assert!(parser.select(&selector).next().is_some());
+    let selector = Selector::parse("a[href=\"/browse/music/\"]").expect("valid selector");
+    assert!(parser.select(&selector).next().is_none());
+
+    let res = reqwest::get(url.join("search?q=b.txt").expect("valid url"))
+        .await
+        .expect("no error with reqwest");
+    assert_eq!(res.status(), StatusCode::OK);
+    let content = res.text().await.expect("no error receiving html");
+    assert!(!content.contains("/dl/music/b.txt"));
+
+    // It can still be reached by knowing where it is
+    let res = reqwest::get(url.join("browse/music/").expect("valid url"))
+        .await
+        .expect("no error with reqwest");
+    assert_eq!(res.status(), StatusCode::OK);
+    let content = res.text().await.expect("no error receiving html");
+    assert!(content.contains("/dl/music/b.txt"));
+}
+
+#[test]
+fn hidden_data_dirs_are_left_out_of_the_root() {
+    start_test(hidden_data_dirs_are_left_out_of_the_root_impl());
+}
+
+async fn empty_dir_provides_no_views_impl(path: &Path) {
+    let SpawnInfo {
+        ref url,
+        dir: ref _tempdir,
+        shutdown: _,
+    } = spawn_app_empty().await;
+
+    let mut url = url.clone();
+    let mut segments = url.path_segments_mut().expect("url is not a base");
+    for segment in path {
+        let segment = segment.to_str().expect("segment was not UTF-8");
+        segments.push(segment);
+    }
+    drop(segments);
+    let res = reqwest::get(url.clone())
+        .await
+        .expect("no error with reqwest");
+    assert_eq!(res.status(), StatusCode::NOT_FOUND);
+}
+
+proptest! {
+    #[test]
+    fn empty_dir_view_only_works_on_root(path in "\\PC+") {
+        let path = PathBuf::from(path);
+        prop_assume!(path != PathBuf::from("."));
+
+        start_test(empty_dir_provides_no_views_impl(&path));
+    }
+}
+
+async fn pages_are_translated_impl() {
+    let summary = |content: &str| {
+        let parser = Html::parse_document(content);
+        let selector = Selector::parse("footer.summary").expect("valid selector");
+        let lang = Selector::parse("html").expect("valid selector");
+        (
+            parser
+                .select(&lang)
+                .next()
+                .and_then(|e| e.value().attr("lang"))
+                .map(str::to_owned),
+            parser
+                .select(&selector)
+                .map(|e| e.text().collect::<String>().trim().to_owned())
+                .collect::<Vec<_>>(),
+        )
+    };
+    let files = || {
+        let dir = tempfile::tempdir().expect("could not create tempdir for data");
+        std::fs::create_dir(dir.path().join("docs")).expect("failed creating dir");
+        std::fs::write(dir.path().join("a.txt"), "first file").expect("failed writing file");
+        dir
+    };
+
+    let SpawnInfo {
+        ref url,
+        dir: ref _tempdir,
+        shutdown: _,
+    } = spawn_app(files()).await;
+
+    let content = reqwest::get(url.join("browse/").expect("valid url"))
+        .await
+        .expect("no error with reqwest")
+        .text()
+        .await
+        .expect("no error receiving html");
+    assert_eq!(
+        summary(&content),
+        (
+            Some("en".to_owned()),
+            vec!["1 directory, 1 file, 10 B total".to_owned()]
+        )
+    );
+
+    // The first language that there's a translation for is picked
+    let res = reqwest::Client::new()
+        .get(url.join("browse/").expect("valid url"))
+        .header(ACCEPT_LANGUAGE, "nl-BE, es;q=0.8, en;q=0.5")
+        .send()
+        .await
+        .expect("no error with reqwest");
+    let content = res.text().await.expect("no error receiving html");
+    assert_eq!(
+        summary(&content),
+        (
+            Some("es".to_owned()),
+            vec!["1 directorio, 1 archivo, 10 B en total".to_owned()]
+        )
+    );
+    assert!(content.contains("[Buscar]"));
+
+    let SpawnInfo {
+        ref url,
+        dir: ref _tempdir,
+        shutdown: _,
+    } = spawn_app_with(files(), |config| config.locale = Some(sfsb::Locale::De)).await;
+
+    // The locale of the config is used whatever the browser asks for
+    let res = reqwest::Client::new()
+        .get(url.join("browse/").expect("valid url"))
+        .header(ACCEPT_LANGUAGE, "es")
+        .send()
+        .await
+        .expect("no error with reqwest");
+    let content = res.text().await.expect("no error receiving html");
+    assert_eq!(
+        summary(&content),
+        (
+            Some("de".to_owned()),
+            vec!["1 Verzeichnis, 1 Datei, 10 B insgesamt".to_owned()]
+        )
+    );
+}
+
+#[test]
+fn pages_are_translated() {
+    start_test(pages_are_translated_impl());
+}
+
+async fn listing_can_be_fetched_alone_impl() {
+    let dir = tempfile::tempdir().expect("could not create tempdir for data");
+    std::fs::create_dir(dir.path().join("docs")).expect("failed creating dir");
+    std::fs::write(dir.path().join("a.txt"), "first file").expect("failed writing file");
+    std::fs::write(dir.path().join("b.txt"), "second").expect("failed writing file");
+
+    let SpawnInfo {
+        ref url,
+        dir: ref _tempdir,
+        shutdown: _,
+    } = spawn_app(dir).await;
+    let names = |content: &str| {
+        let parser = Html::parse_fragment(content);
+        let selector = Selector::parse("#listing td.name-column").expect("valid selector");
+        parser
+            .select(&selector)
+            .map(|e| e.text().collect::<String>().trim().to_owned())
+            .collect::<Vec<_>>()
+    };
+
+    let res = reqwest::get(url.join("browse/?sort=name&ord=desc").expect("valid url"))
+        .await
+        .expect("no error with reqwest");
+    let page_etag = res.headers()["ETag"].clone();
+    let content = res.text().await.expect("no error receiving html");
+    assert!(content.contains("/listing.js"));
+    assert_eq!(names(&content), ["docs", "b.txt", "a.txt"]);
+    let parser = Html::parse_document(&content);
+    let selector = Selector::parse("th a[data-fragment]").expect("valid selector");
+    assert!(parser.select(&selector).next().is_some());
+
+    let res = reqwest::get(
+        url.join("browse/?sort=name&ord=desc&fragment")
+            .expect("valid url"),
+    )
+    .await
+    .expect("no error with reqwest");
+    assert_eq!(res.status(), StatusCode::OK);
+    assert_ne!(res.headers()["ETag"], page_etag);
+    let content = res.text().await.expect("no error receiving html");
+    let content = content.trim();
+    assert!(content.starts_with("<main id=\"listing\""));
+    assert!(content.ends_with("</main>"));
+    assert!(!content.contains("<head>"));
+    assert_eq!(names(content), ["docs", "b.txt", "a.txt"]);
+
+    let res = reqwest::get(url.join("static/listing.js").expect("valid url"))
+        .await
+        .expect("no error with reqwest");
+    assert_eq!(res.status(), StatusCode::OK);
+    assert_eq!(res.headers()[CONTENT_TYPE], "text/javascript");
+}
+
+#[test]
+fn listing_can_be_fetched_alone() {
+    start_test(listing_can_be_fetched_alone_impl());
+}
+
+async fn qr_code_links_to_entries_impl() {
+    let dir = tempfile::tempdir().expect("could not create tempdir for data");
+    std::fs::create_dir(dir.path().join("docs")).expect("failed creating dir");
+    std::fs::write(dir.path().join("docs/a.txt"), "first file").expect("failed writing file");
+
+    let SpawnInfo {
+        ref url,
+        dir: ref _tempdir,
+        shutdown: _,
+    } = spawn_app(dir).await;
+
+    let res = reqwest::get(url.join("browse/docs/").expect("valid url"))
+        .await
+        .expect("no error with reqwest");
+    let content = res.text().await.expect("no error receiving html");
+    let parser = Html::parse_document(&content);
+    let selector = Selector::parse("a[href^=\"/qr?target=\"]").expect("valid selector");
+    let href = parser
+        .select(&selector)
+        .next()
+        .and_then(|e| e.value().attr("href"))
+        .expect("directory links to its QR code")
+        .to_owned();
+
+    for target in [href.as_str(), "qr?target=/dl/docs/a.txt"] {
+        let res = reqwest::get(url.join(target).expect("valid url"))
+            .await
+            .expect("no error with reqwest");
+        assert_eq!(res.status(), StatusCode::OK, "{target}");
+        assert_eq!(res.headers()[CONTENT_TYPE], "image/svg+xml");
+        let content = res.text().await.expect("no error receiving svg");
+        assert!(content.contains("<svg"));
+    }
+
+    for target in [
+        "qr?target=/search",
+        "qr?target=https%3A%2F%2Fexample.com%2Fdl%2Fa.txt",
+    ] {
+        let res = reqwest::get(url.join(target).expect("valid url"))
+            .await
+            .expect("no error with reqwest");
+        assert_eq!(res.status(), StatusCode::NOT_FOUND, "{target}");
+    }
+}
+
+#[test]
+fn qr_code_links_to_entries() {
+    start_test(qr_code_links_to_entries_impl());
+}
+
+async fn links_have_previews_impl() {
+    let dir = tempfile::tempdir().expect("could not create tempdir for data");
+    std::fs::create_dir(dir.path().join("docs")).expect("failed creating dir");
+    std::fs::write(dir.path().join("docs/notes.md"), "# Notes").expect("failed writing file");
+    std::fs::write(dir.path().join("docs/pic.png"), "not really").expect("failed writing file");
+
+    let SpawnInfo {
+        ref url,
+        dir: ref _tempdir,
+        shutdown: _,
+    } = spawn_app(dir).await;
+    let meta = |content: &str, property: &str| {
+        let parser = Html::parse_document(content);
+        let selector =
+            Selector::parse(&format!("meta[property=\"{property}\"]")).expect("valid selector");
+        parser
+            .select(&selector)
+            .next()
+            .and_then(|e| e.value().attr("content"))
+            .map(str::to_owned)
+    };
+
+    let res = reqwest::get(url.join("browse/docs/").expect("valid url"))
+        .await
+        .expect("no error with reqwest");
+    let content = res.text().await.expect("no error receiving html");
+    assert_eq!(
+        meta(&content, "og:description").as_deref(),
+        Some("0 directories, 2 files, 17 B total")
+    );
+    let image = meta(&content, "og:image").expect("directory with a picture has an image");
+    assert!(image.ends_with("/thumb/docs/pic.png?w=512"), "{image}");
+
+    let res = reqwest::get(url.join("browse/").expect("valid url"))
+        .await
+        .expect("no error with reqwest");
+    let content = res.text().await.expect("no error receiving html");
+    assert_eq!(meta(&content, "og:image"), None);
+
+    let res = reqwest::get(url.join("view/docs/notes.md").expect("valid url"))
+        .await
+        .expect("no error with reqwest");
+    let content = res.text().await.expect("no error receiving html");
+    assert_eq!(meta(&content, "og:title").as_deref(), Some("notes.md"));
+    assert_eq!(meta(&content, "og:description").as_deref(), Some("7 B"));
+    let page_url = meta(&content, "og:url").expect("view has a url");
+    assert!(page_url.ends_with("/view/docs/notes.md"), "{page_url}");
+
+    let mut oembed = url.join("oembed").expect("valid url");
+    oembed.query_pairs_mut().append_pair("url", &page_url);
+    let res = reqwest::get(oembed).await.expect("no error with reqwest");
+    assert_eq!(res.status(), StatusCode::OK);
+    let body: serde_json::Value =
+        serde_json::from_str(&res.text().await.expect("no error receiving json"))
+            .expect("oEmbed is JSON");
+    assert_eq!(body["title"], "notes.md (7 B)");
+}
+
+#[test]
+fn links_have_previews() {
+    start_test(links_have_previews_impl());
 }
 
-impl Drop for SpawnInfo {
-    fn drop(&mut self) {
-        let (tx, _) = oneshot::channel();
-        let old = std::mem::replace(&mut self.shutdown, tx);
-        old.send(()).unwrap();
+async fn static_files_are_linked_with_their_hash_impl() {
+    let dir = tempfile::tempdir().expect("could not create tempdir for data");
+    std::fs::write(dir.path().join("a.md"), "# A").expect("failed writing file");
+
+    let SpawnInfo {
+        ref url,
+        dir: ref _tempdir,
+        shutdown: _,
+    } = spawn_app(dir).await;
+
+    for page in ["browse/", "view/a.md", "search", "recent", "tree/"] {
+        let res = reqwest::get(url.join(page).expect("valid url"))
+            .await
+            .expect("no error with reqwest");
+        let content = res.text().await.expect("no error receiving html");
+        assert!(!content.contains("<style>"), "{page} has inline styles");
+        let parser = Html::parse_document(&content);
+        let selector =
+            Selector::parse("link[rel=stylesheet], script[src]").expect("valid selector");
+        let links: Vec<_> = parser
+            .select(&selector)
+            .filter_map(|e| e.value().attr("href").or_else(|| e.value().attr("src")))
+            .map(str::to_owned)
+            .collect();
+        assert!(links.len() >= 2, "{page} links to {links:?}");
+
+        for link in links {
+            assert!(link.starts_with("/static/"), "{link}");
+            assert!(link.contains("?v="), "{link}");
+            let res = reqwest::get(url.join(&link).expect("valid url"))
+                .await
+                .expect("no error with reqwest");
+            assert_eq!(res.status(), StatusCode::OK, "{link}");
+            assert!(res.headers()[CACHE_CONTROL]
+                .to_str()
+                .expect("cache control is text")
+                .contains("immutable"));
+        }
     }
+
+    let res = reqwest::get(url.join("static/missing.css").expect("valid url"))
+        .await
+        .expect("no error with reqwest");
+    assert_eq!(res.status(), StatusCode::NOT_FOUND);
+
+    let res = reqwest::get(url.join("favicon.ico").expect("valid url"))
+        .await
+        .expect("no error with reqwest");
+    assert_eq!(res.status(), StatusCode::OK);
+}
+
+#[test]
+fn static_files_are_linked_with_their_hash() {
+    start_test(static_files_are_linked_with_their_hash_impl());
 }
 
-async fn spawn_app_empty() -> SpawnInfo {
+async fn errors_are_pages_for_browsers_impl() {
     let dir = tempfile::tempdir().expect("could not create tempdir for data");
-    let data_dir = Utf8Path::from_path(dir.path())
-        .expect("temp path was not UTF-8")
-        .to_path_buf();
-    let listener = tokio::net::TcpListener::bind((IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 0))
+    std::fs::create_dir(dir.path().join("docs")).expect("failed creating dir");
+
+    let SpawnInfo {
+        ref url,
+        dir: ref _tempdir,
+        shutdown: _,
+    } = spawn_app(dir).await;
+    let missing = url.join("view/docs/missing.md").expect("valid url");
+
+    let res = reqwest::Client::new()
+        .get(missing.clone())
+        .header(ACCEPT, "text/html,application/xhtml+xml")
+        .send()
         .await
-        .expect("failed binding to port");
-    let addr = listener.local_addr().expect("had local addr");
-    let (tx, rx) = oneshot::channel();
+        .expect("no error with reqwest");
+    assert_eq!(res.status(), StatusCode::NOT_FOUND);
+    assert!(res.headers()[CONTENT_TYPE]
+        .to_str()
+        .expect("content type is text")
+        .starts_with("text/html"));
+    let content = res.text().await.expect("no error receiving html");
+    let parser = Html::parse_document(&content);
+    let selector = Selector::parse("h1").expect("valid selector");
+    let title = parser
+        .select(&selector)
+        .next()
+        .map(|e| e.text().collect::<String>())
+        .expect("page has a title");
+    assert_eq!(title, "404 Not Found");
+    let selector = Selector::parse(".error-message").expect("valid selector");
+    let message = parser
+        .select(&selector)
+        .next()
+        .map(|e| e.text().collect::<String>())
+        .expect("page says what went wrong");
+    assert!(message.contains("does not exist"), "{message}");
+    let selector = Selector::parse(".breadcrumbs a").expect("valid selector");
+    let links: Vec<_> = parser
+        .select(&selector)
+        .filter_map(|e| e.value().attr("href"))
+        .collect();
+    assert_eq!(links, ["/browse/", "/browse/docs"]);
+
+    // Anything else still gets the error as plain text
+    let res = reqwest::get(missing).await.expect("no error with reqwest");
+    assert_eq!(res.status(), StatusCode::NOT_FOUND);
+    assert!(res.headers()[CONTENT_TYPE]
+        .to_str()
+        .expect("content type is text")
+        .starts_with("text/plain"));
+    let content = res.text().await.expect("no error receiving text");
+    assert!(content.contains("does not exist"));
+}
+
+#[test]
+fn errors_are_pages_for_browsers() {
+    start_test(errors_are_pages_for_browsers_impl());
+}
+
+async fn files_have_info_pages_impl() {
+    use sha2::{Digest as _, Sha256};
+
+    let dir = tempfile::tempdir().expect("could not create tempdir for data");
+    std::fs::create_dir(dir.path().join("docs")).expect("failed creating dir");
+    std::fs::write(dir.path().join("docs/notes.txt"), "some notes").expect("failed writing file");
+    image::RgbImage::new(30, 20)
+        .save(dir.path().join("docs/pic.png"))
+        .expect("failed writing picture");
 
-    let config = sfsb::AppConfig {
-        base_url: Url::parse("http://localhost").expect("valid url"),
-        data_dir,
-        listener,
-        shutdown: Some(rx),
+    let SpawnInfo {
+        ref url,
+        dir: ref _tempdir,
+        shutdown: _,
+    } = spawn_app_with(dir, |config| config.hash_files = true).await;
+    let text = |parser: &Html, selector: &str| {
+        let selector = Selector::parse(selector).expect("valid selector");
+        parser
+            .select(&selector)
+            .next()
+            .map(|e| e.text().collect::<String>())
     };
 
-    tokio::spawn(sfsb::run_app(config));
-    let port = addr.port();
+    let res = reqwest::get(url.join("browse/docs/").expect("valid url"))
+        .await
+        .expect("no error with reqwest");
+    let parser = Html::parse_document(&res.text().await.expect("no error receiving html"));
+    let selector = Selector::parse("a[href=\"/info/docs/notes.txt\"]").expect("valid selector");
+    assert!(
+        parser.select(&selector).next().is_some(),
+        "listing links to the info"
+    );
+
+    // Files are hashed in the background after startup
+    let mut parser;
+    let mut tries = 0;
+    loop {
+        let res = reqwest::get(url.join("info/docs/notes.txt").expect("valid url"))
+            .await
+            .expect("no error with reqwest");
+        assert_eq!(res.status(), StatusCode::OK);
+        parser = Html::parse_document(&res.text().await.expect("no error receiving html"));
+        if text(&parser, "#sha256 code").is_some() || tries == 50 {
+            break;
+        }
+        tries += 1;
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+    }
+    assert_eq!(text(&parser, "#size").as_deref(), Some("10 B (10 bytes)"));
+    assert_eq!(
+        text(&parser, "#content-type").as_deref(),
+        Some("text/plain")
+    );
+    assert_eq!(
+        text(&parser, "#sha256 code"),
+        Some(format!("{:x}", Sha256::digest("some notes")))
+    );
+    assert_eq!(text(&parser, "#dimensions"), None);
+    let selector = Selector::parse("#share-download").expect("valid selector");
+    let share = parser
+        .select(&selector)
+        .next()
+        .and_then(|e| e.value().attr("value"))
+        .expect("info has a share link");
+    assert!(share.ends_with("/dl/docs/notes.txt"), "{share}");
+
+    let res = reqwest::get(url.join("info/docs/pic.png").expect("valid url"))
+        .await
+        .expect("no error with reqwest");
+    let parser = Html::parse_document(&res.text().await.expect("no error receiving html"));
+    assert_eq!(text(&parser, "#dimensions").as_deref(), Some("30 × 20"));
+
+    let res = reqwest::get(url.join("info/docs").expect("valid url"))
+        .await
+        .expect("no error with reqwest");
+    assert_eq!(res.status(), StatusCode::NOT_FOUND);
+}
+
+#[test]
+fn files_have_info_pages() {
+    start_test(files_have_info_pages_impl());
+}
+
+async fn listing_shows_checksums_impl() {
+    use sha2::{Digest as _, Sha256};
+
+    let dir = tempfile::tempdir().expect("could not create tempdir for data");
+    std::fs::create_dir(dir.path().join("sub")).expect("failed creating dir");
+    std::fs::write(dir.path().join("a.txt"), "first file").expect("failed writing file");
+
+    let SpawnInfo {
+        ref url,
+        dir: ref _tempdir,
+        shutdown: _,
+    } = spawn_app_with(dir, |config| config.show_checksums = true).await;
+    let checksum = Selector::parse("#a\\.txt-row td.checksum-column code").expect("valid selector");
 
-    SpawnInfo {
-        url: Url::parse(&format!("http://localhost:{port}")).expect("valid url"),
-        dir,
-        shutdown: tx,
+    // Files are hashed in the background after startup
+    let mut parser;
+    let mut tries = 0;
+    loop {
+        let res = reqwest::get(url.clone())
+            .await
+            .expect("no error with reqwest");
+        parser = Html::parse_document(&res.text().await.expect("no error receiving html"));
+        if parser.select(&checksum).next().is_some() || tries == 50 {
+            break;
+        }
+        tries += 1;
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
     }
+    let sha256 = format!("{:x}", Sha256::digest("first file"));
+    let code = parser.select(&checksum).next().expect("file was hashed");
+    assert_eq!(code.value().attr("title"), Some(sha256.as_str()));
+
+    let copies: Vec<_> = parser
+        .select(&Selector::parse("#a\\.txt-row button.copy").expect("valid selector"))
+        .filter_map(|e| e.value().attr("data-copy"))
+        .collect();
+    assert!(copies.contains(&sha256.as_str()), "{copies:?}");
+    assert!(
+        copies.iter().any(|link| link.ends_with("/dl/a.txt")),
+        "{copies:?}"
+    );
+    let selector = Selector::parse("#sub-row button.copy").expect("valid selector");
+    let link = parser
+        .select(&selector)
+        .next()
+        .and_then(|e| e.value().attr("data-copy"))
+        .expect("directory has a link to copy");
+    assert!(link.ends_with("/browse/sub/"), "{link}");
+
+    let dir = tempfile::tempdir().expect("could not create tempdir for data");
+    std::fs::write(dir.path().join("a.txt"), "first file").expect("failed writing file");
+    let SpawnInfo {
+        ref url,
+        dir: ref _tempdir,
+        shutdown: _,
+    } = spawn_app(dir).await;
+    let res = reqwest::get(url.clone())
+        .await
+        .expect("no error with reqwest");
+    let parser = Html::parse_document(&res.text().await.expect("no error receiving html"));
+    let selector = Selector::parse(".checksum-column").expect("valid selector");
+    assert!(parser.select(&selector).next().is_none());
+}
+
+#[test]
+fn listing_shows_checksums() {
+    start_test(listing_shows_checksums_impl());
 }
 
-// Every test of the app needs to be ran using the multi threaded runtime, because otherwise the
-// test task has to yield to the scheduler for the scheduler to poll the shutdown task, on the
-// event of a shutdown, which would involve manually adding a sleep, which I think is jankier and
-// more cumbersome than this workaround. However, the test can run on a single thread, so we just
-// have a single worker thread.
-fn start_test(func: impl Future<Output = ()>) {
-    {
-        return tokio::runtime::Builder::new_multi_thread()
-            .worker_threads(1usize)
-            .enable_all()
-            .build()
-            .expect("Failed building the Runtime")
-            .block_on(func);
+async fn missing_directories_are_not_found_impl() {
+    let dir = tempfile::tempdir().expect("could not create tempdir for data");
+    std::fs::create_dir(dir.path().join("docs")).expect("failed creating dir");
+    std::fs::write(dir.path().join("docs/a.txt"), "first file").expect("failed writing file");
+
+    let SpawnInfo {
+        ref url,
+        dir: ref _tempdir,
+        shutdown: _,
+    } = spawn_app(dir).await;
+    let client = reqwest::Client::builder()
+        .redirect(reqwest::redirect::Policy::none())
+        .build()
+        .expect("valid client");
+
+    for path in [
+        "browse/missing/",
+        "browse/docs/missing",
+        "browse/docs/missing/deeper/than/anything",
+    ] {
+        let res = client
+            .get(url.join(path).expect("valid url"))
+            .header(ACCEPT, "text/html")
+            .send()
+            .await
+            .expect("no error with reqwest");
+        assert_eq!(res.status(), StatusCode::NOT_FOUND, "{path}");
+        let content = res.text().await.expect("no error receiving html");
+        let parser = Html::parse_document(&content);
+        let selector = Selector::parse("h1").expect("valid selector");
+        let title = parser
+            .select(&selector)
+            .next()
+            .map(|e| e.text().collect::<String>());
+        assert_eq!(title.as_deref(), Some("404 Not Found"), "{path}");
     }
+
+    // Files are still downloaded
+    let res = client
+        .get(url.join("browse/docs/a.txt").expect("valid url"))
+        .send()
+        .await
+        .expect("no error with reqwest");
+    assert_eq!(res.status(), StatusCode::PERMANENT_REDIRECT);
+    assert_eq!(res.headers()["location"], "/dl/docs/a.txt");
 }
 
-async fn empty_view_produces_valid_html_impl() {
+#[test]
+fn missing_directories_are_not_found() {
+    start_test(missing_directories_are_not_found_impl());
+}
+
+async fn aria2_lists_files_where_they_are_impl() {
+    let dir = tempfile::tempdir().expect("could not create tempdir for data");
+    std::fs::create_dir_all(dir.path().join("docs/sub dir")).expect("failed creating dirs");
+    std::fs::write(dir.path().join("top.txt"), "top").expect("failed writing file");
+    std::fs::write(dir.path().join("docs/a.txt"), "first file").expect("failed writing file");
+    std::fs::write(dir.path().join("docs/sub dir/b.txt"), "second file")
+        .expect("failed writing file");
+
     let SpawnInfo {
         ref url,
         dir: ref _tempdir,
         shutdown: _,
-    } = spawn_app_empty().await;
+    } = spawn_app(dir).await;
 
-    let res = reqwest::get(url.clone())
+    let res = reqwest::get(url.join("browse/docs/?aria2").expect("valid url"))
         .await
         .expect("no error with reqwest");
     assert_eq!(res.status(), StatusCode::OK);
+    assert_eq!(
+        res.text().await.expect("no error receiving text"),
+        "http://localhost/dl/docs/a.txt\n  dir=docs\n  out=a.txt\n\n\
+         http://localhost/dl/docs/sub%20dir/b.txt\n  dir=docs/sub dir\n  out=b.txt\n\n"
+    );
 
-    let bytes = res.bytes().await.expect("no error receiving html");
-    let content: &[u8] = &bytes.slice(..);
-    let content = std::str::from_utf8(content).expect("response html was not UTF-8");
+    let res = reqwest::get(url.join("browse/docs/sub%20dir/?aria2").expect("valid url"))
+        .await
+        .expect("no error with reqwest");
+    assert_eq!(
+        res.text().await.expect("no error receiving text"),
+        "http://localhost/dl/docs/sub%20dir/b.txt\n  dir=docs/sub dir\n  out=b.txt\n\n"
+    );
 
-    let parser = Html::parse_document(content);
-    // Force fully parsing the file
-    let _ = parser.html();
-    assert_eq!(parser.errors, Vec::<&str>::new());
+    let res = reqwest::get(url.join("browse/?aria2").expect("valid url"))
+        .await
+        .expect("no error with reqwest");
+    let list = res.text().await.expect("no error receiving text");
+    assert!(
+        list.starts_with("http://localhost/dl/top.txt\n  dir=.\n  out=top.txt\n\n"),
+        "{list}"
+    );
+    assert!(
+        list.contains("http://localhost/dl/docs/a.txt\n  dir=docs\n"),
+        "{list}"
+    );
 }
 
 #[test]
-fn empty_view_produces_valid_html() {
-    start_test(empty_view_produces_valid_html_impl());
+fn aria2_lists_files_where_they_are() {
+    start_test(aria2_lists_files_where_they_are_impl());
 }
 
-async fn empty_dir_provides_no_views_impl(path: &Path) {
+async fn aria2_can_be_filtered_impl() {
+    let dir = tempfile::tempdir().expect("could not create tempdir for data");
+    std::fs::create_dir_all(dir.path().join("isos/old/older")).expect("failed creating dirs");
+    std::fs::write(dir.path().join("isos/a.iso"), "a").expect("failed writing file");
+    std::fs::write(dir.path().join("isos/a.txt"), "a").expect("failed writing file");
+    std::fs::write(dir.path().join("isos/old/b.iso"), "b").expect("failed writing file");
+    std::fs::write(dir.path().join("isos/old/older/c.iso"), "c").expect("failed writing file");
+
     let SpawnInfo {
         ref url,
         dir: ref _tempdir,
         shutdown: _,
-    } = spawn_app_empty().await;
+    } = spawn_app(dir).await;
+    let urls = |list: &str| -> Vec<String> {
+        list.lines()
+            .filter(|line| line.starts_with("http"))
+            .map(str::to_owned)
+            .collect()
+    };
 
-    let mut url = url.clone();
-    let mut segments = url.path_segments_mut().expect("url is not a base");
-    for segment in path {
-        let segment = segment.to_str().expect("segment was not UTF-8");
-        segments.push(segment);
-    }
-    drop(segments);
-    let res = reqwest::get(url.clone())
+    let res = reqwest::get(
+        url.join("browse/isos/?aria2&max_depth=2&include=*.iso")
+            .expect("valid url"),
+    )
+    .await
+    .expect("no error with reqwest");
+    assert_eq!(res.status(), StatusCode::OK);
+    assert_eq!(
+        urls(&res.text().await.expect("no error receiving text")),
+        [
+            "http://localhost/dl/isos/a.iso",
+            "http://localhost/dl/isos/old/b.iso"
+        ]
+    );
+
+    let res = reqwest::get(
+        url.join("browse/isos/?aria2&max_depth=1")
+            .expect("valid url"),
+    )
+    .await
+    .expect("no error with reqwest");
+    assert_eq!(
+        urls(&res.text().await.expect("no error receiving text")),
+        [
+            "http://localhost/dl/isos/a.iso",
+            "http://localhost/dl/isos/a.txt"
+        ]
+    );
+
+    // Patterns are matched against the path inside the directory
+    let res = reqwest::get(
+        url.join("browse/isos/?aria2&include=old/*/*")
+            .expect("valid url"),
+    )
+    .await
+    .expect("no error with reqwest");
+    assert_eq!(
+        urls(&res.text().await.expect("no error receiving text")),
+        ["http://localhost/dl/isos/old/older/c.iso"]
+    );
+
+    let res = reqwest::get(url.join("browse/isos/?aria2&include=[").expect("valid url"))
         .await
         .expect("no error with reqwest");
-    assert_eq!(res.status(), StatusCode::NOT_FOUND);
+    assert_eq!(res.status(), StatusCode::BAD_REQUEST);
 }
 
-proptest! {
-    #[test]
-    fn empty_dir_view_only_works_on_root(path in "\\PC+") {
-        let path = PathBuf::from(path);
-        prop_assume!(path != PathBuf::from("."));
+#[test]
+fn aria2_can_be_filtered() {
+    start_test(aria2_can_be_filtered_impl());
+}
 
-        start_test(empty_dir_provides_no_views_impl(&path));
+async fn download_scripts_recreate_directories_impl() {
+    let dir = tempfile::tempdir().expect("could not create tempdir for data");
+    std::fs::create_dir_all(dir.path().join("docs/it's")).expect("failed creating dirs");
+    std::fs::write(dir.path().join("docs/a.txt"), "first file").expect("failed writing file");
+    std::fs::write(dir.path().join("docs/it's/b.txt"), "second file").expect("failed writing file");
+
+    let SpawnInfo {
+        ref url,
+        dir: ref _tempdir,
+        shutdown: _,
+    } = spawn_app(dir).await;
+
+    let res = reqwest::get(url.join("browse/docs/?wget").expect("valid url"))
+        .await
+        .expect("no error with reqwest");
+    assert_eq!(res.status(), StatusCode::OK);
+    assert_eq!(
+        res.text().await.expect("no error receiving text"),
+        "#!/bin/sh\nset -e\n\n\
+         mkdir -p 'docs'\n\
+         wget -O 'docs/a.txt' 'http://localhost/dl/docs/a.txt'\n\
+         mkdir -p 'docs/it'\\''s'\n\
+         wget -O 'docs/it'\\''s/b.txt' 'http://localhost/dl/docs/it'\\''s/b.txt'\n"
+    );
+
+    let res = reqwest::get(
+        url.join("browse/docs/?curl&include=*/*")
+            .expect("valid url"),
+    )
+    .await
+    .expect("no error with reqwest");
+    assert_eq!(res.status(), StatusCode::OK);
+    assert_eq!(
+        res.text().await.expect("no error receiving text"),
+        "#!/bin/sh\nset -e\n\n\
+         curl -f --create-dirs -o 'docs/it'\\''s/b.txt' 'http://localhost/dl/docs/it'\\''s/b.txt'\n"
+    );
+}
+
+#[test]
+fn download_scripts_recreate_directories() {
+    start_test(download_scripts_recreate_directories_impl());
+}
+
+async fn metalinks_have_sizes_and_checksums_impl() {
+    use sha2::{Digest as _, Sha256};
+
+    let dir = tempfile::tempdir().expect("could not create tempdir for data");
+    std::fs::create_dir_all(dir.path().join("docs/sub")).expect("failed creating dirs");
+    std::fs::write(dir.path().join("docs/a&b.txt"), "first file").expect("failed writing file");
+    std::fs::write(dir.path().join("docs/sub/c.txt"), "second file").expect("failed writing file");
+
+    let SpawnInfo {
+        ref url,
+        dir: ref _tempdir,
+        shutdown: _,
+    } = spawn_app_with(dir, |config| config.hash_files = true).await;
+
+    // Files are hashed in the background after startup
+    let mut metalink;
+    let mut tries = 0;
+    loop {
+        let res = reqwest::get(url.join("browse/docs/?metalink").expect("valid url"))
+            .await
+            .expect("no error with reqwest");
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(res.headers()[CONTENT_TYPE], "application/metalink4+xml");
+        metalink = res.text().await.expect("no error receiving metalink");
+        if metalink.matches("<hash").count() == 2 || tries == 50 {
+            break;
+        }
+        tries += 1;
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+    }
+
+    assert!(
+        metalink.starts_with("<?xml version=\"1.0\" encoding=\"UTF-8\"?>"),
+        "{metalink}"
+    );
+    assert!(
+        metalink.contains("<metalink xmlns=\"urn:ietf:params:xml:ns:metalink\">"),
+        "{metalink}"
+    );
+    for (name, contents, link) in [
+        (
+            "docs/a&amp;b.txt",
+            "first file",
+            "http://localhost/dl/docs/a&amp;b.txt",
+        ),
+        (
+            "docs/sub/c.txt",
+            "second file",
+            "http://localhost/dl/docs/sub/c.txt",
+        ),
+    ] {
+        let file = format!(
+            "<file name=\"{name}\">\n\t\t<size>{}</size>\n\t\t\
+             <hash type=\"sha-256\">{:x}</hash>\n\t\t<url>{link}</url>\n\t</file>",
+            contents.len(),
+            Sha256::digest(contents),
+        );
+        assert!(metalink.contains(&file), "{metalink}");
     }
 }
+
+#[test]
+fn metalinks_have_sizes_and_checksums() {
+    start_test(metalinks_have_sizes_and_checksums_impl());
+}
+
+async fn pages_have_security_headers_impl() {
+    let dir = tempfile::tempdir().expect("could not create tempdir for data");
+    std::fs::write(dir.path().join("a.txt"), "file").expect("failed writing file");
+
+    let SpawnInfo {
+        ref url,
+        dir: ref _tempdir,
+        shutdown: _,
+    } = spawn_app(dir).await;
+
+    let res = reqwest::get(url.join("browse/").expect("valid url"))
+        .await
+        .expect("no error with reqwest");
+    let headers = res.headers();
+    assert!(headers["content-security-policy"]
+        .to_str()
+        .expect("header is a string")
+        .starts_with("default-src 'self'"));
+    assert_eq!(headers["x-content-type-options"], "nosniff");
+    assert_eq!(headers["referrer-policy"], "same-origin");
+    assert_eq!(headers["x-frame-options"], "DENY");
+
+    // Files are never sniffed, but the rest is only for pages
+    let res = reqwest::get(url.join("dl/a.txt").expect("valid url"))
+        .await
+        .expect("no error with reqwest");
+    let headers = res.headers();
+    assert_eq!(headers["x-content-type-options"], "nosniff");
+    assert!(!headers.contains_key("content-security-policy"));
+}
+
+#[test]
+fn pages_have_security_headers() {
+    start_test(pages_have_security_headers_impl());
+}
+
+async fn security_headers_can_be_changed_impl() {
+    let dir = tempfile::tempdir().expect("could not create tempdir for data");
+
+    let SpawnInfo {
+        ref url,
+        dir: ref _tempdir,
+        shutdown: _,
+    } = spawn_app_with(dir, |config| {
+        config.security_headers.content_security_policy = String::new();
+        config.security_headers.referrer_policy = "no-referrer".to_owned();
+    })
+    .await;
+
+    let res = reqwest::get(url.join("browse/").expect("valid url"))
+        .await
+        .expect("no error with reqwest");
+    let headers = res.headers();
+    assert!(!headers.contains_key("content-security-policy"));
+    assert_eq!(headers["referrer-policy"], "no-referrer");
+    assert_eq!(headers["x-frame-options"], "DENY");
+}
+
+#[test]
+fn security_headers_can_be_changed() {
+    start_test(security_headers_can_be_changed_impl());
+}
+
+async fn links_unfurl_with_opengraph_and_oembed_impl() {
+    let dir = tempfile::tempdir().expect("could not create tempdir for data");
+    std::fs::create_dir_all(dir.path().join("docs")).expect("failed creating dirs");
+    std::fs::write(dir.path().join("docs/a.txt"), "first file").expect("failed writing file");
+    std::fs::write(dir.path().join("docs/b.png"), "not really a picture")
+        .expect("failed writing file");
+
+    let SpawnInfo {
+        ref url,
+        dir: ref _tempdir,
+        shutdown: _,
+    } = spawn_app(dir).await;
+
+    let res = reqwest::get(url.join("browse/docs/").expect("valid url"))
+        .await
+        .expect("no error with reqwest");
+    let parser = Html::parse_document(&res.text().await.expect("no error receiving html"));
+    let attr = |selector: &str, attr: &str| {
+        let selector = Selector::parse(selector).expect("valid selector");
+        parser
+            .select(&selector)
+            .next()
+            .and_then(|e| e.value().attr(attr))
+            .map(ToOwned::to_owned)
+    };
+    let og = |property: &str| attr(&format!("meta[property=\"og:{property}\"]"), "content");
+
+    let title = og("title").expect("page has a title");
+    assert!(title.contains("docs"), "{title}");
+    let page_url = og("url").expect("page has a url");
+    assert!(
+        page_url.starts_with("http://localhost/browse/docs"),
+        "{page_url}"
+    );
+    let image = og("image").expect("page has a preview picture");
+    assert!(image.contains("/thumb/docs/b.png"), "{image}");
+    assert_eq!(
+        attr("meta[name=\"twitter:card\"]", "content").as_deref(),
+        Some("summary")
+    );
+    let oembed_link =
+        attr("link[type=\"application/json+oembed\"]", "href").expect("page links to its oembed");
+    assert!(oembed_link.starts_with("/oembed?url="), "{oembed_link}");
+
+    let oembed: serde_json::Value = reqwest::get(url.join(&oembed_link).expect("valid url"))
+        .await
+        .expect("no error with reqwest")
+        .json()
+        .await
+        .expect("oembed is json");
+    assert_eq!(oembed["version"], "1.0");
+    assert_eq!(oembed["type"], "link");
+    assert_eq!(oembed["provider_name"], "sfsb");
+
+    let oembed_for = |link: &'static str| async move {
+        let mut oembed_url = url.join("oembed").expect("valid url");
+        oembed_url.query_pairs_mut().append_pair("url", link);
+        reqwest::get(oembed_url)
+            .await
+            .expect("no error with reqwest")
+    };
+    let file: serde_json::Value = oembed_for("http://localhost/dl/docs/a.txt")
+        .await
+        .json()
+        .await
+        .expect("oembed is json");
+    let title = file["title"].as_str().expect("oembed has a title");
+    assert!(title.starts_with("a.txt ("), "{title}");
+    assert_eq!(
+        oembed_for("http://localhost/dl/docs/missing.txt")
+            .await
+            .status(),
+        StatusCode::NOT_FOUND
+    );
+    assert_eq!(
+        oembed_for("https://example.com/dl/docs/a.txt")
+            .await
+            .status(),
+        StatusCode::NOT_FOUND
+    );
+}
+
+#[test]
+fn links_unfurl_with_opengraph_and_oembed() {
+    start_test(links_unfurl_with_opengraph_and_oembed_impl());
+}
+
+// Other systems don't allow names that aren't UTF-8 at all
+#[cfg(target_os = "linux")]
+async fn unreadable_entries_are_skipped_and_reported_impl() {
+    use std::{ffi::OsStr, os::unix::ffi::OsStrExt as _};
+
+    let dir = tempfile::tempdir().expect("could not create tempdir for data");
+    std::fs::create_dir_all(dir.path().join("docs")).expect("failed creating dirs");
+    std::fs::write(dir.path().join("docs/a.txt"), "first file").expect("failed writing file");
+    std::fs::write(
+        dir.path()
+            .join("docs")
+            .join(OsStr::from_bytes(b"not-utf8-\xff.txt")),
+        "unreadable name",
+    )
+    .expect("failed writing file");
+
+    let SpawnInfo {
+        ref url,
+        dir: ref _tempdir,
+        shutdown: _,
+    } = spawn_app(dir).await;
+
+    let res = reqwest::get(url.join("browse/docs/").expect("valid url"))
+        .await
+        .expect("no error with reqwest");
+    assert_eq!(res.status(), StatusCode::OK);
+    let content = res.text().await.expect("no error receiving html");
+    assert!(content.contains("a.txt"), "{content}");
+    assert!(!content.contains("not-utf8"), "{content}");
+
+    let status: serde_json::Value = reqwest::get(url.join("api/cache").expect("valid url"))
+        .await
+        .expect("no error with reqwest")
+        .json()
+        .await
+        .expect("cache status is json");
+    let errors = status["errors"].as_array().expect("errors are a list");
+    assert_eq!(errors.len(), 1, "{status}");
+    let path = errors[0]["path"].as_str().expect("error has a path");
+    assert!(path.contains("not-utf8"), "{status}");
+    let error = errors[0]["error"].as_str().expect("error has a message");
+    assert!(error.contains("invalid unicode"), "{status}");
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn unreadable_entries_are_skipped_and_reported() {
+    start_test(unreadable_entries_are_skipped_and_reported_impl());
+}
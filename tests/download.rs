@@ -0,0 +1,2861 @@
+use camino::Utf8Path;
+use reqwest::StatusCode;
+use std::io::{Cursor, Read as _, Write as _};
+
+mod common;
+use common::{captured_logs, spawn_app, spawn_app_with, start_test, SpawnInfo};
+
+async fn archive_contains_whole_directory_impl() {
+    let dir = tempfile::tempdir().expect("could not create tempdir for data");
+    std::fs::create_dir_all(dir.path().join("sub/nested")).expect("failed creating dirs");
+    std::fs::write(dir.path().join("sub/a.txt"), "first file").expect("failed writing file");
+    std::fs::write(dir.path().join("sub/nested/b.txt"), "second file")
+        .expect("failed writing file");
+
+    let SpawnInfo {
+        ref url,
+        dir: ref _tempdir,
+        shutdown: _,
+    } = spawn_app(dir).await;
+
+    let res = reqwest::get(url.join("arc/sub").expect("valid url"))
+        .await
+        .expect("no error with reqwest");
+    assert_eq!(res.status(), StatusCode::OK);
+    let bytes = res.bytes().await.expect("no error receiving archive");
+
+    let mut archive = zip::ZipArchive::new(Cursor::new(bytes)).expect("archive was a valid zip");
+    let mut names: Vec<_> = archive.file_names().map(ToOwned::to_owned).collect();
+    names.sort();
+    assert_eq!(
+        names,
+        ["sub/", "sub/a.txt", "sub/nested/", "sub/nested/b.txt"]
+    );
+
+    let mut contents = String::new();
+    archive
+        .by_name("sub/nested/b.txt")
+        .expect("file was in archive")
+        .read_to_string(&mut contents)
+        .expect("file was valid");
+    assert_eq!(contents, "second file");
+}
+
+#[test]
+fn archive_contains_whole_directory() {
+    start_test(archive_contains_whole_directory_impl());
+}
+
+async fn archive_only_contains_selected_files_impl() {
+    let dir = tempfile::tempdir().expect("could not create tempdir for data");
+    std::fs::create_dir_all(dir.path().join("sub/nested")).expect("failed creating dirs");
+    std::fs::write(dir.path().join("sub/a.txt"), "first file").expect("failed writing file");
+    std::fs::write(dir.path().join("sub/nested/b.txt"), "second file")
+        .expect("failed writing file");
+    std::fs::write(dir.path().join("sub/nested/c.txt"), "third file").expect("failed writing file");
+
+    let SpawnInfo {
+        ref url,
+        dir: ref _tempdir,
+        shutdown: _,
+    } = spawn_app(dir).await;
+
+    let res = reqwest::get(
+        url.join("arc/sub?files=a.txt&files=nested/b.txt")
+            .expect("valid url"),
+    )
+    .await
+    .expect("no error with reqwest");
+    assert_eq!(res.status(), StatusCode::OK);
+    let bytes = res.bytes().await.expect("no error receiving archive");
+
+    let archive = zip::ZipArchive::new(Cursor::new(bytes)).expect("archive was a valid zip");
+    let mut names: Vec<_> = archive.file_names().map(ToOwned::to_owned).collect();
+    names.sort();
+    assert_eq!(names, ["sub/a.txt", "sub/nested/b.txt"]);
+
+    let res = reqwest::get(
+        url.join("arc/sub/nested?files=../a.txt")
+            .expect("valid url"),
+    )
+    .await
+    .expect("no error with reqwest");
+    assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+}
+
+#[test]
+fn archive_only_contains_selected_files() {
+    start_test(archive_only_contains_selected_files_impl());
+}
+
+async fn stored_archive_has_length_and_ranges_impl() {
+    let dir = tempfile::tempdir().expect("could not create tempdir for data");
+    std::fs::create_dir_all(dir.path().join("sub/nested")).expect("failed creating dirs");
+    std::fs::write(dir.path().join("sub/a.txt"), "first file").expect("failed writing file");
+    std::fs::write(dir.path().join("sub/nested/b.txt"), "second file")
+        .expect("failed writing file");
+
+    let SpawnInfo {
+        ref url,
+        dir: ref _tempdir,
+        shutdown: _,
+    } = spawn_app(dir).await;
+    let archive_url = url.join("arc/sub?store=true").expect("valid url");
+
+    let res = reqwest::get(archive_url.clone())
+        .await
+        .expect("no error with reqwest");
+    assert_eq!(res.status(), StatusCode::OK);
+    let len = res.content_length().expect("stored archive has a length");
+    let bytes = res.bytes().await.expect("no error receiving archive");
+    assert_eq!(bytes.len() as u64, len);
+
+    let mut archive =
+        zip::ZipArchive::new(Cursor::new(bytes.clone())).expect("archive was a valid zip");
+    let mut contents = String::new();
+    archive
+        .by_name("sub/nested/b.txt")
+        .expect("file was in archive")
+        .read_to_string(&mut contents)
+        .expect("file was valid");
+    assert_eq!(contents, "second file");
+
+    let res = reqwest::Client::new()
+        .get(archive_url)
+        .header("Range", "bytes=40-")
+        .send()
+        .await
+        .expect("no error with reqwest");
+    assert_eq!(res.status(), StatusCode::PARTIAL_CONTENT);
+    let rest = res.bytes().await.expect("no error receiving archive");
+    assert_eq!(rest, bytes.slice(40..));
+}
+
+#[test]
+fn stored_archive_has_length_and_ranges() {
+    start_test(stored_archive_has_length_and_ranges_impl());
+}
+
+async fn stored_archive_is_only_resumed_if_unchanged_impl() {
+    let dir = tempfile::tempdir().expect("could not create tempdir for data");
+    std::fs::create_dir_all(dir.path().join("sub")).expect("failed creating dirs");
+    std::fs::write(dir.path().join("sub/a.txt"), "first file").expect("failed writing file");
+
+    let SpawnInfo {
+        ref url,
+        dir: ref tempdir,
+        shutdown: _,
+    } = spawn_app(dir).await;
+    let archive_url = url.join("arc/sub?store=true").expect("valid url");
+
+    let res = reqwest::get(archive_url.clone())
+        .await
+        .expect("no error with reqwest");
+    let etag = res.headers()["ETag"]
+        .to_str()
+        .expect("ETag is ASCII")
+        .to_owned();
+    assert!(etag.starts_with('"'), "{etag} should be a strong ETag");
+    let last_modified = res.headers()["Last-Modified"].clone();
+    let bytes = res.bytes().await.expect("no error receiving archive");
+
+    for if_range in [
+        etag.clone(),
+        last_modified.to_str().expect("ASCII").to_owned(),
+    ] {
+        let res = reqwest::Client::new()
+            .get(archive_url.clone())
+            .header("Range", "bytes=40-")
+            .header("If-Range", if_range)
+            .send()
+            .await
+            .expect("no error with reqwest");
+        assert_eq!(res.status(), StatusCode::PARTIAL_CONTENT);
+        let rest = res.bytes().await.expect("no error receiving archive");
+        assert_eq!(rest, bytes.slice(40..));
+    }
+
+    std::fs::write(tempdir.path().join("sub/a.txt"), "first file, changed")
+        .expect("failed writing file");
+    // The cache is updated in the background
+    let mut res;
+    let mut tries = 0;
+    loop {
+        res = reqwest::Client::new()
+            .get(archive_url.clone())
+            .header("Range", "bytes=40-")
+            .header("If-Range", &etag)
+            .send()
+            .await
+            .expect("no error with reqwest");
+        if res.status() != StatusCode::PARTIAL_CONTENT || tries == 50 {
+            break;
+        }
+        tries += 1;
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+    }
+    assert_eq!(res.status(), StatusCode::OK);
+    assert_ne!(res.headers()["ETag"], etag.as_str());
+    let len = res.content_length().expect("stored archive has a length");
+    let bytes = res.bytes().await.expect("no error receiving archive");
+    assert_eq!(bytes.len() as u64, len);
+    let mut archive = zip::ZipArchive::new(Cursor::new(bytes)).expect("archive was a valid zip");
+    let mut contents = String::new();
+    archive
+        .by_name("sub/a.txt")
+        .expect("file was in archive")
+        .read_to_string(&mut contents)
+        .expect("file was valid");
+    assert_eq!(contents, "first file, changed");
+}
+
+#[test]
+fn stored_archive_is_only_resumed_if_unchanged() {
+    start_test(stored_archive_is_only_resumed_if_unchanged_impl());
+}
+
+async fn resumed_stored_archive_uses_cached_crcs_impl() {
+    let dir = tempfile::tempdir().expect("could not create tempdir for data");
+    std::fs::create_dir_all(dir.path().join("sub")).expect("failed creating dirs");
+    std::fs::write(dir.path().join("sub/a.txt"), "first file").expect("failed writing file");
+    std::fs::write(dir.path().join("sub/b.txt"), "second file").expect("failed writing file");
+    let a_path = dir.path().join("sub/a.txt");
+    let tmp_path = dir.path().join("a.tmp");
+
+    let SpawnInfo {
+        ref url,
+        dir: ref _tempdir,
+        shutdown: _,
+    } = spawn_app_with(dir, |config| config.hash_files = true).await;
+
+    // Files are hashed in the background after startup
+    let mut tries = 0;
+    loop {
+        let res = reqwest::get(url.join("arc/sub?checksums=1").expect("valid url"))
+            .await
+            .expect("no error with reqwest");
+        if res.status() != StatusCode::SERVICE_UNAVAILABLE || tries == 50 {
+            break;
+        }
+        tries += 1;
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+    }
+    let archive_url = url.join("arc/sub?store=true").expect("valid url");
+    let bytes = reqwest::get(archive_url.clone())
+        .await
+        .expect("no error with reqwest")
+        .bytes()
+        .await
+        .expect("no error receiving archive");
+
+    // Same size and time, so only reading it again would notice it changed
+    let modified = std::fs::metadata(&a_path)
+        .expect("file exists")
+        .modified()
+        .expect("file has a modification time");
+    std::fs::write(&tmp_path, "other file").expect("failed writing file");
+    std::fs::File::options()
+        .write(true)
+        .open(&tmp_path)
+        .expect("file exists")
+        .set_modified(modified)
+        .expect("failed setting modification time");
+    std::fs::rename(&tmp_path, &a_path).expect("failed replacing file");
+
+    // From the central directory, which has the CRCs, to the end
+    let end = bytes.len();
+    let start = u32::from_le_bytes(
+        bytes[end - 6..end - 2]
+            .try_into()
+            .expect("slice has the length of the offset"),
+    ) as usize;
+    let res = reqwest::Client::new()
+        .get(archive_url)
+        .header("Range", format!("bytes={start}-"))
+        .send()
+        .await
+        .expect("no error with reqwest");
+    assert_eq!(res.status(), StatusCode::PARTIAL_CONTENT);
+    let rest = res.bytes().await.expect("no error receiving archive");
+    assert_eq!(rest, bytes.slice(start..));
+}
+
+#[test]
+fn resumed_stored_archive_uses_cached_crcs() {
+    start_test(resumed_stored_archive_uses_cached_crcs_impl());
+}
+
+async fn cached_archive_is_served_again_impl() {
+    let dir = tempfile::tempdir().expect("could not create tempdir for data");
+    std::fs::create_dir_all(dir.path().join("sub")).expect("failed creating dirs");
+    std::fs::write(dir.path().join("sub/a.txt"), "first file").expect("failed writing file");
+    let spool_dir = tempfile::tempdir().expect("could not create tempdir for spool");
+    let spool_path = Utf8Path::from_path(spool_dir.path())
+        .expect("temp path was not UTF-8")
+        .to_path_buf();
+
+    let SpawnInfo {
+        ref url,
+        dir: ref _tempdir,
+        shutdown: _,
+    } = spawn_app_with(dir, |config| config.archive_cache_dir = Some(spool_path)).await;
+    let archive_url = url.join("arc/sub?format=tar.gz").expect("valid url");
+
+    let first = reqwest::get(archive_url.clone())
+        .await
+        .expect("no error with reqwest")
+        .bytes()
+        .await
+        .expect("no error receiving archive");
+    // The archive is only added to the cache after the body is finished
+    tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+    assert_eq!(
+        std::fs::read_dir(spool_dir.path())
+            .expect("spool dir exists")
+            .count(),
+        1
+    );
+
+    let res = reqwest::get(archive_url)
+        .await
+        .expect("no error with reqwest");
+    assert_eq!(res.content_length(), Some(first.len() as u64));
+    let second = res.bytes().await.expect("no error receiving archive");
+    assert_eq!(first, second);
+}
+
+#[test]
+fn cached_archive_is_served_again() {
+    start_test(cached_archive_is_served_again_impl());
+}
+
+async fn archive_over_limit_is_refused_impl() {
+    let dir = tempfile::tempdir().expect("could not create tempdir for data");
+    std::fs::create_dir_all(dir.path().join("sub")).expect("failed creating dirs");
+    std::fs::write(dir.path().join("sub/a.txt"), "first file").expect("failed writing file");
+    std::fs::write(dir.path().join("sub/b.txt"), "second file").expect("failed writing file");
+
+    let SpawnInfo {
+        ref url,
+        dir: ref _tempdir,
+        shutdown: _,
+    } = spawn_app_with(dir, |config| config.max_archive_bytes = Some(15)).await;
+
+    let res = reqwest::get(url.join("arc/sub").expect("valid url"))
+        .await
+        .expect("no error with reqwest");
+    assert_eq!(res.status(), StatusCode::PAYLOAD_TOO_LARGE);
+
+    let res = reqwest::get(url.join("arc/sub?files=a.txt").expect("valid url"))
+        .await
+        .expect("no error with reqwest");
+    assert_eq!(res.status(), StatusCode::OK);
+}
+
+#[test]
+fn archive_over_limit_is_refused() {
+    start_test(archive_over_limit_is_refused_impl());
+}
+
+/// Value of an octal field of a tar header
+fn tar_octal(field: &[u8]) -> u64 {
+    let digits = std::str::from_utf8(field)
+        .expect("field was ASCII")
+        .trim_end_matches('\0');
+    u64::from_str_radix(digits, 8).expect("field was octal")
+}
+
+#[cfg(unix)]
+async fn tar_keeps_mtime_and_mode_impl() {
+    use std::os::unix::fs::PermissionsExt as _;
+
+    let dir = tempfile::tempdir().expect("could not create tempdir for data");
+    std::fs::create_dir_all(dir.path().join("sub")).expect("failed creating dirs");
+    let path = dir.path().join("sub/a.txt");
+    std::fs::write(&path, "first file").expect("failed writing file");
+    std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o600))
+        .expect("failed setting permissions");
+    std::fs::File::options()
+        .write(true)
+        .open(&path)
+        .expect("failed opening file")
+        .set_modified(std::time::UNIX_EPOCH + std::time::Duration::from_secs(1_000_000_000))
+        .expect("failed setting mtime");
+
+    let SpawnInfo {
+        ref url,
+        dir: ref _tempdir,
+        shutdown: _,
+    } = spawn_app(dir).await;
+
+    let res = reqwest::get(url.join("arc/sub?format=tar.gz").expect("valid url"))
+        .await
+        .expect("no error with reqwest");
+    assert_eq!(res.status(), StatusCode::OK);
+    let bytes = res.bytes().await.expect("no error receiving archive");
+    let mut tar = vec![];
+    flate2::read::GzDecoder::new(&bytes[..])
+        .read_to_end(&mut tar)
+        .expect("archive was valid gzip");
+
+    // The directory comes first, taking a single block
+    let header = &tar[512..1024];
+    assert!(header.starts_with(b"sub/a.txt\0"));
+    assert_eq!(tar_octal(&header[100..107]), 0o600);
+    assert_eq!(tar_octal(&header[136..147]), 1_000_000_000);
+}
+
+#[cfg(unix)]
+#[test]
+fn tar_keeps_mtime_and_mode() {
+    start_test(tar_keeps_mtime_and_mode_impl());
+}
+
+async fn archive_has_checksums_impl() {
+    use sha2::{Digest as _, Sha256};
+
+    let dir = tempfile::tempdir().expect("could not create tempdir for data");
+    std::fs::create_dir_all(dir.path().join("sub/nested")).expect("failed creating dirs");
+    std::fs::write(dir.path().join("sub/a.txt"), "first file").expect("failed writing file");
+    std::fs::write(dir.path().join("sub/nested/b.txt"), "second file")
+        .expect("failed writing file");
+
+    let SpawnInfo {
+        ref url,
+        dir: ref _tempdir,
+        shutdown: _,
+    } = spawn_app_with(dir, |config| config.hash_files = true).await;
+
+    // Files are hashed in the background after startup
+    let mut res;
+    let mut tries = 0;
+    loop {
+        res = reqwest::get(url.join("arc/sub?checksums=1").expect("valid url"))
+            .await
+            .expect("no error with reqwest");
+        if res.status() != StatusCode::SERVICE_UNAVAILABLE || tries == 50 {
+            break;
+        }
+        tries += 1;
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+    }
+    assert_eq!(res.status(), StatusCode::OK);
+    let bytes = res.bytes().await.expect("no error receiving archive");
+
+    let mut archive = zip::ZipArchive::new(Cursor::new(bytes)).expect("archive was a valid zip");
+    let mut sums = String::new();
+    archive
+        .by_name("sub/SHA256SUMS")
+        .expect("checksums were in archive")
+        .read_to_string(&mut sums)
+        .expect("checksums were valid");
+    assert_eq!(
+        sums,
+        format!(
+            "{:x}  a.txt\n{:x}  nested/b.txt\n",
+            Sha256::digest("first file"),
+            Sha256::digest("second file")
+        )
+    );
+}
+
+#[test]
+fn archive_has_checksums() {
+    start_test(archive_has_checksums_impl());
+}
+
+async fn directory_download_redirects_to_archive_impl() {
+    let dir = tempfile::tempdir().expect("could not create tempdir for data");
+    std::fs::create_dir_all(dir.path().join("sub dir")).expect("failed creating dirs");
+    std::fs::write(dir.path().join("sub dir/a.txt"), "first file").expect("failed writing file");
+
+    let SpawnInfo {
+        ref url,
+        dir: ref _tempdir,
+        shutdown: _,
+    } = spawn_app(dir).await;
+
+    let res = reqwest::get(url.join("dl/sub%20dir").expect("valid url"))
+        .await
+        .expect("no error with reqwest");
+    assert_eq!(res.status(), StatusCode::OK);
+    assert_eq!(res.url().path(), "/arc/sub%20dir");
+    assert_eq!(
+        res.headers()
+            .get("Content-Type")
+            .expect("archive has a content type"),
+        "application/zip"
+    );
+}
+
+#[test]
+fn directory_download_redirects_to_archive() {
+    start_test(directory_download_redirects_to_archive_impl());
+}
+
+async fn multiple_ranges_are_multipart_impl() {
+    let dir = tempfile::tempdir().expect("could not create tempdir for data");
+    std::fs::write(dir.path().join("a.txt"), "0123456789").expect("failed writing file");
+
+    let SpawnInfo {
+        ref url,
+        dir: ref _tempdir,
+        shutdown: _,
+    } = spawn_app(dir).await;
+
+    let res = reqwest::Client::new()
+        .get(url.join("dl/a.txt").expect("valid url"))
+        .header("Range", "bytes=0-1,-3")
+        .send()
+        .await
+        .expect("no error with reqwest");
+    assert_eq!(res.status(), StatusCode::PARTIAL_CONTENT);
+    let content_type = res
+        .headers()
+        .get("Content-Type")
+        .expect("response has a content type")
+        .to_str()
+        .expect("content type is ASCII")
+        .to_owned();
+    let boundary = content_type
+        .strip_prefix("multipart/byteranges; boundary=")
+        .expect("response is multipart");
+    let len = res.content_length().expect("response has a length");
+    let body = res.text().await.expect("no error receiving body");
+    assert_eq!(body.len() as u64, len);
+    assert_eq!(
+        body,
+        format!(
+            "--{boundary}\r\nContent-Type: text/plain; charset=utf-8\r\nContent-Range: bytes 0-1/10\r\n\r\n01\r\n\
+             --{boundary}\r\nContent-Type: text/plain; charset=utf-8\r\nContent-Range: bytes 7-9/10\r\n\r\n789\r\n\
+             --{boundary}--\r\n"
+        )
+    );
+}
+
+#[test]
+fn multiple_ranges_are_multipart() {
+    start_test(multiple_ranges_are_multipart_impl());
+}
+
+async fn overlapping_ranges_are_sent_once_impl() {
+    let dir = tempfile::tempdir().expect("could not create tempdir for data");
+    std::fs::write(dir.path().join("a.txt"), "0123456789").expect("failed writing file");
+
+    let SpawnInfo {
+        ref url,
+        dir: ref _tempdir,
+        shutdown: _,
+    } = spawn_app(dir).await;
+    let client = reqwest::Client::new();
+
+    let whole_file_many_times = format!("bytes={}", vec!["0-"; 1000].join(","));
+    for (range, content_range, body) in [
+        (whole_file_many_times.as_str(), "bytes 0-9/10", "0123456789"),
+        ("bytes=6-6,0-3,2-5", "bytes 0-6/10", "0123456"),
+        ("bytes=-2,7-7", "bytes 7-9/10", "789"),
+    ] {
+        let res = client
+            .get(url.join("dl/a.txt").expect("valid url"))
+            .header("Range", range)
+            .send()
+            .await
+            .expect("no error with reqwest");
+        assert_eq!(res.status(), StatusCode::PARTIAL_CONTENT, "{range}");
+        assert_eq!(res.headers()["Content-Range"], content_range, "{range}");
+        assert_eq!(res.text().await.expect("no error receiving body"), body);
+    }
+}
+
+#[test]
+fn overlapping_ranges_are_sent_once() {
+    start_test(overlapping_ranges_are_sent_once_impl());
+}
+
+async fn too_many_ranges_send_the_whole_file_impl() {
+    let dir = tempfile::tempdir().expect("could not create tempdir for data");
+    let contents: String = ('a'..='z').chain('A'..='Z').collect();
+    std::fs::write(dir.path().join("a.txt"), &contents).expect("failed writing file");
+
+    let SpawnInfo {
+        ref url,
+        dir: ref _tempdir,
+        shutdown: _,
+    } = spawn_app(dir).await;
+    let client = reqwest::Client::new();
+    let ranges = |count: u64| {
+        let ranges: Vec<_> = (0..count).map(|i| format!("{0}-{0}", i * 2)).collect();
+        format!("bytes={}", ranges.join(","))
+    };
+
+    // Right at the limit, every part is sent
+    let res = client
+        .get(url.join("dl/a.txt").expect("valid url"))
+        .header("Range", ranges(16))
+        .send()
+        .await
+        .expect("no error with reqwest");
+    assert_eq!(res.status(), StatusCode::PARTIAL_CONTENT);
+    let body = res.text().await.expect("no error receiving body");
+    assert_eq!(body.matches("Content-Range").count(), 16, "{body}");
+
+    let res = client
+        .get(url.join("dl/a.txt").expect("valid url"))
+        .header("Range", ranges(17))
+        .send()
+        .await
+        .expect("no error with reqwest");
+    assert_eq!(res.status(), StatusCode::OK);
+    assert!(res.headers().get("Content-Range").is_none());
+    assert_eq!(res.content_length(), Some(contents.len() as u64));
+    assert_eq!(res.text().await.expect("no error receiving body"), contents);
+}
+
+#[test]
+fn too_many_ranges_send_the_whole_file() {
+    start_test(too_many_ranges_send_the_whole_file_impl());
+}
+
+async fn unchanged_file_is_not_modified_impl() {
+    let dir = tempfile::tempdir().expect("could not create tempdir for data");
+    std::fs::write(dir.path().join("a.txt"), "first file").expect("failed writing file");
+
+    let SpawnInfo {
+        ref url,
+        dir: ref _tempdir,
+        shutdown: _,
+    } = spawn_app(dir).await;
+    let file_url = url.join("dl/a.txt").expect("valid url");
+
+    let res = reqwest::get(file_url.clone())
+        .await
+        .expect("no error with reqwest");
+    assert_eq!(res.status(), StatusCode::OK);
+    let etag = res.headers().get("ETag").expect("file has an ETag").clone();
+    let last_modified = res
+        .headers()
+        .get("Last-Modified")
+        .expect("file has a Last-Modified")
+        .clone();
+
+    let client = reqwest::Client::new();
+    let res = client
+        .get(file_url.clone())
+        .header("If-None-Match", etag)
+        .send()
+        .await
+        .expect("no error with reqwest");
+    assert_eq!(res.status(), StatusCode::NOT_MODIFIED);
+
+    let res = client
+        .get(file_url.clone())
+        .header("If-Modified-Since", last_modified)
+        .send()
+        .await
+        .expect("no error with reqwest");
+    assert_eq!(res.status(), StatusCode::NOT_MODIFIED);
+
+    let res = client
+        .get(file_url)
+        .header("If-None-Match", "W/\"something-else\"")
+        .send()
+        .await
+        .expect("no error with reqwest");
+    assert_eq!(res.status(), StatusCode::OK);
+}
+
+#[test]
+fn unchanged_file_is_not_modified() {
+    start_test(unchanged_file_is_not_modified_impl());
+}
+
+async fn inline_flag_overrides_disposition_impl() {
+    let dir = tempfile::tempdir().expect("could not create tempdir for data");
+    std::fs::write(dir.path().join("a.txt"), "first file").expect("failed writing file");
+
+    let SpawnInfo {
+        ref url,
+        dir: ref _tempdir,
+        shutdown: _,
+    } = spawn_app(dir).await;
+
+    for (path, disposition) in [
+        ("dl/a.txt", "attachment; filename=\"a.txt\""),
+        ("dl/a.txt?inline=1", "inline; filename=\"a.txt\""),
+    ] {
+        let res = reqwest::get(url.join(path).expect("valid url"))
+            .await
+            .expect("no error with reqwest");
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(
+            res.headers()
+                .get("Content-Disposition")
+                .expect("file has a disposition"),
+            disposition
+        );
+    }
+}
+
+#[test]
+fn inline_flag_overrides_disposition() {
+    start_test(inline_flag_overrides_disposition_impl());
+}
+
+async fn precompressed_file_is_served_impl() {
+    let dir = tempfile::tempdir().expect("could not create tempdir for data");
+    std::fs::write(dir.path().join("a.js"), "plain").expect("failed writing file");
+    std::fs::write(dir.path().join("a.js.gz"), "compressed").expect("failed writing file");
+
+    let SpawnInfo {
+        ref url,
+        dir: ref _tempdir,
+        shutdown: _,
+    } = spawn_app(dir).await;
+    let file_url = url.join("dl/a.js").expect("valid url");
+    let client = reqwest::Client::new();
+
+    let res = client
+        .get(file_url.clone())
+        .header("Accept-Encoding", "gzip, br;q=0")
+        .send()
+        .await
+        .expect("no error with reqwest");
+    assert_eq!(res.status(), StatusCode::OK);
+    assert_eq!(
+        res.headers()
+            .get("Content-Encoding")
+            .expect("response is encoded"),
+        "gzip"
+    );
+    assert_eq!(
+        res.headers().get("Vary").expect("response varies"),
+        "Accept-Encoding"
+    );
+    assert_eq!(
+        res.bytes().await.expect("no error receiving file"),
+        "compressed"
+    );
+
+    let res = client
+        .get(file_url)
+        .header("Accept-Encoding", "identity")
+        .send()
+        .await
+        .expect("no error with reqwest");
+    assert!(res.headers().get("Content-Encoding").is_none());
+    assert_eq!(res.bytes().await.expect("no error receiving file"), "plain");
+}
+
+#[test]
+fn precompressed_file_is_served() {
+    start_test(precompressed_file_is_served_impl());
+}
+
+async fn content_type_is_detected_impl() {
+    const PNG_MAGIC: &[u8] = b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR";
+
+    let dir = tempfile::tempdir().expect("could not create tempdir for data");
+    std::fs::write(dir.path().join("image"), PNG_MAGIC).expect("failed writing file");
+    std::fs::write(dir.path().join("a.PNG"), PNG_MAGIC).expect("failed writing file");
+    std::fs::write(dir.path().join("a.md"), "# Title").expect("failed writing file");
+
+    let SpawnInfo {
+        ref url,
+        dir: ref _tempdir,
+        shutdown: _,
+    } = spawn_app_with(dir, |config| {
+        config.mime_overrides = vec!["md=text/markdown".parse().expect("valid override")];
+    })
+    .await;
+
+    for (path, content_type) in [
+        ("dl/image", "image/png"),
+        ("dl/a.PNG", "image/png"),
+        ("dl/a.md", "text/markdown; charset=utf-8"),
+    ] {
+        let res = reqwest::get(url.join(path).expect("valid url"))
+            .await
+            .expect("no error with reqwest");
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(
+            res.headers()
+                .get("Content-Type")
+                .expect("file has a content type"),
+            content_type
+        );
+    }
+}
+
+#[test]
+fn content_type_is_detected() {
+    start_test(content_type_is_detected_impl());
+}
+
+async fn unknown_content_type_is_sniffed_impl() {
+    let dir = tempfile::tempdir().expect("could not create tempdir for data");
+    std::fs::write(dir.path().join("notes"), "just some text").expect("failed writing file");
+    std::fs::write(dir.path().join("blob"), b"\x00\x01\x02\xff").expect("failed writing file");
+
+    let SpawnInfo {
+        ref url,
+        dir: ref _tempdir,
+        shutdown: _,
+    } = spawn_app(dir).await;
+
+    for (path, content_type) in [
+        ("dl/notes", "text/plain; charset=utf-8"),
+        ("dl/blob", "application/octet-stream"),
+    ] {
+        let res = reqwest::get(url.join(path).expect("valid url"))
+            .await
+            .expect("no error with reqwest");
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(
+            res.headers()
+                .get("Content-Type")
+                .expect("file has a content type"),
+            content_type
+        );
+    }
+}
+
+#[test]
+fn unknown_content_type_is_sniffed() {
+    start_test(unknown_content_type_is_sniffed_impl());
+}
+
+async fn concurrent_downloads_are_limited_impl() {
+    let dir = tempfile::tempdir().expect("could not create tempdir for data");
+    // Big enough that it can't all be buffered, so the first download stays in progress
+    std::fs::write(dir.path().join("big.bin"), vec![0u8; 64 * 1024 * 1024])
+        .expect("failed writing file");
+    std::fs::write(dir.path().join("a.txt"), "small").expect("failed writing file");
+
+    let SpawnInfo {
+        ref url,
+        dir: ref _tempdir,
+        shutdown: _,
+    } = spawn_app_with(dir, |config| config.max_downloads = Some(1)).await;
+
+    let first = reqwest::get(url.join("dl/big.bin").expect("valid url"))
+        .await
+        .expect("no error with reqwest");
+    assert_eq!(first.status(), StatusCode::OK);
+
+    let res = reqwest::get(url.join("dl/a.txt").expect("valid url"))
+        .await
+        .expect("no error with reqwest");
+    assert_eq!(res.status(), StatusCode::SERVICE_UNAVAILABLE);
+    assert!(res.headers().get("Retry-After").is_some());
+
+    drop(first);
+    // The slot is only freed once the server notices the client went away
+    for _ in 0..50 {
+        let res = reqwest::get(url.join("dl/a.txt").expect("valid url"))
+            .await
+            .expect("no error with reqwest");
+        if res.status() == StatusCode::OK {
+            assert_eq!(res.text().await.expect("no error receiving file"), "small");
+            return;
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+    }
+    panic!("download slot was never freed");
+}
+
+#[test]
+fn concurrent_downloads_are_limited() {
+    start_test(concurrent_downloads_are_limited_impl());
+}
+
+async fn cache_control_rules_are_applied_impl() {
+    let dir = tempfile::tempdir().expect("could not create tempdir for data");
+    std::fs::create_dir_all(dir.path().join("sub")).expect("failed creating dirs");
+    std::fs::write(dir.path().join("sub/disk.iso"), "image").expect("failed writing file");
+    std::fs::write(dir.path().join("a.txt"), "first file").expect("failed writing file");
+
+    let SpawnInfo {
+        ref url,
+        dir: ref _tempdir,
+        shutdown: _,
+    } = spawn_app_with(dir, |config| {
+        config.cache_control = vec![
+            "*.iso => public, max-age=86400"
+                .parse()
+                .expect("valid rule"),
+            "/browse/* => no-cache".parse().expect("valid rule"),
+        ];
+    })
+    .await;
+
+    for (path, cache_control) in [
+        ("dl/sub/disk.iso", Some("public, max-age=86400")),
+        ("browse/sub/", Some("no-cache")),
+        ("dl/a.txt", None),
+    ] {
+        let res = reqwest::get(url.join(path).expect("valid url"))
+            .await
+            .expect("no error with reqwest");
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(
+            res.headers()
+                .get("Cache-Control")
+                .map(|v| v.to_str().expect("header is ASCII")),
+            cache_control,
+            "wrong Cache-Control for {path}"
+        );
+    }
+}
+
+#[test]
+fn cache_control_rules_are_applied() {
+    start_test(cache_control_rules_are_applied_impl());
+}
+
+async fn shallow_cache_archive_contains_unread_dirs_impl() {
+    let dir = tempfile::tempdir().expect("could not create tempdir for data");
+    std::fs::create_dir_all(dir.path().join("sub/nested")).expect("failed creating dirs");
+    std::fs::write(dir.path().join("sub/nested/b.txt"), "second file")
+        .expect("failed writing file");
+
+    let SpawnInfo {
+        ref url,
+        dir: ref _tempdir,
+        shutdown: _,
+    } = spawn_app_with(dir, |config| config.cache_depth = Some(0)).await;
+
+    let res = reqwest::get(url.join("arc/sub").expect("valid url"))
+        .await
+        .expect("no error with reqwest");
+    assert_eq!(res.status(), StatusCode::OK);
+    let bytes = res.bytes().await.expect("no error receiving archive");
+
+    let archive = zip::ZipArchive::new(Cursor::new(bytes)).expect("archive was a valid zip");
+    let mut names: Vec<_> = archive.file_names().map(ToOwned::to_owned).collect();
+    names.sort();
+    assert_eq!(names, ["sub/", "sub/nested/", "sub/nested/b.txt"]);
+}
+
+#[test]
+fn shallow_cache_archive_contains_unread_dirs() {
+    start_test(shallow_cache_archive_contains_unread_dirs_impl());
+}
+
+#[cfg(unix)]
+async fn followed_symlinks_stay_inside_data_dir_impl() {
+    use std::os::unix::fs::symlink;
+
+    let outside = tempfile::tempdir().expect("could not create tempdir outside of data");
+    std::fs::write(outside.path().join("secret.txt"), "secret").expect("failed writing file");
+
+    let dir = tempfile::tempdir().expect("could not create tempdir for data");
+    std::fs::create_dir_all(dir.path().join("sub")).expect("failed creating dirs");
+    std::fs::write(dir.path().join("sub/a.txt"), "first file").expect("failed writing file");
+    symlink(
+        dir.path().join("sub/a.txt"),
+        dir.path().join("sub/alias.txt"),
+    )
+    .expect("failed creating symlink");
+    symlink(dir.path().join("sub"), dir.path().join("sub/loop")).expect("failed creating symlink");
+    symlink(
+        outside.path().join("secret.txt"),
+        dir.path().join("sub/secret.txt"),
+    )
+    .expect("failed creating symlink");
+
+    let SpawnInfo {
+        ref url,
+        dir: ref _tempdir,
+        shutdown: _,
+    } = spawn_app_with(dir, |config| {
+        config.symlinks = sfsb::SymlinkPolicy::Follow;
+    })
+    .await;
+
+    let res = reqwest::get(url.join("dl/sub/alias.txt").expect("valid url"))
+        .await
+        .expect("no error with reqwest");
+    assert_eq!(res.status(), StatusCode::OK);
+    assert_eq!(
+        res.text().await.expect("no error receiving file"),
+        "first file"
+    );
+
+    let res = reqwest::get(url.join("dl/sub/secret.txt").expect("valid url"))
+        .await
+        .expect("no error with reqwest");
+    assert_eq!(res.status(), StatusCode::FORBIDDEN);
+
+    let res = reqwest::get(url.join("arc/sub").expect("valid url"))
+        .await
+        .expect("no error with reqwest");
+    assert_eq!(res.status(), StatusCode::OK);
+    let bytes = res.bytes().await.expect("no error receiving archive");
+    let archive = zip::ZipArchive::new(Cursor::new(bytes)).expect("archive was a valid zip");
+    let mut names: Vec<_> = archive.file_names().map(ToOwned::to_owned).collect();
+    names.sort();
+    assert_eq!(names, ["sub/", "sub/a.txt", "sub/alias.txt"]);
+}
+
+#[cfg(unix)]
+#[test]
+fn followed_symlinks_stay_inside_data_dir() {
+    start_test(followed_symlinks_stay_inside_data_dir_impl());
+}
+
+async fn thumbnails_are_made_and_cached_impl() {
+    let dir = tempfile::tempdir().expect("could not create tempdir for data");
+    image::RgbImage::new(100, 50)
+        .save(dir.path().join("wide.png"))
+        .expect("failed writing picture");
+    std::fs::write(dir.path().join("a.txt"), "first file").expect("failed writing file");
+    let thumbnail_dir = tempfile::tempdir().expect("could not create tempdir for thumbnails");
+    let thumbnail_path = Utf8Path::from_path(thumbnail_dir.path())
+        .expect("temp path was not UTF-8")
+        .to_path_buf();
+
+    let SpawnInfo {
+        ref url,
+        dir: ref _tempdir,
+        shutdown: _,
+    } = spawn_app_with(dir, |config| {
+        config.thumbnail_cache_dir = Some(thumbnail_path);
+    })
+    .await;
+
+    for _ in 0..2 {
+        let res = reqwest::get(url.join("thumb/wide.png?w=20").expect("valid url"))
+            .await
+            .expect("no error with reqwest");
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(
+            res.headers()
+                .get("Content-Type")
+                .expect("thumbnail has a content type"),
+            "image/jpeg"
+        );
+        let data = res.bytes().await.expect("no error receiving thumbnail");
+        let thumbnail = image::load_from_memory(&data).expect("thumbnail was not a picture");
+        assert_eq!((thumbnail.width(), thumbnail.height()), (20, 10));
+
+        let cached = std::fs::read_dir(thumbnail_dir.path())
+            .expect("failed reading thumbnail dir")
+            .count();
+        assert_eq!(cached, 1);
+    }
+
+    let res = reqwest::get(url.join("thumb/wide.png?format=webp").expect("valid url"))
+        .await
+        .expect("no error with reqwest");
+    assert_eq!(res.status(), StatusCode::OK);
+    let data = res.bytes().await.expect("no error receiving thumbnail");
+    assert_eq!(
+        image::guess_format(&data).expect("thumbnail was not a picture"),
+        image::ImageFormat::WebP
+    );
+
+    for path in ["thumb/a.txt", "thumb/missing.png"] {
+        let res = reqwest::get(url.join(path).expect("valid url"))
+            .await
+            .expect("no error with reqwest");
+        assert_eq!(res.status(), StatusCode::NOT_FOUND, "{path}");
+    }
+}
+
+#[test]
+fn thumbnails_are_made_and_cached() {
+    start_test(thumbnails_are_made_and_cached_impl());
+}
+
+async fn archive_members_can_be_downloaded_impl() {
+    let dir = tempfile::tempdir().expect("could not create tempdir for data");
+    std::fs::create_dir(dir.path().join("docs")).expect("failed creating dir");
+
+    let mut zip = zip::ZipWriter::new(
+        std::fs::File::create(dir.path().join("docs/bundle.zip")).expect("failed creating zip"),
+    );
+    let options = zip::write::SimpleFileOptions::default();
+    zip.start_file("a.txt", options)
+        .expect("failed adding file to zip");
+    zip.write_all(b"first file")
+        .expect("failed writing file to zip");
+    zip.add_directory("dir/", options)
+        .expect("failed adding dir to zip");
+    zip.start_file("dir/b.txt", options)
+        .expect("failed adding file to zip");
+    zip.write_all(b"second")
+        .expect("failed writing file to zip");
+    zip.finish().expect("failed finishing zip");
+
+    let mut tar = tar::Builder::new(
+        std::fs::File::create(dir.path().join("docs/bundle.tar")).expect("failed creating tar"),
+    );
+    let mut header = tar::Header::new_gnu();
+    header.set_size(5);
+    header.set_mode(0o644);
+    header.set_cksum();
+    tar.append_data(&mut header, "c.txt", &b"third"[..])
+        .expect("failed adding file to tar");
+    tar.finish().expect("failed finishing tar");
+    std::fs::write(dir.path().join("docs/notes.txt"), "not an archive")
+        .expect("failed writing file");
+
+    let SpawnInfo {
+        ref url,
+        dir: ref _tempdir,
+        shutdown: _,
+    } = spawn_app(dir).await;
+
+    let res = reqwest::get(url.join("browse/docs/").expect("valid url"))
+        .await
+        .expect("no error with reqwest");
+    let content = res.text().await.expect("no error receiving html");
+    assert!(content.contains("href=\"/browse-archive/docs/bundle.zip\""));
+    assert!(content.contains("href=\"/browse-archive/docs/bundle.tar\""));
+    assert!(!content.contains("/browse-archive/docs/notes.txt"));
+
+    let res = reqwest::get(
+        url.join("browse-archive/docs/bundle.zip")
+            .expect("valid url"),
+    )
+    .await
+    .expect("no error with reqwest");
+    assert_eq!(res.status(), StatusCode::OK);
+    let content = res.text().await.expect("no error receiving html");
+    assert!(content.contains("?member=a.txt"));
+    assert!(content.contains("?member=dir/b.txt"));
+
+    for (path, contents) in [
+        ("browse-archive/docs/bundle.zip?member=a.txt", "first file"),
+        ("browse-archive/docs/bundle.zip?member=dir/b.txt", "second"),
+        ("browse-archive/docs/bundle.tar?member=c.txt", "third"),
+    ] {
+        let res = reqwest::get(url.join(path).expect("valid url"))
+            .await
+            .expect("no error with reqwest");
+        assert_eq!(res.status(), StatusCode::OK, "{path}");
+        let disposition = res.headers()["Content-Disposition"]
+            .to_str()
+            .expect("disposition is text")
+            .to_owned();
+        assert!(disposition.starts_with("attachment"), "{disposition}");
+        assert_eq!(res.text().await.expect("no error receiving file"), contents);
+    }
+
+    for path in [
+        "browse-archive/docs/bundle.zip?member=missing.txt",
+        "browse-archive/docs/bundle.zip?member=dir/",
+        "browse-archive/docs/bundle.tar?member=a.txt",
+        "browse-archive/docs/notes.txt",
+        "browse-archive/docs/missing.zip",
+    ] {
+        let res = reqwest::get(url.join(path).expect("valid url"))
+            .await
+            .expect("no error with reqwest");
+        assert_eq!(res.status(), StatusCode::NOT_FOUND, "{path}");
+    }
+}
+
+#[test]
+fn archive_members_can_be_downloaded() {
+    start_test(archive_members_can_be_downloaded_impl());
+}
+
+async fn torrents_have_web_seeds_impl() {
+    use sha1::{Digest as _, Sha1};
+
+    let dir = tempfile::tempdir().expect("could not create tempdir for data");
+    std::fs::create_dir_all(dir.path().join("docs/sub")).expect("failed creating dirs");
+    std::fs::write(dir.path().join("docs/a.txt"), "first file").expect("failed writing file");
+    std::fs::write(dir.path().join("docs/sub/b.txt"), "second file").expect("failed writing file");
+
+    let SpawnInfo {
+        ref url,
+        dir: ref _tempdir,
+        shutdown: _,
+    } = spawn_app(dir).await;
+    let torrent = |path: &'static str| async move {
+        // The pieces are hashed in the background the first time
+        let mut tries = 0;
+        loop {
+            let res = reqwest::get(url.join(path).expect("valid url"))
+                .await
+                .expect("no error with reqwest");
+            if res.status() != StatusCode::SERVICE_UNAVAILABLE || tries == 50 {
+                assert_eq!(res.status(), StatusCode::OK);
+                assert_eq!(res.headers()["content-type"], "application/x-bittorrent");
+                return res.bytes().await.expect("no error receiving torrent");
+            }
+            assert!(res.headers().contains_key("retry-after"));
+            tries += 1;
+            tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+        }
+    };
+
+    let pieces = Sha1::digest("first filesecond file");
+    let mut expected = b"d10:created by4:sfsb4:infod5:filesl\
+        d6:lengthi10e4:pathl5:a.txtee\
+        d6:lengthi11e4:pathl3:sub5:b.txtee\
+        e4:name4:docs12:piece lengthi262144e6:pieces20:"
+        .to_vec();
+    expected.extend_from_slice(&pieces);
+    expected.extend_from_slice(b"e8:url-list20:http://localhost/dl/e");
+    assert_eq!(torrent("torrent/docs").await, expected);
+
+    let pieces = Sha1::digest("second file");
+    let mut expected =
+        b"d10:created by4:sfsb4:infod6:lengthi11e4:name5:b.txt12:piece lengthi262144e6:pieces20:"
+            .to_vec();
+    expected.extend_from_slice(&pieces);
+    expected.extend_from_slice(b"e8:url-list34:http://localhost/dl/docs/sub/b.txte");
+    assert_eq!(torrent("torrent/docs/sub/b.txt").await, expected);
+
+    let res = reqwest::get(url.join("torrent/missing").expect("valid url"))
+        .await
+        .expect("no error with reqwest");
+    assert_eq!(res.status(), StatusCode::NOT_FOUND);
+}
+
+#[test]
+fn torrents_have_web_seeds() {
+    start_test(torrents_have_web_seeds_impl());
+}
+
+async fn checksum_manifests_can_be_checked_impl() {
+    use md5::Md5;
+    use sha2::{Digest as _, Sha256};
+
+    let dir = tempfile::tempdir().expect("could not create tempdir for data");
+    std::fs::create_dir_all(dir.path().join("sub/nested")).expect("failed creating dirs");
+    std::fs::write(dir.path().join("top.txt"), "top file").expect("failed writing file");
+    std::fs::write(dir.path().join("sub/a.txt"), "first file").expect("failed writing file");
+    std::fs::write(dir.path().join("sub/nested/b.txt"), "second file")
+        .expect("failed writing file");
+
+    let SpawnInfo {
+        ref url,
+        dir: ref _tempdir,
+        shutdown: _,
+    } = spawn_app_with(dir, |config| config.hash_files = true).await;
+    let manifest = |path: &'static str| async move {
+        // Files are hashed in the background after startup
+        let mut tries = 0;
+        loop {
+            let res = reqwest::get(url.join(path).expect("valid url"))
+                .await
+                .expect("no error with reqwest");
+            if res.status() != StatusCode::SERVICE_UNAVAILABLE || tries == 50 {
+                assert_eq!(res.status(), StatusCode::OK, "{path}");
+                return res.text().await.expect("no error receiving manifest");
+            }
+            tries += 1;
+            tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+        }
+    };
+
+    assert_eq!(
+        manifest("sha256sum/sub").await,
+        format!(
+            "{:x}  a.txt\n{:x}  nested/b.txt\n",
+            Sha256::digest("first file"),
+            Sha256::digest("second file")
+        )
+    );
+    assert_eq!(
+        manifest("md5sum/sub/").await,
+        format!(
+            "{:x}  a.txt\n{:x}  nested/b.txt\n",
+            Md5::digest("first file"),
+            Md5::digest("second file")
+        )
+    );
+    assert_eq!(
+        manifest("sha256sum/sub/nested/b.txt").await,
+        format!("{:x}  b.txt\n", Sha256::digest("second file"))
+    );
+    assert_eq!(
+        manifest("md5sum").await,
+        format!(
+            "{:x}  sub/a.txt\n{:x}  sub/nested/b.txt\n{:x}  top.txt\n",
+            Md5::digest("first file"),
+            Md5::digest("second file"),
+            Md5::digest("top file")
+        )
+    );
+
+    let res = reqwest::get(url.join("sha256sum/missing").expect("valid url"))
+        .await
+        .expect("no error with reqwest");
+    assert_eq!(res.status(), StatusCode::NOT_FOUND);
+}
+
+#[test]
+fn checksum_manifests_can_be_checked() {
+    start_test(checksum_manifests_can_be_checked_impl());
+}
+
+async fn zsync_files_have_block_sums_impl() {
+    use md4::{Digest as _, Md4};
+    use sha1::Sha1;
+
+    let dir = tempfile::tempdir().expect("could not create tempdir for data");
+    // Two blocks, the last one padded
+    let contents: Vec<u8> = (0..3000).map(|i| (i % 251) as u8).collect();
+    std::fs::write(dir.path().join("big.bin"), &contents).expect("failed writing file");
+
+    let SpawnInfo {
+        ref url,
+        dir: ref _tempdir,
+        shutdown: _,
+    } = spawn_app(dir).await;
+    // The blocks are hashed in the background the first time
+    let mut tries = 0;
+    let res = loop {
+        let res = reqwest::get(url.join("zsync/big.bin").expect("valid url"))
+            .await
+            .expect("no error with reqwest");
+        if res.status() != StatusCode::SERVICE_UNAVAILABLE || tries == 50 {
+            break res;
+        }
+        assert!(res.headers().contains_key("retry-after"));
+        tries += 1;
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+    };
+    assert_eq!(res.status(), StatusCode::OK);
+    assert_eq!(res.headers()["content-type"], "application/x-zsync");
+    let body = res.bytes().await.expect("no error receiving zsync file");
+
+    let split = body
+        .windows(2)
+        .position(|w| w == b"\n\n")
+        .expect("header ends in a blank line");
+    let header = std::str::from_utf8(&body[..split]).expect("header is UTF-8");
+    let lines: Vec<_> = header.lines().filter(|l| !l.starts_with("MTime")).collect();
+    let sha1 = format!("{:x}", Sha1::digest(&contents));
+    assert_eq!(
+        lines,
+        [
+            "zsync: 0.6.2",
+            "Filename: big.bin",
+            "Blocksize: 2048",
+            "Length: 3000",
+            "Hash-Lengths: 2,2,3",
+            "URL: http://localhost/dl/big.bin",
+            &format!("SHA-1: {sha1}"),
+        ]
+    );
+
+    let mut last = contents[2048..].to_vec();
+    last.resize(2048, 0);
+    let sums = &body[split + 2..];
+    assert_eq!(sums.len(), 2 * (2 + 3));
+    assert_eq!(sums[2..5], Md4::digest(&contents[..2048])[..3]);
+    assert_eq!(sums[7..10], Md4::digest(&last)[..3]);
+
+    let res = reqwest::get(url.join("zsync/missing").expect("valid url"))
+        .await
+        .expect("no error with reqwest");
+    assert_eq!(res.status(), StatusCode::NOT_FOUND);
+}
+
+#[test]
+fn zsync_files_have_block_sums() {
+    start_test(zsync_files_have_block_sums_impl());
+}
+
+async fn tokens_are_required_when_configured_impl() {
+    let dir = tempfile::tempdir().expect("could not create tempdir for data");
+    std::fs::create_dir_all(dir.path().join("photos")).expect("failed creating dirs");
+    std::fs::write(dir.path().join("photos/a.txt"), "photo").expect("failed writing file");
+    std::fs::write(dir.path().join("secret.txt"), "secret").expect("failed writing file");
+
+    let SpawnInfo {
+        ref url,
+        dir: ref _tempdir,
+        shutdown: _,
+    } = spawn_app_with(dir, |config| {
+        config.api_tokens = vec![
+            "admin".parse().expect("valid token"),
+            "viewer:photos".parse().expect("valid token"),
+        ];
+    })
+    .await;
+    let client = reqwest::Client::new();
+    let get = |path: &str, token: Option<&str>| {
+        let request = client.get(url.join(path).expect("valid url"));
+        let request = match token {
+            Some(token) => request.bearer_auth(token),
+            None => request,
+        };
+        async move { request.send().await.expect("no error with reqwest") }
+    };
+
+    let res = get("dl/secret.txt", None).await;
+    assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+    assert_eq!(res.headers()["www-authenticate"], "Bearer");
+    assert_eq!(
+        get("dl/secret.txt", Some("wrong")).await.status(),
+        StatusCode::UNAUTHORIZED
+    );
+    // Styles and icons don't show anything from the data dir
+    assert_eq!(get("favicon.svg", None).await.status(), StatusCode::OK);
+
+    let res = get("dl/secret.txt", Some("admin")).await;
+    assert_eq!(res.status(), StatusCode::OK);
+    assert_eq!(res.text().await.expect("no error receiving file"), "secret");
+    assert_eq!(get("browse/", Some("admin")).await.status(), StatusCode::OK);
+
+    assert_eq!(
+        get("dl/photos/a.txt", Some("viewer")).await.status(),
+        StatusCode::OK
+    );
+    assert_eq!(
+        get("browse/photos/", Some("viewer")).await.status(),
+        StatusCode::OK
+    );
+    for path in ["dl/secret.txt", "browse/", "search?q=secret"] {
+        assert_eq!(
+            get(path, Some("viewer")).await.status(),
+            StatusCode::FORBIDDEN,
+            "{path}"
+        );
+    }
+
+    // Tools that can't set headers use the query, which browsers then keep in a cookie
+    let res = get("dl/photos/a.txt?token=viewer", None).await;
+    assert_eq!(res.status(), StatusCode::OK);
+    let cookie = res.headers()["set-cookie"]
+        .to_str()
+        .expect("cookie is a string")
+        .to_owned();
+    assert!(cookie.starts_with("token=viewer;"), "{cookie}");
+    let res = client
+        .get(url.join("browse/photos/").expect("valid url"))
+        .header("Cookie", "token=viewer")
+        .send()
+        .await
+        .expect("no error with reqwest");
+    assert_eq!(res.status(), StatusCode::OK);
+}
+
+#[test]
+fn tokens_are_required_when_configured() {
+    start_test(tokens_are_required_when_configured_impl());
+}
+
+async fn invalid_tokens_are_logged_impl() {
+    let dir = tempfile::tempdir().expect("could not create tempdir for data");
+    std::fs::write(dir.path().join("logged.txt"), "secret").expect("failed writing file");
+    std::fs::write(dir.path().join("not-logged.txt"), "secret").expect("failed writing file");
+    captured_logs();
+
+    let SpawnInfo {
+        ref url,
+        dir: ref _tempdir,
+        shutdown: _,
+    } = spawn_app_with(dir, |config| {
+        config.api_tokens = vec!["admin".parse().expect("valid token")];
+    })
+    .await;
+    let client = reqwest::Client::new();
+    let res = client
+        .get(url.join("dl/logged.txt").expect("valid url"))
+        .bearer_auth("wrong")
+        .send()
+        .await
+        .expect("no error with reqwest");
+    assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+    assert_eq!(res.headers()["www-authenticate"], "Bearer");
+    let res = client
+        .get(url.join("dl/not-logged.txt").expect("valid url"))
+        .bearer_auth("admin")
+        .send()
+        .await
+        .expect("no error with reqwest");
+    assert_eq!(res.status(), StatusCode::OK);
+
+    let logs = captured_logs();
+    // What fail2ban matches with `failregex = Failed authentication from <HOST> for`
+    let line = logs
+        .lines()
+        .find(|l| l.contains("/dl/logged.txt"))
+        .unwrap_or_else(|| panic!("invalid token was not logged: {logs}"));
+    assert!(line.contains("sfsb::auth_failures"), "{line}");
+    assert!(
+        line.ends_with(
+            r#"Failed authentication from 127.0.0.1 for "/dl/logged.txt": invalid token"#
+        ),
+        "{line}"
+    );
+    assert!(!logs.contains("/dl/not-logged.txt"), "{logs}");
+}
+
+#[test]
+fn invalid_tokens_are_logged() {
+    start_test(invalid_tokens_are_logged_impl());
+}
+
+async fn clients_behind_trusted_proxies_are_logged_impl() {
+    let dir = tempfile::tempdir().expect("could not create tempdir for data");
+    captured_logs();
+
+    let SpawnInfo {
+        ref url,
+        dir: ref _tempdir,
+        shutdown: _,
+    } = spawn_app_with(dir, |config| {
+        config.api_tokens = vec!["admin".parse().expect("valid token")];
+        config.trusted_proxies = vec![
+            "127.0.0.0/8".parse().expect("valid proxy"),
+            "10.0.0.0/8".parse().expect("valid proxy"),
+        ];
+    })
+    .await;
+    let client = reqwest::Client::new();
+    let cases = [
+        ("dl/proxied-xff", "X-Forwarded-For", "203.0.113.7"),
+        // The nearest address that isn't another proxy
+        (
+            "dl/proxied-chain",
+            "X-Forwarded-For",
+            "198.51.100.1, 203.0.113.8, 10.1.2.3",
+        ),
+        (
+            "dl/proxied-forwarded",
+            "Forwarded",
+            r#"for=192.0.2.60;proto=http, for="[2001:db8::1]:4711""#,
+        ),
+        // Can't tell who it was, so it's the proxy
+        ("dl/proxied-unknown", "Forwarded", "for=_hidden"),
+    ];
+    for (path, header, value) in cases {
+        let res = client
+            .get(url.join(path).expect("valid url"))
+            .bearer_auth("wrong")
+            .header(header, value)
+            .send()
+            .await
+            .expect("no error with reqwest");
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+    }
+
+    let logs = captured_logs();
+    let line = |path: &str| {
+        logs.lines()
+            .find(|l| l.contains(&format!("\"/{path}\"")))
+            .unwrap_or_else(|| panic!("invalid token was not logged: {logs}"))
+            .to_owned()
+    };
+    assert!(
+        line("dl/proxied-xff").contains("from 203.0.113.7 for"),
+        "{logs}"
+    );
+    assert!(
+        line("dl/proxied-chain").contains("from 203.0.113.8 for"),
+        "{logs}"
+    );
+    assert!(
+        line("dl/proxied-forwarded").contains("from 2001:db8::1 for"),
+        "{logs}"
+    );
+    assert!(
+        line("dl/proxied-unknown").contains("from 127.0.0.1 for"),
+        "{logs}"
+    );
+}
+
+#[test]
+fn clients_behind_trusted_proxies_are_logged() {
+    start_test(clients_behind_trusted_proxies_are_logged_impl());
+}
+
+async fn forwarding_headers_are_ignored_from_untrusted_peers_impl() {
+    let dir = tempfile::tempdir().expect("could not create tempdir for data");
+    captured_logs();
+
+    let SpawnInfo {
+        ref url,
+        dir: ref _tempdir,
+        shutdown: _,
+    } = spawn_app_with(dir, |config| {
+        config.api_tokens = vec!["admin".parse().expect("valid token")];
+        config.trusted_proxies = vec!["10.0.0.0/8".parse().expect("valid proxy")];
+    })
+    .await;
+    let res = reqwest::Client::new()
+        .get(url.join("dl/spoofed").expect("valid url"))
+        .bearer_auth("wrong")
+        .header("X-Forwarded-For", "203.0.113.9")
+        .send()
+        .await
+        .expect("no error with reqwest");
+    assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+
+    let logs = captured_logs();
+    let line = logs
+        .lines()
+        .find(|l| l.contains(r#""/dl/spoofed""#))
+        .unwrap_or_else(|| panic!("invalid token was not logged: {logs}"));
+    assert!(line.contains("from 127.0.0.1 for"), "{line}");
+}
+
+#[test]
+fn forwarding_headers_are_ignored_from_untrusted_peers() {
+    start_test(forwarding_headers_are_ignored_from_untrusted_peers_impl());
+}
+
+async fn access_rules_hide_restricted_directories_impl() {
+    let dir = tempfile::tempdir().expect("could not create tempdir for data");
+    std::fs::create_dir_all(dir.path().join("docs/private")).expect("failed creating dirs");
+    std::fs::write(dir.path().join("docs/a.txt"), "public").expect("failed writing file");
+    std::fs::write(dir.path().join("docs/private/b.txt"), "private").expect("failed writing file");
+
+    let SpawnInfo {
+        ref url,
+        dir: ref _tempdir,
+        shutdown: _,
+    } = spawn_app_with(dir, |config| {
+        config.api_tokens = vec!["admin".parse().expect("valid token")];
+        config.access_rules = vec![
+            "/ => public".parse().expect("valid rule"),
+            "docs/private => admin".parse().expect("valid rule"),
+        ];
+    })
+    .await;
+    let client = reqwest::Client::new();
+    let get = |path: &str, token: Option<&str>| {
+        let request = client.get(url.join(path).expect("valid url"));
+        let request = match token {
+            Some(token) => request.bearer_auth(token),
+            None => request,
+        };
+        async move { request.send().await.expect("no error with reqwest") }
+    };
+    let archive_names = |res: reqwest::Response| async move {
+        assert_eq!(res.status(), StatusCode::OK);
+        let bytes = res.bytes().await.expect("no error receiving archive");
+        let archive = zip::ZipArchive::new(Cursor::new(bytes)).expect("archive was a valid zip");
+        let mut names: Vec<_> = archive.file_names().map(ToOwned::to_owned).collect();
+        names.sort();
+        names
+    };
+
+    assert_eq!(get("dl/docs/a.txt", None).await.status(), StatusCode::OK);
+    assert_eq!(
+        get("dl/docs/private/b.txt", None).await.status(),
+        StatusCode::UNAUTHORIZED
+    );
+    assert_eq!(
+        get("dl/docs/private/b.txt", Some("admin")).await.status(),
+        StatusCode::OK
+    );
+    // Stats could show anything, so only those who can reach everything can see them
+    assert_eq!(
+        get("api/stats/files", None).await.status(),
+        StatusCode::UNAUTHORIZED
+    );
+    let search = get("search?q=b.txt", None)
+        .await
+        .text()
+        .await
+        .expect("no error receiving search");
+    assert!(!search.contains("private"), "{search}");
+
+    let listing = get("browse/docs/", None)
+        .await
+        .text()
+        .await
+        .expect("no error receiving listing");
+    assert!(listing.contains("a.txt"));
+    assert!(!listing.contains("private"), "{listing}");
+    let listing = get("browse/docs/", Some("admin"))
+        .await
+        .text()
+        .await
+        .expect("no error receiving listing");
+    assert!(listing.contains("private"));
+
+    assert_eq!(
+        archive_names(get("arc/docs", None).await).await,
+        ["docs/", "docs/a.txt"]
+    );
+    assert_eq!(
+        archive_names(get("arc/docs", Some("admin")).await).await,
+        ["docs/", "docs/a.txt", "docs/private/", "docs/private/b.txt"]
+    );
+}
+
+#[test]
+fn access_rules_hide_restricted_directories() {
+    start_test(access_rules_hide_restricted_directories_impl());
+}
+
+async fn links_to_public_paths_have_qr_codes_and_oembed_impl() {
+    let dir = tempfile::tempdir().expect("could not create tempdir for data");
+    std::fs::create_dir_all(dir.path().join("docs/private")).expect("failed creating dirs");
+    std::fs::write(dir.path().join("docs/a.txt"), "public").expect("failed writing file");
+    std::fs::write(dir.path().join("docs/private/b.txt"), "private").expect("failed writing file");
+
+    let SpawnInfo {
+        ref url,
+        dir: ref _tempdir,
+        shutdown: _,
+    } = spawn_app_with(dir, |config| {
+        config.api_tokens = vec!["admin".parse().expect("valid token")];
+        // Everything is public but a single directory
+        config.access_rules = vec![
+            "/ => public".parse().expect("valid rule"),
+            "docs/private => admin".parse().expect("valid rule"),
+        ];
+    })
+    .await;
+    let client = reqwest::Client::new();
+    let get = |path: &str, query: (&str, &str), token: Option<&str>| {
+        let request = client
+            .get(url.join(path).expect("valid url"))
+            .query(&[query]);
+        let request = match token {
+            Some(token) => request.bearer_auth(token),
+            None => request,
+        };
+        async move { request.send().await.expect("no error with reqwest") }
+    };
+
+    for target in [
+        "/browse/docs/",
+        "/dl/docs/a.txt",
+        "http://localhost/dl/docs/a.txt",
+    ] {
+        let res = get("qr", ("target", target), None).await;
+        assert_eq!(res.status(), StatusCode::OK, "{target}");
+        assert_eq!(res.headers()["Content-Type"], "image/svg+xml");
+    }
+    let res = get("oembed", ("url", "http://localhost/view/docs/a.txt"), None).await;
+    assert_eq!(res.status(), StatusCode::OK);
+    let oembed: serde_json::Value = res.json().await.expect("oembed is json");
+    assert!(
+        oembed["title"]
+            .as_str()
+            .is_some_and(|t| t.starts_with("a.txt")),
+        "{oembed}"
+    );
+
+    // Not even whether they exist is given away
+    for target in ["/dl/docs/private/b.txt", "/browse/docs/private/../private/"] {
+        let res = get("qr", ("target", target), None).await;
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED, "{target}");
+        let res = get("qr", ("target", target), Some("admin")).await;
+        assert_eq!(res.status(), StatusCode::OK, "{target}");
+    }
+    let private = ("url", "http://localhost/dl/docs/private/b.txt");
+    assert_eq!(
+        get("oembed", private, None).await.status(),
+        StatusCode::UNAUTHORIZED
+    );
+    assert_eq!(
+        get("oembed", private, Some("admin")).await.status(),
+        StatusCode::OK
+    );
+}
+
+#[test]
+fn links_to_public_paths_have_qr_codes_and_oembed() {
+    start_test(links_to_public_paths_have_qr_codes_and_oembed_impl());
+}
+
+async fn other_sites_can_download_with_cors_impl() {
+    let dir = tempfile::tempdir().expect("could not create tempdir for data");
+    std::fs::write(dir.path().join("a.txt"), "file").expect("failed writing file");
+
+    let SpawnInfo {
+        ref url,
+        dir: ref _tempdir,
+        shutdown: _,
+    } = spawn_app_with(dir, |config| {
+        config.cors.origins = vec!["https://example.com".to_owned()];
+        config.api_tokens = vec!["secret".parse().expect("valid token")];
+    })
+    .await;
+    let client = reqwest::Client::new();
+    let file_url = url.join("dl/a.txt").expect("valid url");
+
+    // Preflight requests can't have the token
+    let res = client
+        .request(reqwest::Method::OPTIONS, file_url.clone())
+        .header("Origin", "https://example.com")
+        .header("Access-Control-Request-Method", "GET")
+        .header("Access-Control-Request-Headers", "authorization")
+        .send()
+        .await
+        .expect("no error with reqwest");
+    assert_eq!(res.status(), StatusCode::OK);
+    let headers = res.headers();
+    assert_eq!(
+        headers["access-control-allow-origin"],
+        "https://example.com"
+    );
+    assert!(headers["access-control-allow-headers"]
+        .to_str()
+        .expect("header is a string")
+        .to_lowercase()
+        .contains("authorization"));
+
+    let res = client
+        .get(file_url.clone())
+        .header("Origin", "https://example.com")
+        .bearer_auth("secret")
+        .send()
+        .await
+        .expect("no error with reqwest");
+    assert_eq!(res.status(), StatusCode::OK);
+    let headers = res.headers();
+    assert_eq!(
+        headers["access-control-allow-origin"],
+        "https://example.com"
+    );
+    assert!(headers["access-control-expose-headers"]
+        .to_str()
+        .expect("header is a string")
+        .contains("content-range"));
+
+    let res = client
+        .get(file_url)
+        .header("Origin", "https://other.example.com")
+        .bearer_auth("secret")
+        .send()
+        .await
+        .expect("no error with reqwest");
+    assert!(!res.headers().contains_key("access-control-allow-origin"));
+}
+
+#[test]
+fn other_sites_can_download_with_cors() {
+    start_test(other_sites_can_download_with_cors_impl());
+}
+
+async fn downloads_stay_inside_the_data_dir_impl() {
+    use std::os::unix::fs::symlink;
+
+    let outside = tempfile::tempdir().expect("could not create tempdir outside data");
+    std::fs::write(outside.path().join("secret.txt"), "secret").expect("failed writing file");
+    let dir = tempfile::tempdir().expect("could not create tempdir for data");
+    std::fs::create_dir_all(dir.path().join("sub")).expect("failed creating dirs");
+    std::fs::write(dir.path().join("a.js"), "plain").expect("failed writing file");
+    symlink(
+        outside.path().join("secret.txt"),
+        dir.path().join("a.js.gz"),
+    )
+    .expect("failed creating symlink");
+
+    let SpawnInfo {
+        ref url,
+        dir: ref _tempdir,
+        shutdown: _,
+    } = spawn_app(dir).await;
+    let client = reqwest::Client::new();
+
+    // Encoded slashes get past the url parser, but not the path normalisation
+    let escape = format!(
+        "dl/sub%2F..%2F..%2F{}%2Fsecret.txt",
+        outside
+            .path()
+            .file_name()
+            .and_then(|n| n.to_str())
+            .expect("tempdir has a name")
+    );
+    let res = client
+        .get(url.join(&escape).expect("valid url"))
+        .send()
+        .await
+        .expect("no error with reqwest");
+    assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+
+    // Precompressed variants that are symlinks could point anywhere
+    let res = client
+        .get(url.join("dl/a.js").expect("valid url"))
+        .header("Accept-Encoding", "gzip")
+        .send()
+        .await
+        .expect("no error with reqwest");
+    assert_eq!(res.status(), StatusCode::OK);
+    assert!(!res.headers().contains_key("content-encoding"));
+    assert_eq!(res.text().await.expect("no error receiving file"), "plain");
+
+    // Files that aren't in the cache can't be downloaded either
+    let res = client
+        .get(url.join("dl/missing.txt").expect("valid url"))
+        .send()
+        .await
+        .expect("no error with reqwest");
+    assert_eq!(res.status(), StatusCode::NOT_FOUND);
+}
+
+#[test]
+fn downloads_stay_inside_the_data_dir() {
+    start_test(downloads_stay_inside_the_data_dir_impl());
+}
+
+async fn unlisted_directories_are_only_reached_directly_impl() {
+    let dir = tempfile::tempdir().expect("could not create tempdir for data");
+    std::fs::create_dir_all(dir.path().join("docs/secret")).expect("failed creating dirs");
+    std::fs::write(dir.path().join("docs/a.txt"), "public").expect("failed writing file");
+    std::fs::write(dir.path().join("docs/secret/b.txt"), "secret").expect("failed writing file");
+
+    let SpawnInfo {
+        ref url,
+        dir: ref _tempdir,
+        shutdown: _,
+    } = spawn_app_with(dir, |config| {
+        config.api_tokens = vec!["friend".parse().expect("valid token")];
+        config.access_rules = vec![
+            "/ => public".parse().expect("valid rule"),
+            "docs/secret => unlisted *".parse().expect("valid rule"),
+        ];
+    })
+    .await;
+    let client = reqwest::Client::new();
+    let get = |path: &str, token: Option<&str>| {
+        let request = client.get(url.join(path).expect("valid url"));
+        let request = match token {
+            Some(token) => request.bearer_auth(token),
+            None => request,
+        };
+        async move { request.send().await.expect("no error with reqwest") }
+    };
+    let text = |res: reqwest::Response| async move {
+        assert_eq!(res.status(), StatusCode::OK);
+        res.text().await.expect("no error receiving body")
+    };
+
+    // Not even those who can reach it see it in the directory it's in
+    for token in [None, Some("friend")] {
+        let listing = text(get("browse/docs/", token).await).await;
+        assert!(listing.contains("a.txt"));
+        assert!(!listing.contains("secret"), "{listing}");
+        let aria2 = text(get("browse/docs/?aria2", token).await).await;
+        assert!(!aria2.contains("b.txt"), "{aria2}");
+        let search = text(get("search?q=b.txt", token).await).await;
+        assert!(!search.contains("secret"), "{search}");
+    }
+
+    assert_eq!(
+        get("dl/docs/secret/b.txt", None).await.status(),
+        StatusCode::UNAUTHORIZED
+    );
+    let listing = text(get("browse/docs/secret/", Some("friend")).await).await;
+    assert!(listing.contains("b.txt"));
+    assert_eq!(
+        text(get("dl/docs/secret/b.txt", Some("friend")).await).await,
+        "secret"
+    );
+}
+
+#[test]
+fn unlisted_directories_are_only_reached_directly() {
+    start_test(unlisted_directories_are_only_reached_directly_impl());
+}
+
+async fn downloads_are_written_to_the_audit_log_impl() {
+    let dir = tempfile::tempdir().expect("could not create tempdir for data");
+    std::fs::write(dir.path().join("a.txt"), "some text").expect("failed writing file");
+    let log_dir = tempfile::tempdir().expect("could not create tempdir for the audit log");
+    let log_path = Utf8Path::from_path(log_dir.path())
+        .expect("tempdir is utf-8")
+        .join("audit.log");
+
+    let SpawnInfo {
+        ref url,
+        dir: ref _tempdir,
+        shutdown: _,
+    } = spawn_app_with(dir, |config| config.audit_log = Some(log_path.clone())).await;
+
+    let res = reqwest::Client::new()
+        .get(url.join("dl/a.txt").expect("valid url"))
+        .header("Range", "bytes=0-3")
+        .send()
+        .await
+        .expect("no error with reqwest");
+    assert_eq!(res.status(), StatusCode::PARTIAL_CONTENT);
+    assert_eq!(res.text().await.expect("no error receiving body"), "some");
+
+    // The record is written from another thread once the body is finished
+    let mut log = String::new();
+    for _ in 0..20 {
+        log = std::fs::read_to_string(&log_path).expect("audit log exists");
+        if !log.is_empty() {
+            break;
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+    }
+    assert_eq!(log.lines().count(), 1, "{log}");
+    assert!(log.contains(r#""path":"/dl/a.txt""#), "{log}");
+    assert!(log.contains(r#""status":206"#), "{log}");
+    assert!(log.contains(r#""range":"bytes=0-3""#), "{log}");
+    assert!(log.contains(r#""bytes":4"#), "{log}");
+    assert!(log.contains(r#""client":"127.0.0.1""#), "{log}");
+
+    // Pages aren't downloads
+    reqwest::get(url.join("browse/").expect("valid url"))
+        .await
+        .expect("no error with reqwest");
+    tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+    let log = std::fs::read_to_string(&log_path).expect("audit log exists");
+    assert_eq!(log.lines().count(), 1, "{log}");
+}
+
+#[test]
+fn downloads_are_written_to_the_audit_log() {
+    start_test(downloads_are_written_to_the_audit_log_impl());
+}
+
+/// Lines of the audit log at `path`, once there are `count` of them
+async fn wait_for_audit_lines(path: &Utf8Path, count: usize) -> Vec<String> {
+    let mut tries = 0;
+    loop {
+        // It's gone for a moment while it's being rotated
+        let log = std::fs::read_to_string(path).unwrap_or_default();
+        let lines: Vec<_> = log.lines().map(ToOwned::to_owned).collect();
+        if lines.len() >= count {
+            return lines;
+        }
+        tries += 1;
+        assert!(
+            tries < 50,
+            "audit log only had {} lines: {log}",
+            lines.len()
+        );
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+    }
+}
+
+async fn refused_downloads_are_written_to_the_audit_log_impl() {
+    let dir = tempfile::tempdir().expect("could not create tempdir for data");
+    std::fs::create_dir_all(dir.path().join("photos")).expect("failed creating dirs");
+    std::fs::write(dir.path().join("secret.txt"), "secret").expect("failed writing file");
+    let log_dir = tempfile::tempdir().expect("could not create tempdir for the audit log");
+    let log_path = Utf8Path::from_path(log_dir.path())
+        .expect("tempdir is utf-8")
+        .join("audit.log");
+
+    let SpawnInfo {
+        ref url,
+        dir: ref _tempdir,
+        shutdown: _,
+    } = spawn_app_with(dir, |config| {
+        config.audit_log = Some(log_path.clone());
+        config.api_tokens = vec!["viewer:photos".parse().expect("valid token")];
+    })
+    .await;
+    let client = reqwest::Client::new();
+
+    let res = client
+        .get(url.join("dl/secret.txt").expect("valid url"))
+        .send()
+        .await
+        .expect("no error with reqwest");
+    assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+    res.bytes().await.expect("no error receiving body");
+    let lines = wait_for_audit_lines(&log_path, 1).await;
+    assert!(lines[0].contains(r#""path":"/dl/secret.txt""#), "{lines:?}");
+    assert!(lines[0].contains(r#""status":401"#), "{lines:?}");
+
+    let res = client
+        .get(url.join("arc/").expect("valid url"))
+        .bearer_auth("viewer")
+        .send()
+        .await
+        .expect("no error with reqwest");
+    assert_eq!(res.status(), StatusCode::FORBIDDEN);
+    res.bytes().await.expect("no error receiving body");
+    let lines = wait_for_audit_lines(&log_path, 2).await;
+    assert!(lines[1].contains(r#""path":"/arc/""#), "{lines:?}");
+    assert!(lines[1].contains(r#""status":403"#), "{lines:?}");
+    // The token was valid, it just can't reach the whole data dir
+    assert!(!lines[1].contains(r#""token":null"#), "{lines:?}");
+    assert!(lines[0].contains(r#""token":null"#), "{lines:?}");
+}
+
+#[test]
+fn refused_downloads_are_written_to_the_audit_log() {
+    start_test(refused_downloads_are_written_to_the_audit_log_impl());
+}
+
+async fn audit_log_has_who_made_the_download_impl() {
+    use sha2::{Digest as _, Sha256};
+
+    let dir = tempfile::tempdir().expect("could not create tempdir for data");
+    std::fs::write(dir.path().join("a.txt"), "some text").expect("failed writing file");
+    let log_dir = tempfile::tempdir().expect("could not create tempdir for the audit log");
+    let log_path = Utf8Path::from_path(log_dir.path())
+        .expect("tempdir is utf-8")
+        .join("audit.log");
+
+    let SpawnInfo {
+        ref url,
+        dir: ref _tempdir,
+        shutdown: _,
+    } = spawn_app_with(dir, |config| {
+        config.audit_log = Some(log_path.clone());
+        config.api_tokens = vec!["admin-secret".parse().expect("valid token")];
+        config.trusted_proxies = vec!["127.0.0.1".parse().expect("valid proxy")];
+    })
+    .await;
+
+    let res = reqwest::Client::new()
+        .get(url.join("dl/a.txt").expect("valid url"))
+        .bearer_auth("admin-secret")
+        .header("X-Forwarded-For", "203.0.113.5")
+        .send()
+        .await
+        .expect("no error with reqwest");
+    assert_eq!(res.status(), StatusCode::OK);
+    res.bytes().await.expect("no error receiving body");
+
+    let lines = wait_for_audit_lines(&log_path, 1).await;
+    let record: serde_json::Value =
+        serde_json::from_str(&lines[0]).expect("record is a line of json");
+    assert_eq!(record["client"], "203.0.113.5", "{record}");
+    let fingerprint = format!("{:x}", Sha256::digest(b"admin-secret"));
+    assert_eq!(record["token"], fingerprint[..12], "{record}");
+    // Tokens are secrets, they're told apart without writing them down
+    assert!(!lines[0].contains("admin-secret"), "{record}");
+}
+
+#[test]
+fn audit_log_has_who_made_the_download() {
+    start_test(audit_log_has_who_made_the_download_impl());
+}
+
+async fn audit_log_is_rotated_impl() {
+    let dir = tempfile::tempdir().expect("could not create tempdir for data");
+    std::fs::write(dir.path().join("a.txt"), "some text").expect("failed writing file");
+    let log_dir = tempfile::tempdir().expect("could not create tempdir for the audit log");
+    let log_path = Utf8Path::from_path(log_dir.path())
+        .expect("tempdir is utf-8")
+        .join("audit.log");
+    let rotated_path = log_dir.path().join("audit.log.1");
+
+    let SpawnInfo {
+        ref url,
+        dir: ref _tempdir,
+        shutdown: _,
+    } = spawn_app_with(dir, |config| {
+        config.audit_log = Some(log_path.clone());
+        // Room for a couple of records
+        config.audit_log_max_bytes = 400;
+    })
+    .await;
+
+    let mut rotated = false;
+    for _ in 0..10 {
+        let res = reqwest::get(url.join("dl/a.txt").expect("valid url"))
+            .await
+            .expect("no error with reqwest");
+        assert_eq!(res.status(), StatusCode::OK);
+        res.bytes().await.expect("no error receiving body");
+
+        let lines = wait_for_audit_lines(&log_path, 1).await;
+        assert!(
+            lines.iter().map(|l| l.len() + 1).sum::<usize>() <= 400,
+            "{lines:?}"
+        );
+        if rotated_path.exists() {
+            rotated = true;
+            break;
+        }
+    }
+    assert!(rotated, "audit log was never rotated");
+
+    let old = std::fs::read_to_string(&rotated_path).expect("rotated log exists");
+    assert!(!old.is_empty());
+    assert!(old.len() <= 400, "{old}");
+    for line in old.lines() {
+        let record: serde_json::Value =
+            serde_json::from_str(line).expect("rotated records are whole lines of json");
+        assert_eq!(record["status"], 200, "{line}");
+    }
+}
+
+#[test]
+fn audit_log_is_rotated() {
+    start_test(audit_log_is_rotated_impl());
+}
+
+async fn saved_checksums_are_used_after_restarts_impl() {
+    let dir = tempfile::tempdir().expect("could not create tempdir for data");
+    std::fs::write(dir.path().join("a.txt"), "first file").expect("failed writing file");
+    let sums_dir = tempfile::tempdir().expect("could not create tempdir for checksums");
+    let sums_path = Utf8Path::from_path(sums_dir.path())
+        .expect("tempdir is utf-8")
+        .join("checksums.json");
+
+    // Made up digests, which are only served if they were loaded instead of hashing the file
+    let file_path = Utf8Path::from_path(dir.path())
+        .expect("tempdir is utf-8")
+        .join("a.txt");
+    let modified = std::fs::metadata(&file_path)
+        .and_then(|m| m.modified())
+        .expect("file has a modification time")
+        .duration_since(std::time::UNIX_EPOCH)
+        .expect("file was modified after the epoch")
+        .as_secs();
+    let saved = serde_json::json!({
+        file_path.as_str(): {
+            "size": 10,
+            "modified": modified,
+            "sha256": "feed",
+            "md5": "beef",
+        }
+    });
+    std::fs::write(&sums_path, saved.to_string()).expect("failed writing checksums");
+
+    let SpawnInfo {
+        ref url,
+        dir: ref _tempdir,
+        shutdown: _,
+    } = spawn_app_with(dir, |config| {
+        config.hash_files = true;
+        config.checksums_file = Some(sums_path.clone());
+    })
+    .await;
+
+    let res = reqwest::get(url.join("sha256sum/a.txt").expect("valid url"))
+        .await
+        .expect("no error with reqwest");
+    assert_eq!(res.status(), StatusCode::OK);
+    assert_eq!(
+        res.text().await.expect("no error receiving manifest"),
+        "feed  a.txt\n"
+    );
+}
+
+#[test]
+fn saved_checksums_are_used_after_restarts() {
+    start_test(saved_checksums_are_used_after_restarts_impl());
+}
+
+async fn files_are_only_hashed_when_asked_to_impl() {
+    let dir = tempfile::tempdir().expect("could not create tempdir for data");
+    std::fs::write(dir.path().join("a.txt"), "first file").expect("failed writing file");
+
+    let SpawnInfo {
+        ref url,
+        dir: ref _tempdir,
+        shutdown: _,
+    } = spawn_app(dir).await;
+
+    let res = reqwest::get(url.join("sha256sum/a.txt").expect("valid url"))
+        .await
+        .expect("no error with reqwest");
+    assert_eq!(res.status(), StatusCode::NOT_FOUND);
+    let res = reqwest::get(url.join("arc/?checksums=1").expect("valid url"))
+        .await
+        .expect("no error with reqwest");
+    assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+}
+
+#[test]
+fn files_are_only_hashed_when_asked_to() {
+    start_test(files_are_only_hashed_when_asked_to_impl());
+}
+
+async fn downloads_have_digest_headers_impl() {
+    use base64::{engine::general_purpose::STANDARD, Engine as _};
+    use md5::Md5;
+    use sha2::{Digest as _, Sha256};
+
+    let dir = tempfile::tempdir().expect("could not create tempdir for data");
+    std::fs::write(dir.path().join("a.txt"), "first file").expect("failed writing file");
+
+    let SpawnInfo {
+        ref url,
+        dir: ref _tempdir,
+        shutdown: _,
+    } = spawn_app_with(dir, |config| config.hash_files = true).await;
+    let client = reqwest::Client::new();
+    let file_url = url.join("dl/a.txt").expect("valid url");
+    let sha256 = STANDARD.encode(Sha256::digest("first file"));
+    let md5 = STANDARD.encode(Md5::digest("first file"));
+
+    // Files are hashed in the background after startup
+    let mut tries = 0;
+    let res = loop {
+        let res = reqwest::get(file_url.clone())
+            .await
+            .expect("no error with reqwest");
+        if res.headers().contains_key("Repr-Digest") || tries == 50 {
+            break res;
+        }
+        tries += 1;
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+    };
+    assert_eq!(res.headers()["Repr-Digest"], format!("sha-256=:{sha256}:"));
+    assert!(!res.headers().contains_key("Content-Digest"));
+    assert!(!res.headers().contains_key("Digest"));
+
+    let res = client
+        .get(file_url.clone())
+        .header("Want-Repr-Digest", "sha-256=1, md5=5")
+        .header("Want-Content-Digest", "sha-256=1")
+        .header("Want-Digest", "MD5;q=0.5, SHA-256")
+        .send()
+        .await
+        .expect("no error with reqwest");
+    assert_eq!(
+        res.headers()["Repr-Digest"],
+        format!("md5=:{md5}:, sha-256=:{sha256}:")
+    );
+    assert_eq!(
+        res.headers()["Content-Digest"],
+        format!("sha-256=:{sha256}:")
+    );
+    assert_eq!(
+        res.headers()["Digest"],
+        format!("SHA-256={sha256},MD5={md5}")
+    );
+
+    // Ranges have the digest of the whole file, but not of what was sent
+    let res = client
+        .get(file_url)
+        .header("Range", "bytes=0-4")
+        .header("Want-Content-Digest", "sha-256=1")
+        .send()
+        .await
+        .expect("no error with reqwest");
+    assert_eq!(res.status(), StatusCode::PARTIAL_CONTENT);
+    assert_eq!(res.headers()["Repr-Digest"], format!("sha-256=:{sha256}:"));
+    assert!(!res.headers().contains_key("Content-Digest"));
+}
+
+#[test]
+fn downloads_have_digest_headers() {
+    start_test(downloads_have_digest_headers_impl());
+}
+
+async fn disposition_can_be_configured_per_content_type_impl() {
+    let dir = tempfile::tempdir().expect("could not create tempdir for data");
+    std::fs::write(dir.path().join("a.txt"), "first file").expect("failed writing file");
+    std::fs::write(dir.path().join("b.png"), "not really a picture").expect("failed writing file");
+    std::fs::write(dir.path().join("c.pdf"), "not really a pdf").expect("failed writing file");
+    std::fs::write(dir.path().join("d.zip"), "not really a zip").expect("failed writing file");
+
+    let SpawnInfo {
+        ref url,
+        dir: ref _tempdir,
+        shutdown: _,
+    } = spawn_app_with(dir, |config| {
+        config.disposition = sfsb::Disposition::Inline;
+        config.disposition_overrides = vec![
+            "image=attachment".parse().expect("valid override"),
+            "application=attachment".parse().expect("valid override"),
+            "application/pdf=inline".parse().expect("valid override"),
+        ];
+    })
+    .await;
+
+    for (path, disposition) in [
+        ("dl/a.txt", "inline; filename=\"a.txt\""),
+        ("dl/b.png", "attachment; filename=\"b.png\""),
+        // Full content types win over top level ones
+        ("dl/c.pdf", "inline; filename=\"c.pdf\""),
+        ("dl/d.zip", "attachment; filename=\"d.zip\""),
+        ("dl/d.zip?inline=1", "inline; filename=\"d.zip\""),
+    ] {
+        let res = reqwest::get(url.join(path).expect("valid url"))
+            .await
+            .expect("no error with reqwest");
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(
+            res.headers()
+                .get("Content-Disposition")
+                .expect("file has a disposition"),
+            disposition,
+            "{path}"
+        );
+    }
+}
+
+#[test]
+fn disposition_can_be_configured_per_content_type() {
+    start_test(disposition_can_be_configured_per_content_type_impl());
+}
+
+async fn etags_stay_the_same_across_restarts_impl() {
+    let data = tempfile::tempdir().expect("could not create tempdir for data");
+    std::fs::write(data.path().join("a.txt"), "first file").expect("failed writing file");
+    let data_dir = sfsb::DataDir {
+        name: None,
+        path: Utf8Path::from_path(data.path())
+            .expect("tempdir is utf-8")
+            .to_owned(),
+        hidden: false,
+        preview_only: false,
+    };
+
+    let data_dir = &data_dir;
+    let etag = || async move {
+        // A new server every time, serving the same data dir
+        let empty = tempfile::tempdir().expect("could not create tempdir");
+        let SpawnInfo {
+            ref url,
+            dir: ref _tempdir,
+            shutdown: _,
+        } = spawn_app_with(empty, |config| config.data_dirs = vec![data_dir.clone()]).await;
+        let res = reqwest::get(url.join("dl/a.txt").expect("valid url"))
+            .await
+            .expect("no error with reqwest");
+        assert_eq!(res.status(), StatusCode::OK);
+        res.headers()
+            .get("ETag")
+            .expect("file has an ETag")
+            .to_str()
+            .expect("ETag is ASCII")
+            .to_owned()
+    };
+
+    let first = etag().await;
+    assert_eq!(etag().await, first);
+
+    let file = std::fs::File::options()
+        .write(true)
+        .open(data.path().join("a.txt"))
+        .expect("failed opening file");
+    file.set_modified(std::time::SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(1000))
+        .expect("failed changing modification time");
+    drop(file);
+    assert_ne!(etag().await, first);
+}
+
+#[test]
+fn etags_stay_the_same_across_restarts() {
+    start_test(etags_stay_the_same_across_restarts_impl());
+}
+
+async fn aborted_transfers_are_counted_apart_impl() {
+    const SIZE: usize = 16 * 1024 * 1024;
+
+    let dir = tempfile::tempdir().expect("could not create tempdir for data");
+    std::fs::write(dir.path().join("big.bin"), vec![7; SIZE]).expect("failed writing file");
+
+    let SpawnInfo {
+        ref url,
+        dir: ref _tempdir,
+        shutdown: _,
+    } = spawn_app(dir).await;
+    let file_url = url.join("dl/big.bin").expect("valid url");
+    let client = reqwest::Client::new();
+
+    let res = client
+        .get(file_url.clone())
+        .send()
+        .await
+        .expect("no error with reqwest");
+    assert_eq!(
+        res.bytes().await.expect("no error receiving file").len(),
+        SIZE
+    );
+    let res = client
+        .get(file_url.clone())
+        .header("Range", "bytes=0-9")
+        .send()
+        .await
+        .expect("no error with reqwest");
+    assert_eq!(
+        res.bytes().await.expect("no error receiving file").len(),
+        10
+    );
+
+    // Leaves after the first chunk, which closes the connection
+    let mut res = reqwest::Client::new()
+        .get(file_url)
+        .send()
+        .await
+        .expect("no error with reqwest");
+    res.chunk()
+        .await
+        .expect("no error receiving file")
+        .expect("file has a chunk");
+    drop(res);
+
+    // Transfers are recorded once the server notices they ended
+    let mut tries = 0;
+    let stats = loop {
+        let stats: serde_json::Value =
+            reqwest::get(url.join("api/stats/files").expect("valid url"))
+                .await
+                .expect("no error with reqwest")
+                .json()
+                .await
+                .expect("stats are json");
+        if stats["big.bin"]["aborted"] == 1 || tries == 50 {
+            break stats;
+        }
+        tries += 1;
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+    };
+    let file = &stats["big.bin"];
+    assert_eq!(file["completed"], 2, "{stats}");
+    assert_eq!(file["aborted"], 1, "{stats}");
+    let bytes_sent = file["bytes_sent"].as_u64().expect("bytes sent is a number");
+    assert!(bytes_sent > SIZE as u64 + 10, "{stats}");
+    assert!(bytes_sent < 2 * SIZE as u64, "{stats}");
+}
+
+#[test]
+fn aborted_transfers_are_counted_apart() {
+    start_test(aborted_transfers_are_counted_apart_impl());
+}
+
+async fn tar_gz_archive_can_be_unpacked_impl() {
+    let long_name = format!("{}.txt", "long-name-".repeat(15));
+    let dir = tempfile::tempdir().expect("could not create tempdir for data");
+    std::fs::create_dir_all(dir.path().join("sub/nested")).expect("failed creating dirs");
+    std::fs::write(dir.path().join("sub/a.txt"), "first file").expect("failed writing file");
+    std::fs::write(dir.path().join("sub/nested/b.txt"), "second file")
+        .expect("failed writing file");
+    std::fs::write(dir.path().join("sub/nested").join(&long_name), "long file")
+        .expect("failed writing file");
+
+    let SpawnInfo {
+        ref url,
+        dir: ref _tempdir,
+        shutdown: _,
+    } = spawn_app(dir).await;
+
+    let res = reqwest::get(url.join("arc/sub?format=tar.gz").expect("valid url"))
+        .await
+        .expect("no error with reqwest");
+    assert_eq!(res.status(), StatusCode::OK);
+    assert_eq!(res.headers()["Content-Type"], "application/gzip");
+    assert_eq!(
+        res.headers()["Content-Disposition"],
+        "attachment; filename=\"sub.tar.gz\""
+    );
+    let bytes = res.bytes().await.expect("no error receiving archive");
+
+    let mut archive = tar::Archive::new(flate2::read::GzDecoder::new(&bytes[..]));
+    let mut files = vec![];
+    for entry in archive.entries().expect("archive was a valid tar") {
+        let mut entry = entry.expect("entry was valid");
+        let path = entry
+            .path()
+            .expect("entry has a path")
+            .to_str()
+            .expect("path is utf-8")
+            .to_owned();
+        if entry.header().entry_type().is_dir() {
+            continue;
+        }
+        let size = entry.header().size().expect("entry has a size");
+        let mut contents = String::new();
+        entry
+            .read_to_string(&mut contents)
+            .expect("entry was readable");
+        files.push((path, size, contents));
+    }
+    files.sort();
+
+    // The long name doesn't fit in the header, so it's only right if the PAX record was read
+    assert!(format!("sub/nested/{long_name}").len() > 100);
+    assert_eq!(
+        files,
+        [
+            ("sub/a.txt".to_owned(), 10, "first file".to_owned()),
+            ("sub/nested/b.txt".to_owned(), 11, "second file".to_owned()),
+            (format!("sub/nested/{long_name}"), 9, "long file".to_owned()),
+        ]
+    );
+}
+
+#[test]
+fn tar_gz_archive_can_be_unpacked() {
+    start_test(tar_gz_archive_can_be_unpacked_impl());
+}
+
+async fn excluded_precompressed_files_are_not_served_impl() {
+    let dir = tempfile::tempdir().expect("could not create tempdir for data");
+    std::fs::write(dir.path().join("secret"), "plain").expect("failed writing file");
+    std::fs::write(dir.path().join("secret.gz"), "excluded by config")
+        .expect("failed writing file");
+    std::fs::write(dir.path().join("secret.br"), "excluded by ignore file")
+        .expect("failed writing file");
+    std::fs::write(dir.path().join(".sfsbignore"), "secret.br\n").expect("failed writing file");
+
+    let SpawnInfo {
+        ref url,
+        dir: ref _tempdir,
+        shutdown: _,
+    } = spawn_app_with(dir, |config| config.exclude = vec!["*.gz".to_owned()]).await;
+
+    for encoding in ["gzip", "br", "br, gzip"] {
+        let res = reqwest::Client::new()
+            .get(url.join("dl/secret").expect("valid url"))
+            .header("Accept-Encoding", encoding)
+            .send()
+            .await
+            .expect("no error with reqwest");
+        assert_eq!(res.status(), StatusCode::OK);
+        assert!(
+            res.headers().get("Content-Encoding").is_none(),
+            "{encoding}"
+        );
+        // Not even whether there are any is given away
+        assert!(res.headers().get("Vary").is_none(), "{encoding}");
+        assert_eq!(res.bytes().await.expect("no error receiving file"), "plain");
+    }
+}
+
+#[test]
+fn excluded_precompressed_files_are_not_served() {
+    start_test(excluded_precompressed_files_are_not_served_impl());
+}
+
+async fn files_in_large_and_deep_directories_are_found_impl() {
+    let dir = tempfile::tempdir().expect("could not create tempdir for data");
+    std::fs::create_dir(dir.path().join("big")).expect("failed creating dir");
+    for i in 0..20_000 {
+        std::fs::write(dir.path().join(format!("big/{i}.txt")), i.to_string())
+            .expect("failed writing file");
+    }
+    let deep: String = (0..64).map(|i| format!("d{i}/")).collect();
+    std::fs::create_dir_all(dir.path().join(&deep)).expect("failed creating dirs");
+    std::fs::write(dir.path().join(format!("{deep}file.txt")), "deep")
+        .expect("failed writing file");
+
+    let SpawnInfo {
+        ref url,
+        dir: ref _tempdir,
+        shutdown: _,
+    } = spawn_app(dir).await;
+
+    for i in [0, 9_999, 19_999] {
+        let res = reqwest::get(url.join(&format!("dl/big/{i}.txt")).expect("valid url"))
+            .await
+            .expect("no error with reqwest");
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(
+            res.text().await.expect("no error receiving file"),
+            i.to_string()
+        );
+    }
+    for missing in ["dl/big/20000.txt", "dl/big/0.txt/0.txt", "dl/big/0"] {
+        let res = reqwest::get(url.join(missing).expect("valid url"))
+            .await
+            .expect("no error with reqwest");
+        assert_eq!(res.status(), StatusCode::NOT_FOUND, "{missing}");
+    }
+
+    let res = reqwest::get(url.join(&format!("dl/{deep}file.txt")).expect("valid url"))
+        .await
+        .expect("no error with reqwest");
+    assert_eq!(res.status(), StatusCode::OK);
+    assert_eq!(res.text().await.expect("no error receiving file"), "deep");
+    let res = reqwest::get(url.join(&format!("dl/{deep}other.txt")).expect("valid url"))
+        .await
+        .expect("no error with reqwest");
+    assert_eq!(res.status(), StatusCode::NOT_FOUND);
+}
+
+#[test]
+fn files_in_large_and_deep_directories_are_found() {
+    start_test(files_in_large_and_deep_directories_are_found_impl());
+}
+
+async fn preview_only_data_dirs_are_not_downloaded_impl() {
+    let dir = tempfile::tempdir().expect("could not create tempdir for data");
+    std::fs::write(dir.path().join("a.txt"), "first file").expect("failed writing file");
+    let other_dir = tempfile::tempdir().expect("could not create tempdir for data");
+    std::fs::write(other_dir.path().join("b.txt"), "second file").expect("failed writing file");
+    let other_path = Utf8Path::from_path(other_dir.path())
+        .expect("temp path was not UTF-8")
+        .to_path_buf();
+    let preview_only: sfsb::DataDir = format!("music:preview-only={other_path}")
+        .parse()
+        .expect("valid data dir");
+
+    let SpawnInfo {
+        ref url,
+        dir: ref _tempdir,
+        shutdown: _,
+    } = spawn_app_with(dir, |config| {
+        config.data_dirs[0].name = Some("movies".to_owned());
+        config.data_dirs.push(preview_only);
+    })
+    .await;
+
+    for path in ["dl/music/b.txt", "arc/music", "arc/music?store=true"] {
+        let res = reqwest::get(url.join(path).expect("valid url"))
+            .await
+            .expect("no error with reqwest");
+        assert_eq!(res.status(), StatusCode::FORBIDDEN, "{path}");
+    }
+
+    // Previews still get it inline
+    let res = reqwest::get(url.join("dl/music/b.txt?inline").expect("valid url"))
+        .await
+        .expect("no error with reqwest");
+    assert_eq!(res.status(), StatusCode::OK);
+    assert_eq!(
+        res.text().await.expect("no error receiving body"),
+        "second file"
+    );
+
+    let res = reqwest::get(url.join("arc").expect("valid url"))
+        .await
+        .expect("no error with reqwest");
+    assert_eq!(res.status(), StatusCode::OK);
+    let bytes = res.bytes().await.expect("no error receiving archive");
+    let archive = zip::ZipArchive::new(Cursor::new(bytes)).expect("archive was a valid zip");
+    let names: Vec<_> = archive.file_names().collect();
+    assert!(names.contains(&"root/movies/a.txt"), "{names:?}");
+    assert!(
+        !names.iter().any(|n| n.starts_with("root/music")),
+        "{names:?}"
+    );
+}
+
+#[test]
+fn preview_only_data_dirs_are_not_downloaded() {
+    start_test(preview_only_data_dirs_are_not_downloaded_impl());
+}
+
+async fn token_quotas_refuse_downloads_past_them_impl() {
+    for (daily, total, refused) in [
+        (Some(15), None, StatusCode::TOO_MANY_REQUESTS),
+        (None, Some(15), StatusCode::FORBIDDEN),
+    ] {
+        let dir = tempfile::tempdir().expect("could not create tempdir for data");
+        std::fs::write(dir.path().join("a.txt"), "first file").expect("failed writing file");
+
+        let SpawnInfo {
+            ref url,
+            dir: ref _tempdir,
+            shutdown: _,
+        } = spawn_app_with(dir, |config| {
+            config.api_tokens = vec![
+                "first".parse().expect("valid token"),
+                "second".parse().expect("valid token"),
+            ];
+            config.token_daily_bytes = daily;
+            config.token_total_bytes = total;
+        })
+        .await;
+        let client = reqwest::Client::new();
+        let download = |token: &'static str| {
+            client
+                .get(url.join("dl/a.txt").expect("valid url"))
+                .bearer_auth(token)
+                .send()
+        };
+
+        // Both start under the quota, so they're sent whole even if it ends up over it
+        for _ in 0..2 {
+            let res = download("first").await.expect("no error with reqwest");
+            assert_eq!(res.status(), StatusCode::OK);
+            assert_eq!(
+                res.text().await.expect("no error receiving body"),
+                "first file"
+            );
+        }
+        let res = download("first").await.expect("no error with reqwest");
+        assert_eq!(res.status(), refused);
+        if daily.is_some() {
+            let retry_after: u64 = res.headers()["Retry-After"]
+                .to_str()
+                .expect("Retry-After is ASCII")
+                .parse()
+                .expect("Retry-After is in seconds");
+            assert!(retry_after <= 24 * 60 * 60);
+        }
+
+        // Listings don't count, and neither do other tokens
+        let res = client
+            .get(url.join("browse/").expect("valid url"))
+            .bearer_auth("first")
+            .send()
+            .await
+            .expect("no error with reqwest");
+        assert_eq!(res.status(), StatusCode::OK);
+        let res = download("second").await.expect("no error with reqwest");
+        assert_eq!(res.status(), StatusCode::OK);
+    }
+}
+
+#[test]
+fn token_quotas_refuse_downloads_past_them() {
+    start_test(token_quotas_refuse_downloads_past_them_impl());
+}
+
+async fn token_usage_is_kept_across_restarts_impl() {
+    use sha2::{Digest as _, Sha256};
+
+    let data = tempfile::tempdir().expect("could not create tempdir for data");
+    std::fs::write(data.path().join("a.txt"), "first file").expect("failed writing file");
+    let data_path = Utf8Path::from_path(data.path())
+        .expect("tempdir is utf-8")
+        .to_owned();
+    let usage_dir = tempfile::tempdir().expect("could not create tempdir for usage");
+    let usage_path = Utf8Path::from_path(usage_dir.path())
+        .expect("tempdir is utf-8")
+        .join("usage.json");
+
+    let spawn = || {
+        let data_path = data_path.clone();
+        let usage_path = usage_path.clone();
+        let empty = tempfile::tempdir().expect("could not create tempdir");
+        spawn_app_with(empty, move |config| {
+            config.data_dirs[0].path = data_path;
+            config.api_tokens = vec!["first".parse().expect("valid token")];
+            config.token_total_bytes = Some(10);
+            config.token_usage_file = Some(usage_path);
+        })
+    };
+    let download = |url: reqwest::Url| async move {
+        reqwest::Client::new()
+            .get(url.join("dl/a.txt").expect("valid url"))
+            .bearer_auth("first")
+            .send()
+            .await
+            .expect("no error with reqwest")
+    };
+
+    {
+        let SpawnInfo {
+            ref url,
+            dir: ref _tempdir,
+            shutdown: _,
+        } = spawn().await;
+        let res = download(url.clone()).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(
+            res.text().await.expect("no error receiving body"),
+            "first file"
+        );
+    }
+
+    // It's saved once the server stops
+    let mut tries = 0;
+    while !usage_path.exists() && tries < 50 {
+        tries += 1;
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+    }
+    let usage: serde_json::Value =
+        serde_json::from_slice(&std::fs::read(&usage_path).expect("usage was saved"))
+            .expect("usage is JSON");
+    let fingerprint = &format!("{:x}", Sha256::digest("first"))[..12];
+    assert_eq!(usage[fingerprint]["total_bytes"], 10, "{usage}");
+    assert!(!usage.to_string().contains("\"first\""), "{usage}");
+
+    let SpawnInfo {
+        ref url,
+        dir: ref _tempdir,
+        shutdown: _,
+    } = spawn().await;
+    let res = download(url.clone()).await;
+    assert_eq!(res.status(), StatusCode::FORBIDDEN);
+}
+
+#[test]
+fn token_usage_is_kept_across_restarts() {
+    start_test(token_usage_is_kept_across_restarts_impl());
+}